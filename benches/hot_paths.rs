@@ -0,0 +1,168 @@
+//! Benchmarks of the hot paths.
+//!
+//! Run with `cargo bench`,
+//! or e.g. `cargo bench -- debouncer` to run only some of them.
+//! Criterion keeps the results of the previous run in `target/criterion`
+//! and reports the change against it.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use base64::Engine as _;
+use criterion::{criterion_group, criterion_main, Criterion};
+use pgp::composed::{
+    KeyType, Message, SecretKeyParamsBuilder, SignedPublicKey, SubkeyParamsBuilder,
+};
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::ser::Serialize as _;
+
+use notifiers::bench_support::{Debouncer, PgpDecryptor};
+use notifiers::schedule::Schedule;
+use notifiers::server::NotificationToken;
+
+/// Number of threads of concurrent benchmarks,
+/// like the notifier workers sharing the debouncer.
+const THREADS: u64 = 8;
+
+fn bench_debouncer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("debouncer");
+    let now = Instant::now();
+
+    let debouncer = Debouncer::new(Duration::from_secs(1));
+    let mut i = 0u64;
+    group.bench_function("notify_distinct", |b| {
+        b.iter(|| {
+            i += 1;
+            debouncer.notify(now, format!("token-{i}"))
+        })
+    });
+
+    let debouncer = Debouncer::new(Duration::from_secs(1));
+    let mut i = 0u64;
+    group.bench_function("notify_repeated", |b| {
+        b.iter(|| {
+            i += 1;
+            debouncer.notify(now, format!("token-{}", i % 100))
+        })
+    });
+
+    // A single shard behaves like a debouncer with one global lock.
+    for shards in [1, 16] {
+        let debouncer = Debouncer::with_shards(Duration::from_secs(1), shards);
+        group.bench_function(format!("concurrent/{shards}_shards"), |b| {
+            // Measures the time until all threads have done `iters` notifications each,
+            // so the result is the time per notification of a single thread under contention.
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                std::thread::scope(|scope| {
+                    for thread in 0..THREADS {
+                        let debouncer = &debouncer;
+                        scope.spawn(move || {
+                            for i in 0..iters {
+                                let token = format!("token-{}", (thread * iters + i) % 10_000);
+                                black_box(debouncer.notify(Instant::now(), token));
+                            }
+                        });
+                    }
+                });
+                start.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_token_parsing(c: &mut Criterion) {
+    let tokens = [
+        (
+            "apns",
+            "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+        ),
+        (
+            "apns_sandbox",
+            "sandbox:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+        ),
+        (
+            "fcm",
+            "fcm-chat.delta:dQw4w9WgXcQ:APA91bHun4MxP5egoKMwt2KZFBaFUH-1RYqx",
+        ),
+        ("ubports", "ubports-chat.delta_deltatouch_1.0.0"),
+        (
+            "webpush",
+            "webpush:https://push.example.org/abc|BPublicKey|auth",
+        ),
+    ];
+    let mut group = c.benchmark_group("token/parse");
+    for (name, token) in tokens {
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(token).parse::<NotificationToken>().ok())
+        });
+    }
+    group.finish();
+}
+
+/// Generates a keyring and a token encrypted to it.
+fn encrypted_token(token: &str) -> Result<(String, String)> {
+    let mut rng = rand::thread_rng();
+    let params = SecretKeyParamsBuilder::default()
+        .key_type(KeyType::EdDSALegacy)
+        .can_certify(true)
+        .can_sign(true)
+        .primary_user_id("bench <bench@example.org>".into())
+        .subkeys(vec![SubkeyParamsBuilder::default()
+            .key_type(KeyType::ECDH(pgp::crypto::ecc_curve::ECCCurve::Curve25519))
+            .can_encrypt(true)
+            .build()?])
+        .build()?;
+    let secret_key = params.generate(&mut rng)?.sign(&mut rng, String::new)?;
+    let public_key = SignedPublicKey::from(secret_key.clone());
+    let message = Message::new_literal_bytes("", token.as_bytes()).encrypt_to_keys_seipdv1(
+        &mut rng,
+        SymmetricKeyAlgorithm::AES128,
+        &[&public_key.public_subkeys[0]],
+    )?;
+    let keyring = secret_key.to_armored_string(Default::default())?;
+    let encrypted = base64::engine::general_purpose::STANDARD.encode(message.to_bytes()?);
+    Ok((keyring, encrypted))
+}
+
+fn bench_openpgp(c: &mut Criterion) {
+    let (keyring, encrypted) = encrypted_token("0123456789abcdef").unwrap();
+    let decryptor = PgpDecryptor::new(&keyring).unwrap();
+    c.bench_function("openpgp/decrypt", |b| {
+        b.iter(|| decryptor.decrypt(black_box(&encrypted)).ok())
+    });
+}
+
+fn bench_schedule(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let schedule = Schedule::new(&dir.path().join("db.sled")).unwrap();
+    let mut group = c.benchmark_group("schedule");
+    let mut i = 0u64;
+    group.bench_function("insert", |b| {
+        b.iter(|| {
+            i += 1;
+            schedule.insert_token(&format!("token-{i}"), i).unwrap();
+        })
+    });
+    let mut i = 0u64;
+    group.bench_function("insert_remove", |b| {
+        b.iter(|| {
+            i += 1;
+            let token = format!("transient-{i}");
+            schedule.insert_token(&token, i).unwrap();
+            schedule.remove_token(&token).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_debouncer,
+    bench_token_parsing,
+    bench_openpgp,
+    bench_schedule
+);
+criterion_main!(benches);
@@ -0,0 +1,415 @@
+//! # Admin endpoints.
+//!
+//! All endpoints require an API key with the `admin` scope.
+
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::{Path, Query, State as AxumState};
+use axum::http::{header, StatusCode};
+use axum::middleware;
+use axum::response::IntoResponse;
+use axum::routing::{get, post, put};
+use axum::Json;
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::apikeys::{ApiKeyInfo, Scope};
+use crate::compression;
+use crate::flags::{Flag, FlagInfo};
+use crate::logging::redact;
+use crate::ratelimit::RateLimit;
+use crate::server::AppError;
+use crate::state::State;
+use crate::traces::{TraceInfo, MAX_TRACE_DURATION};
+
+pub(crate) fn router() -> axum::Router<State> {
+    axum::Router::new()
+        .route("/api-keys", get(list_api_keys))
+        .route("/api-keys/:name", put(put_api_key).delete(delete_api_key))
+        .route("/flags", get(list_flags))
+        .route("/debug-codes/:code", post(redeem_debug_code))
+        .route("/backup", post(backup))
+        .route("/drain", post(drain))
+        .route("/flags/:flag", put(put_flag))
+        .route("/relays", get(list_relays))
+        .route("/tokens", get(count_tokens))
+        .route("/traces/:id", get(get_trace).delete(stop_trace))
+        .route("/tokens/:hash", get(lookup_token).delete(remove_token))
+        .route(
+            "/relays/:relay/tokens",
+            get(list_relay_tokens).delete(purge_relay_tokens),
+        )
+        .layer(middleware::from_fn(compression::compress))
+}
+
+/// Default time for which a token is traced after redeeming its code.
+const DEFAULT_TRACE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Deserialize)]
+struct RedeemQuery {
+    /// Time for which the token is traced, e.g. `30m`.
+    #[serde(default)]
+    duration: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RedeemedCode {
+    /// ID of the trace.
+    trace: String,
+}
+
+/// Starts tracing the token of a debug code shown by a client app.
+async fn redeem_debug_code(
+    AxumState(state): AxumState<State>,
+    Path(code): Path<String>,
+    Query(query): Query<RedeemQuery>,
+) -> Result<Json<RedeemedCode>, StatusCode> {
+    let duration = match query.duration {
+        Some(duration) => {
+            humantime::parse_duration(&duration).map_err(|_| StatusCode::BAD_REQUEST)?
+        }
+        None => DEFAULT_TRACE_DURATION,
+    };
+    if duration.is_zero() || duration > MAX_TRACE_DURATION {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let trace = state
+        .traces()
+        .redeem(Instant::now(), &code, duration)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    info!(
+        "Tracing deliveries for {} as trace {trace}.",
+        humantime::format_duration(duration)
+    );
+    Ok(Json(RedeemedCode { trace }))
+}
+
+async fn get_trace(
+    AxumState(state): AxumState<State>,
+    Path(id): Path<String>,
+) -> Result<Json<TraceInfo>, StatusCode> {
+    state
+        .traces()
+        .get(Instant::now(), &id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn stop_trace(AxumState(state): AxumState<State>, Path(id): Path<String>) -> StatusCode {
+    if state.traces().stop(&id) {
+        info!("Stopped trace {id}.");
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Time `/admin/drain` waits for pending notifications.
+const DRAIN_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize)]
+struct DrainStatus {
+    /// No notifications are pending.
+    drained: bool,
+
+    /// Notifications queued, being delivered or waiting for a retry.
+    pending_notifications: usize,
+}
+
+/// Stops reporting the gateway as ready
+/// and waits until pending notifications are delivered.
+///
+/// Meant to be called from a Kubernetes `preStop` hook.
+async fn drain(AxumState(state): AxumState<State>) -> (StatusCode, Json<DrainStatus>) {
+    state.start_draining();
+    let drained = tokio::time::timeout(DRAIN_WAIT, state.queue().drained())
+        .await
+        .is_ok();
+    let status = DrainStatus {
+        drained,
+        pending_notifications: state.queue().pending_len(),
+    };
+    if drained {
+        (StatusCode::OK, Json(status))
+    } else {
+        warn!(
+            "{} notifications still pending after draining for {}.",
+            status.pending_notifications,
+            humantime::format_duration(DRAIN_WAIT)
+        );
+        (StatusCode::SERVICE_UNAVAILABLE, Json(status))
+    }
+}
+
+/// Removes the file when dropped.
+struct RemoveOnDrop(std::path::PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove backup {}: {err:#}.", self.0.display());
+            }
+        }
+    }
+}
+
+/// Returns a snapshot of the database as an SQLite database file.
+///
+/// The file can be restored with the `restore` subcommand.
+async fn backup(AxumState(state): AxumState<State>) -> Result<impl IntoResponse, AppError> {
+    let path = std::env::temp_dir().join(format!("notifiers-backup-{}.sqlite", Uuid::new_v4()));
+    let started = Instant::now();
+    let file = tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
+        // Removed on return, also on errors and panics,
+        // the open file is streamed after it is unlinked.
+        let path = RemoveOnDrop(path);
+        let copied = state.schedule().backup(&path.0)?;
+        let entries: usize = copied.values().sum();
+        info!(
+            "Backed up {entries} entries of {} trees in {}.",
+            copied.len(),
+            humantime::format_duration(started.elapsed())
+        );
+        Ok(std::fs::File::open(&path.0)?)
+    })
+    .await??;
+    let stream = ReaderStream::new(tokio::fs::File::from_std(file));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/vnd.sqlite3"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"notifiers-backup.sqlite\"",
+            ),
+        ],
+        Body::from_stream(stream),
+    ))
+}
+
+async fn list_api_keys(AxumState(state): AxumState<State>) -> Json<Vec<ApiKeyInfo>> {
+    Json(state.api_keys().list())
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyRequest {
+    key: String,
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+}
+
+/// Adds a runtime API key.
+///
+/// Responds with `409 Conflict` if a key with the name exists already.
+async fn put_api_key(
+    AxumState(state): AxumState<State>,
+    Path(name): Path<String>,
+    Json(request): Json<ApiKeyRequest>,
+) -> Result<StatusCode, AppError> {
+    if request.key.is_empty() {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+    if let Err(err) =
+        state
+            .api_keys()
+            .insert(&name, &request.key, request.scopes, request.rate_limit)
+    {
+        warn!("Failed to add API key {name:?}: {err:#}.");
+        return Ok(StatusCode::CONFLICT);
+    }
+    state.schedule().flush().await?;
+    info!("Added API key {name:?}.");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_api_key(
+    AxumState(state): AxumState<State>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    match state.api_keys().remove(&name) {
+        Ok(true) => {
+            state.schedule().flush().await?;
+            info!("Removed API key {name:?}.");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Ok(StatusCode::NOT_FOUND),
+        Err(err) => {
+            warn!("Failed to remove API key {name:?}: {err:#}.");
+            Ok(StatusCode::CONFLICT)
+        }
+    }
+}
+
+async fn list_flags(AxumState(state): AxumState<State>) -> Json<Vec<FlagInfo>> {
+    Json(state.flags().list())
+}
+
+#[derive(Debug, Deserialize)]
+struct FlagRequest {
+    enabled: bool,
+
+    /// Percentage of tokens to enable the flag for, 100 by default.
+    #[serde(default)]
+    percent: Option<u8>,
+}
+
+/// Enables or disables a feature flag.
+async fn put_flag(
+    AxumState(state): AxumState<State>,
+    Path(flag): Path<Flag>,
+    Json(request): Json<FlagRequest>,
+) -> Result<StatusCode, AppError> {
+    let percent = if request.enabled {
+        request.percent.unwrap_or(100)
+    } else {
+        0
+    };
+    if percent > 100 {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+    state.flags().set_percent(flag, percent)?;
+    state.schedule().flush().await?;
+    info!("Set feature flag {flag:?} to {percent}%.");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+struct RelayInfo {
+    relay: String,
+    tokens: usize,
+}
+
+/// Lists relays with registered heartbeat tokens.
+async fn list_relays(AxumState(state): AxumState<State>) -> Json<Vec<RelayInfo>> {
+    let relays = state
+        .schedule()
+        .owners()
+        .into_iter()
+        .map(|(relay, tokens)| RelayInfo { relay, tokens })
+        .collect();
+    Json(relays)
+}
+
+#[derive(Debug, Serialize)]
+struct TokenInfo {
+    /// Token, redacted unless `--log-full-tokens` is used.
+    token: String,
+    registered_at: u64,
+}
+
+/// Lists heartbeat tokens registered by the relay.
+async fn list_relay_tokens(
+    AxumState(state): AxumState<State>,
+    Path(relay): Path<String>,
+) -> Result<Json<Vec<TokenInfo>>, AppError> {
+    let tokens = state
+        .schedule()
+        .owner_tokens(&relay)?
+        .into_iter()
+        .map(|(token, provenance)| TokenInfo {
+            token: redact(&token).to_string(),
+            registered_at: provenance.registered_at,
+        })
+        .collect();
+    Ok(Json(tokens))
+}
+
+#[derive(Debug, Serialize)]
+struct PurgeResult {
+    removed: usize,
+}
+
+/// Removes all heartbeat tokens registered by the relay.
+async fn purge_relay_tokens(
+    AxumState(state): AxumState<State>,
+    Path(relay): Path<String>,
+) -> Result<Json<PurgeResult>, AppError> {
+    let schedule = state.schedule();
+    let removed = schedule.purge_owner(&relay)?;
+    schedule.flush().await?;
+    info!("Purged {removed} tokens registered by relay {relay:?}.");
+    Ok(Json(PurgeResult { removed }))
+}
+
+#[derive(Debug, Serialize)]
+struct TokenCount {
+    tokens: usize,
+    relays: usize,
+}
+
+/// Returns the number of registered heartbeat tokens.
+async fn count_tokens(AxumState(state): AxumState<State>) -> Json<TokenCount> {
+    let schedule = state.schedule();
+    Json(TokenCount {
+        tokens: schedule.stored_token_count(),
+        relays: schedule.owners().len(),
+    })
+}
+
+/// Decodes the hex-encoded SHA-256 hash of a token.
+fn parse_token_hash(hash: &str) -> Result<Vec<u8>, StatusCode> {
+    match hex::decode(hash) {
+        Ok(hash) if hash.len() == 32 => Ok(hash),
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TokenLookup {
+    /// Token, redacted unless `--log-full-tokens` is used.
+    token: String,
+
+    /// Unix timestamp of the last heartbeat or registration.
+    last_heartbeat: u64,
+
+    /// Relay that registered the token, if known.
+    relay: Option<String>,
+    registered_at: Option<u64>,
+}
+
+/// Looks up a heartbeat token by the SHA-256 hash
+/// of the token or of the token as sent by the relay.
+async fn lookup_token(
+    AxumState(state): AxumState<State>,
+    Path(hash): Path<String>,
+) -> Result<Result<Json<TokenLookup>, StatusCode>, AppError> {
+    let hash = match parse_token_hash(&hash) {
+        Ok(hash) => hash,
+        Err(status) => return Ok(Err(status)),
+    };
+    let schedule = state.schedule();
+    let Some((token, last_heartbeat)) = schedule.find_token(&hash)? else {
+        return Ok(Err(StatusCode::NOT_FOUND));
+    };
+    let provenance = schedule.lookup(&token)?.and_then(|entry| entry.provenance);
+    Ok(Ok(Json(TokenLookup {
+        token: redact(&token).to_string(),
+        last_heartbeat,
+        relay: provenance.as_ref().map(|p| p.relay.clone()),
+        registered_at: provenance.map(|p| p.registered_at),
+    })))
+}
+
+/// Removes a heartbeat token found by the SHA-256 hash
+/// of the token or of the token as sent by the relay.
+async fn remove_token(
+    AxumState(state): AxumState<State>,
+    Path(hash): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let hash = match parse_token_hash(&hash) {
+        Ok(hash) => hash,
+        Err(status) => return Ok(status),
+    };
+    let schedule = state.schedule();
+    let Some((token, _)) = schedule.find_token(&hash)? else {
+        return Ok(StatusCode::NOT_FOUND);
+    };
+    schedule.remove_token(&token)?;
+    schedule.flush().await?;
+    info!("Removed token {} on admin request.", redact(&token));
+    Ok(StatusCode::NO_CONTENT)
+}
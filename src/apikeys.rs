@@ -0,0 +1,360 @@
+//! # API keys.
+//!
+//! Callers authenticate with an `Authorization: Bearer <key>` header.
+//! Each key has a name, a set of scopes limiting the endpoints it can use
+//! and an optional rate limit.
+//!
+//! Keys are either defined in the configuration file
+//! or managed at runtime via the admin endpoints,
+//! in which case they are persisted in the database.
+//! Only SHA-256 hashes of the keys are kept.
+//!
+//! Requests without a key are accepted for all public endpoints
+//! as long as no keys exist, or only for the `anonymous_scopes`
+//! from the configuration file if it is set.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{bail, Context as _, Result};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::config::ApiKeyConfig;
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::schedule::storage::Tree;
+
+/// Prefix of relay identities of callers authenticated with an API key.
+pub(crate) const KEY_RELAY_PREFIX: &str = "key:";
+
+/// Returns the relay identity of callers using the API key `name`.
+pub(crate) fn key_relay_identity(name: &str) -> String {
+    format!("{KEY_RELAY_PREFIX}{name}")
+}
+
+/// Permission granted to an API key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Sending notifications with `/notify`.
+    Notify,
+
+    /// Registering heartbeat tokens with `/register`.
+    Register,
+
+    /// Using the admin endpoints.
+    Admin,
+}
+
+impl Scope {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Notify => "notify",
+            Self::Register => "register",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+/// Runtime key as stored in the database.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredKey {
+    /// Hex-encoded SHA-256 hash of the key.
+    hash: String,
+    scopes: Vec<Scope>,
+    rate_limit: Option<RateLimit>,
+}
+
+pub(crate) struct ApiKey {
+    name: String,
+    scopes: Vec<Scope>,
+    rate_limit: Option<RateLimit>,
+    limiter: Option<RateLimiter>,
+
+    /// True if the key is defined in the configuration file
+    /// and cannot be changed at runtime.
+    from_config: bool,
+}
+
+impl ApiKey {
+    fn new(
+        name: String,
+        scopes: Vec<Scope>,
+        rate_limit: Option<RateLimit>,
+        from_config: bool,
+    ) -> Self {
+        Self {
+            name,
+            scopes,
+            rate_limit,
+            limiter: rate_limit.map(RateLimiter::new),
+            from_config,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// Returns false if the key exceeded its rate limit.
+    pub(crate) fn check_rate_limit(&self, now: Instant) -> bool {
+        self.limiter
+            .as_ref()
+            .is_none_or(|limiter| limiter.check(now, ""))
+    }
+}
+
+/// Description of an API key returned by the admin endpoints.
+#[derive(Debug, Serialize)]
+pub(crate) struct ApiKeyInfo {
+    name: String,
+    scopes: Vec<Scope>,
+    rate_limit: Option<RateLimit>,
+    from_config: bool,
+}
+
+fn hash_key(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+pub(crate) struct ApiKeys {
+    /// Database tree with runtime keys by name.
+    tree: Arc<dyn Tree>,
+
+    /// All keys by key hash.
+    keys: RwLock<HashMap<[u8; 32], Arc<ApiKey>>>,
+
+    /// Scopes usable without a key, if configured.
+    anonymous_scopes: Option<Vec<Scope>>,
+}
+
+impl ApiKeys {
+    pub(crate) fn new(
+        tree: Arc<dyn Tree>,
+        configured: &[ApiKeyConfig],
+        anonymous_scopes: Option<Vec<Scope>>,
+    ) -> Result<Self> {
+        if anonymous_scopes
+            .as_ref()
+            .is_some_and(|scopes| scopes.contains(&Scope::Admin))
+        {
+            bail!("Admin scope cannot be anonymous");
+        }
+        let mut keys = HashMap::new();
+        for key in configured {
+            if keys.values().any(|k: &Arc<ApiKey>| k.name == key.name) {
+                bail!("Duplicate API key name {:?}", key.name);
+            }
+            let hash = hash_key(&key.key);
+            keys.insert(
+                hash,
+                Arc::new(ApiKey::new(
+                    key.name.clone(),
+                    key.scopes.clone(),
+                    key.rate_limit,
+                    true,
+                )),
+            );
+        }
+        for entry in tree.iter() {
+            let (name, value) = entry?;
+            let name = String::from_utf8(name.to_vec()).context("Invalid API key name")?;
+            let stored: StoredKey = serde_json::from_slice(&value)
+                .with_context(|| format!("Invalid stored API key {name:?}"))?;
+            let mut hash = [0; 32];
+            hex::decode_to_slice(&stored.hash, &mut hash)?;
+            keys.insert(
+                hash,
+                Arc::new(ApiKey::new(name, stored.scopes, stored.rate_limit, false)),
+            );
+        }
+        Ok(Self {
+            tree,
+            keys: RwLock::new(keys),
+            anonymous_scopes,
+        })
+    }
+
+    /// Returns true if requests without a key may use the scope.
+    pub(crate) fn allows_anonymous(&self, scope: Scope) -> bool {
+        match &self.anonymous_scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => scope != Scope::Admin && self.is_empty(),
+        }
+    }
+
+    /// Returns true if no API keys are configured.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keys.read().is_empty()
+    }
+
+    /// Looks up the API key.
+    pub(crate) fn authenticate(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.keys.read().get(&hash_key(key)).cloned()
+    }
+
+    pub(crate) fn list(&self) -> Vec<ApiKeyInfo> {
+        let mut res: Vec<ApiKeyInfo> = self
+            .keys
+            .read()
+            .values()
+            .map(|key| ApiKeyInfo {
+                name: key.name.clone(),
+                scopes: key.scopes.clone(),
+                rate_limit: key.rate_limit,
+                from_config: key.from_config,
+            })
+            .collect();
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        res
+    }
+
+    /// Adds a runtime API key.
+    ///
+    /// Fails if a key with the same name exists,
+    /// either in the configuration file or in the database.
+    pub(crate) fn insert(
+        &self,
+        name: &str,
+        key: &str,
+        scopes: Vec<Scope>,
+        rate_limit: Option<RateLimit>,
+    ) -> Result<()> {
+        let mut keys = self.keys.write();
+        if let Some(existing) = keys.values().find(|k| k.name == name) {
+            if existing.from_config {
+                bail!("API key {name:?} is defined in the configuration file");
+            }
+            bail!("API key {name:?} already exists");
+        }
+        let hash = hash_key(key);
+        if keys.contains_key(&hash) {
+            bail!("The same key is already used under another name");
+        }
+        let stored = StoredKey {
+            hash: hex::encode(hash),
+            scopes: scopes.clone(),
+            rate_limit,
+        };
+        self.tree
+            .insert(name.as_bytes(), &serde_json::to_vec(&stored)?)?;
+        keys.insert(
+            hash,
+            Arc::new(ApiKey::new(name.to_string(), scopes, rate_limit, false)),
+        );
+        Ok(())
+    }
+
+    /// Removes a runtime API key.
+    ///
+    /// Returns false if there is no such key.
+    pub(crate) fn remove(&self, name: &str) -> Result<bool> {
+        let mut keys = self.keys.write();
+        if keys.values().any(|k| k.name == name && k.from_config) {
+            bail!("API key {name:?} is defined in the configuration file");
+        }
+        let removed = self.tree.remove(name.as_bytes())?.is_some();
+        keys.retain(|_, k| k.name != name);
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    use crate::schedule::storage::{SledStorage, Storage as _};
+
+    #[test]
+    fn test_api_keys() -> Result<()> {
+        let dir = tempdir()?;
+        let db = SledStorage::open(&dir.path().join("db.sled"))?;
+        let configured = [ApiKeyConfig {
+            name: "operator".to_string(),
+            key: "admin-secret".to_string(),
+            scopes: vec![Scope::Admin],
+            rate_limit: None,
+            invalidation_url: None,
+        }];
+
+        let keys = ApiKeys::new(db.open_tree("api_keys")?, &configured, None)?;
+        assert!(!keys.is_empty());
+        assert!(!keys.allows_anonymous(Scope::Register));
+        let key = keys.authenticate("admin-secret").unwrap();
+        assert_eq!(key.name(), "operator");
+        assert!(key.has_scope(Scope::Admin));
+        assert!(!key.has_scope(Scope::Notify));
+        assert!(keys.authenticate("wrong").is_none());
+
+        keys.insert("relay", "relay-secret", vec![Scope::Notify], None)?;
+        assert!(keys
+            .authenticate("relay-secret")
+            .unwrap()
+            .has_scope(Scope::Notify));
+        assert!(keys
+            .insert("operator", "new-secret", vec![Scope::Admin], None)
+            .is_err());
+
+        // Names are unique across configured and runtime keys.
+        assert!(keys
+            .insert("relay", "relay-secret2", vec![Scope::Notify], None)
+            .is_err());
+        assert!(keys.authenticate("relay-secret2").is_none());
+
+        // Replacing the key invalidates the old secret.
+        assert!(keys.remove("relay")?);
+        keys.insert("relay", "relay-secret2", vec![Scope::Notify], None)?;
+        assert!(keys.authenticate("relay-secret").is_none());
+        assert_eq!(keys.list().len(), 2);
+
+        // Runtime keys are persisted.
+        let keys = ApiKeys::new(db.open_tree("api_keys")?, &configured, None)?;
+        assert!(keys.authenticate("relay-secret2").is_some());
+
+        assert!(keys.remove("relay")?);
+        assert!(!keys.remove("relay")?);
+        assert!(keys.authenticate("relay-secret2").is_none());
+        assert!(keys.remove("operator").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_anonymous_scopes() -> Result<()> {
+        let dir = tempdir()?;
+        let db = SledStorage::open(&dir.path().join("db.sled"))?;
+
+        let keys = ApiKeys::new(db.open_tree("api_keys")?, &[], None)?;
+        assert!(keys.allows_anonymous(Scope::Notify));
+        assert!(keys.allows_anonymous(Scope::Register));
+        assert!(!keys.allows_anonymous(Scope::Admin));
+
+        // Only `/notify` requires a key.
+        let keys = ApiKeys::new(db.open_tree("api_keys")?, &[], Some(vec![Scope::Register]))?;
+        assert!(!keys.allows_anonymous(Scope::Notify));
+        assert!(keys.allows_anonymous(Scope::Register));
+
+        assert!(ApiKeys::new(db.open_tree("api_keys")?, &[], Some(vec![Scope::Admin])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_api_key_rate_limit() {
+        let key = ApiKey::new(
+            "relay".to_string(),
+            vec![Scope::Notify],
+            Some("1/1h".parse().unwrap()),
+            true,
+        );
+        let now = Instant::now();
+        assert!(key.check_rate_limit(now));
+        assert!(!key.check_rate_limit(now));
+    }
+}
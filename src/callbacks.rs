@@ -0,0 +1,209 @@
+//! # Token invalidation callbacks.
+//!
+//! When a heartbeat token is removed because the provider reported it invalid,
+//! the relay that registered the token is notified with a POST request
+//! to its configured invalidation URL,
+//! so it can drop the token immediately.
+//!
+//! The JSON body contains the token exactly as the relay registered it,
+//! i.e. still encrypted if it was encrypted.
+//! The body is signed with HMAC-SHA256 and the signature is sent
+//! as `X-Notifiers-Signature: sha256=<hex>`.
+//! API key relays are verified with their API key,
+//! anonymous relays with the configured `invalidation_secret`.
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use hmac::{Hmac, Mac as _};
+use log::*;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::apikeys::{key_relay_identity, KEY_RELAY_PREFIX};
+use crate::config::Config;
+use crate::logging::redact;
+use crate::metrics::{CallbackLabels, Metrics};
+use crate::schedule::Provenance;
+
+/// Header carrying the body signature.
+pub(crate) const SIGNATURE_HEADER: &str = "x-notifiers-signature";
+
+#[derive(Debug, Clone)]
+struct Target {
+    url: String,
+    secret: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Invalidation<'a> {
+    /// Token as registered by the relay.
+    token: &'a str,
+
+    /// Reason reported by the provider, e.g. `BadDeviceToken`.
+    reason: &'a str,
+
+    /// Unix timestamp of the removal.
+    timestamp: u64,
+}
+
+pub(crate) struct Callbacks {
+    client: reqwest::Client,
+
+    /// Invalidation targets by relay identity.
+    targets: HashMap<String, Target>,
+
+    /// Target for relays without an API key.
+    anonymous: Option<Target>,
+}
+
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+impl Callbacks {
+    pub(crate) fn new(config: &Config, client: reqwest::Client) -> Self {
+        let targets = config
+            .api_keys
+            .iter()
+            .filter_map(|key| {
+                let url = key.invalidation_url.clone()?;
+                let target = Target {
+                    url,
+                    secret: key.key.clone(),
+                };
+                Some((key_relay_identity(&key.name), target))
+            })
+            .collect();
+        let anonymous = config
+            .invalidation_url
+            .clone()
+            .zip(config.invalidation_secret.clone())
+            .map(|(url, secret)| Target { url, secret });
+        Self {
+            client,
+            targets,
+            anonymous,
+        }
+    }
+
+    fn target(&self, relay: &str) -> Option<&Target> {
+        if relay.starts_with(KEY_RELAY_PREFIX) {
+            self.targets.get(relay)
+        } else {
+            self.anonymous.as_ref()
+        }
+    }
+
+    /// Notifies the relay that registered the removed token in the background.
+    pub(crate) fn token_removed(
+        &self,
+        token: &str,
+        provenance: Option<Provenance>,
+        reason: &str,
+        metrics: &Metrics,
+    ) {
+        let Some(provenance) = provenance else {
+            return;
+        };
+        let Some(target) = self.target(&provenance.relay) else {
+            return;
+        };
+        let invalidation = Invalidation {
+            token: provenance.registered_token.as_deref().unwrap_or(token),
+            reason,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let body = match serde_json::to_vec(&invalidation) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Failed to serialize invalidation callback: {err:#}.");
+                return;
+            }
+        };
+        let signature = format!("sha256={}", sign(&target.secret, &body));
+        let request = self
+            .client
+            .post(&target.url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .body(body);
+        let relay = provenance.relay;
+        let redacted = redact(token).to_string();
+        let counter = metrics.invalidation_callbacks_total.clone();
+        tokio::task::spawn(async move {
+            let res: Result<()> = async {
+                request.send().await?.error_for_status()?;
+                Ok(())
+            }
+            .await;
+            let result = match res {
+                Ok(()) => {
+                    debug!("Notified relay {relay} about removal of {redacted}.");
+                    "delivered"
+                }
+                Err(err) => {
+                    warn!("Failed to notify relay {relay} about removal of {redacted}: {err:#}.");
+                    "failed"
+                }
+            };
+            counter
+                .get_or_create(&CallbackLabels {
+                    result: result.to_string(),
+                })
+                .inc();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_target() -> Result<()> {
+        let config = Config::parse(
+            r#"
+invalidation_url = "https://anonymous.example.org/invalidated"
+invalidation_secret = "anonymous-secret"
+
+[[api_keys]]
+name = "relay1"
+key = "secret1"
+scopes = ["register"]
+invalidation_url = "https://relay1.example.org/invalidated"
+
+[[api_keys]]
+name = "relay2"
+key = "secret2"
+scopes = ["register"]
+"#,
+        )?;
+        let callbacks = Callbacks::new(&config, reqwest::Client::new());
+        let target = callbacks.target("key:relay1").unwrap();
+        assert_eq!(target.url, "https://relay1.example.org/invalidated");
+        assert_eq!(target.secret, "secret1");
+        assert!(callbacks.target("key:relay2").is_none());
+        assert_eq!(
+            callbacks.target("192.0.2.1").unwrap().secret,
+            "anonymous-secret"
+        );
+        Ok(())
+    }
+}
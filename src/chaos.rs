@@ -0,0 +1,228 @@
+//! # Fault injection.
+//!
+//! For exercising the delivery queue, retries and outage detection in staging,
+//! the gateway can delay provider requests and fail some of them on purpose.
+//! Faults are configured in the `[chaos]` section of the configuration file
+//! and only injected if the gateway is started with `--chaos`.
+//!
+//! Injected failures are answered with the configured status
+//! without sending anything to the provider.
+//! They are counted in `failures_total` with the reason `chaos`
+//! and recorded as failed requests for outage detection.
+
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use axum::async_trait;
+use axum::http::StatusCode;
+use log::*;
+use rand::Rng as _;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::metrics::{FailureLabels, NotificationKind, NotificationProvider};
+use crate::provider::{Delivery, Disposition, Provider};
+use crate::server::{NotificationToken, NotifyOptions};
+use crate::state::State;
+
+/// Fault injection settings defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChaosConfig {
+    /// Maximum delay added to provider requests in milliseconds.
+    ///
+    /// Each request is delayed by a random time up to this value.
+    #[serde(default)]
+    pub max_delay_ms: u64,
+
+    /// Share of provider requests failing, between 0 and 1.
+    #[serde(default)]
+    pub error_rate: f64,
+
+    /// Status of failed requests.
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+
+    /// Providers faults are injected into, all if empty.
+    #[serde(default)]
+    pub providers: Vec<NotificationProvider>,
+}
+
+fn default_error_status() -> u16 {
+    503
+}
+
+impl ChaosConfig {
+    pub(crate) fn check(&self) -> Result<StatusCode> {
+        ensure!(
+            (0.0..=1.0).contains(&self.error_rate),
+            "Chaos error rate must be between 0 and 1"
+        );
+        let status = StatusCode::from_u16(self.error_status)?;
+        // Other statuses remove tokens or drop notifications
+        // as if the provider rejected them.
+        ensure!(
+            status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+            "Chaos error status must be 429 or a server error"
+        );
+        Ok(status)
+    }
+
+    /// Returns true if faults are injected into the provider.
+    fn applies_to(&self, provider: NotificationProvider) -> bool {
+        self.providers.is_empty() || self.providers.contains(&provider)
+    }
+}
+
+/// Provider injecting faults into the requests of another provider.
+pub(crate) struct ChaosProvider {
+    inner: Box<dyn Provider>,
+    max_delay: Duration,
+    error_rate: f64,
+    error_status: StatusCode,
+}
+
+impl ChaosProvider {
+    /// Wraps the provider if the configuration applies to it.
+    pub(crate) fn wrap(
+        inner: Box<dyn Provider>,
+        config: Option<&ChaosConfig>,
+    ) -> Result<Box<dyn Provider>> {
+        let Some(config) = config.filter(|config| config.applies_to(inner.label())) else {
+            return Ok(inner);
+        };
+        let error_status = config.check()?;
+        let injected = Delivery {
+            status: error_status,
+            reason: Some("chaos".to_string()),
+            failure: None,
+        };
+        ensure!(
+            inner.classify(&injected) == Disposition::Retry,
+            "Chaos error status {error_status} is not retried by {:?}",
+            inner.label()
+        );
+        Ok(Box::new(Self {
+            inner,
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            error_rate: config.error_rate,
+            error_status,
+        }))
+    }
+}
+
+#[async_trait]
+impl Provider for ChaosProvider {
+    fn label(&self) -> NotificationProvider {
+        self.inner.label()
+    }
+
+    fn supports_heartbeats(&self, token: &NotificationToken) -> bool {
+        self.inner.supports_heartbeats(token)
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let (delay, fail) = {
+            let mut rng = rand::thread_rng();
+            (
+                rng.gen_range(Duration::ZERO..=self.max_delay),
+                rng.gen_bool(self.error_rate),
+            )
+        };
+        tokio::time::sleep(delay).await;
+        if fail {
+            let provider = self.label();
+            debug!(
+                "Injecting {} into {provider:?} notification {id}.",
+                self.error_status
+            );
+            let metrics = state.metrics();
+            metrics.inflight_request(provider).finish(false);
+            return Ok(Delivery {
+                status: self.error_status,
+                reason: Some("chaos".to_string()),
+                failure: Some(FailureLabels::new(provider, "chaos", "")),
+            });
+        }
+        self.inner.send(state, id, token, kind, options).await
+    }
+
+    fn classify(&self, delivery: &Delivery) -> Disposition {
+        self.inner.classify(delivery)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::Providers;
+
+    #[test]
+    fn test_check() {
+        let config = |error_rate, error_status| ChaosConfig {
+            max_delay_ms: 0,
+            error_rate,
+            error_status,
+            providers: Vec::new(),
+        };
+        assert_eq!(
+            config(0.5, 503).check().unwrap(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(config(1.5, 503).check().is_err());
+        assert!(config(-0.1, 503).check().is_err());
+        assert!(config(0.5, 200).check().is_err());
+        assert!(config(0.5, 1000).check().is_err());
+        assert!(config(0.5, 429).check().is_ok());
+        assert!(config(0.5, 400).check().is_err());
+        assert!(config(0.5, 404).check().is_err());
+        assert!(config(0.5, 410).check().is_err());
+    }
+
+    #[test]
+    fn test_injected_failures_are_retried() {
+        for error_status in 100..600 {
+            let config = ChaosConfig {
+                max_delay_ms: 0,
+                error_rate: 0.5,
+                error_status,
+                providers: Vec::new(),
+            };
+            let Ok(status) = config.check() else {
+                continue;
+            };
+            // Wrapping checks that no provider classifies the status
+            // as gone or fatal.
+            let providers = Providers::new(Some(&config), false).unwrap();
+            let delivery = Delivery {
+                status,
+                reason: Some("chaos".to_string()),
+                failure: None,
+            };
+            for provider in [NotificationProvider::APNS, NotificationProvider::FCM] {
+                assert_eq!(
+                    providers.get(provider).classify(&delivery),
+                    Disposition::Retry
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_applies_to() {
+        let config: ChaosConfig = toml::from_str("providers = [\"fcm\"]").unwrap();
+        assert!(config.applies_to(NotificationProvider::FCM));
+        assert!(!config.applies_to(NotificationProvider::APNS));
+        assert_eq!(config.error_status, 503);
+
+        let config: ChaosConfig = toml::from_str("error_rate = 0.1").unwrap();
+        assert!(config.applies_to(NotificationProvider::APNS));
+    }
+}
@@ -0,0 +1,166 @@
+//! # Request and response compression.
+//!
+//! Large JSON responses such as token listings are gzip-compressed
+//! if the client sends `Accept-Encoding: gzip`.
+//! Responses smaller than [`MIN_SIZE`] are sent as is.
+//!
+//! Bulk requests may be sent gzip-compressed with `Content-Encoding: gzip`.
+//! Decompressed bodies are limited to [`MAX_DECOMPRESSED_SIZE`].
+
+use std::io::{Read as _, Write as _};
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::*;
+
+/// Minimum size of a response body worth compressing.
+const MIN_SIZE: usize = 1024;
+
+/// Maximum size of a decompressed request body.
+pub(crate) const MAX_DECOMPRESSED_SIZE: usize = 32 * 1024 * 1024;
+
+/// Returns true if the `Accept-Encoding` header allows gzip.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let disabled = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !disabled
+        })
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Decompresses gzip data.
+///
+/// Returns `None` if the data exceeds `limit` after decompression.
+fn gunzip(data: &[u8], limit: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    Ok((decompressed.len() <= limit).then_some(decompressed))
+}
+
+/// Middleware decompressing request bodies sent with `Content-Encoding: gzip`.
+pub(crate) async fn decompress(request: Request, next: Next) -> Response {
+    let Some(encoding) = request.headers().get(header::CONTENT_ENCODING) else {
+        return next.run(request).await;
+    };
+    if !encoding.as_bytes().eq_ignore_ascii_case(b"gzip") {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_DECOMPRESSED_SIZE).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let decompressed = match gunzip(&bytes, MAX_DECOMPRESSED_SIZE) {
+        Ok(Some(decompressed)) => decompressed,
+        Ok(None) => {
+            debug!("Rejecting request exceeding the decompressed size limit.");
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+        Err(err) => {
+            debug!("Failed to decompress request body: {err:#}.");
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    next.run(Request::from_parts(parts, Body::from(decompressed)))
+        .await
+}
+
+/// Middleware compressing responses negotiated via `Accept-Encoding`.
+pub(crate) async fn compress(request: Request, next: Next) -> Response {
+    let accepts_gzip = accepts_gzip(request.headers());
+    let response = next.run(request).await;
+    if !accepts_gzip || response.headers().contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            warn!("Failed to read response body: {err:#}.");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if bytes.len() < MIN_SIZE {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    match gzip(&bytes) {
+        Ok(compressed) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(err) => {
+            warn!("Failed to compress response: {err:#}.");
+            Response::from_parts(parts, Body::from(bytes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(accept_encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_str(accept_encoding).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(accepts_gzip(&headers("gzip")));
+        assert!(accepts_gzip(&headers("br, gzip;q=0.8")));
+        assert!(accepts_gzip(&headers("*")));
+        assert!(!accepts_gzip(&headers("br")));
+        assert!(!accepts_gzip(&headers("gzip;q=0")));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let data = b"{\"tokens\":[]}".repeat(100);
+        let compressed = gzip(&data).unwrap();
+        assert!(compressed.len() < data.len());
+
+        assert_eq!(gunzip(&compressed, data.len()).unwrap().unwrap(), data);
+
+        // Decompression bombs are cut off at the limit.
+        assert!(gunzip(&compressed, data.len() - 1).unwrap().is_none());
+        assert!(gunzip(&data, data.len()).is_err());
+    }
+}
@@ -0,0 +1,518 @@
+//! # Configuration file.
+//!
+//! Settings that do not fit into command line arguments,
+//! such as lists of API keys, are read from an optional TOML file
+//! passed with `--config`.
+//!
+//! Before starting, the gateway validates the configuration
+//! together with the command line arguments and the environment
+//! and reports all problems found at once.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use serde::Deserialize;
+
+use crate::apikeys::Scope;
+use crate::chaos::ChaosConfig;
+use crate::endpoints::EndpointConfig;
+use crate::exclusion::ExclusionWindow;
+use crate::expiry::ExpiryReminderConfig;
+use crate::fcm::FcmProjectConfig;
+use crate::openpgp::PgpDecryptor;
+use crate::ratelimit::RateLimit;
+use crate::schedule::storage::StorageKind;
+use crate::templates::TemplateConfig;
+use crate::tls::AcmeConfig;
+use crate::webhook::WebhookConfig;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// API keys for relays and operators.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+
+    /// Scopes usable without an API key.
+    ///
+    /// By default requests without a key are only accepted
+    /// while no API keys exist.
+    #[serde(default)]
+    pub anonymous_scopes: Option<Vec<Scope>>,
+
+    /// Base URL of the FCM API,
+    /// used by FCM endpoints that do not set their own.
+    #[serde(default)]
+    pub fcm_url: Option<String>,
+
+    /// FCM endpoints in the order of preference.
+    #[serde(default)]
+    pub fcm_endpoints: Vec<EndpointConfig>,
+
+    /// Firebase projects of apps not using the default project.
+    #[serde(default)]
+    pub fcm_projects: Vec<FcmProjectConfig>,
+
+    /// URL notified about invalidated tokens
+    /// registered by relays without an API key.
+    #[serde(default)]
+    pub invalidation_url: Option<String>,
+
+    /// Secret for signing callbacks to `invalidation_url`.
+    #[serde(default)]
+    pub invalidation_secret: Option<String>,
+
+    /// Templates replacing the built-in notification content.
+    #[serde(default)]
+    pub templates: Vec<TemplateConfig>,
+
+    /// OneSignal apps notifications are forwarded to.
+    #[serde(default)]
+    pub onesignal_apps: Vec<OneSignalAppConfig>,
+
+    /// Vivo push credentials.
+    #[serde(default)]
+    pub vivo: Option<VivoConfig>,
+
+    /// OPPO push credentials.
+    #[serde(default)]
+    pub oppo: Option<OppoConfig>,
+
+    /// Windows Push Notification Services credentials.
+    #[serde(default)]
+    pub wns: Option<WnsConfig>,
+
+    /// APNS apps served in addition to the one passed on the command line.
+    #[serde(default)]
+    pub apns_apps: Vec<ApnsAppConfig>,
+
+    /// Reminders about the APNS certificate expiry.
+    #[serde(default)]
+    pub expiry_reminder: Option<ExpiryReminderConfig>,
+
+    /// Daily windows in which no heartbeats are sent.
+    #[serde(default)]
+    pub heartbeat_exclusions: Vec<ExclusionWindow>,
+
+    /// Storage backend of the database, unless set with `--storage`.
+    #[serde(default)]
+    pub storage: Option<StorageKind>,
+
+    /// Hosts `webhook:` tokens may point to.
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+
+    /// Faults injected into provider requests with `--chaos`.
+    #[serde(default)]
+    pub chaos: Option<ChaosConfig>,
+
+    /// Certificates for serving the public API over HTTPS.
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+}
+
+/// Additional APNS app defined in the configuration file.
+///
+/// Either `certificate_path` or `key_path` must be set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApnsAppConfig {
+    /// Bundle ID of the app used as the APNS topic,
+    /// e.g. `chat.delta.testflight`,
+    /// or the website push ID of Safari notifications.
+    pub topic: String,
+
+    /// Path to the `.p12` certificate.
+    #[serde(default)]
+    pub certificate_path: Option<PathBuf>,
+
+    /// Password of the certificate.
+    #[serde(default)]
+    pub password: String,
+
+    /// Path to the `.p8` signing key.
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+
+    #[serde(default)]
+    pub key_id: String,
+
+    #[serde(default)]
+    pub team_id: String,
+}
+
+/// OneSignal app defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OneSignalAppConfig {
+    pub app_id: String,
+
+    /// REST API key of the app.
+    pub api_key: String,
+}
+
+/// Vivo push app credentials.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VivoConfig {
+    pub app_id: String,
+    pub app_key: String,
+    pub app_secret: String,
+}
+
+/// OPPO push app credentials.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OppoConfig {
+    pub app_key: String,
+    pub master_secret: String,
+}
+
+/// Windows Push Notification Services app credentials.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WnsConfig {
+    /// Package security identifier (SID) of the app.
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// API key defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Name of the key, e.g. the relay domain.
+    pub name: String,
+
+    /// Secret passed by the caller as a bearer token.
+    pub key: String,
+
+    /// Endpoints the key is allowed to use.
+    pub scopes: Vec<Scope>,
+
+    /// Rate limit for all requests made with the key.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+
+    /// URL notified about invalidated tokens registered with the key.
+    #[serde(default)]
+    pub invalidation_url: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Loads the configuration from an OpenPGP-encrypted bundle.
+    ///
+    /// `keyring_armor` contains the ASCII-armored secret keys
+    /// the bundle is encrypted to.
+    pub fn load_bundle(path: &Path, keyring_armor: &str) -> Result<Self> {
+        let bundle = std::fs::read(path)
+            .with_context(|| format!("Failed to read config bundle {}", path.display()))?;
+        let decryptor = PgpDecryptor::new(keyring_armor).context("Invalid config bundle key")?;
+        let content = decryptor
+            .decrypt_bytes(&bundle)
+            .with_context(|| format!("Failed to decrypt config bundle {}", path.display()))?;
+        let content = String::from_utf8(content).context("Config bundle is not UTF-8")?;
+        Self::parse(&content).with_context(|| format!("Invalid config bundle {}", path.display()))
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<Self> {
+        let config = toml::from_str(content)?;
+        Ok(config)
+    }
+
+    /// Records the problems of the configuration file.
+    pub fn validate(&self, problems: &mut Problems) {
+        let mut names = HashSet::new();
+        let mut keys = HashSet::new();
+        for api_key in &self.api_keys {
+            if !names.insert(&api_key.name) {
+                problems.push(format!("API key name {:?} is used twice", api_key.name));
+            }
+            if api_key.key.is_empty() {
+                problems.push(format!("API key {:?} is empty", api_key.name));
+            } else if !keys.insert(&api_key.key) {
+                problems.push(format!(
+                    "API key {:?} has the same secret as another key",
+                    api_key.name
+                ));
+            }
+        }
+
+        let mut topics = HashSet::new();
+        for app in &self.apns_apps {
+            if !topics.insert(&app.topic) {
+                problems.push(format!("APNS app {} is defined twice", app.topic));
+            }
+            match (&app.certificate_path, &app.key_path) {
+                (Some(certificate_path), None) => {
+                    problems.file(
+                        format_args!("Certificate of APNS app {}", app.topic),
+                        certificate_path,
+                    );
+                }
+                (None, Some(key_path)) => {
+                    problems.file(
+                        format_args!("Signing key of APNS app {}", app.topic),
+                        key_path,
+                    );
+                    if app.key_id.is_empty() || app.team_id.is_empty() {
+                        problems.push(format!(
+                            "APNS app {} needs key_id and team_id for its signing key",
+                            app.topic
+                        ));
+                    }
+                }
+                _ => problems.push(format!(
+                    "APNS app {} must have either certificate_path or key_path",
+                    app.topic
+                )),
+            }
+        }
+
+        let mut projects = HashSet::new();
+        for project in &self.fcm_projects {
+            if !projects.insert(&project.project_id) {
+                problems.push(format!(
+                    "FCM project {} is defined twice",
+                    project.project_id
+                ));
+            }
+            problems.file(
+                format_args!("Service account key of FCM project {}", project.project_id),
+                Path::new(&project.key_path),
+            );
+        }
+
+        let mut app_ids = HashSet::new();
+        for app in &self.onesignal_apps {
+            if !app_ids.insert(&app.app_id) {
+                problems.push(format!("OneSignal app {} is defined twice", app.app_id));
+            }
+        }
+
+        if self.invalidation_url.is_some() != self.invalidation_secret.is_some() {
+            // Callbacks to anonymous relays are only sent with both.
+            problems.push("invalidation_url and invalidation_secret must be set together");
+        }
+
+        if let Some(chaos) = &self.chaos {
+            if let Err(err) = chaos.check() {
+                problems.push(format!("[chaos]: {err:#}"));
+            }
+        }
+
+        if let Some(acme) = &self.acme {
+            if let Err(err) = acme.check() {
+                problems.push(format!("[acme]: {err:#}"));
+            }
+        }
+    }
+}
+
+/// Problems found by validating the configuration.
+#[derive(Debug, Default)]
+pub struct Problems(Vec<String>);
+
+impl Problems {
+    pub fn push(&mut self, problem: impl Into<String>) {
+        self.0.push(problem.into());
+    }
+
+    /// Records a problem unless `path` is a readable file.
+    pub fn file(&mut self, what: impl Display, path: &Path) {
+        if let Err(err) = std::fs::File::open(path) {
+            self.push(format!("{what} {} cannot be read: {err}", path.display()));
+        } else if !path.is_file() {
+            self.push(format!("{what} {} is not a file", path.display()));
+        }
+    }
+
+    /// Fails with a list of all problems, if any.
+    pub fn into_result(self) -> Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let list: Vec<String> = self.0.iter().map(|p| format!("  - {p}")).collect();
+        bail!(
+            "Found {} configuration problem(s):\n{}",
+            self.0.len(),
+            list.join("\n")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let config = Config::parse(
+            r#"
+anonymous_scopes = ["register"]
+fcm_url = "https://fcm.example.org"
+
+[[api_keys]]
+name = "relay.example.org"
+key = "secret"
+scopes = ["notify", "register"]
+rate_limit = "1000/1m"
+
+[[api_keys]]
+name = "operator"
+key = "other-secret"
+scopes = ["admin"]
+
+[[fcm_endpoints]]
+region = "eu"
+url = "https://fcm.googleapis.com"
+interface = "eth1"
+
+[[fcm_endpoints]]
+region = "us"
+local_address = "192.0.2.1"
+
+[[fcm_projects]]
+project_id = "delta-chat-beta"
+key_path = "/etc/notifiers/beta.json"
+packages = ["chat.delta.beta", "chat.delta.nightly"]
+
+[[apns_apps]]
+topic = "chat.delta.testflight"
+key_path = "/etc/notifiers/AuthKey_ABC123DEFG.p8"
+key_id = "ABC123DEFG"
+team_id = "DEF123GHIJ"
+
+[[heartbeat_exclusions]]
+start = "02:00"
+end = "04:00"
+
+[acme]
+domains = ["notifications.example.org"]
+contact = ["mailto:admin@example.org"]
+cache_dir = "/var/lib/notifiers/acme"
+"#,
+        )?;
+        assert_eq!(config.anonymous_scopes, Some(vec![Scope::Register]));
+        assert_eq!(config.api_keys.len(), 2);
+        assert_eq!(config.api_keys[0].scopes, [Scope::Notify, Scope::Register]);
+        assert_eq!(config.api_keys[0].rate_limit, Some("1000/1m".parse()?));
+        assert_eq!(config.api_keys[1].rate_limit, None);
+        assert_eq!(config.fcm_url.as_deref(), Some("https://fcm.example.org"));
+        assert_eq!(config.fcm_endpoints.len(), 2);
+        assert_eq!(config.fcm_endpoints[1].url, None);
+        assert_eq!(config.fcm_projects[0].packages.len(), 2);
+        assert_eq!(config.apns_apps[0].topic, "chat.delta.testflight");
+        assert!(config.apns_apps[0].certificate_path.is_none());
+        assert_eq!(config.heartbeat_exclusions[0].end.to_string(), "04:00");
+        let acme = config.acme.as_ref().unwrap();
+        assert_eq!(acme.domains, ["notifications.example.org"]);
+        assert!(acme.directory.contains("letsencrypt.org"));
+        assert_eq!(config.fcm_endpoints[0].interface.as_deref(), Some("eth1"));
+        assert_eq!(
+            config.fcm_endpoints[1].local_address,
+            Some("192.0.2.1".parse()?)
+        );
+
+        assert!(Config::parse("unknown = 1").is_err());
+        assert!(Config::parse("").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let config = Config::parse(
+            r#"
+invalidation_secret = "secret"
+
+[[api_keys]]
+name = "relay.example.org"
+key = "secret"
+scopes = ["notify"]
+
+[[api_keys]]
+name = "relay.example.org"
+key = "secret"
+scopes = ["register"]
+
+[[apns_apps]]
+topic = "chat.delta.testflight"
+key_path = "/nonexistent/AuthKey.p8"
+
+[chaos]
+error_rate = 2.0
+
+[acme]
+domains = []
+cache_dir = "/var/lib/notifiers/acme"
+"#,
+        )?;
+        let mut problems = Problems::default();
+        config.validate(&mut problems);
+        // Duplicate name and secret, missing key, missing key ID,
+        // secret without URL, invalid chaos error rate and no ACME domains.
+        assert_eq!(problems.0.len(), 7, "{problems:?}");
+        let err = problems.into_result().unwrap_err().to_string();
+        assert!(err.starts_with("Found 7 configuration problem(s):"));
+        assert!(err.contains("key_id and team_id"));
+
+        let mut problems = Problems::default();
+        Config::default().validate(&mut problems);
+        assert!(problems.into_result().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_bundle() -> Result<()> {
+        use pgp::composed::{
+            KeyType, Message, SecretKeyParamsBuilder, SignedPublicKey, SubkeyParamsBuilder,
+        };
+        use pgp::crypto::sym::SymmetricKeyAlgorithm;
+
+        let mut rng = rand::thread_rng();
+        let params = SecretKeyParamsBuilder::default()
+            .key_type(KeyType::EdDSALegacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id("config <config@example.org>".into())
+            .subkeys(vec![SubkeyParamsBuilder::default()
+                .key_type(KeyType::ECDH(pgp::crypto::ecc_curve::ECCCurve::Curve25519))
+                .can_encrypt(true)
+                .build()?])
+            .build()?;
+        let secret_key = params.generate(&mut rng)?.sign(&mut rng, String::new)?;
+        let public_key = SignedPublicKey::from(secret_key.clone());
+        let config = r#"
+[[api_keys]]
+name = "relay.example.org"
+key = "secret"
+scopes = ["notify"]
+"#;
+        let bundle = Message::new_literal_bytes("", config.as_bytes())
+            .encrypt_to_keys_seipdv1(
+                &mut rng,
+                SymmetricKeyAlgorithm::AES128,
+                &[&public_key.public_subkeys[0]],
+            )?
+            .to_armored_string(Default::default())?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.toml.asc");
+        std::fs::write(&path, bundle)?;
+
+        let keyring = secret_key.to_armored_string(Default::default())?;
+        let config = Config::load_bundle(&path, &keyring)?;
+        assert_eq!(config.api_keys[0].key, "secret");
+
+        // Bundle cannot be loaded as a plain configuration file.
+        assert!(Config::load(&path).is_err());
+        Ok(())
+    }
+}
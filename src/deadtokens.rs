@@ -0,0 +1,138 @@
+//! # Negative cache of dead tokens.
+//!
+//! Relays remove tokens only after they get a `410 Gone` response
+//! and may keep notifying a token for a while after the first one,
+//! e.g. when a message is sent to several mailboxes
+//! of the same uninstalled app at once.
+//! Tokens confirmed gone by the provider are remembered for some time,
+//! and further notifications to them are answered with `410 Gone`
+//! without contacting the provider again.
+
+use parking_lot::RwLock;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+pub struct DeadTokens {
+    /// Time for which a token is remembered as dead.
+    ttl: Duration,
+
+    state: RwLock<DeadTokensState>,
+}
+
+#[derive(Default)]
+struct DeadTokensState {
+    /// Dead tokens with the time they were reported gone.
+    tokens: HashMap<String, Instant>,
+
+    /// Tokens sorted by the time they were reported gone.
+    ///
+    /// Entries of removed or reinserted tokens are skipped on cleanup.
+    heap: BinaryHeap<Reverse<(Instant, String)>>,
+}
+
+impl DeadTokensState {
+    /// Removes tokens reported gone more than `ttl` ago.
+    fn cleanup(&mut self, now: Instant, ttl: Duration) {
+        while let Some(Reverse((timestamp, _))) = self.heap.peek() {
+            if now.saturating_duration_since(*timestamp) < ttl {
+                break;
+            }
+            let Some(Reverse((timestamp, token))) = self.heap.pop() else {
+                break;
+            };
+            if self.tokens.get(&token) == Some(&timestamp) {
+                self.tokens.remove(&token);
+            }
+        }
+    }
+}
+
+impl DeadTokens {
+    /// Creates a cache remembering tokens for `ttl`.
+    ///
+    /// Zero `ttl` disables the cache.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Default::default(),
+        }
+    }
+
+    /// Remembers that the provider reported the token gone.
+    pub fn insert(&self, now: Instant, token: String) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut state = self.state.write();
+        state.cleanup(now, self.ttl);
+        state.tokens.insert(token.clone(), now);
+        state.heap.push(Reverse((now, token)));
+    }
+
+    /// Returns true if the token was recently reported gone.
+    pub fn contains(&self, now: Instant, token: &str) -> bool {
+        let state = self.state.read();
+        state
+            .tokens
+            .get(token)
+            .is_some_and(|timestamp| now.saturating_duration_since(*timestamp) < self.ttl)
+    }
+
+    /// Forgets the token, e.g. because it was registered again.
+    pub fn remove(&self, token: &str) {
+        self.state.write().tokens.remove(token);
+    }
+
+    /// Removes expired tokens and releases unused memory.
+    pub fn shrink(&self, now: Instant) {
+        let mut state = self.state.write();
+        state.cleanup(now, self.ttl);
+        let DeadTokensState { tokens, heap } = &mut *state;
+        heap.retain(|Reverse((timestamp, token))| tokens.get(token) == Some(timestamp));
+        heap.shrink_to_fit();
+        tokens.shrink_to_fit();
+    }
+
+    /// Returns the number of remembered tokens.
+    ///
+    /// This function does not remove expired tokens.
+    #[cfg(test)]
+    pub fn count(&self) -> usize {
+        self.state.read().tokens.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_tokens() {
+        let mut now = Instant::now();
+        let dead_tokens = DeadTokens::new(Duration::from_secs(60));
+
+        assert!(!dead_tokens.contains(now, "foo"));
+        dead_tokens.insert(now, "foo".to_string());
+        assert!(dead_tokens.contains(now, "foo"));
+        assert!(!dead_tokens.contains(now, "bar"));
+
+        now += Duration::from_secs(30);
+        dead_tokens.insert(now, "bar".to_string());
+        now += Duration::from_secs(31);
+        assert!(!dead_tokens.contains(now, "foo"));
+        assert!(dead_tokens.contains(now, "bar"));
+        dead_tokens.shrink(now);
+        assert_eq!(dead_tokens.count(), 1);
+
+        // Registered tokens are forgotten.
+        dead_tokens.remove("bar");
+        assert!(!dead_tokens.contains(now, "bar"));
+
+        // Zero TTL disables the cache.
+        let dead_tokens = DeadTokens::new(Duration::ZERO);
+        dead_tokens.insert(now, "foo".to_string());
+        assert!(!dead_tokens.contains(now, "foo"));
+        assert_eq!(dead_tokens.count(), 0);
+    }
+}
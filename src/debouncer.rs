@@ -10,15 +10,41 @@
 //! as only the notification gateway
 //! can decrypt them, notification gateway needs
 //! to debounce notifications to the same token.
+//!
+//! All `/notify` handlers go through the debouncer,
+//! so its state is split into shards by token hash,
+//! each with its own lock, to avoid contention on a single lock.
+//!
+//! The number of debounced tokens can be limited,
+//! so that a flood of unique tokens cannot exhaust memory.
+//! When the limit is reached, the tokens notified least recently
+//! are evicted and can be notified again before their window ends.
 
-use parking_lot::RwLock;
+use parking_lot::Mutex;
+use prometheus_client::metrics::counter::Counter;
 use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
 use std::collections::{BinaryHeap, HashSet};
+use std::hash::BuildHasher as _;
 use std::time::{Duration, Instant};
 
-#[derive(Default)]
-pub(crate) struct Debouncer {
-    state: RwLock<DebouncerState>,
+/// Default number of shards.
+const SHARDS: usize = 16;
+
+pub struct Debouncer {
+    /// Time during which repeated notifications to the same token are suppressed.
+    window: Duration,
+
+    /// Hasher assigning tokens to shards.
+    hasher: RandomState,
+
+    shards: Box<[Mutex<DebouncerState>]>,
+
+    /// Maximum number of tokens in each shard.
+    max_shard_entries: usize,
+
+    /// Counter of evicted tokens.
+    evictions: Counter,
 }
 
 #[derive(Default)]
@@ -39,14 +65,14 @@ struct DebouncerState {
 
 impl DebouncerState {
     /// Removes old entries for tokens that can be notified again.
-    fn cleanup(&mut self, now: Instant) {
+    fn cleanup(&mut self, now: Instant, window: Duration) {
         loop {
             let Some(Reverse((timestamp, token))) = self.heap.pop() else {
                 debug_assert!(self.tokens.is_empty());
                 break;
             };
 
-            if now.duration_since(timestamp) < Duration::from_secs(1) {
+            if now.duration_since(timestamp) < window {
                 self.heap.push(Reverse((timestamp, token)));
                 break;
             }
@@ -56,18 +82,40 @@ impl DebouncerState {
     }
 
     #[cfg(test)]
-    fn is_debounced(&mut self, now: Instant, token: &String) -> bool {
-        self.cleanup(now);
+    fn is_debounced(&mut self, now: Instant, window: Duration, token: &String) -> bool {
+        self.cleanup(now, window);
         self.tokens.contains(token)
     }
 
-    fn notify(&mut self, now: Instant, token: String) -> bool {
-        self.cleanup(now);
+    /// Returns whether the token was inserted
+    /// and the number of tokens evicted to stay within `max_entries`.
+    fn notify(
+        &mut self,
+        now: Instant,
+        window: Duration,
+        token: String,
+        max_entries: usize,
+    ) -> (bool, u64) {
+        self.cleanup(now, window);
         let inserted = self.tokens.insert(token.clone());
         if inserted {
             self.heap.push(Reverse((now, token)));
         }
-        inserted
+        let mut evicted = 0;
+        while self.tokens.len() > max_entries {
+            let Some(Reverse((_, token))) = self.heap.pop() else {
+                break;
+            };
+            self.tokens.remove(&token);
+            evicted += 1;
+        }
+        (inserted, evicted)
+    }
+
+    fn shrink(&mut self, now: Instant, window: Duration) {
+        self.cleanup(now, window);
+        self.tokens.shrink_to_fit();
+        self.heap.shrink_to_fit();
     }
 
     fn count(&self) -> usize {
@@ -77,19 +125,69 @@ impl DebouncerState {
     }
 }
 
+impl Default for Debouncer {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1))
+    }
+}
+
 impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        Self::with_shards(window, SHARDS)
+    }
+
+    /// Creates a debouncer with the state split into `shards` locks.
+    pub fn with_shards(window: Duration, shards: usize) -> Self {
+        Self {
+            window,
+            hasher: RandomState::new(),
+            shards: (0..shards.max(1)).map(|_| Default::default()).collect(),
+            max_shard_entries: usize::MAX,
+            evictions: Counter::default(),
+        }
+    }
+
+    /// Limits the number of debounced tokens to about `max_entries`,
+    /// counting evicted tokens in `evictions`.
+    ///
+    /// The limit is split evenly between the shards.
+    pub fn with_max_entries(mut self, max_entries: usize, evictions: Counter) -> Self {
+        self.max_shard_entries = max_entries.div_ceil(self.shards.len()).max(1);
+        self.evictions = evictions;
+        self
+    }
+
+    fn shard(&self, token: &str) -> &Mutex<DebouncerState> {
+        let index = self.hasher.hash_one(token) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
     /// Returns true if the token was notified recently
     /// and should not be notified again.
     #[cfg(test)]
     pub(crate) fn is_debounced(&self, now: Instant, token: &String) -> bool {
-        let mut state = self.state.write();
-        state.is_debounced(now, token)
+        let mut state = self.shard(token).lock();
+        state.is_debounced(now, self.window, token)
     }
 
     /// Returns true if notification should be sent,
     /// false if the token is currently debounced.
-    pub(crate) fn notify(&self, now: Instant, token: String) -> bool {
-        self.state.write().notify(now, token)
+    pub fn notify(&self, now: Instant, token: String) -> bool {
+        let (inserted, evicted) =
+            self.shard(&token)
+                .lock()
+                .notify(now, self.window, token, self.max_shard_entries);
+        if evicted > 0 {
+            self.evictions.inc_by(evicted);
+        }
+        inserted
+    }
+
+    /// Removes expired tokens and releases unused memory.
+    pub fn shrink(&self, now: Instant) {
+        for shard in self.shards.iter() {
+            shard.lock().shrink(now, self.window)
+        }
     }
 
     /// Returns number of currently debounced notification tokens.
@@ -97,8 +195,8 @@ impl Debouncer {
     /// This is used for metrics to display the size of the set.
     ///
     /// This function does not remove expired tokens.
-    pub(crate) fn count(&self) -> usize {
-        self.state.read().count()
+    pub fn count(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().count()).sum()
     }
 }
 
@@ -131,4 +229,60 @@ mod tests {
         assert!(!debouncer.is_debounced(now, &token2));
         assert_eq!(debouncer.count(), 0);
     }
+
+    #[test]
+    fn test_debouncer_window() {
+        let mut now = Instant::now();
+        let token = "foobar".to_string();
+
+        let debouncer = Debouncer::new(Duration::from_secs(60));
+        assert!(debouncer.notify(now, token.clone()));
+        now += Duration::from_secs(30);
+        assert!(!debouncer.notify(now, token.clone()));
+        now += Duration::from_secs(31);
+        assert!(debouncer.notify(now, token.clone()));
+
+        // Zero window disables debouncing.
+        let debouncer = Debouncer::new(Duration::ZERO);
+        assert!(debouncer.notify(now, token.clone()));
+        assert!(debouncer.notify(now, token.clone()));
+    }
+
+    #[test]
+    fn test_debouncer_shards() {
+        let mut now = Instant::now();
+        let debouncer = Debouncer::with_shards(Duration::from_secs(1), 4);
+        for i in 0..100 {
+            assert!(debouncer.notify(now, format!("token-{i}")));
+        }
+        assert!(!debouncer.notify(now, "token-42".to_string()));
+        assert_eq!(debouncer.count(), 100);
+        assert!(debouncer
+            .shards
+            .iter()
+            .all(|shard| shard.lock().count() < 100));
+
+        now += Duration::from_secs(2);
+        debouncer.shrink(now);
+        assert_eq!(debouncer.count(), 0);
+    }
+
+    #[test]
+    fn test_debouncer_max_entries() {
+        let mut now = Instant::now();
+        let evictions = Counter::default();
+        let debouncer = Debouncer::with_shards(Duration::from_secs(60), 1)
+            .with_max_entries(3, evictions.clone());
+        for i in 0..5 {
+            assert!(debouncer.notify(now, format!("token-{i}")));
+            now += Duration::from_secs(1);
+        }
+        assert_eq!(debouncer.count(), 3);
+        assert_eq!(evictions.get(), 2);
+
+        // Oldest tokens were evicted and can be notified again.
+        assert!(!debouncer.notify(now, "token-4".to_string()));
+        assert!(debouncer.notify(now, "token-0".to_string()));
+        assert_eq!(evictions.get(), 3);
+    }
 }
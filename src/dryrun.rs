@@ -0,0 +1,77 @@
+//! # Dry-run mode.
+//!
+//! For validating configuration changes against live relay traffic in staging,
+//! the gateway can be started with `--dry-run`.
+//! Providers then log the notifications they would send
+//! and count them in `dry_run_notifications_total`,
+//! but never contact APNS, FCM or any other provider.
+//!
+//! Notifications are reported to the relay as delivered,
+//! so tokens stay scheduled and nothing is retried.
+//! The dry-run provider wraps fault injection,
+//! so injected failures cannot remove tokens either.
+
+use anyhow::Result;
+use axum::async_trait;
+use axum::http::StatusCode;
+use log::*;
+use uuid::Uuid;
+
+use crate::logging::redact;
+use crate::metrics::{DryRunLabels, NotificationKind, NotificationProvider};
+use crate::provider::{Delivery, Disposition, Provider};
+use crate::server::{NotificationToken, NotifyOptions};
+use crate::state::State;
+
+/// Provider logging notifications instead of sending them.
+pub(crate) struct DryRunProvider {
+    inner: Box<dyn Provider>,
+}
+
+impl DryRunProvider {
+    /// Wraps the provider if `dry_run` is set.
+    pub(crate) fn wrap(inner: Box<dyn Provider>, dry_run: bool) -> Box<dyn Provider> {
+        if dry_run {
+            Box::new(Self { inner })
+        } else {
+            inner
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for DryRunProvider {
+    fn label(&self) -> NotificationProvider {
+        self.inner.label()
+    }
+
+    fn supports_heartbeats(&self, token: &NotificationToken) -> bool {
+        self.inner.supports_heartbeats(token)
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let provider = self.label();
+        info!(
+            "Dry run: not sending {kind:?} notification {id} to {} via {provider:?} (push type {:?}).",
+            redact(&token.to_string()),
+            options.push_type
+        );
+        state
+            .metrics()
+            .dry_run_notifications_total
+            .get_or_create(&DryRunLabels { provider, kind })
+            .inc();
+        Ok(StatusCode::OK.into())
+    }
+
+    fn classify(&self, delivery: &Delivery) -> Disposition {
+        self.inner.classify(delivery)
+    }
+}
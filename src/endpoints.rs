@@ -0,0 +1,195 @@
+//! # Outbound endpoint selection.
+//!
+//! Gateways deployed in networks where one route to the provider is unreliable
+//! can configure several endpoints, e.g. one per region or egress interface.
+//! Endpoints are tried in the configured order.
+//! An endpoint that fails with a connection error or a server error
+//! is skipped for [`FAILURE_BACKOFF`] and the next one is used instead.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+/// Time for which a failed endpoint is not preferred.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Outbound endpoint defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointConfig {
+    /// Name of the endpoint used in logs, e.g. the region.
+    pub region: String,
+
+    /// Base URL of the provider API, e.g. `https://fcm.googleapis.com`.
+    ///
+    /// Defaults to the URL of the provider.
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// Local address to send requests from.
+    #[serde(default)]
+    pub local_address: Option<IpAddr>,
+
+    /// Network interface to send requests from.
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+pub(crate) struct Endpoint {
+    region: String,
+    url: String,
+    client: reqwest::Client,
+
+    /// Time until which the endpoint is considered unhealthy.
+    failed_until: Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    pub(crate) fn region(&self) -> &str {
+        &self.region
+    }
+
+    /// Returns the base URL without a trailing slash.
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub(crate) fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Marks the endpoint as unhealthy after a failed request.
+    pub(crate) fn mark_failed(&self, now: Instant) {
+        *self.failed_until.lock() = Some(now + FAILURE_BACKOFF);
+    }
+
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.failed_until
+            .lock()
+            .is_none_or(|failed_until| now >= failed_until)
+    }
+}
+
+/// Ordered list of endpoints for a provider.
+pub(crate) struct Endpoints {
+    endpoints: Vec<Endpoint>,
+}
+
+impl Endpoints {
+    /// Creates a list of endpoints.
+    ///
+    /// If no endpoints are configured,
+    /// the `default_url` is used with the shared `client`.
+    /// It is also used by configured endpoints without a URL.
+    /// Otherwise each endpoint gets its own client
+    /// created from `client_builder`.
+    pub(crate) fn new(
+        configured: &[EndpointConfig],
+        default_url: &str,
+        client: &reqwest::Client,
+        client_builder: impl Fn() -> reqwest::ClientBuilder,
+    ) -> Result<Self> {
+        if configured.is_empty() {
+            return Ok(Self {
+                endpoints: vec![Endpoint {
+                    region: "default".to_string(),
+                    url: default_url.trim_end_matches('/').to_string(),
+                    client: client.clone(),
+                    failed_until: Mutex::new(None),
+                }],
+            });
+        }
+
+        let mut endpoints = Vec::new();
+        for config in configured {
+            let mut builder = client_builder().local_address(config.local_address);
+            if let Some(interface) = &config.interface {
+                builder = builder.interface(interface);
+            }
+            let client = builder.build().with_context(|| {
+                format!("Failed to build HTTP client for region {}", config.region)
+            })?;
+            endpoints.push(Endpoint {
+                region: config.region.clone(),
+                url: config
+                    .url
+                    .as_deref()
+                    .unwrap_or(default_url)
+                    .trim_end_matches('/')
+                    .to_string(),
+                client,
+                failed_until: Mutex::new(None),
+            });
+        }
+        Ok(Self { endpoints })
+    }
+
+    /// Returns endpoints in the order they should be tried.
+    ///
+    /// Healthy endpoints come first in the configured order,
+    /// followed by the endpoints that failed recently.
+    pub(crate) fn candidates(&self, now: Instant) -> Vec<&Endpoint> {
+        let (mut healthy, unhealthy): (Vec<&Endpoint>, Vec<&Endpoint>) = self
+            .endpoints
+            .iter()
+            .partition(|endpoint| endpoint.is_healthy(now));
+        healthy.extend(unhealthy);
+        healthy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failover() -> Result<()> {
+        let configured: Vec<EndpointConfig> = ["eu", "us"]
+            .iter()
+            .map(|region| EndpointConfig {
+                region: region.to_string(),
+                url: Some(format!("https://{region}.example.org/")),
+                local_address: None,
+                interface: None,
+            })
+            .collect();
+        let client = reqwest::Client::new();
+        let endpoints = Endpoints::new(&configured, "", &client, reqwest::ClientBuilder::new)?;
+
+        let now = Instant::now();
+        let regions = |now| -> Vec<String> {
+            endpoints
+                .candidates(now)
+                .iter()
+                .map(|endpoint| endpoint.region().to_string())
+                .collect()
+        };
+        assert_eq!(regions(now), ["eu", "us"]);
+        assert_eq!(endpoints.candidates(now)[0].url(), "https://eu.example.org");
+
+        endpoints.candidates(now)[0].mark_failed(now);
+        assert_eq!(regions(now), ["us", "eu"]);
+
+        // Failed endpoint is preferred again after the backoff.
+        assert_eq!(regions(now + FAILURE_BACKOFF), ["eu", "us"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_endpoint() -> Result<()> {
+        let client = reqwest::Client::new();
+        let endpoints = Endpoints::new(
+            &[],
+            "https://fcm.googleapis.com",
+            &client,
+            reqwest::ClientBuilder::new,
+        )?;
+        let candidates = endpoints.candidates(Instant::now());
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].region(), "default");
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+//! # Delivery event log.
+//!
+//! Optional newline-delimited JSON log of notification deliveries and failures
+//! intended for offline analysis.
+//! It is written independently of the regular log output
+//! and rotated when the current file becomes too large or too old.
+//!
+//! Tokens are never written to the event log.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context as _, Result};
+use parking_lot::Mutex;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::metrics::{NotificationKind, NotificationProvider};
+
+/// Single line of the event log.
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    /// Unix timestamp of the event in seconds.
+    pub timestamp: u64,
+
+    /// Direct or heartbeat notification.
+    pub kind: NotificationKind,
+
+    pub provider: NotificationProvider,
+
+    /// HTTP status code returned to the caller
+    /// or derived from the provider response.
+    pub status: u16,
+
+    /// Short failure reason if the notification was not delivered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'a str>,
+
+    /// ID of a direct notification, see `X-Notification-Id`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(kind: NotificationKind, provider: NotificationProvider, status: u16) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self {
+            timestamp,
+            kind,
+            provider,
+            status,
+            reason: None,
+            id: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: &'a str) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+}
+
+struct CurrentFile {
+    file: File,
+
+    /// Size of the current file in bytes.
+    size: u64,
+
+    /// Time when the current file was started.
+    created: SystemTime,
+}
+
+/// Rotating JSONL event log.
+pub struct EventLog {
+    path: PathBuf,
+
+    /// Size in bytes after which the file is rotated.
+    max_size: u64,
+
+    /// Age after which the file is rotated.
+    max_age: Duration,
+
+    /// Number of rotated files to keep.
+    retention: usize,
+
+    current: Mutex<CurrentFile>,
+}
+
+impl EventLog {
+    pub fn open(path: &Path, max_size: u64, max_age: Duration, retention: usize) -> Result<Self> {
+        let current = open_file(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            max_age,
+            retention,
+            current: Mutex::new(current),
+        })
+    }
+
+    /// Appends an event to the log.
+    ///
+    /// Errors are logged and otherwise ignored
+    /// so a full disk does not affect notification delivery.
+    pub fn record(&self, event: &Event) {
+        if let Err(err) = self.try_record(event) {
+            log::warn!("Failed to write delivery event log: {err:#}.");
+        }
+    }
+
+    fn try_record(&self, event: &Event) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        let mut current = self.current.lock();
+        let age = SystemTime::now()
+            .duration_since(current.created)
+            .unwrap_or_default();
+        if current.size > 0
+            && (current.size + line.len() as u64 > self.max_size || age >= self.max_age)
+        {
+            self.rotate()?;
+            *current = open_file(&self.path)?;
+        }
+        current.file.write_all(&line)?;
+        current.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts rotated files by one, dropping the oldest one.
+    ///
+    /// The current file becomes `<path>.1`.
+    fn rotate(&self) -> Result<()> {
+        if self.retention == 0 {
+            std::fs::remove_file(&self.path).context("Failed to remove event log")?;
+            return Ok(());
+        }
+        let oldest = rotated_path(&self.path, self.retention);
+        if oldest.exists() {
+            std::fs::remove_file(&oldest)
+                .with_context(|| format!("Failed to remove {}", oldest.display()))?;
+        }
+        for n in (1..self.retention).rev() {
+            let from = rotated_path(&self.path, n);
+            if from.exists() {
+                std::fs::rename(&from, rotated_path(&self.path, n + 1))
+                    .with_context(|| format!("Failed to rotate {}", from.display()))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated_path(&self.path, 1))
+            .context("Failed to rotate event log")?;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn open_file(path: &Path) -> Result<CurrentFile> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open event log {}", path.display()))?;
+    let metadata = file.metadata()?;
+    let size = metadata.len();
+    // Creation time is not available on all filesystems,
+    // modification time is a good enough approximation
+    // for a file that is only appended to.
+    let created = if size == 0 {
+        SystemTime::now()
+    } else {
+        metadata.created().or_else(|_| metadata.modified())?
+    };
+    Ok(CurrentFile {
+        file,
+        size,
+        created,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_event_log_rotation() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("events.jsonl");
+        let event_log = EventLog::open(&path, 100, Duration::from_secs(3600), 2)?;
+
+        let event = Event::new(NotificationKind::Direct, NotificationProvider::APNS, 200);
+        for _ in 0..10 {
+            event_log.record(&event);
+        }
+
+        assert!(path.exists());
+        assert!(rotated_path(&path, 1).exists());
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+
+        let content = std::fs::read_to_string(&path)?;
+        assert!(content.len() <= 100);
+        let line = content.lines().next().unwrap();
+        let value: serde_json::Value = serde_json::from_str(line)?;
+        assert_eq!(value["kind"], "direct");
+        assert_eq!(value["provider"], "apns");
+        assert_eq!(value["status"], 200);
+        assert!(value.get("reason").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! # Heartbeat exclusion windows.
+//!
+//! Operators can configure daily windows in UTC,
+//! e.g. during relay maintenance,
+//! in which no heartbeat notifications are sent.
+//! Heartbeats that become due inside a window
+//! are rescheduled to a random time shortly after its end,
+//! so they do not all arrive at the same moment.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context as _, Error, Result};
+use serde::Deserialize;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Time of day in UTC, written as `HH:MM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay {
+    /// Seconds since midnight.
+    seconds: u64,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        let (hours, minutes) = s
+            .split_once(':')
+            .with_context(|| format!("Invalid time of day {s:?}, expected HH:MM"))?;
+        let hours: u64 = hours.parse().context("Invalid hours")?;
+        let minutes: u64 = minutes.parse().context("Invalid minutes")?;
+        if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+            bail!("Invalid time of day {s:?}");
+        }
+        Ok(Self {
+            seconds: hours * 3600 + minutes * 60,
+        })
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}",
+            self.seconds / 3600,
+            self.seconds / 60 % 60
+        )
+    }
+}
+
+/// Daily window without heartbeats defined in the configuration file.
+///
+/// Windows ending before they start wrap around midnight.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExclusionWindow {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+impl ExclusionWindow {
+    /// Returns the time left until the end of the window
+    /// if `now` is inside it.
+    fn remaining(&self, now: SystemTime) -> Option<Duration> {
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            % DAY;
+        let (start, end) = (self.start.seconds, self.end.seconds);
+        let remaining = if start <= end {
+            (start..end).contains(&now).then(|| end - now)
+        } else if now >= start {
+            Some(end + DAY - now)
+        } else {
+            (now < end).then(|| end - now)
+        };
+        remaining.map(Duration::from_secs)
+    }
+}
+
+/// Returns the end of the exclusion window `now` is in, if any.
+pub(crate) fn excluded_until(windows: &[ExclusionWindow], now: SystemTime) -> Option<SystemTime> {
+    windows
+        .iter()
+        .filter_map(|window| window.remaining(now))
+        .max()
+        .map(|remaining| now + remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(start: &str, end: &str) -> ExclusionWindow {
+        ExclusionWindow {
+            start: TimeOfDay::try_from(start.to_string()).unwrap(),
+            end: TimeOfDay::try_from(end.to_string()).unwrap(),
+        }
+    }
+
+    fn at(hours: u64, minutes: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(DAY * 1000 + hours * 3600 + minutes * 60)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            TimeOfDay::try_from("02:30".to_string())
+                .unwrap()
+                .to_string(),
+            "02:30"
+        );
+        assert!(TimeOfDay::try_from("24:00".to_string()).is_ok());
+        assert!(TimeOfDay::try_from("2".to_string()).is_err());
+        assert!(TimeOfDay::try_from("25:00".to_string()).is_err());
+        assert!(TimeOfDay::try_from("02:60".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_excluded_until() {
+        let windows = [window("02:00", "04:00")];
+        assert_eq!(excluded_until(&windows, at(1, 59)), None);
+        assert_eq!(excluded_until(&windows, at(2, 0)), Some(at(4, 0)));
+        assert_eq!(excluded_until(&windows, at(3, 30)), Some(at(4, 0)));
+        assert_eq!(excluded_until(&windows, at(4, 0)), None);
+
+        // Windows may wrap around midnight.
+        let windows = [window("23:00", "01:00")];
+        assert_eq!(excluded_until(&windows, at(22, 59)), None);
+        assert_eq!(excluded_until(&windows, at(23, 30)), Some(at(25, 0)));
+        assert_eq!(excluded_until(&windows, at(0, 30)), Some(at(1, 0)));
+        assert_eq!(excluded_until(&windows, at(1, 0)), None);
+
+        let windows = [window("00:00", "24:00")];
+        assert_eq!(excluded_until(&windows, at(0, 0)), Some(at(24, 0)));
+    }
+}
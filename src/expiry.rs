@@ -0,0 +1,224 @@
+//! # APNS certificate expiry reminders.
+//!
+//! The expiry time of the APNS certificate is exported
+//! as the `apns_certificate_expiry_timestamp_seconds` gauge.
+//! Operators who don't watch metrics can configure an `[expiry_reminder]`
+//! to get a notification on their own device
+//! and/or a POST request to a webhook
+//! once a day while the certificate expires within the configured number of days.
+//!
+//! Token-based authentication keys (`.p8`) do not expire,
+//! so no reminders are sent for them.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context as _, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::server::{self, NotificationToken, NotifyOptions};
+use crate::state::{ApnsCredentials, State};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Interval at which the expiry is checked.
+const CHECK_INTERVAL: Duration = DAY;
+
+/// Value of the `relay` metrics label for reminder notifications.
+const REMINDER_RELAY: &str = "expiry_reminder";
+
+fn default_days() -> u64 {
+    30
+}
+
+/// Expiry reminder settings defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpiryReminderConfig {
+    /// Device token of the operator in the format accepted by `/register`.
+    #[serde(default)]
+    pub token: Option<String>,
+
+    /// URL receiving a JSON POST request with the expiry time.
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+
+    /// Number of days before the expiry from which reminders are sent.
+    #[serde(default = "default_days")]
+    pub days: u64,
+}
+
+/// Body of the webhook request.
+#[derive(Debug, Serialize)]
+struct Reminder {
+    /// Unix timestamp of the certificate expiry.
+    expires: u64,
+
+    /// Number of whole days left until the expiry.
+    days_left: u64,
+}
+
+/// Returns the earliest expiry time of the certificates in the PKCS #12 archive.
+pub(crate) fn certificate_expiry(pkcs12: &[u8], password: &str) -> Result<SystemTime> {
+    let keystore = p12_keystore::KeyStore::from_pkcs12(pkcs12, password)
+        .context("Failed to parse PKCS #12 archive")?;
+    let Some((_alias, chain)) = keystore.private_key_chain() else {
+        bail!("PKCS #12 archive contains no private key");
+    };
+    let mut expiry = None;
+    for certificate in chain.chain() {
+        let (_, certificate) = x509_parser::parse_x509_certificate(certificate.as_der())
+            .context("Failed to parse certificate")?;
+        let not_after = certificate.validity().not_after.timestamp();
+        let not_after = SystemTime::UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64);
+        expiry = Some(expiry.map_or(not_after, |expiry: SystemTime| expiry.min(not_after)));
+    }
+    expiry.context("PKCS #12 archive contains no certificates")
+}
+
+/// Returns the number of whole days left until the expiry
+/// if it is within `days` days.
+fn reminder_due(now: SystemTime, expiry: SystemTime, days: u64) -> Option<u64> {
+    let left = expiry.duration_since(now).unwrap_or_default();
+    (left < DAY * days as u32).then(|| left.as_secs() / DAY.as_secs())
+}
+
+async fn send_reminder(
+    state: &State,
+    token: Option<&NotificationToken>,
+    webhook_url: Option<&str>,
+    reminder: &Reminder,
+) -> Result<()> {
+    if let Some(token) = token {
+        let status_code = server::deliver(
+            state,
+            Uuid::new_v4(),
+            REMINDER_RELAY,
+            token.clone(),
+            &NotifyOptions::default(),
+        )
+        .await?;
+        if !status_code.is_success() {
+            bail!("Reminder notification failed with {status_code}");
+        }
+    }
+    if let Some(url) = webhook_url {
+        state
+            .http_client()
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(reminder)?)
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
+
+/// Exports the expiry time of the APNS certificate.
+///
+/// Returns `None` if APNS is authenticated with a signing key
+/// or the certificate cannot be parsed.
+pub(crate) fn update(state: &State) -> Option<SystemTime> {
+    let Some(ApnsCredentials::Certificate {
+        certificate,
+        password,
+    }) = state.apns_credentials()
+    else {
+        return None;
+    };
+    let expiry = match certificate_expiry(&certificate, &password) {
+        Ok(expiry) => expiry,
+        Err(err) => {
+            warn!("Failed to read APNS certificate expiry: {err:#}.");
+            return None;
+        }
+    };
+    let expires = expiry
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    state
+        .metrics()
+        .apns_certificate_expiry_timestamp_seconds
+        .set(expires as i64);
+    Some(expiry)
+}
+
+/// Exports the APNS certificate expiry and sends reminders when it is close.
+///
+/// The certificate is checked again on each round,
+/// so reloaded certificates are picked up.
+pub async fn start(state: State, config: Option<ExpiryReminderConfig>) {
+    let token = match config
+        .as_ref()
+        .and_then(|config| config.token.as_deref())
+        .map(str::parse)
+        .transpose()
+    {
+        Ok(token) => token,
+        Err(err) => {
+            error!("Invalid expiry reminder token: {err:#}.");
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(expiry) = update(&state) else {
+            continue;
+        };
+        info!(
+            "APNS certificate expires at {}.",
+            humantime::format_rfc3339_seconds(expiry)
+        );
+        let Some(config) = &config else {
+            continue;
+        };
+        let Some(days_left) = reminder_due(SystemTime::now(), expiry, config.days) else {
+            continue;
+        };
+        warn!("APNS certificate expires in {days_left} days.");
+        let reminder = Reminder {
+            expires: expiry
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            days_left,
+        };
+        if let Err(err) = send_reminder(
+            &state,
+            token.as_ref(),
+            config.webhook_url.as_deref(),
+            &reminder,
+        )
+        .await
+        {
+            warn!("Failed to send APNS certificate expiry reminder: {err:#}.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reminder_due() {
+        let now = SystemTime::UNIX_EPOCH + DAY * 1000;
+        assert_eq!(reminder_due(now, now + DAY * 31, 30), None);
+        assert_eq!(reminder_due(now, now + DAY * 30, 30), None);
+        assert_eq!(reminder_due(now, now + DAY * 29, 30), Some(29));
+        assert_eq!(reminder_due(now, now + DAY / 2, 30), Some(0));
+
+        // Expired certificates keep getting reminders.
+        assert_eq!(reminder_due(now, now - DAY, 30), Some(0));
+    }
+
+    #[test]
+    fn test_invalid_certificate() {
+        assert!(certificate_expiry(b"not a certificate", "").is_err());
+    }
+}
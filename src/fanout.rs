@@ -0,0 +1,200 @@
+//! # Fan-out tokens.
+//!
+//! Clients maintaining redundant push channels, e.g. FCM and UnifiedPush,
+//! can register a single token of the form
+//! `fanout:<policy>:<JSON array of tokens>`
+//! so the relay does not need to know about the channels.
+//!
+//! With the `first` policy the tokens are notified in order
+//! until a notification is delivered.
+//! With the `all` policy all tokens are notified at once.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context as _, Error, Result};
+use axum::http::StatusCode;
+use log::*;
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+use crate::server::{self, NotificationToken, NotifyOptions};
+use crate::state::State;
+
+/// Maximum number of tokens in a fan-out token.
+const MAX_TOKENS: usize = 4;
+
+/// How notifications to a fan-out token are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanOutPolicy {
+    /// Notify the tokens in order until a notification is delivered.
+    First,
+
+    /// Notify all tokens at once.
+    All,
+}
+
+impl FromStr for FanOutPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "first" => Ok(Self::First),
+            "all" => Ok(Self::All),
+            _ => bail!("Unknown fan-out policy {s:?}"),
+        }
+    }
+}
+
+impl fmt::Display for FanOutPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::First => write!(f, "first"),
+            Self::All => write!(f, "all"),
+        }
+    }
+}
+
+/// Parses the part of a fan-out token after the `fanout:` prefix.
+pub(crate) fn parse(s: &str) -> Result<(FanOutPolicy, Vec<NotificationToken>)> {
+    let (policy, tokens) = s.split_once(':').context("Missing fan-out policy")?;
+    let policy = policy.parse()?;
+    let tokens: Vec<String> = serde_json::from_str(tokens).context("Invalid fan-out tokens")?;
+    ensure!(!tokens.is_empty(), "Fan-out token is empty");
+    ensure!(
+        tokens.len() <= MAX_TOKENS,
+        "Fan-out token has more than {MAX_TOKENS} tokens"
+    );
+    let tokens = tokens
+        .iter()
+        .map(|token| {
+            // Fan-out tokens are encrypted as a whole.
+            ensure!(
+                !token.starts_with("fanout:") && !token.starts_with("openpgp:"),
+                "Nested fan-out or encrypted tokens are not supported"
+            );
+            token.parse()
+        })
+        .collect::<Result<_>>()?;
+    Ok((policy, tokens))
+}
+
+/// Formats the part of a fan-out token after the `fanout:` prefix.
+pub(crate) fn format(policy: FanOutPolicy, tokens: &[NotificationToken]) -> String {
+    let tokens: Vec<String> = tokens.iter().map(|token| token.to_string()).collect();
+    let tokens = serde_json::to_string(&tokens).unwrap_or_default();
+    format!("{policy}:{tokens}")
+}
+
+/// Combines the statuses of the notified tokens
+/// into the status returned to the relay.
+///
+/// The notification is delivered if any token was notified.
+/// The token is gone only if all tokens are gone.
+fn combine(statuses: &[StatusCode]) -> StatusCode {
+    if let Some(status) = statuses.iter().find(|status| status.is_success()) {
+        *status
+    } else if let Some(status) = statuses.iter().find(|status| **status != StatusCode::GONE) {
+        *status
+    } else {
+        StatusCode::GONE
+    }
+}
+
+/// Delivers a direct notification to a single token of a fan-out token.
+///
+/// The future is boxed to break the recursion with [`server::deliver`].
+fn deliver_token<'a>(
+    state: &'a State,
+    id: Uuid,
+    relay: &'a str,
+    token: NotificationToken,
+    options: &'a NotifyOptions,
+) -> Pin<Box<dyn Future<Output = Result<StatusCode>> + Send + 'a>> {
+    Box::pin(server::deliver(state, id, relay, token, options))
+}
+
+/// Delivers a direct notification to the tokens of a fan-out token.
+///
+/// Each token is accounted in metrics and the event log separately.
+pub(crate) async fn deliver(
+    state: &State,
+    id: Uuid,
+    relay: &str,
+    policy: FanOutPolicy,
+    tokens: Vec<NotificationToken>,
+    options: &NotifyOptions,
+) -> Result<StatusCode> {
+    let mut statuses = Vec::with_capacity(tokens.len());
+    match policy {
+        FanOutPolicy::First => {
+            for token in tokens {
+                let status = deliver_token(state, id, relay, token, options).await;
+                let status = status.unwrap_or_else(|err| {
+                    warn!("Failed to deliver fan-out notification {id}: {err:#}.");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                });
+                statuses.push(status);
+                if status.is_success() {
+                    break;
+                }
+            }
+        }
+        FanOutPolicy::All => {
+            let mut tasks = JoinSet::new();
+            for token in tokens {
+                let state = state.clone();
+                let relay = relay.to_string();
+                let options = options.clone();
+                tasks
+                    .spawn(async move { deliver_token(&state, id, &relay, token, &options).await });
+            }
+            while let Some(res) = tasks.join_next().await {
+                let status = res.map_err(Error::from).and_then(|res| res);
+                statuses.push(status.unwrap_or_else(|err| {
+                    warn!("Failed to deliver fan-out notification {id}: {err:#}.");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }));
+            }
+        }
+    }
+    Ok(combine(&statuses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let s = r#"first:["fcm-chat.delta:abc","up:https://ntfy.example.org/x"]"#;
+        let (policy, tokens) = parse(s)?;
+        assert_eq!(policy, FanOutPolicy::First);
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(format(policy, &tokens), s);
+
+        assert!(parse(r#"all:["foo"]"#).is_ok());
+        assert!(parse(r#"some:["foo"]"#).is_err());
+        assert!(parse("all:[]").is_err());
+        assert!(parse(r#"all:["a","b","c","d","e"]"#).is_err());
+        assert!(parse(r#"all:["openpgp:foo"]"#).is_err());
+        assert!(parse(r#"all:["fanout:all:[\"foo\"]"]"#).is_err());
+        assert!(parse("all:foo").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_combine() {
+        use StatusCode as S;
+
+        assert_eq!(combine(&[S::GONE, S::OK]), S::OK);
+        assert_eq!(combine(&[S::GONE, S::GONE]), S::GONE);
+        assert_eq!(
+            combine(&[S::GONE, S::TOO_MANY_REQUESTS]),
+            S::TOO_MANY_REQUESTS
+        );
+        assert_eq!(combine(&[]), S::GONE);
+    }
+}
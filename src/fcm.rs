@@ -0,0 +1,326 @@
+//! # FCM projects.
+//!
+//! FCM tokens carry the package name of the app they belong to.
+//! Packages of apps published from other Firebase projects,
+//! e.g. forks or beta builds, are mapped to their project
+//! in the configuration file.
+//! Packages that are not mapped use the default project
+//! authenticated with `--fcm-key-path` or the `NOTIFIERS_FCM_KEY` environment variable.
+//!
+//! If refreshing an access token fails, the last valid token
+//! is used until it expires while the refresh is retried
+//! in the background with exponential backoff,
+//! so short Google auth hiccups do not fail Android notifications.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context as _, Result};
+use log::*;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use yup_oauth2::authenticator::DefaultAuthenticator;
+
+use crate::state::FcmKey;
+
+/// Base URL of the FCM API used unless `fcm_url` is configured.
+pub(crate) const DEFAULT_URL: &str = "https://fcm.googleapis.com";
+
+/// ID of the default Firebase project.
+const DEFAULT_PROJECT_ID: &str = "delta-chat-fcm";
+
+/// OAuth 2.0 scope for sending FCM messages.
+const SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+
+/// Delay before the first background refresh attempt, doubled for each following one.
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between background refresh attempts.
+const MAX_REFRESH_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Last access token obtained from Google.
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    expires_at: Option<SystemTime>,
+}
+
+impl CachedToken {
+    fn new(token: &yup_oauth2::AccessToken) -> Option<Self> {
+        Some(Self {
+            token: token.token()?.to_string(),
+            expires_at: token.expiration_time().map(|expires_at| {
+                SystemTime::UNIX_EPOCH
+                    + Duration::from_secs(expires_at.unix_timestamp().max(0) as u64)
+            }),
+        })
+    }
+
+    fn is_valid_at(&self, now: SystemTime) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+/// Firebase project defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FcmProjectConfig {
+    /// Firebase project ID.
+    pub project_id: String,
+
+    /// Path to the service account JSON key of the project.
+    pub key_path: String,
+
+    /// Package names of the apps using the project.
+    pub packages: Vec<String>,
+}
+
+pub(crate) struct FcmProject {
+    project_id: String,
+    authenticator: Option<DefaultAuthenticator>,
+
+    /// Last valid access token.
+    cached: Arc<Mutex<Option<CachedToken>>>,
+
+    /// True while the token is refreshed in the background.
+    refreshing: Arc<AtomicBool>,
+
+    /// Task refreshing the token in the background.
+    refresh_task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl FcmProject {
+    async fn new(project_id: &str, key: Option<&FcmKey>) -> Result<Self> {
+        let authenticator = match key {
+            Some(key) => {
+                let key = match key {
+                    FcmKey::Path(key_path) => yup_oauth2::read_service_account_key(key_path)
+                        .await
+                        .with_context(|| format!("Failed to read key {}", key_path.display()))?,
+                    FcmKey::Json(json) => yup_oauth2::parse_service_account_key(json)
+                        .context("Failed to parse service account key")?,
+                };
+                let authenticator = yup_oauth2::ServiceAccountAuthenticator::builder(key)
+                    .build()
+                    .await
+                    .context("Failed to create authenticator")?;
+                Some(authenticator)
+            }
+            None => None,
+        };
+        Ok(Self {
+            project_id: project_id.to_string(),
+            authenticator,
+            cached: Default::default(),
+            refreshing: Default::default(),
+            refresh_task: Default::default(),
+        })
+    }
+
+    pub(crate) fn project_id(&self) -> &str {
+        &self.project_id
+    }
+
+    pub(crate) fn has_authenticator(&self) -> bool {
+        self.authenticator.is_some()
+    }
+
+    /// Returns the cached access token if it has not expired.
+    fn cached_token(&self, now: SystemTime) -> Option<String> {
+        self.cached
+            .lock()
+            .as_ref()
+            .filter(|cached| cached.is_valid_at(now))
+            .map(|cached| cached.token.clone())
+    }
+
+    /// Returns an OAuth 2.0 access token,
+    /// refreshing it if it is about to expire.
+    ///
+    /// If refreshing fails, the last valid token is returned
+    /// and the refresh is retried in the background.
+    pub(crate) async fn access_token(&self) -> Result<Option<String>> {
+        let Some(authenticator) = &self.authenticator else {
+            return Ok(None);
+        };
+        if self.refreshing.load(Ordering::Relaxed) {
+            if let Some(token) = self.cached_token(SystemTime::now()) {
+                return Ok(Some(token));
+            }
+        }
+        match authenticator.token(&[SCOPE]).await {
+            Ok(token) => {
+                let cached = CachedToken::new(&token);
+                let token = cached.as_ref().map(|cached| cached.token.clone());
+                if cached.is_some() {
+                    *self.cached.lock() = cached;
+                }
+                Ok(token)
+            }
+            Err(err) => {
+                let Some(token) = self.cached_token(SystemTime::now()) else {
+                    return Err(err.into());
+                };
+                warn!(
+                    "Failed to refresh FCM access token for {}, using the cached one: {err}.",
+                    self.project_id
+                );
+                self.spawn_refresh(authenticator.clone());
+                Ok(Some(token))
+            }
+        }
+    }
+
+    /// Retries refreshing the access token in the background until it succeeds.
+    fn spawn_refresh(&self, authenticator: DefaultAuthenticator) {
+        if self.refreshing.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let project_id = self.project_id.clone();
+        let cached = self.cached.clone();
+        let refreshing = self.refreshing.clone();
+        let task = tokio::task::spawn(async move {
+            let mut delay = REFRESH_RETRY_DELAY;
+            loop {
+                tokio::time::sleep(delay).await;
+                match authenticator.token(&[SCOPE]).await {
+                    Ok(token) => {
+                        if let Some(token) = CachedToken::new(&token) {
+                            *cached.lock() = Some(token);
+                        }
+                        info!("Refreshed FCM access token for {project_id}.");
+                        break;
+                    }
+                    Err(err) => {
+                        warn!("Failed to refresh FCM access token for {project_id}: {err}.");
+                        delay = (delay * 2).min(MAX_REFRESH_RETRY_DELAY);
+                    }
+                }
+            }
+            refreshing.store(false, Ordering::Relaxed);
+        });
+        *self.refresh_task.lock() = Some(task);
+    }
+
+    /// Stops refreshing the access token in the background.
+    fn abort_refresh(&self) {
+        if let Some(task) = self.refresh_task.lock().take() {
+            task.abort();
+        }
+    }
+}
+
+pub(crate) struct FcmProjects {
+    default: Arc<FcmProject>,
+
+    /// Configured projects by package name.
+    packages: HashMap<String, Arc<FcmProject>>,
+}
+
+impl FcmProjects {
+    pub(crate) async fn new(
+        default_key: Option<&FcmKey>,
+        configured: &[FcmProjectConfig],
+    ) -> Result<Self> {
+        let default = Arc::new(FcmProject::new(DEFAULT_PROJECT_ID, default_key).await?);
+        let mut packages = HashMap::new();
+        for config in configured {
+            let key = FcmKey::Path(PathBuf::from(&config.key_path));
+            let project = Arc::new(
+                FcmProject::new(&config.project_id, Some(&key))
+                    .await
+                    .with_context(|| format!("Invalid FCM project {}", config.project_id))?,
+            );
+            for package in &config.packages {
+                packages.insert(package.clone(), project.clone());
+            }
+        }
+        Ok(Self { default, packages })
+    }
+
+    /// Returns the project for the package.
+    pub(crate) fn get(&self, package_name: &str) -> Arc<FcmProject> {
+        self.packages
+            .get(package_name)
+            .unwrap_or(&self.default)
+            .clone()
+    }
+
+    /// Returns all projects, each of them once.
+    pub(crate) fn projects(&self) -> Vec<Arc<FcmProject>> {
+        let mut projects = vec![self.default.clone()];
+        for project in self.packages.values() {
+            if !projects.iter().any(|p| Arc::ptr_eq(p, project)) {
+                projects.push(project.clone());
+            }
+        }
+        projects
+    }
+
+    /// Returns the default project.
+    pub(crate) fn default_project(&self) -> &FcmProject {
+        &self.default
+    }
+
+    /// Stops background refreshes of access tokens of all projects,
+    /// e.g. because the projects are replaced after reloading credentials.
+    pub(crate) fn abort_refreshes(&self) {
+        for project in self.projects() {
+            project.abort_refresh();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get() -> Result<()> {
+        let mut projects = FcmProjects::new(None, &[]).await?;
+        let beta = Arc::new(FcmProject::new("delta-chat-beta", None).await?);
+        projects
+            .packages
+            .insert("chat.delta.beta".to_string(), beta.clone());
+        projects
+            .packages
+            .insert("chat.delta.nightly".to_string(), beta);
+
+        assert_eq!(projects.get("chat.delta").project_id(), DEFAULT_PROJECT_ID);
+        assert_eq!(
+            projects.get("chat.delta.beta").project_id(),
+            "delta-chat-beta"
+        );
+        assert!(!projects.default_project().has_authenticator());
+
+        // Projects shared by several packages are listed once.
+        assert_eq!(projects.projects().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_token() {
+        let now = SystemTime::now();
+        let project = FcmProject {
+            project_id: DEFAULT_PROJECT_ID.to_string(),
+            authenticator: None,
+            cached: Default::default(),
+            refreshing: Default::default(),
+            refresh_task: Default::default(),
+        };
+        assert_eq!(project.cached_token(now), None);
+
+        *project.cached.lock() = Some(CachedToken {
+            token: "token".to_string(),
+            expires_at: Some(now + Duration::from_secs(60)),
+        });
+        assert_eq!(project.cached_token(now), Some("token".to_string()));
+
+        // Expired tokens are not used.
+        assert_eq!(project.cached_token(now + Duration::from_secs(60)), None);
+    }
+}
@@ -0,0 +1,231 @@
+//! # Runtime feature flags.
+//!
+//! Risky behaviors are disabled by default
+//! and can be enabled at runtime with the admin endpoints,
+//! so they can be tried out on a running gateway and turned off again
+//! without a restart.
+//! Flag states are persisted in the database.
+//!
+//! Flags can also be rolled out gradually
+//! by enabling them for a percentage of tokens.
+//! Tokens are assigned to the rollout by a hash,
+//! so the same token always takes the same path
+//! and tokens only join the rollout as the percentage grows.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{ensure, Result};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::schedule::storage::Tree;
+
+/// Feature flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Flag {
+    /// Retry APNS production tokens rejected as `BadDeviceToken`
+    /// with the sandbox server before treating them as invalid.
+    SandboxFallback,
+}
+
+impl Flag {
+    /// All known flags.
+    const ALL: &'static [Flag] = &[Flag::SandboxFallback];
+
+    fn key(self) -> String {
+        serde_json::to_string(&self)
+            .unwrap_or_default()
+            .trim_matches('"')
+            .to_string()
+    }
+}
+
+/// State of a flag returned by the admin endpoints.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct FlagInfo {
+    pub(crate) flag: Flag,
+    pub(crate) enabled: bool,
+
+    /// Percentage of tokens the flag is enabled for.
+    pub(crate) percent: u8,
+}
+
+/// Returns the rollout bucket of the token, from 0 to 99.
+fn bucket(flag: Flag, token: &str) -> u8 {
+    let hash = Sha256::new()
+        .chain_update(flag.key())
+        .chain_update(b":")
+        .chain_update(token)
+        .finalize();
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    (u64::from_be_bytes(bytes) % 100) as u8
+}
+
+pub(crate) struct Flags {
+    /// Database tree with enabled flags.
+    ///
+    /// Values hold the rollout percentage,
+    /// empty values written by older versions mean 100%.
+    tree: Arc<dyn Tree>,
+
+    /// Rollout percentages of enabled flags.
+    percents: RwLock<HashMap<Flag, u8>>,
+}
+
+impl Flags {
+    pub(crate) fn new(tree: Arc<dyn Tree>) -> Result<Self> {
+        let mut percents = HashMap::new();
+        for &flag in Flag::ALL {
+            if let Some(value) = tree.get(flag.key().as_bytes())? {
+                percents.insert(flag, value.first().copied().unwrap_or(100).min(100));
+            }
+        }
+        Ok(Self {
+            tree,
+            percents: RwLock::new(percents),
+        })
+    }
+
+    /// Returns the percentage of tokens the flag is enabled for.
+    pub(crate) fn percent(&self, flag: Flag) -> u8 {
+        self.percents.read().get(&flag).copied().unwrap_or_default()
+    }
+
+    /// Returns true if the flag is enabled for the token.
+    pub(crate) fn is_enabled_for(&self, flag: Flag, token: &str) -> bool {
+        match self.percent(flag) {
+            0 => false,
+            100 => true,
+            percent => bucket(flag, token) < percent,
+        }
+    }
+
+    /// Enables the flag for a percentage of tokens.
+    ///
+    /// Zero disables the flag.
+    pub(crate) fn set_percent(&self, flag: Flag, percent: u8) -> Result<()> {
+        ensure!(percent <= 100, "Percentage {percent} is over 100");
+        if percent > 0 {
+            self.tree.insert(flag.key().as_bytes(), &[percent])?;
+            self.percents.write().insert(flag, percent);
+        } else {
+            self.tree.remove(flag.key().as_bytes())?;
+            self.percents.write().remove(&flag);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn list(&self) -> Vec<FlagInfo> {
+        Flag::ALL
+            .iter()
+            .map(|&flag| {
+                let percent = self.percent(flag);
+                FlagInfo {
+                    flag,
+                    enabled: percent > 0,
+                    percent,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::schedule::storage::{SledStorage, Storage as _};
+
+    #[test]
+    fn test_flags() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = SledStorage::open(&dir.path().join("db.sled"))?;
+        let flags = Flags::new(db.open_tree("flags")?)?;
+        assert!(!flags.is_enabled_for(Flag::SandboxFallback, "token"));
+
+        flags.set_percent(Flag::SandboxFallback, 100)?;
+        assert!(flags.is_enabled_for(Flag::SandboxFallback, "token"));
+        assert_eq!(Flag::SandboxFallback.key(), "sandbox_fallback");
+
+        // Flags are persisted.
+        let flags = Flags::new(db.open_tree("flags")?)?;
+        assert!(flags.is_enabled_for(Flag::SandboxFallback, "token"));
+        assert_eq!(
+            flags.list(),
+            [FlagInfo {
+                flag: Flag::SandboxFallback,
+                enabled: true,
+                percent: 100,
+            }]
+        );
+
+        flags.set_percent(Flag::SandboxFallback, 0)?;
+        let flags = Flags::new(db.open_tree("flags")?)?;
+        assert!(!flags.is_enabled_for(Flag::SandboxFallback, "token"));
+
+        // Flags enabled by older versions are enabled for all tokens.
+        db.open_tree("flags")?.insert(b"sandbox_fallback", &[])?;
+        let flags = Flags::new(db.open_tree("flags")?)?;
+        assert_eq!(flags.percent(Flag::SandboxFallback), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket() {
+        for &flag in Flag::ALL {
+            let mut counts = [0; 10];
+            for i in 0..10_000 {
+                let token = format!("token{i}");
+                let token_bucket = bucket(flag, &token);
+                assert!(token_bucket < 100);
+                // Tokens always land in the same bucket.
+                assert_eq!(token_bucket, bucket(flag, &token));
+                counts[usize::from(token_bucket / 10)] += 1;
+            }
+            // Buckets are spread evenly.
+            assert!(
+                counts.iter().all(|count| (800..1200).contains(count)),
+                "{:?}",
+                counts
+            );
+        }
+    }
+
+    #[test]
+    fn test_rollout() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = SledStorage::open(&dir.path().join("db.sled"))?;
+        let flags = Flags::new(db.open_tree("flags")?)?;
+        let tokens: Vec<String> = (0..1000).map(|i| format!("token{i}")).collect();
+        for &flag in Flag::ALL {
+            let enabled = |flags: &Flags| -> Vec<bool> {
+                tokens
+                    .iter()
+                    .map(|token| flags.is_enabled_for(flag, token))
+                    .collect()
+            };
+
+            flags.set_percent(flag, 10)?;
+            let ten = enabled(&flags);
+            let count = ten.iter().filter(|enabled| **enabled).count();
+            assert!((50..150).contains(&count), "{}", count);
+
+            // Tokens stay in the rollout as it grows.
+            flags.set_percent(flag, 50)?;
+            let fifty = enabled(&flags);
+            assert!(ten.iter().zip(&fifty).all(|(ten, fifty)| !ten || *fifty));
+
+            assert!(flags.set_percent(flag, 101).is_err());
+            assert_eq!(flags.percent(flag), 50);
+
+            // Rollout percentage is persisted.
+            let flags = Flags::new(db.open_tree("flags")?)?;
+            assert_eq!(enabled(&flags), fifty);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! # HTTP/3 listener.
+//!
+//! Relays on lossy links suffer from head-of-line blocking on TCP,
+//! so with `--http3` the public API is also served over QUIC
+//! on the UDP port of the HTTPS listener, using the ACME certificates.
+//! The TCP listener keeps working for clients without HTTP/3 support
+//! and advertises the QUIC listener in the `Alt-Svc` header.
+//!
+//! Requests are dispatched to the same router as TCP requests,
+//! so they are subject to the same authentication, rate limits and body limits.
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{Response, StatusCode};
+use axum::Router;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h3::server::RequestResolver;
+use log::*;
+use quinn::crypto::rustls::QuicServerConfig;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt as _;
+use tower::ServiceExt as _;
+
+use crate::compression::MAX_DECOMPRESSED_SIZE;
+use crate::state::State;
+use crate::tls::Certificates;
+
+/// Returns the value of the `Alt-Svc` header advertising the QUIC listener on `port`.
+pub(crate) fn alt_svc(port: u16) -> String {
+    format!("h3=\":{port}\"; ma=86400")
+}
+
+/// Binds the QUIC endpoint.
+pub(crate) fn bind(address: SocketAddr, certificates: &Certificates) -> Result<quinn::Endpoint> {
+    let crypto = QuicServerConfig::try_from(certificates.http3_config())
+        .context("Invalid TLS configuration for QUIC")?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    let endpoint = quinn::Endpoint::server(config, address)
+        .with_context(|| format!("Failed to bind HTTP/3 listener to {address}"))?;
+    Ok(endpoint)
+}
+
+/// Serves `app` on the QUIC endpoint.
+///
+/// Returns when the gateway is shutting down
+/// after the requests in progress are finished.
+pub(crate) async fn serve(state: State, app: Router, endpoint: quinn::Endpoint) -> Result<()> {
+    let mut connections = JoinSet::new();
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = state.shutdown_requested() => break,
+        };
+        let Some(incoming) = incoming else {
+            break;
+        };
+        let state = state.clone();
+        let app = app.clone();
+        connections.spawn(async move {
+            if let Err(err) = serve_connection(state, app, incoming).await {
+                debug!("HTTP/3 connection failed: {err:#}.");
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// Serves the requests of a single QUIC connection.
+///
+/// On shutdown, the client is told with a GOAWAY frame
+/// not to send further requests,
+/// and the connection is closed once the requests in progress are answered.
+async fn serve_connection(state: State, app: Router, incoming: quinn::Incoming) -> Result<()> {
+    let connection = incoming.await?;
+    let peer = connection.remote_address();
+    let mut connection: h3::server::Connection<_, Bytes> =
+        h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+    let mut requests = JoinSet::new();
+    loop {
+        let resolver = tokio::select! {
+            resolver = connection.accept() => resolver,
+            _ = state.shutdown_requested() => {
+                connection.shutdown(0).await?;
+                break;
+            }
+        };
+        match resolver {
+            Ok(Some(resolver)) => {
+                let app = app.clone();
+                requests.spawn(async move {
+                    if let Err(err) = serve_request(app, peer, resolver).await {
+                        debug!("HTTP/3 request failed: {err:#}.");
+                    }
+                });
+            }
+            Ok(None) => break,
+            Err(err) if err.is_h3_no_error() => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    while requests.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Reads a request from the stream, passes it to `app` and sends the response.
+async fn serve_request(
+    app: Router,
+    peer: SocketAddr,
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+) -> Result<()> {
+    let (request, stream) = resolver.resolve_request().await?;
+    let (mut send, mut recv) = stream.split();
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = recv.recv_data().await? {
+        if body.len() + chunk.remaining() > MAX_DECOMPRESSED_SIZE {
+            send.send_response(
+                Response::builder()
+                    .status(StatusCode::PAYLOAD_TOO_LARGE)
+                    .body(())?,
+            )
+            .await?;
+            send.finish().await?;
+            return Ok(());
+        }
+        body.put(chunk);
+    }
+    let mut request = request.map(|()| Body::from(body.freeze()));
+    request.extensions_mut().insert(ConnectInfo(peer));
+
+    let response = app.oneshot(request).await?;
+    let (parts, body) = response.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        send.send_data(chunk?).await?;
+    }
+    send.finish().await?;
+    Ok(())
+}
@@ -1,7 +1,56 @@
+mod admin;
+mod apikeys;
+mod callbacks;
+mod chaos;
+mod compression;
+pub mod config;
+mod deadtokens;
 mod debouncer;
+mod dryrun;
+mod endpoints;
+pub mod eventlog;
+mod exclusion;
+pub mod expiry;
+mod fanout;
+mod fcm;
+mod flags;
+mod http3;
+pub mod logging;
+pub mod memory;
 pub mod metrics;
 pub mod notifier;
+mod onesignal;
 mod openpgp;
+mod oppo;
+pub mod otel;
+mod outage;
+mod pow;
+mod provider;
+pub mod proxy;
+pub mod queue;
+pub mod ratelimit;
+mod receipts;
+mod retry;
+pub mod sampling;
+mod sanitize;
 pub mod schedule;
 pub mod server;
 pub mod state;
+pub mod stats;
+pub mod supervisor;
+mod templates;
+pub mod tls;
+mod traces;
+mod unifiedpush;
+mod vivo;
+mod webhook;
+mod wns;
+
+pub use openpgp::{reencrypt_tokens, ReencryptionReport};
+
+/// Internals exported for the benchmarks in `benches/`.
+#[doc(hidden)]
+pub mod bench_support {
+    pub use crate::debouncer::Debouncer;
+    pub use crate::openpgp::PgpDecryptor;
+}
@@ -0,0 +1,698 @@
+//! # Log output backends.
+//!
+//! By default logs are printed to stdout using `femme`.
+//! Alternatively they can be shipped to a syslog daemon
+//! as [RFC 5424](https://www.rfc-editor.org/rfc/rfc5424) messages
+//! over a unix datagram socket or UDP,
+//! or sent to journald using its
+//! [native protocol](https://systemd.io/JOURNAL_NATIVE_PROTOCOL/)
+//! so that key-value pairs of log records such as `provider` and `status`
+//! become journal fields that can be filtered with `journalctl`.
+//!
+//! Device tokens are pushable identifiers and should not end up in logs.
+//! Log lines refer to tokens via [`redact`], which prints a short salted hash
+//! unless full tokens are explicitly enabled for debugging.
+//!
+//! In debug mode debug records of this crate are logged as well,
+//! covering payload sizes, timings, header decisions and schedule changes.
+//! Debug mode never logs full tokens or payload contents.
+//!
+//! With the JSON log format, records written to stdout or syslog
+//! are single-line JSON objects with `timestamp`, `level`, `target` and `message`
+//! and the key-value pairs of the record as further fields,
+//! e.g. `provider`, `token`, `status` and `latency_ms` of deliveries.
+//! Debug records of dependencies are filtered out
+//! because some of them, e.g. OAuth clients, log secrets at debug level.
+
+use std::fmt;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context as _, Result};
+use chrono::{SecondsFormat, Utc};
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rand::RngCore as _;
+use sha2::{Digest as _, Sha256};
+
+/// Where the logs are written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTarget {
+    Stdout,
+    Syslog,
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stdout" => Ok(Self::Stdout),
+            "syslog" => Ok(Self::Syslog),
+            "journald" => Ok(Self::Journald),
+            _ => bail!("Unknown log target {s:?}, expected \"stdout\", \"syslog\" or \"journald\""),
+        }
+    }
+}
+
+/// How records are formatted on stdout and syslog.
+///
+/// Journald always receives key-value pairs as separate fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unknown log format {s:?}, expected \"text\" or \"json\""),
+        }
+    }
+}
+
+/// Whether tokens are logged in full instead of hashed.
+static LOG_FULL_TOKENS: AtomicBool = AtomicBool::new(false);
+
+/// Random per-process salt for token hashes.
+static TOKEN_SALT: OnceLock<[u8; 16]> = OnceLock::new();
+
+/// Enables or disables logging of full tokens.
+///
+/// This should only be enabled for debugging.
+pub fn set_log_full_tokens(enabled: bool) {
+    LOG_FULL_TOKENS.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns true if full tokens may be logged.
+pub fn log_full_tokens() -> bool {
+    LOG_FULL_TOKENS.load(Ordering::Relaxed)
+}
+
+/// Token wrapper that displays a short salted hash of the token.
+pub struct RedactedToken<'a>(&'a str);
+
+/// Returns a value to use in place of the token in log lines.
+///
+/// Hashes are stable for the lifetime of the process,
+/// so log lines about the same token can be correlated.
+pub fn redact(token: &str) -> RedactedToken<'_> {
+    RedactedToken(token)
+}
+
+impl fmt::Display for RedactedToken<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if log_full_tokens() {
+            return write!(f, "{}", self.0);
+        }
+        let salt = TOKEN_SALT.get_or_init(|| {
+            let mut salt = [0; 16];
+            rand::thread_rng().fill_bytes(&mut salt);
+            salt
+        });
+        let hash = Sha256::new()
+            .chain_update(salt)
+            .chain_update(self.0.as_bytes())
+            .finalize();
+        write!(f, "token#{}", hex::encode(&hash[..4]))
+    }
+}
+
+impl fmt::Debug for RedactedToken<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Path to the journald native protocol socket.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Initializes the global logger.
+///
+/// If no target is given, logs go to journald
+/// when the process runs as a systemd service with its output connected to the journal
+/// and to stdout otherwise.
+///
+/// `syslog_address` is either a path to a unix socket such as `/dev/log`
+/// or a `host:port` UDP address.
+///
+/// If `debug` is true, debug records of this crate are logged too
+/// and full tokens are never logged.
+pub fn start(
+    target: Option<LogTarget>,
+    format: LogFormat,
+    syslog_address: &str,
+    debug: bool,
+) -> Result<()> {
+    let target = target.unwrap_or_else(|| {
+        if std::env::var_os("JOURNAL_STREAM").is_some() {
+            LogTarget::Journald
+        } else {
+            LogTarget::Stdout
+        }
+    });
+    let logger: Box<dyn Log> = match target {
+        LogTarget::Stdout if format == LogFormat::Json => Box::new(JsonLogger),
+        LogTarget::Stdout if !debug => {
+            femme::start();
+            return Ok(());
+        }
+        LogTarget::Stdout => Box::new(StdoutLogger),
+        LogTarget::Syslog => Box::new(SyslogLogger::connect(syslog_address, format)?),
+        LogTarget::Journald => Box::new(JournaldLogger::connect()?),
+    };
+    if debug {
+        set_log_full_tokens(false);
+        log::set_boxed_logger(Box::new(CrateDebugFilter(logger)))?;
+        log::set_max_level(LevelFilter::Debug);
+    } else {
+        log::set_boxed_logger(logger)?;
+        log::set_max_level(LevelFilter::Info);
+    }
+    Ok(())
+}
+
+/// Logger wrapper that passes debug records of this crate only.
+struct CrateDebugFilter(Box<dyn Log>);
+
+impl Log for CrateDebugFilter {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        (metadata.level() <= Level::Info || metadata.target().starts_with("notifiers"))
+            && self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Plain stdout logger used in debug mode.
+///
+/// `femme` does not allow filtering records by target.
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        println!(
+            "{timestamp} {:<5} {} {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logger writing records to stdout as single-line JSON objects.
+struct JsonLogger;
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        println!("{}", format_json_record(&timestamp, record));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Converts a value of a log record into JSON,
+/// keeping numbers and booleans as such.
+fn json_value(value: &Value) -> serde_json::Value {
+    if let Some(value) = value.to_u64() {
+        value.into()
+    } else if let Some(value) = value.to_i64() {
+        value.into()
+    } else if let Some(value) = value.to_f64().and_then(serde_json::Number::from_f64) {
+        value.into()
+    } else if let Some(value) = value.to_bool() {
+        value.into()
+    } else {
+        value.to_string().into()
+    }
+}
+
+/// Collects key-value pairs of a log record into JSON fields.
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.insert(key.as_str().to_string(), json_value(&value));
+        Ok(())
+    }
+}
+
+/// Formats a record as a single-line JSON object.
+///
+/// Key-value pairs named like the common fields are overwritten by them.
+fn format_json_record(timestamp: &str, record: &Record) -> String {
+    let mut fields = serde_json::Map::new();
+    record.key_values().visit(&mut JsonFields(&mut fields)).ok();
+    fields.insert("timestamp".to_string(), timestamp.into());
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+    serde_json::Value::Object(fields).to_string()
+}
+
+/// Maps log level to syslog severity.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+enum SyslogTransport {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+/// Logger sending RFC 5424 messages to a syslog daemon.
+struct SyslogLogger {
+    transport: SyslogTransport,
+    format: LogFormat,
+    hostname: String,
+    procid: u32,
+}
+
+/// Facility `daemon` as defined in RFC 5424.
+const FACILITY_DAEMON: u8 = 3;
+
+const APP_NAME: &str = "notifiers";
+
+impl SyslogLogger {
+    fn connect(address: &str, format: LogFormat) -> Result<Self> {
+        let transport = if address.starts_with('/') {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(address)
+                .with_context(|| format!("Failed to connect to syslog socket {address}"))?;
+            SyslogTransport::Unix(socket)
+        } else {
+            let socket = UdpSocket::bind(("::", 0)).or_else(|_| UdpSocket::bind(("0.0.0.0", 0)))?;
+            socket
+                .connect(address)
+                .with_context(|| format!("Failed to connect to syslog server {address}"))?;
+            SyslogTransport::Udp(socket)
+        };
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+        Ok(Self {
+            transport,
+            format,
+            hostname,
+            procid: std::process::id(),
+        })
+    }
+}
+
+/// Formats a single RFC 5424 syslog message.
+fn format_message(level: Level, timestamp: &str, hostname: &str, procid: u32, msg: &str) -> String {
+    let pri = FACILITY_DAEMON * 8 + severity(level);
+    let hostname = if hostname.is_empty() { "-" } else { hostname };
+
+    // MSGID and STRUCTURED-DATA are not used.
+    format!("<{pri}>1 {timestamp} {hostname} {APP_NAME} {procid} - - {msg}")
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+        let msg = match self.format {
+            LogFormat::Text => record.args().to_string(),
+            LogFormat::Json => format_json_record(&timestamp, record),
+        };
+        let message = format_message(
+            record.level(),
+            &timestamp,
+            &self.hostname,
+            self.procid,
+            &msg,
+        );
+        // There is nowhere to report logging errors to.
+        let _ = match &self.transport {
+            SyslogTransport::Unix(socket) => socket.send(message.as_bytes()),
+            SyslogTransport::Udp(socket) => socket.send(message.as_bytes()),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logger sending records to journald using the native protocol.
+struct JournaldLogger {
+    socket: UnixDatagram,
+}
+
+impl JournaldLogger {
+    fn connect() -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(JOURNALD_SOCKET)
+            .with_context(|| format!("Failed to connect to journald socket {JOURNALD_SOCKET}"))?;
+        Ok(Self { socket })
+    }
+}
+
+/// Appends a single field to the journald datagram.
+///
+/// Values containing newlines use the binary length-prefixed encoding.
+fn append_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+/// Converts a key of a log record into a valid journal field name.
+///
+/// Journal field names consist of uppercase letters, digits and underscores
+/// and may not start with an underscore, which is reserved for trusted fields.
+fn journald_field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    name.trim_start_matches('_').to_string()
+}
+
+/// Collects key-value pairs of a log record into journal fields.
+struct JournaldFields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for JournaldFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let name = journald_field_name(key.as_str());
+        if !name.is_empty() {
+            append_field(self.0, &name, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+fn format_journald_record(record: &Record) -> Vec<u8> {
+    let mut buf = Vec::new();
+    append_field(&mut buf, "MESSAGE", &record.args().to_string());
+    append_field(&mut buf, "PRIORITY", &severity(record.level()).to_string());
+    append_field(&mut buf, "SYSLOG_IDENTIFIER", APP_NAME);
+    append_field(&mut buf, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        append_field(&mut buf, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        append_field(&mut buf, "CODE_LINE", &line.to_string());
+    }
+    record
+        .key_values()
+        .visit(&mut JournaldFields(&mut buf))
+        .ok();
+    buf
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let _ = self.socket.send(&format_journald_record(record));
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_message() {
+        assert_eq!(
+            format_message(
+                Level::Warn,
+                "2024-01-01T00:00:00.000000Z",
+                "gateway",
+                42,
+                "Hello"
+            ),
+            "<28>1 2024-01-01T00:00:00.000000Z gateway notifiers 42 - - Hello"
+        );
+        assert_eq!(
+            format_message(Level::Info, "2024-01-01T00:00:00.000000Z", "", 1, "x"),
+            "<30>1 2024-01-01T00:00:00.000000Z - notifiers 1 - - x"
+        );
+    }
+
+    #[test]
+    fn test_journald_record() {
+        let kvs = [("provider", "apns"), ("status", "410")];
+        let record = Record::builder()
+            .args(format_args!("multi\nline"))
+            .level(Level::Info)
+            .target("notifiers::server")
+            .key_values(&kvs)
+            .build();
+        let buf = format_journald_record(&record);
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&10u64.to_le_bytes());
+        expected.extend_from_slice(b"multi\nline\n");
+        expected.extend_from_slice(
+            b"PRIORITY=6\nSYSLOG_IDENTIFIER=notifiers\nTARGET=notifiers::server\nPROVIDER=apns\nSTATUS=410\n",
+        );
+        assert_eq!(buf, expected);
+
+        assert_eq!(journald_field_name("_status.code"), "STATUS_CODE");
+    }
+
+    #[test]
+    fn test_json_record() {
+        let kvs: [(&str, Value); 4] = [
+            ("provider", Value::from("apns")),
+            ("status", Value::from(410u16)),
+            ("latency_ms", Value::from(12u64)),
+            ("message", Value::from("ignored")),
+        ];
+        let record = Record::builder()
+            .args(format_args!("Failed to deliver \"notification\""))
+            .level(Level::Warn)
+            .target("notifiers::server")
+            .key_values(&kvs)
+            .build();
+        let line = format_json_record("2024-01-01T00:00:00.000Z", &record);
+        assert!(!line.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": "2024-01-01T00:00:00.000Z",
+                "level": "WARN",
+                "target": "notifiers::server",
+                "message": "Failed to deliver \"notification\"",
+                "provider": "apns",
+                "status": 410,
+                "latency_ms": 12,
+            })
+        );
+    }
+
+    #[test]
+    fn test_redact() {
+        let redacted = redact("secret-token").to_string();
+        assert!(redacted.starts_with("token#"));
+        assert_eq!(redacted.len(), "token#".len() + 8);
+        assert!(!redacted.contains("secret"));
+        assert_eq!(redacted, redact("secret-token").to_string());
+        assert_ne!(redacted, redact("other-token").to_string());
+    }
+
+    /// Returns the arguments of log macro invocations in the source,
+    /// with string literals replaced by the arguments they capture.
+    fn log_arguments(source: &str) -> Vec<String> {
+        let mut arguments = Vec::new();
+        for name in ["trace!(", "debug!(", "info!(", "warn!(", "error!(", "log!("] {
+            for (start, _) in source.match_indices(name) {
+                let preceding = source[..start].chars().next_back();
+                if preceding.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    continue;
+                }
+                let mut args = String::new();
+                let mut capture = None;
+                let mut depth = 1;
+                let mut string = false;
+                let mut chars = source[start + name.len()..].chars();
+                while let Some(c) = chars.next() {
+                    if string {
+                        match (c, &mut capture) {
+                            ('\\', _) => {
+                                chars.next();
+                            }
+                            ('"', _) => string = false,
+                            ('{', None) => capture = Some(String::new()),
+                            ('}' | ':', Some(ident)) => {
+                                args.push_str(&format!(" {ident} "));
+                                capture = None;
+                            }
+                            (c, Some(ident)) => ident.push(c),
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    match c {
+                        '"' => string = true,
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    args.push(c);
+                }
+                arguments.push(args);
+            }
+        }
+        arguments
+    }
+
+    /// Returns log arguments that refer to device tokens without hashing them.
+    fn unredacted_tokens(arguments: &str) -> Vec<String> {
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+        let mut found = Vec::new();
+        let mut offset = 0;
+        while let Some(start) = arguments[offset..].find(is_ident).map(|i| offset + i) {
+            let end = arguments[start..]
+                .find(|c: char| !is_ident(c) && c != '.')
+                .map_or(arguments.len(), |i| start + i);
+            let path = &arguments[start..end];
+            let is_token = path.split('.').any(|ident| {
+                ident == "token"
+                    || ident.ends_with("_token")
+                    || ident == "endpoint"
+                    || ident == "channel_uri"
+            });
+            let before = arguments[..start].trim_end_matches('&');
+            let after = &arguments[end..];
+            // Keys of structured fields are followed by `=` or a format specifier.
+            let is_key = after.trim_start().starts_with('=') || after.starts_with(":%");
+            if is_token && !before.ends_with("redact(") && !is_key {
+                found.push(path.to_string());
+            }
+            offset = end;
+        }
+        found
+    }
+
+    /// Appends paths of the Rust source files in `dir` and its subdirectories.
+    fn source_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                source_files(&path, files);
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                files.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_log_arguments() {
+        let source = r#"
+            info!("Removing token {}.", redact(&token));
+            warn!(provider = "fcm", token:% = redact(&device_token); "Failed.");
+            debug!("Sent to {token} in {:?}.", endpoint.elapsed());
+            error!("Failed: {}", res.device_token);
+        "#;
+        let found: Vec<_> = log_arguments(source)
+            .iter()
+            .flat_map(|arguments| unredacted_tokens(arguments))
+            .collect();
+        assert_eq!(found, ["token", "endpoint.elapsed", "res.device_token"]);
+    }
+
+    #[test]
+    fn test_tokens_are_redacted() {
+        let mut files = Vec::new();
+        source_files(
+            &std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+            &mut files,
+        );
+        let mut found = Vec::new();
+        for path in files {
+            // The OTLP endpoint is the URL of the trace collector.
+            if path.ends_with("otel.rs") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            // Tests do not run in production.
+            let source = source.split("#[cfg(test)]").next().unwrap_or_default();
+            for arguments in log_arguments(source) {
+                for token in unredacted_tokens(&arguments) {
+                    // Region label of the endpoint.
+                    if token != "endpoint.region" {
+                        found.push(format!("{}: {token}", path.display()));
+                    }
+                }
+            }
+        }
+        assert!(
+            found.is_empty(),
+            "Tokens logged without redact: {:?}",
+            found
+        );
+    }
+}
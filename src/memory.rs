@@ -0,0 +1,116 @@
+//! # Memory usage metrics and soft memory limit.
+//!
+//! The binary uses jemalloc as the global allocator,
+//! and the allocated and resident memory reported by its statistics
+//! are exported as gauges.
+//!
+//! When allocated memory exceeds the configured soft limit,
+//! expired debouncer and dead token entries, finished notification statuses,
+//! expired delivery receipts and cached schedule entries are dropped
+//! and the memory of their collections is released.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context as _, Result};
+use log::*;
+use tikv_jemalloc_ctl::{epoch, stats};
+
+use crate::state::State;
+
+/// Interval at which memory usage is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Memory usage reported by jemalloc.
+struct Usage {
+    /// Bytes allocated by the application.
+    allocated: u64,
+
+    /// Bytes in physically resident pages mapped by the allocator.
+    resident: u64,
+}
+
+/// Returns the current memory usage.
+///
+/// jemalloc caches its statistics,
+/// so they are refreshed by advancing the epoch first.
+fn usage() -> Result<Usage> {
+    epoch::advance().context("Failed to refresh jemalloc statistics")?;
+    let allocated = stats::allocated::read().context("Failed to read allocated memory")?;
+    let resident = stats::resident::read().context("Failed to read resident memory")?;
+    Ok(Usage {
+        allocated: allocated as u64,
+        resident: resident as u64,
+    })
+}
+
+/// Parses a size like `512MiB` into bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().context("Invalid size")?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        unit => bail!("Unknown size unit {unit:?}, expected B, KiB, MiB or GiB"),
+    };
+    number.checked_mul(multiplier).context("Size is too large")
+}
+
+/// Releases memory of caches that can be rebuilt or are best-effort.
+fn shrink(state: &State) {
+    let now = Instant::now();
+    state.debouncer().shrink(now);
+    state.dead_tokens().shrink(now);
+    state.queue().shrink(now);
+    state.receipts().shrink(now);
+    state.traces().shrink(now);
+    state.schedule().shrink_cache();
+}
+
+/// Periodically updates memory metrics and enforces the soft limit.
+pub async fn start(state: State, soft_limit: Option<u64>) {
+    let metrics = state.metrics();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Usage {
+            allocated,
+            resident,
+        } = match usage() {
+            Ok(usage) => usage,
+            Err(err) => {
+                error!("{err:#}.");
+                continue;
+            }
+        };
+        metrics.allocated_memory_bytes.set(allocated as i64);
+        metrics.resident_memory_bytes.set(resident as i64);
+        if let Some(soft_limit) = soft_limit.filter(|limit| allocated > *limit) {
+            warn!("Allocated memory {allocated} exceeds the soft limit {soft_limit}, shrinking caches.");
+            metrics.memory_pressure_total.inc();
+            shrink(&state);
+            if let Ok(usage) = usage() {
+                debug!("Allocated memory after shrinking: {}.", usage.allocated);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000").unwrap(), 1000);
+        assert_eq!(parse_size("64KiB").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("512 MiB").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1MB").is_err());
+        assert!(parse_size("MiB").is_err());
+    }
+}
@@ -4,27 +4,124 @@
 //! to allow exposting it on a private network only
 //! independently of the main service.
 
-use std::sync::atomic::AtomicI64;
+use std::sync::atomic::{AtomicI64, AtomicU64};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use axum::http::{header, HeaderMap};
 use axum::response::IntoResponse;
 use axum::routing::get;
 use prometheus_client::encoding::text::encode;
-use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder};
 use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::exemplar::CounterWithExemplar;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::registry::Registry;
+use serde::{Deserialize, Serialize};
 
+use crate::outage::{OutageDetector, ProviderStatus};
 use crate::state::State;
 
-#[derive(Debug, Copy, Clone, EncodeLabelValue, Eq, Hash, PartialEq)]
+#[derive(Debug, Copy, Clone, EncodeLabelValue, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum NotificationProvider {
     APNS,
     FCM,
     UBports,
     WebPush,
+    OneSignal,
+    Vivo,
+    OPPO,
+    UnifiedPush,
+    Webhook,
+    WNS,
+}
+
+impl NotificationProvider {
+    pub const ALL: [Self; 10] = [
+        Self::APNS,
+        Self::FCM,
+        Self::UBports,
+        Self::WebPush,
+        Self::OneSignal,
+        Self::Vivo,
+        Self::OPPO,
+        Self::UnifiedPush,
+        Self::Webhook,
+        Self::WNS,
+    ];
+
+    /// Returns the lowercase name of the provider as in the configuration file.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::APNS => "apns",
+            Self::FCM => "fcm",
+            Self::UBports => "ubports",
+            Self::WebPush => "webpush",
+            Self::OneSignal => "onesignal",
+            Self::Vivo => "vivo",
+            Self::OPPO => "oppo",
+            Self::UnifiedPush => "unifiedpush",
+            Self::Webhook => "webhook",
+            Self::WNS => "wns",
+        }
+    }
+}
+
+/// Type of the notification.
+#[derive(Debug, Copy, Clone, EncodeLabelValue, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationKind {
+    /// Visible notification requested by the chatmail relay.
+    Direct,
+
+    /// Silent periodic heartbeat notification.
+    Heartbeat,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct DebounceLabels {
+    pub kind: NotificationKind,
+}
+
+/// Class of the HTTP status returned by the provider.
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq)]
+pub enum StatusClass {
+    Success,
+    ClientError,
+    ServerError,
+
+    /// No response or a status of another class.
+    None,
+}
+
+impl StatusClass {
+    pub fn from_u16(status: u16) -> Self {
+        match status {
+            200..=299 => Self::Success,
+            400..=499 => Self::ClientError,
+            500..=599 => Self::ServerError,
+            _ => Self::None,
+        }
+    }
+
+    /// Returns the label value such as `4xx`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "2xx",
+            Self::ClientError => "4xx",
+            Self::ServerError => "5xx",
+            Self::None => "none",
+        }
+    }
+}
+
+impl EncodeLabelValue for StatusClass {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        self.as_str().encode(encoder)
+    }
 }
 
 #[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
@@ -32,6 +129,139 @@ pub struct FailureLabels {
     pub provider: NotificationProvider,
     pub reason: String,
     pub details: String,
+
+    /// Class of the HTTP status returned by the provider, if any.
+    pub status_class: StatusClass,
+
+    /// Whether the failure made the gateway delete the token from the schedule.
+    pub token_removed: bool,
+}
+
+impl FailureLabels {
+    pub fn new(
+        provider: NotificationProvider,
+        reason: impl Into<String>,
+        details: impl Into<String>,
+    ) -> Self {
+        Self {
+            provider,
+            reason: reason.into(),
+            details: details.into(),
+            status_class: StatusClass::None,
+            token_removed: false,
+        }
+    }
+
+    /// Sets the HTTP status returned by the provider.
+    pub fn with_status(self, status: u16) -> Self {
+        Self {
+            status_class: StatusClass::from_u16(status),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct ProviderLabels {
+    pub provider: NotificationProvider,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct ProviderStatusLabels {
+    pub provider: NotificationProvider,
+
+    /// 1 for the current status of the provider, 0 for the others.
+    pub status: ProviderStatus,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct RateLimitLabels {
+    /// Rate limited route, e.g. `register`.
+    pub route: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct RelayNotificationLabels {
+    /// API key name of the relay or `anonymous`.
+    pub relay: String,
+    pub provider: NotificationProvider,
+
+    /// HTTP status code returned to the relay.
+    pub status: u16,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct SampleLabels {
+    /// `delivered`, `removed` or `failed`.
+    pub result: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct RoundLabels {
+    /// `delivered`, `removed` or `failed`.
+    pub result: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct DryRunLabels {
+    pub provider: NotificationProvider,
+    pub kind: NotificationKind,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct OpenPgpKeyLabels {
+    pub key_id: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct RequestDurationLabels {
+    pub provider: NotificationProvider,
+
+    /// `production` or `sandbox` for APNS, the region of the endpoint for FCM.
+    pub endpoint: String,
+
+    /// `delivered`, `rejected` if the provider answered with an error
+    /// or `error` if no response was received.
+    pub outcome: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct QuarantineLabels {
+    /// `invalid_utf8`, `invalid_token`, `invalid_timestamp` or `orphaned_provenance`.
+    pub reason: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct CallbackLabels {
+    /// `delivered` or `failed`.
+    pub result: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct WorkerLabels {
+    /// Kind of the worker, e.g. `notifier`.
+    pub worker: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct FcmProjectLabels {
+    /// Firebase project ID.
+    pub project: String,
+}
+
+/// Exemplar labels linking a metric sample to a notification.
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct NotificationIdLabels {
+    pub notification_id: String,
+}
+
+#[derive(Debug, EncodeLabelSet, Eq, Hash, PartialEq, Clone)]
+pub struct RelayRegistrationLabels {
+    /// API key name of the relay or `anonymous`.
+    pub relay: String,
+
+    /// HTTP status code returned to the relay.
+    pub status: u16,
 }
 
 #[derive(Debug)]
@@ -50,8 +280,18 @@ pub struct Metrics {
     /// Number of successfully sent visible web push notifications.
     pub webpush_notifications_total: Counter,
 
-    /// Number of debounced notifications.
-    pub debounced_notifications_total: Counter,
+    /// Number of successfully sent UnifiedPush notifications,
+    /// both direct and heartbeat.
+    pub unifiedpush_notifications_total: Counter,
+
+    /// Number of successfully sent webhook notifications.
+    pub webhook_notifications_total: Counter,
+
+    /// Number of debounced notifications by notification type.
+    ///
+    /// Heartbeats are spread by the schedule and not debounced,
+    /// so only direct notifications are counted at the moment.
+    pub debounced_notifications_total: Family<DebounceLabels, Counter>,
 
     /// Number of tokens notified recently.
     pub debounced_set_size: Gauge<i64, AtomicI64>,
@@ -70,6 +310,155 @@ pub struct Metrics {
 
     /// Total failed notifications.
     pub failures_total: Family<FailureLabels, Counter>,
+
+    /// Number of requests rejected by rate limiting.
+    pub rate_limited_requests_total: Family<RateLimitLabels, Counter>,
+
+    /// Number of registrations rejected due to missing or invalid proof-of-work.
+    pub pow_rejections_total: Counter,
+
+    /// Number of registrations rejected because the relay exceeded its quota.
+    pub quota_rejections_total: Counter,
+
+    /// Number of direct notifications by relay, provider and status.
+    ///
+    /// The exemplar holds the ID of the latest notification.
+    pub relay_notifications_total:
+        Family<RelayNotificationLabels, CounterWithExemplar<NotificationIdLabels>>,
+
+    /// Number of heartbeat registrations by relay and status.
+    pub relay_registrations_total: Family<RelayRegistrationLabels, Counter>,
+
+    /// Number of requests to providers waiting for a response.
+    ///
+    /// Open HTTP/2 connections are not counted
+    /// because the client libraries do not report them.
+    pub inflight_requests: Family<ProviderLabels, Gauge<i64, AtomicI64>>,
+
+    /// Number of requests retried after a connection failure.
+    pub retries_total: Family<ProviderLabels, Counter>,
+
+    /// Number of notifications waiting in the background delivery queue.
+    pub queued_notifications: Gauge<i64, AtomicI64>,
+
+    /// Number of token invalidation callbacks to relays by result.
+    pub invalidation_callbacks_total: Family<CallbackLabels, Counter>,
+
+    /// Number of delivery receipts reported by client apps.
+    pub notification_receipts_total: Family<ProviderLabels, Counter>,
+
+    /// Number of panics in workers and request handlers.
+    pub panics_total: Counter,
+
+    /// Number of background worker restarts after a panic or an error.
+    pub worker_restarts_total: Family<WorkerLabels, Counter>,
+
+    /// Number of background workers restarted by the watchdog after stalling.
+    pub worker_stalls_total: Family<WorkerLabels, Counter>,
+
+    /// Number of bytes allocated on the heap.
+    pub allocated_memory_bytes: Gauge<i64, AtomicI64>,
+
+    /// Bytes in resident pages mapped by the allocator.
+    pub resident_memory_bytes: Gauge<i64, AtomicI64>,
+
+    /// Number of times allocated memory exceeded the soft limit.
+    pub memory_pressure_total: Counter,
+
+    /// Number of successfully sent visible OneSignal notifications.
+    pub onesignal_notifications_total: Counter,
+
+    /// Number of successfully sent visible Vivo notifications.
+    pub vivo_notifications_total: Counter,
+
+    /// Number of successfully sent visible OPPO notifications.
+    pub oppo_notifications_total: Counter,
+
+    /// Number of APNS payloads built over the size limit.
+    pub apns_oversized_payloads_total: Counter,
+
+    /// Number of registrations rejected because the encrypted token envelope is missing or too old.
+    pub stale_envelope_rejections_total: Counter,
+
+    /// Number of successfully sent FCM notifications by Firebase project.
+    pub fcm_project_notifications_total: Family<FcmProjectLabels, Counter>,
+
+    /// Unix timestamp at which the APNS certificate expires.
+    pub apns_certificate_expiry_timestamp_seconds: Gauge<i64, AtomicI64>,
+
+    /// Number of sampled heartbeat tokens by result.
+    pub heartbeat_samples_total: Family<SampleLabels, Counter>,
+
+    /// Share of sampled heartbeat tokens rejected by APNS in the last round.
+    pub heartbeat_sample_divergence: Gauge<f64, AtomicU64>,
+
+    /// Number of queued notifications replaced by a newer one to the same token.
+    pub coalesced_notifications_total: Counter,
+
+    /// Number of corrupt schedule database entries quarantined on startup by reason.
+    pub schedule_quarantined_entries: Family<QuarantineLabels, Counter>,
+
+    /// Number of heartbeats sent together in one batch.
+    pub heartbeat_batch_size: Histogram,
+
+    /// Health of each push provider derived from recent error rates,
+    /// exported as the `provider_status` gauge.
+    pub outage_detector: OutageDetector,
+
+    /// Number of heartbeats rescheduled because they were due in an exclusion window.
+    pub heartbeat_excluded_total: Counter,
+
+    /// Number of schedule lookups served from the in-memory cache.
+    pub schedule_cache_hits_total: Counter,
+
+    /// Number of schedule lookups that read the database.
+    pub schedule_cache_misses_total: Counter,
+
+    /// Number of notifications answered with 410 Gone because the token was recently reported gone.
+    pub dead_token_hits_total: Counter,
+
+    /// Number of successfully sent WNS notifications.
+    pub wns_notifications_total: Counter,
+
+    /// Number of registrations rejected because the schedule is full.
+    pub schedule_full_rejections_total: Counter,
+
+    /// Number of tokens evicted to make room for new registrations.
+    pub evicted_tokens_total: Counter,
+
+    /// Number of notification deliveries retried after a transient failure.
+    pub delivery_retries_total: Family<ProviderLabels, Counter>,
+
+    /// Number of notifications dropped after all delivery attempts failed.
+    pub dead_lettered_notifications_total: Family<ProviderLabels, Counter>,
+
+    /// Number of debounced tokens evicted.
+    pub debouncer_evictions_total: Counter,
+
+    /// Delay of heartbeats after their scheduled time.
+    pub heartbeat_skew_seconds: Histogram,
+
+    /// Number of heartbeats attempted in the last heartbeat round.
+    pub heartbeat_round_attempted: Gauge<i64, AtomicI64>,
+
+    /// Number of heartbeats in the last heartbeat round by result.
+    pub heartbeat_round_results: Family<RoundLabels, Gauge<i64, AtomicI64>>,
+
+    /// Time taken to send one heartbeat per token.
+    pub heartbeat_round_duration_seconds: Histogram,
+
+    /// Number of heartbeat tokens quarantined after repeated failures.
+    pub heartbeat_quarantined_tokens: Gauge<i64, AtomicI64>,
+
+    /// Number of notifications not sent in dry-run mode.
+    pub dry_run_notifications_total: Family<DryRunLabels, Counter>,
+
+    /// Duration of requests to APNS and FCM by endpoint and outcome.
+    pub provider_request_duration_seconds:
+        Family<RequestDurationLabels, Histogram, fn() -> Histogram>,
+
+    /// Number of decrypted OpenPGP tokens by the ID of the key that decrypted them.
+    pub openpgp_decryptions_total: Family<OpenPgpKeyLabels, Counter>,
 }
 
 impl Metrics {
@@ -104,10 +493,24 @@ impl Metrics {
             webpush_notifications_total.clone(),
         );
 
-        let debounced_notifications_total = Counter::default();
+        let unifiedpush_notifications_total = Counter::default();
+        registry.register(
+            "unifiedpush_notifications",
+            "Number of UnifiedPush notifications",
+            unifiedpush_notifications_total.clone(),
+        );
+
+        let webhook_notifications_total = Counter::default();
+        registry.register(
+            "webhook_notifications",
+            "Number of webhook notifications",
+            webhook_notifications_total.clone(),
+        );
+
+        let debounced_notifications_total = Family::<DebounceLabels, Counter>::default();
         registry.register(
             "debounced_notifications",
-            "Number of debounced notifications",
+            "Number of debounced notifications by notification type",
             debounced_notifications_total.clone(),
         );
 
@@ -149,16 +552,352 @@ impl Metrics {
         let failures_total = Family::<FailureLabels, Counter>::default();
         registry.register(
             "notification_failures",
-            "Number of failed notifications by provider and error type",
+            "Number of failed notifications by provider, error type and token removal",
             failures_total.clone(),
         );
 
+        let rate_limited_requests_total = Family::<RateLimitLabels, Counter>::default();
+        registry.register(
+            "rate_limited_requests",
+            "Number of requests rejected by rate limiting by route",
+            rate_limited_requests_total.clone(),
+        );
+
+        let pow_rejections_total = Counter::default();
+        registry.register(
+            "pow_rejections",
+            "Number of registrations rejected due to missing or invalid proof-of-work",
+            pow_rejections_total.clone(),
+        );
+
+        let quota_rejections_total = Counter::default();
+        registry.register(
+            "quota_rejections",
+            "Number of registrations rejected because the relay exceeded its quota",
+            quota_rejections_total.clone(),
+        );
+
+        let relay_notifications_total =
+            Family::<RelayNotificationLabels, CounterWithExemplar<NotificationIdLabels>>::default();
+        registry.register(
+            "relay_notifications",
+            "Number of direct notifications by relay, provider and status",
+            relay_notifications_total.clone(),
+        );
+
+        let relay_registrations_total = Family::<RelayRegistrationLabels, Counter>::default();
+        registry.register(
+            "relay_registrations",
+            "Number of heartbeat registrations by relay and status",
+            relay_registrations_total.clone(),
+        );
+
+        let inflight_requests = Family::<ProviderLabels, Gauge<i64, AtomicI64>>::default();
+        registry.register(
+            "inflight_requests",
+            "Number of requests to providers waiting for a response",
+            inflight_requests.clone(),
+        );
+
+        let retries_total = Family::<ProviderLabels, Counter>::default();
+        registry.register(
+            "request_retries",
+            "Number of provider requests retried after a connection failure",
+            retries_total.clone(),
+        );
+
+        let queued_notifications = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "queued_notifications",
+            "Number of notifications waiting in the background delivery queue",
+            queued_notifications.clone(),
+        );
+
+        let invalidation_callbacks_total = Family::<CallbackLabels, Counter>::default();
+        registry.register(
+            "invalidation_callbacks",
+            "Number of token invalidation callbacks to relays by result",
+            invalidation_callbacks_total.clone(),
+        );
+
+        let notification_receipts_total = Family::<ProviderLabels, Counter>::default();
+        registry.register(
+            "notification_receipts",
+            "Number of delivery receipts reported by client apps",
+            notification_receipts_total.clone(),
+        );
+
+        let panics_total = Counter::default();
+        registry.register(
+            "panics",
+            "Number of panics in workers and request handlers",
+            panics_total.clone(),
+        );
+
+        let worker_restarts_total = Family::<WorkerLabels, Counter>::default();
+        registry.register(
+            "worker_restarts",
+            "Number of background worker restarts after a panic or an error",
+            worker_restarts_total.clone(),
+        );
+
+        let worker_stalls_total = Family::<WorkerLabels, Counter>::default();
+        registry.register(
+            "worker_stalls",
+            "Number of background workers restarted by the watchdog after stalling",
+            worker_stalls_total.clone(),
+        );
+
+        let allocated_memory_bytes = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "allocated_memory_bytes",
+            "Number of bytes allocated on the heap",
+            allocated_memory_bytes.clone(),
+        );
+
+        let resident_memory_bytes = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "resident_memory_bytes",
+            "Number of bytes in resident pages mapped by the allocator",
+            resident_memory_bytes.clone(),
+        );
+
+        let memory_pressure_total = Counter::default();
+        registry.register(
+            "memory_pressure",
+            "Number of times allocated memory exceeded the soft limit",
+            memory_pressure_total.clone(),
+        );
+
+        let onesignal_notifications_total = Counter::default();
+        registry.register(
+            "onesignal_notifications",
+            "Number of OneSignal notifications",
+            onesignal_notifications_total.clone(),
+        );
+
+        let vivo_notifications_total = Counter::default();
+        registry.register(
+            "vivo_notifications",
+            "Number of Vivo notifications",
+            vivo_notifications_total.clone(),
+        );
+
+        let oppo_notifications_total = Counter::default();
+        registry.register(
+            "oppo_notifications",
+            "Number of OPPO notifications",
+            oppo_notifications_total.clone(),
+        );
+
+        let apns_oversized_payloads_total = Counter::default();
+        registry.register(
+            "apns_oversized_payloads",
+            "Number of oversized APNS payloads",
+            apns_oversized_payloads_total.clone(),
+        );
+
+        let stale_envelope_rejections_total = Counter::default();
+        registry.register(
+            "stale_envelope_rejections",
+            "Number of registrations with stale token envelopes",
+            stale_envelope_rejections_total.clone(),
+        );
+
+        let fcm_project_notifications_total = Family::<FcmProjectLabels, Counter>::default();
+        registry.register(
+            "fcm_project_notifications",
+            "Number of FCM notifications by project",
+            fcm_project_notifications_total.clone(),
+        );
+
+        let apns_certificate_expiry_timestamp_seconds = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "apns_certificate_expiry_timestamp_seconds",
+            "Unix timestamp at which the APNS certificate expires",
+            apns_certificate_expiry_timestamp_seconds.clone(),
+        );
+
+        let heartbeat_samples_total = Family::<SampleLabels, Counter>::default();
+        registry.register(
+            "heartbeat_samples",
+            "Number of sampled heartbeat tokens by result",
+            heartbeat_samples_total.clone(),
+        );
+
+        let heartbeat_sample_divergence = Gauge::<f64, AtomicU64>::default();
+        registry.register(
+            "heartbeat_sample_divergence",
+            "Share of sampled heartbeat tokens rejected by APNS in the last round",
+            heartbeat_sample_divergence.clone(),
+        );
+
+        let coalesced_notifications_total = Counter::default();
+        registry.register(
+            "coalesced_notifications",
+            "Number of queued notifications replaced by a newer one to the same token",
+            coalesced_notifications_total.clone(),
+        );
+
+        let schedule_quarantined_entries = Family::<QuarantineLabels, Counter>::default();
+        registry.register(
+            "schedule_quarantined_entries",
+            "Number of corrupt schedule database entries quarantined on startup by reason",
+            schedule_quarantined_entries.clone(),
+        );
+
+        let heartbeat_batch_size = Histogram::new(exponential_buckets(1.0, 2.0, 8));
+        registry.register(
+            "heartbeat_batch_size",
+            "Number of heartbeats sent together in one batch",
+            heartbeat_batch_size.clone(),
+        );
+
+        let provider_status = Family::<ProviderStatusLabels, Gauge>::default();
+        registry.register(
+            "provider_status",
+            "Health of each push provider derived from recent error rates",
+            provider_status.clone(),
+        );
+        let outage_detector = OutageDetector::new(provider_status);
+
+        let heartbeat_excluded_total = Counter::default();
+        registry.register(
+            "heartbeat_excluded",
+            "Number of heartbeats rescheduled because they were due in an exclusion window",
+            heartbeat_excluded_total.clone(),
+        );
+
+        let schedule_cache_hits_total = Counter::default();
+        registry.register(
+            "schedule_cache_hits",
+            "Number of schedule lookups served from the in-memory cache",
+            schedule_cache_hits_total.clone(),
+        );
+
+        let schedule_cache_misses_total = Counter::default();
+        registry.register(
+            "schedule_cache_misses",
+            "Number of schedule lookups that read the database",
+            schedule_cache_misses_total.clone(),
+        );
+
+        let dead_token_hits_total = Counter::default();
+        registry.register(
+            "dead_token_hits",
+            "Number of notifications answered with 410 Gone because the token was recently reported gone",
+            dead_token_hits_total.clone(),
+        );
+
+        let wns_notifications_total = Counter::default();
+        registry.register(
+            "wns_notifications",
+            "Number of WNS notifications",
+            wns_notifications_total.clone(),
+        );
+
+        let schedule_full_rejections_total = Counter::default();
+        registry.register(
+            "schedule_full_rejections",
+            "Number of registrations rejected because the schedule is full",
+            schedule_full_rejections_total.clone(),
+        );
+
+        let evicted_tokens_total = Counter::default();
+        registry.register(
+            "evicted_tokens",
+            "Number of tokens evicted to make room for new registrations",
+            evicted_tokens_total.clone(),
+        );
+
+        let delivery_retries_total = Family::<ProviderLabels, Counter>::default();
+        registry.register(
+            "delivery_retries",
+            "Number of notification deliveries retried after a transient failure",
+            delivery_retries_total.clone(),
+        );
+
+        let dead_lettered_notifications_total = Family::<ProviderLabels, Counter>::default();
+        registry.register(
+            "dead_lettered_notifications",
+            "Number of notifications dropped after all delivery attempts failed",
+            dead_lettered_notifications_total.clone(),
+        );
+
+        let debouncer_evictions_total = Counter::default();
+        registry.register(
+            "debouncer_evictions",
+            "Number of debounced tokens evicted to stay within the maximum entry count",
+            debouncer_evictions_total.clone(),
+        );
+
+        let heartbeat_skew_seconds = Histogram::new(exponential_buckets(1.0, 2.0, 15));
+        registry.register(
+            "heartbeat_skew_seconds",
+            "Delay of heartbeats after their scheduled time in seconds",
+            heartbeat_skew_seconds.clone(),
+        );
+
+        let heartbeat_round_attempted = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "heartbeat_round_attempted",
+            "Number of heartbeats attempted in the last heartbeat round",
+            heartbeat_round_attempted.clone(),
+        );
+
+        let heartbeat_round_results = Family::<RoundLabels, Gauge<i64, AtomicI64>>::default();
+        registry.register(
+            "heartbeat_round_results",
+            "Number of heartbeats in the last heartbeat round by result",
+            heartbeat_round_results.clone(),
+        );
+
+        let heartbeat_round_duration_seconds = Histogram::new(exponential_buckets(60.0, 2.0, 10));
+        registry.register(
+            "heartbeat_round_duration_seconds",
+            "Time taken to send one heartbeat per token in seconds",
+            heartbeat_round_duration_seconds.clone(),
+        );
+
+        let heartbeat_quarantined_tokens = Gauge::<i64, AtomicI64>::default();
+        registry.register(
+            "heartbeat_quarantined_tokens",
+            "Number of heartbeat tokens quarantined after repeated failures",
+            heartbeat_quarantined_tokens.clone(),
+        );
+
+        let dry_run_notifications_total = Family::<DryRunLabels, Counter>::default();
+        registry.register(
+            "dry_run_notifications",
+            "Number of notifications not sent in dry-run mode",
+            dry_run_notifications_total.clone(),
+        );
+
+        let provider_request_duration_seconds =
+            Family::<RequestDurationLabels, Histogram, fn() -> Histogram>::new_with_constructor(
+                || Histogram::new(exponential_buckets(0.005, 2.0, 12)),
+            );
+        registry.register(
+            "provider_request_duration_seconds",
+            "Duration of requests to APNS and FCM in seconds",
+            provider_request_duration_seconds.clone(),
+        );
+
+        let openpgp_decryptions_total = Family::<OpenPgpKeyLabels, Counter>::default();
+        registry.register(
+            "openpgp_decryptions",
+            "Number of decrypted OpenPGP tokens by key ID",
+            openpgp_decryptions_total.clone(),
+        );
+
         Self {
             registry,
             direct_notifications_total,
             fcm_notifications_total,
             ubports_notifications_total,
             webpush_notifications_total,
+            unifiedpush_notifications_total,
+            webhook_notifications_total,
             debounced_notifications_total,
             debounced_set_size,
             heartbeat_notifications_total,
@@ -166,20 +905,164 @@ impl Metrics {
             heartbeat_tokens,
             openpgp_decryption_failures_total,
             failures_total,
+            rate_limited_requests_total,
+            pow_rejections_total,
+            quota_rejections_total,
+            relay_notifications_total,
+            relay_registrations_total,
+            inflight_requests,
+            retries_total,
+            queued_notifications,
+            invalidation_callbacks_total,
+            notification_receipts_total,
+            panics_total,
+            worker_restarts_total,
+            worker_stalls_total,
+            allocated_memory_bytes,
+            resident_memory_bytes,
+            memory_pressure_total,
+            onesignal_notifications_total,
+            vivo_notifications_total,
+            oppo_notifications_total,
+            apns_oversized_payloads_total,
+            stale_envelope_rejections_total,
+            fcm_project_notifications_total,
+            apns_certificate_expiry_timestamp_seconds,
+            heartbeat_samples_total,
+            heartbeat_sample_divergence,
+            coalesced_notifications_total,
+            schedule_quarantined_entries,
+            heartbeat_batch_size,
+            outage_detector,
+            heartbeat_excluded_total,
+            schedule_cache_hits_total,
+            schedule_cache_misses_total,
+            dead_token_hits_total,
+            wns_notifications_total,
+            schedule_full_rejections_total,
+            evicted_tokens_total,
+            delivery_retries_total,
+            dead_lettered_notifications_total,
+            debouncer_evictions_total,
+            heartbeat_skew_seconds,
+            heartbeat_round_attempted,
+            heartbeat_round_results,
+            heartbeat_round_duration_seconds,
+            heartbeat_quarantined_tokens,
+            dry_run_notifications_total,
+            provider_request_duration_seconds,
+            openpgp_decryptions_total,
+        }
+    }
+
+    /// Counts a failed notification.
+    pub fn count_failure(&self, labels: FailureLabels) {
+        self.failures_total.get_or_create(&labels).inc();
+    }
+
+    /// Counts a token decrypted with the OpenPGP key `key_id`.
+    pub fn count_openpgp_decryption(&self, key_id: &str) {
+        self.openpgp_decryptions_total
+            .get_or_create(&OpenPgpKeyLabels {
+                key_id: key_id.to_string(),
+            })
+            .inc();
+    }
+
+    /// Records the duration of a request to APNS or FCM.
+    pub fn observe_request_duration(
+        &self,
+        provider: NotificationProvider,
+        endpoint: &str,
+        outcome: &str,
+        duration: Duration,
+    ) {
+        self.provider_request_duration_seconds
+            .get_or_create(&RequestDurationLabels {
+                provider,
+                endpoint: endpoint.to_string(),
+                outcome: outcome.to_string(),
+            })
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Counts a request to the provider as in-flight
+    /// until the returned guard is dropped.
+    pub fn inflight_request(&self, provider: NotificationProvider) -> InflightGuard<'_> {
+        let gauge = self
+            .inflight_requests
+            .get_or_create(&ProviderLabels { provider })
+            .clone();
+        gauge.inc();
+        InflightGuard {
+            gauge,
+            provider,
+            outage_detector: &self.outage_detector,
+            succeeded: false,
         }
     }
 }
 
+/// Returns the outcome label of an APNS request.
+pub(crate) fn apns_outcome(res: &Result<apns_h2::Response, apns_h2::Error>) -> &'static str {
+    match res {
+        Ok(_) => "delivered",
+        Err(apns_h2::Error::ResponseError(_)) => "rejected",
+        Err(_) => "error",
+    }
+}
+
+/// Returns the outcome label of an HTTP request to a provider.
+pub(crate) fn http_outcome(res: &reqwest::Result<reqwest::Response>) -> &'static str {
+    match res {
+        Ok(res) if res.status().is_success() => "delivered",
+        Ok(_) => "rejected",
+        Err(_) => "error",
+    }
+}
+
+/// Guard returned by [`Metrics::inflight_request`].
+///
+/// Requests whose guard is dropped without [`InflightGuard::finish`],
+/// e.g. because sending failed, are recorded as failed.
+#[derive(Debug)]
+pub struct InflightGuard<'a> {
+    gauge: Gauge<i64, AtomicI64>,
+    provider: NotificationProvider,
+    outage_detector: &'a OutageDetector,
+    succeeded: bool,
+}
+
+impl InflightGuard<'_> {
+    /// Records the outcome of the request for outage detection.
+    pub fn finish(mut self, succeeded: bool) {
+        self.succeeded = succeeded;
+    }
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.gauge.dec();
+        self.outage_detector
+            .record(Instant::now(), self.provider, self.succeeded);
+    }
+}
+
 impl Default for Metrics {
     fn default() -> Self {
         Self::new()
     }
 }
 
-pub async fn start(state: State, server: String) -> Result<()> {
-    let app = axum::Router::new()
-        .route("/metrics", get(metrics))
-        .with_state(state);
+/// Starts the metrics listener.
+///
+/// With `admin`, the `/admin/` endpoints are served on the same listener.
+pub async fn start(state: State, server: String, admin: bool) -> Result<()> {
+    let mut app = axum::Router::new().route("/metrics", get(metrics));
+    if admin {
+        app = app.merge(crate::server::admin_router(&state));
+    }
+    let app = app.with_state(state);
     let listener = tokio::net::TcpListener::bind(server).await?;
     axum::serve(listener, app).await?;
     Ok(())
@@ -197,3 +1080,26 @@ async fn metrics(axum::extract::State(state): axum::extract::State<State>) -> im
     );
     (headers, encoded)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_status_class() {
+        let metrics = Metrics::new();
+        metrics.count_failure(
+            FailureLabels::new(NotificationProvider::APNS, "410", "Unregistered").with_status(410),
+        );
+        metrics.count_failure(FailureLabels::new(NotificationProvider::FCM, "send", ""));
+
+        let mut encoded = String::new();
+        encode(&mut encoded, &metrics.registry).unwrap();
+        assert!(encoded.contains(
+            r#"provider="APNS",reason="410",details="Unregistered",status_class="4xx",token_removed="false""#
+        ));
+        assert!(encoded.contains(
+            r#"provider="FCM",reason="send",details="",status_class="none",token_removed="false""#
+        ));
+    }
+}
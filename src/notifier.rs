@@ -1,42 +1,319 @@
-use std::time::{Duration, SystemTime};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::{bail, Context as _, Result};
-use apns_h2::{
-    Client, DefaultNotificationBuilder, Error::ResponseError, NotificationBuilder,
-    NotificationOptions, Priority,
-};
+use anyhow::{Context as _, Result};
+use axum::http::StatusCode;
 use log::*;
+use parking_lot::Mutex;
+use rand::Rng as _;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
 
-use crate::metrics::{FailureLabels, Metrics, NotificationProvider};
+use crate::eventlog::Event;
+use crate::exclusion;
+use crate::logging::redact;
+use crate::metrics::{FailureLabels, Metrics, NotificationKind, RoundLabels};
+use crate::otel;
+use crate::provider::{Delivery, Disposition};
 use crate::schedule::Schedule;
-use crate::server::NotificationToken;
+use crate::server::{NotificationToken, NotifyOptions};
 use crate::state::State;
+use crate::supervisor::Heartbeat;
+use crate::traces::TraceEvent;
 
-pub async fn start(state: State, interval: std::time::Duration) -> Result<()> {
+/// Maximum number of heartbeats a worker sends in one batch.
+const BATCH_SIZE: usize = 100;
+
+/// Time by which heartbeats may be sent early
+/// to join the batch of an earlier token.
+const BATCH_LOOKAHEAD: Duration = Duration::from_secs(5);
+
+/// Time over which heartbeats due in an exclusion window
+/// are spread after its end.
+const EXCLUSION_SPREAD: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum number of heartbeats in flight over all workers.
+const MAX_CONCURRENT_HEARTBEATS: usize = 500;
+
+/// Time over which the pace allows one batch of heartbeats.
+///
+/// Small schedules send heartbeats one by one
+/// and only large ones reach [`BATCH_SIZE`].
+const PACING_WINDOW: Duration = Duration::from_secs(1);
+
+/// Spreads heartbeats evenly over the interval.
+///
+/// Tokens notified at the same time, e.g. after a restart,
+/// would otherwise be due at the same time again in every round.
+/// The pacer limits the send rate to the number of tokens per interval,
+/// so such bursts are spread over the interval once
+/// and stay spread because tokens are rescheduled when notified.
+/// Gaps between batches are randomly shortened by up to half
+/// so that backlogs drain and batches do not fall into lockstep.
+///
+/// One pacer is shared by all workers,
+/// each reserving the slot of its next batch.
+struct Pacer {
+    interval: Duration,
+
+    /// Earliest time to send the next batch.
+    next: Instant,
+}
+
+impl Pacer {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Instant::now(),
+        }
+    }
+
+    /// Returns the size of batches that keeps the pace for `tokens` scheduled tokens.
+    fn batch_size(&self, tokens: usize) -> usize {
+        let per_window = tokens as f64 * PACING_WINDOW.as_secs_f64() / self.interval.as_secs_f64();
+        (per_window.ceil() as usize).clamp(1, BATCH_SIZE)
+    }
+
+    /// Reserves the slot of a batch of `sent` heartbeats out of `tokens` scheduled tokens.
+    ///
+    /// `jitter` from 0.5 to 1 scales the gap before the next batch.
+    /// Returns the time to wait before sending the batch.
+    fn reserve(&mut self, now: Instant, sent: usize, tokens: usize, jitter: f64) -> Duration {
+        let start = self.next.max(now);
+        let gap = self
+            .interval
+            .mul_f64(sent as f64 / tokens.max(1) as f64 * jitter);
+        self.next = start + gap;
+        start.duration_since(now)
+    }
+}
+
+/// Outcomes of heartbeats during one round over the schedule.
+///
+/// The schedule has no fixed rounds because every token has its own due time.
+/// A round starts with the next batch
+/// and ends once as many tokens were handled as were scheduled at its start,
+/// so a round taking longer than the interval means
+/// that the interval is too short for the number of tokens.
+struct Round {
+    started: Instant,
+
+    /// Number of scheduled tokens at the start of the round.
+    tokens: usize,
+
+    /// Number of tokens handled, including excluded ones.
+    handled: usize,
+
+    attempted: usize,
+    delivered: usize,
+    removed: usize,
+    failed: usize,
+}
+
+impl Round {
+    fn new(started: Instant, tokens: usize) -> Self {
+        Self {
+            started,
+            tokens,
+            handled: 0,
+            attempted: 0,
+            delivered: 0,
+            removed: 0,
+            failed: 0,
+        }
+    }
+
+    /// Counts tokens handled without sending a heartbeat.
+    fn skip(&mut self, n: usize) {
+        self.handled += n;
+    }
+
+    /// Counts a heartbeat.
+    fn record(&mut self, outcome: Outcome) {
+        self.handled += 1;
+        self.attempted += 1;
+        match outcome {
+            Outcome::Delivered => self.delivered += 1,
+            Outcome::Removed => self.removed += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.handled >= self.tokens
+    }
+
+    /// Exports the outcomes of the round.
+    fn report(&self, now: Instant, metrics: &Metrics) {
+        let duration = now.duration_since(self.started);
+        info!(
+            "Heartbeat round over {} tokens took {}: {} attempted, {} delivered, {} removed, {} failed.",
+            self.tokens,
+            humantime::format_duration(Duration::from_secs(duration.as_secs())),
+            self.attempted,
+            self.delivered,
+            self.removed,
+            self.failed
+        );
+        metrics
+            .heartbeat_round_duration_seconds
+            .observe(duration.as_secs_f64());
+        metrics.heartbeat_round_attempted.set(self.attempted as i64);
+        for (result, count) in [
+            ("delivered", self.delivered),
+            ("removed", self.removed),
+            ("failed", self.failed),
+        ] {
+            metrics
+                .heartbeat_round_results
+                .get_or_create(&RoundLabels {
+                    result: result.to_string(),
+                })
+                .set(count as i64);
+        }
+    }
+}
+
+/// Pace, round and concurrency of heartbeats shared by all notifier workers.
+pub(crate) struct Heartbeats {
+    pacer: Mutex<Pacer>,
+    round: Mutex<Option<Round>>,
+
+    /// Permits for heartbeats in flight.
+    requests: Arc<Semaphore>,
+}
+
+impl Heartbeats {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            pacer: Mutex::new(Pacer::new(interval)),
+            round: Mutex::new(None),
+            requests: Arc::new(Semaphore::new(MAX_CONCURRENT_HEARTBEATS)),
+        }
+    }
+
+    /// Reserves the slot of the next batch for `tokens` scheduled tokens.
+    ///
+    /// Returns the maximum size of the batch
+    /// and the time to wait before sending it.
+    fn reserve(&self, now: Instant, tokens: usize) -> (usize, Duration) {
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+        let mut pacer = self.pacer.lock();
+        let size = pacer.batch_size(tokens);
+        (size, pacer.reserve(now, size, tokens, jitter))
+    }
+
+    /// Updates the current round, starting one if needed,
+    /// and reports it once complete.
+    fn update(&self, metrics: &Metrics, tokens: usize, update: impl FnOnce(&mut Round)) {
+        let now = Instant::now();
+        let mut round = self.round.lock();
+        update(round.get_or_insert_with(|| Round::new(now, tokens)));
+        if round.as_ref().is_some_and(Round::is_complete) {
+            if let Some(round) = round.take() {
+                round.report(now, metrics);
+            }
+        }
+    }
+}
+
+/// APNS connection a heartbeat is sent over.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Destination {
+    Production,
+    Sandbox,
+    App {
+        topic: String,
+        sandbox: bool,
+    },
+
+    /// Tokens of non-APNS providers, sent individually.
+    Other,
+}
+
+impl Destination {
+    fn of(token: &str) -> Self {
+        match token.parse() {
+            Ok(NotificationToken::ApnsProduction(_)) => Self::Production,
+            Ok(NotificationToken::ApnsSandbox(_)) => Self::Sandbox,
+            Ok(NotificationToken::ApnsApp { topic, sandbox, .. }) => Self::App { topic, sandbox },
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Groups tokens by the connection their heartbeats are sent over.
+///
+/// Only APNS tokens are batched per connection.
+/// Tokens of other providers are collected in [`Destination::Other`].
+fn group_by_destination(tokens: Vec<String>) -> BTreeMap<Destination, Vec<String>> {
+    let mut groups: BTreeMap<Destination, Vec<String>> = BTreeMap::new();
+    for token in tokens {
+        groups
+            .entry(Destination::of(&token))
+            .or_default()
+            .push(token);
+    }
+    groups
+}
+
+/// Outcome of a heartbeat notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// APNS accepted the notification.
+    Delivered,
+
+    /// Token was removed from the schedule, e.g. because APNS rejected it.
+    Removed,
+
+    /// Notification could not be delivered and the token was rescheduled.
+    Failed,
+}
+
+/// Sends heartbeat notifications to scheduled tokens.
+///
+/// Once a token is due, tokens becoming due within [`BATCH_LOOKAHEAD`]
+/// are taken from the schedule with it,
+/// and their heartbeats are sent in one contiguous batch per APNS connection
+/// instead of being interleaved with other destinations.
+/// Batches of all workers are paced by one [`Pacer`]
+/// and their outcomes are reported per [`Round`] over all workers.
+///
+/// Returns after the current batch when the gateway is shutting down
+/// and right away when the watchdog cancels the worker.
+pub async fn start(
+    state: State,
+    interval: std::time::Duration,
+    heartbeat: Heartbeat,
+) -> Result<()> {
     let schedule = state.schedule();
     let metrics = state.metrics();
-    let production_client = state.production_client();
-    let sandbox_client = state.sandbox_client();
-    let topic = state.topic();
 
     info!(
         "Waking up devices every {}",
         humantime::format_duration(interval)
     );
 
-    loop {
-        metrics.heartbeat_tokens.set(schedule.token_count() as i64);
+    let heartbeats = state.heartbeats();
+    while !state.is_shutting_down() && !heartbeat.is_cancelled() {
+        let tokens = schedule.token_count();
+        metrics.heartbeat_tokens.set(tokens as i64);
+        metrics
+            .heartbeat_quarantined_tokens
+            .set(schedule.quarantined_count() as i64);
 
-        let Some((timestamp, token)) = schedule.pop()? else {
+        let Some((key, token)) = schedule.pop()? else {
             debug!("No tokens to notify, sleeping for a minute.");
-            tokio::time::sleep(Duration::from_secs(60)).await;
+            state.sleep(Duration::from_secs(60)).await;
             continue;
         };
 
         // Sleep until we need to notify the token.
         let now = SystemTime::now();
         let timestamp: SystemTime = SystemTime::UNIX_EPOCH
-            .checked_add(Duration::from_secs(timestamp))
+            .checked_add(Duration::from_secs(key))
             .unwrap_or(now);
         let timestamp = std::cmp::min(timestamp, now);
         let delay = timestamp
@@ -50,123 +327,482 @@ pub async fn start(state: State, interval: std::time::Duration) -> Result<()> {
                 "Sleeping for {} before next notification.",
                 humantime::format_duration(delay)
             );
-            tokio::time::sleep(delay).await;
+            if !state.sleep(delay).await {
+                // The token stays in the database
+                // and is notified after the restart.
+                break;
+            }
         }
 
-        if let Err(err) = wakeup(
-            schedule,
-            metrics,
-            production_client,
-            sandbox_client,
-            topic,
-            token,
-        )
-        .await
+        let (batch_size, delay) = heartbeats.reserve(Instant::now(), tokens);
+        if !delay.is_zero() && !state.sleep(delay).await {
+            break;
+        }
+
+        let mut batch = vec![(key, token)];
+        let cutoff = SystemTime::now()
+            .checked_add(BATCH_LOOKAHEAD)
+            .and_then(|cutoff| cutoff.checked_sub(interval))
+            .and_then(|cutoff| cutoff.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |cutoff| cutoff.as_secs());
+        batch.extend(schedule.pop_until(cutoff, batch_size - 1)?);
+
+        if let Some(end) =
+            exclusion::excluded_until(state.heartbeat_exclusions(), SystemTime::now())
         {
-            error!("Failed to notify token: {err:#}");
+            debug!(
+                "Rescheduling {} heartbeats due in an exclusion window.",
+                batch.len()
+            );
+            let end = end
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let mut rng = rand::thread_rng();
+            for (_timestamp, token) in &batch {
+                let due = end + rng.gen_range(0..EXCLUSION_SPREAD.as_secs());
+                if let Err(err) =
+                    schedule.insert_token(token, due.saturating_sub(interval.as_secs()))
+                {
+                    error!("Failed to reschedule excluded token: {err:#}");
+                }
+            }
+            metrics.heartbeat_excluded_total.inc_by(batch.len() as u64);
+            heartbeats.update(metrics, tokens, |round| round.skip(batch.len()));
+            continue;
+        }
 
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for (timestamp, _token) in &batch {
+            let due = timestamp.saturating_add(interval.as_secs());
+            metrics
+                .heartbeat_skew_seconds
+                .observe(now.saturating_sub(due) as f64);
+        }
+        let batch: Vec<String> = batch.into_iter().map(|(_timestamp, token)| token).collect();
+        metrics.heartbeat_batch_size.observe(batch.len() as f64);
+
+        let busy = heartbeat.busy();
+        let failed = send_batch(
+            schedule,
+            &heartbeat,
+            &heartbeats.requests,
+            batch,
+            |token| {
+                let state = state.clone();
+                async move { wakeup(&state, token).await }
+            },
+            |outcome| heartbeats.update(metrics, tokens, |round| round.record(outcome)),
+        )
+        .await;
+        drop(busy);
+        let Some(failed) = failed else {
+            return Ok(());
+        };
+        if failed {
             // Sleep to avoid busy looping and flooding APNS
             // with requests in case of database errors.
-            tokio::time::sleep(Duration::from_secs(60)).await;
+            state.sleep(Duration::from_secs(60)).await;
         }
     }
+    Ok(())
 }
 
-async fn wakeup(
+/// Sends the heartbeats of a batch with `send`,
+/// one destination group after another.
+///
+/// Returns whether any heartbeat failed,
+/// or `None` if the watchdog cancelled the worker.
+/// All tokens of the batch were taken from the schedule,
+/// so on cancellation the unfinished ones and those of the following groups
+/// are rescheduled to not be lost until a restart.
+async fn send_batch<F, Fut>(
     schedule: &Schedule,
-    metrics: &Metrics,
-    production_client: &Option<Client>,
-    sandbox_client: &Option<Client>,
-    topic: Option<&str>,
-    key_device_token: String,
-) -> Result<()> {
-    debug!("notify: {}", key_device_token);
+    heartbeat: &Heartbeat,
+    permits: &Arc<Semaphore>,
+    batch: Vec<String>,
+    send: F,
+    mut record: impl FnMut(Outcome),
+) -> Option<bool>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Outcome>> + Send + 'static,
+{
+    let mut failed = false;
+    let mut groups = group_by_destination(batch).into_iter();
+    while let Some((destination, group)) = groups.next() {
+        debug!("Sending {} heartbeats to {destination:?}.", group.len());
+        let mut requests = tokio::task::JoinSet::new();
+        // Tokens of unfinished requests by task.
+        let mut pending = HashMap::new();
+        for token in group {
+            let permits = permits.clone();
+            let request = send(token.clone());
+            let task = requests.spawn(async move {
+                let _permit = permits.acquire().await;
+                request.await
+            });
+            pending.insert(task.id(), token);
+        }
+        loop {
+            let res = tokio::select! {
+                res = requests.join_next_with_id() => res,
+                _ = heartbeat.cancelled() => {
+                    // Stalled, e.g. on a dead connection.
+                    requests.abort_all();
+                    let tokens: Vec<String> = pending
+                        .into_values()
+                        .chain(groups.flat_map(|(_destination, group)| group))
+                        .collect();
+                    warn!("Rescheduling {} heartbeats of cancelled worker.", tokens.len());
+                    for token in &tokens {
+                        if let Err(err) = schedule.insert_token_now(token) {
+                            error!("Failed to reschedule token: {err:#}");
+                        }
+                    }
+                    return None;
+                }
+            };
+            let Some(res) = res else {
+                break;
+            };
+            let outcome = match res {
+                Ok((id, res)) => {
+                    pending.remove(&id);
+                    res.unwrap_or_else(|err| {
+                        error!("Failed to notify token: {err:#}");
+                        failed = true;
+                        Outcome::Failed
+                    })
+                }
+                Err(err) => {
+                    // The token was popped from the schedule
+                    // and is lost until restart unless rescheduled.
+                    error!("Heartbeat task failed: {err}.");
+                    if let Some(token) = pending.remove(&err.id()) {
+                        if let Err(err) = schedule.insert_token_now(&token) {
+                            error!("Failed to reschedule token: {err:#}");
+                        }
+                    }
+                    failed = true;
+                    Outcome::Failed
+                }
+            };
+            record(outcome);
+        }
+    }
+    Some(failed)
+}
+
+/// Sends a heartbeat to the token
+/// and reschedules or removes it depending on the result.
+pub(crate) async fn wakeup(state: &State, key_device_token: String) -> Result<Outcome> {
+    let schedule = state.schedule();
+    let metrics = state.metrics();
+    debug!("notify: {}", redact(&key_device_token));
 
     let device_token: NotificationToken = key_device_token.as_str().parse()?;
+    let provider = state.providers().for_token(&device_token);
+    if !provider.supports_heartbeats(&device_token) {
+        // Only APNS, UnifiedPush and WNS tokens can be registered for periodic notifications,
+        // Safari cannot receive silent notifications.
+        info!("Removing non-heartbeat token {}", redact(&key_device_token));
+        schedule
+            .remove_token(&key_device_token)
+            .with_context(|| format!("Failed to remove {}", redact(&key_device_token)))?;
+        return Ok(Outcome::Removed);
+    }
 
-    let (client, device_token) = match device_token {
-        NotificationToken::Fcm { .. }
-        | NotificationToken::UBports(..)
-        | NotificationToken::WebPush { .. } => {
-            // Only APNS tokens can be registered for periodic notifications.
-            info!("Removing FCM token {key_device_token}");
-            schedule
-                .remove_token(&key_device_token)
-                .with_context(|| format!("Failed to remove {}", &key_device_token))?;
-            return Ok(());
+    let label = provider.label();
+    let mut span = otel::Span::new(otel::HEARTBEAT_SPAN, otel::SpanKind::Client, None);
+    span.set_attribute("notification.provider", label.as_str());
+    let started = Instant::now();
+    let res = provider
+        .send(
+            state,
+            Uuid::new_v4(),
+            device_token,
+            NotificationKind::Heartbeat,
+            &NotifyOptions::default(),
+        )
+        .await;
+    let res_failed = res.is_err();
+    let delivery = match res {
+        Ok(delivery) => delivery,
+        Err(err) => {
+            warn!(
+                "Failed to send heartbeat to {}: {err:#}.",
+                redact(&key_device_token)
+            );
+            Delivery {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                reason: Some("send".to_string()),
+                failure: None,
+            }
         }
-        NotificationToken::ApnsSandbox(token) => (sandbox_client, token),
-        NotificationToken::ApnsProduction(token) => (production_client, token),
     };
-
-    // Send silent notification.
-    // According to <https://developer.apple.com/documentation/usernotifications/generating-a-remote-notification>
-    // to send a silent notification you need to set background notification flag `content-available` to 1
-    // and don't include `alert`, `badge` or `sound`.
-    let payload = DefaultNotificationBuilder::new().content_available().build(
-        &device_token,
-        NotificationOptions {
-            // Normal priority (5) means
-            // "send the notification based on power considerations on the user’s device".
-            // <https://developer.apple.com/documentation/usernotifications/sending-notification-requests-to-apns>
-            apns_priority: Some(Priority::Normal),
-            apns_topic: topic,
-            ..Default::default()
-        },
+    // Failed heartbeats are logged at info level,
+    // successful ones only in debug mode as there is one per device every interval.
+    let level = if delivery.status.is_success() {
+        Level::Debug
+    } else {
+        Level::Info
+    };
+    log!(
+        level,
+        provider = label.as_str(),
+        token:% = redact(&key_device_token),
+        status = delivery.status.as_u16(),
+        latency_ms = started.elapsed().as_millis() as u64;
+        "Heartbeat finished with {}.", delivery.status
     );
+    span.set_attribute("http.response.status_code", delivery.status.as_u16());
+    if res_failed {
+        span.set_error("send");
+    } else if !delivery.status.is_success() {
+        span.set_error("http_status");
+    }
+    drop(span);
+    if let Some(event_log) = state.event_log() {
+        let mut event = Event::new(NotificationKind::Heartbeat, label, delivery.status.as_u16());
+        if let Some(reason) = &delivery.reason {
+            event = event.with_reason(reason);
+        }
+        event_log.record(&event);
+    }
 
-    let Some(client) = client else {
-        bail!("APNS client is not configured");
-    };
-    match client.send(payload).await {
-        Ok(res) => match res.code {
-            200 => {
-                debug!("delivered notification for {}", device_token);
-                schedule
-                    .insert_token_now(&key_device_token)
-                    .context("Failed to update latest notification timestamp")?;
-                metrics.heartbeat_notifications_total.inc();
-            }
-            _ => {
-                bail!("unexpected status: {:?}", res);
-            }
-        },
-        Err(ResponseError(res)) => {
-            metrics
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::APNS,
-                    reason: res.code.to_string(),
-                    details: res
-                        .error
-                        .as_ref()
-                        .map(|e| e.reason.to_string())
-                        .unwrap_or_default(),
-                })
-                .inc();
+    state.traces().record(&key_device_token, || {
+        let event = TraceEvent::new("heartbeat").with_status(delivery.status.as_u16());
+        match &delivery.reason {
+            Some(reason) => event.with_reason(reason),
+            None => event,
+        }
+    });
+
+    let disposition = provider.classify(&delivery);
+    if disposition == Disposition::Gone {
+        info!(
+            "Removing token {} gone at {label:?}.",
+            redact(&key_device_token)
+        );
+        let removed = schedule.remove_stored_token(&key_device_token);
+        if let Some(failure) = delivery.failure {
+            metrics.count_failure(FailureLabels {
+                token_removed: matches!(removed, Ok((true, _))),
+                ..failure
+            });
+        }
+        let (_, provenance) =
+            removed.with_context(|| format!("Failed to remove {}", redact(&key_device_token)))?;
+        let reason = delivery.reason.as_deref().unwrap_or("Gone");
+        state
+            .callbacks()
+            .token_removed(&key_device_token, provenance, reason, metrics);
+        return Ok(Outcome::Removed);
+    }
+
+    if let Some(failure) = delivery.failure {
+        metrics.count_failure(failure);
+    }
+    if disposition == Disposition::Delivered {
+        schedule
+            .insert_token_now(&key_device_token)
+            .context("Failed to update latest notification timestamp")?;
+        if schedule.reset_failures(&key_device_token)? {
             info!(
-                "Removing token {} due to error {:?}.",
-                &key_device_token, res
+                "Released token {} from quarantine after a delivered heartbeat.",
+                redact(&key_device_token)
             );
-            schedule
-                .remove_token(&key_device_token)
-                .with_context(|| format!("Failed to remove {}", &key_device_token))?;
         }
-        Err(err) => {
-            metrics
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::APNS,
-                    reason: "send".to_string(),
-                    details: String::new(),
-                })
-                .inc();
-            // Update notification time regardless of success
-            // to avoid busy looping.
-            schedule
-                .insert_token_now(&key_device_token)
-                .with_context(|| format!("Failed to update token timestamp: {err:?}"))?;
+        debug!("Rescheduled {}.", redact(&key_device_token));
+        metrics.heartbeat_notifications_total.inc();
+        Ok(Outcome::Delivered)
+    } else {
+        // Failed heartbeats are rescheduled as well to avoid busy looping.
+        let delay = schedule
+            .reschedule_failed(&key_device_token)
+            .context("Failed to reschedule failed heartbeat")?;
+        match delay {
+            Some(delay) => warn!(
+                "Quarantined {} after repeated heartbeat failures, delaying next heartbeat by {}.",
+                redact(&key_device_token),
+                humantime::format_duration(delay)
+            ),
+            None => debug!("Rescheduled {}.", redact(&key_device_token)),
         }
+        Ok(Outcome::Failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_by_destination() {
+        let tokens = [
+            "abc",
+            "sandbox:def",
+            "apns:chat.delta:ghi",
+            "jkl",
+            "apns-sandbox:chat.delta:mno",
+            "fcm-chat.delta:pqr",
+        ];
+        let groups = group_by_destination(tokens.iter().map(|t| t.to_string()).collect());
+        let groups: Vec<_> = groups.into_iter().collect();
+        assert_eq!(
+            groups,
+            [
+                (
+                    Destination::Production,
+                    vec!["abc".to_string(), "jkl".to_string()]
+                ),
+                (Destination::Sandbox, vec!["sandbox:def".to_string()]),
+                (
+                    Destination::App {
+                        topic: "chat.delta".to_string(),
+                        sandbox: false
+                    },
+                    vec!["apns:chat.delta:ghi".to_string()]
+                ),
+                (
+                    Destination::App {
+                        topic: "chat.delta".to_string(),
+                        sandbox: true
+                    },
+                    vec!["apns-sandbox:chat.delta:mno".to_string()]
+                ),
+                (Destination::Other, vec!["fcm-chat.delta:pqr".to_string()]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_cancelled() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let schedule = Schedule::new(&dir.path().join("db.sled"))?;
+        let heartbeat = Heartbeat::default();
+        let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_HEARTBEATS));
+        // One token for each of three destinations.
+        let batch = vec![
+            "fcm-chat.delta:ghi".to_string(),
+            "sandbox:def".to_string(),
+            "abc".to_string(),
+        ];
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let res = send_batch(
+            &schedule,
+            &heartbeat,
+            &permits,
+            batch,
+            |token| {
+                let sent = sent.clone();
+                let heartbeat = heartbeat.clone();
+                async move {
+                    // The first request stalls until the watchdog cancels the worker.
+                    sent.lock().push(token);
+                    heartbeat.cancel();
+                    std::future::pending::<Result<Outcome>>().await
+                }
+            },
+            |_outcome| panic!("No request finishes"),
+        )
+        .await;
+        assert_eq!(res, None);
+        assert_eq!(*sent.lock(), ["abc"]);
+
+        // Tokens of the following groups are rescheduled as well.
+        assert_eq!(schedule.token_count(), 3);
+        let mut rescheduled = Vec::new();
+        while let Some((_timestamp, token)) = schedule.pop()? {
+            rescheduled.push(token);
+        }
+        rescheduled.sort();
+        assert_eq!(rescheduled, ["abc", "fcm-chat.delta:ghi", "sandbox:def"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pacer() {
+        let interval = Duration::from_secs(1200);
+        let mut pacer = Pacer::new(interval);
+        let now = pacer.next;
+
+        // 2400 tokens are sent two per second.
+        assert_eq!(pacer.batch_size(2400), 2);
+        assert_eq!(pacer.batch_size(10), 1);
+        assert_eq!(pacer.batch_size(10_000_000), BATCH_SIZE);
+        assert_eq!(pacer.reserve(now, 2, 2400, 1.0), Duration::ZERO);
+        assert_eq!(pacer.reserve(now, 2, 2400, 0.5), Duration::from_secs(1));
+        assert_eq!(
+            pacer.reserve(now, 2, 2400, 1.0),
+            Duration::from_millis(1500)
+        );
+
+        // Idle time does not allow bursts later.
+        let later = now + interval;
+        assert_eq!(pacer.reserve(later, 2, 2400, 1.0), Duration::ZERO);
+        assert_eq!(pacer.reserve(later, 2, 2400, 1.0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_shared_heartbeats() {
+        let interval = Duration::from_secs(1200);
+        let heartbeats = Heartbeats::new(interval);
+        let now = Instant::now();
+
+        // Workers reserve consecutive slots of the same pace.
+        let slots: Vec<_> = (0..3).map(|_| heartbeats.reserve(now, 1200).1).collect();
+        assert_eq!(slots[0], Duration::ZERO);
+        assert!(slots[1] >= Duration::from_millis(500) && slots[1] <= Duration::from_secs(1));
+        assert!(slots[2] > slots[1]);
+
+        // Outcomes of all workers complete one round.
+        let metrics = Metrics::new();
+        heartbeats.update(&metrics, 3, |round| round.record(Outcome::Delivered));
+        heartbeats.update(&metrics, 3, |round| round.skip(1));
+        assert!(heartbeats.round.lock().is_some());
+        heartbeats.update(&metrics, 3, |round| round.record(Outcome::Failed));
+        assert!(heartbeats.round.lock().is_none());
+        assert_eq!(metrics.heartbeat_round_attempted.get(), 2);
+    }
+
+    #[test]
+    fn test_round() {
+        let start = Instant::now();
+        let mut round = Round::new(start, 4);
+        round.record(Outcome::Delivered);
+        round.skip(1);
+        round.record(Outcome::Removed);
+        assert!(!round.is_complete());
+        round.record(Outcome::Failed);
+        assert!(round.is_complete());
+        assert_eq!(
+            (
+                round.attempted,
+                round.delivered,
+                round.removed,
+                round.failed
+            ),
+            (3, 1, 1, 1)
+        );
+
+        let metrics = Metrics::new();
+        round.report(start + Duration::from_secs(90), &metrics);
+        assert_eq!(metrics.heartbeat_round_attempted.get(), 3);
+        let removed = RoundLabels {
+            result: "removed".to_string(),
+        };
+        assert_eq!(
+            metrics
+                .heartbeat_round_results
+                .get_or_create(&removed)
+                .get(),
+            1
+        );
     }
-    Ok(())
 }
@@ -0,0 +1,207 @@
+//! # OneSignal provider.
+//!
+//! Apps distributed with OneSignal SDKs register tokens
+//! of the form `onesignal:<app-id>:<player-id>`.
+//! Notifications are forwarded to the OneSignal REST API
+//! with the REST API key configured for the app.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use log::*;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::logging::redact;
+use crate::metrics::{FailureLabels, Metrics, NotificationProvider};
+use crate::outage;
+use crate::retry;
+use crate::templates::Template;
+
+const URL: &str = "https://onesignal.com/api/v1/notifications";
+
+/// Error returned when none of the players can receive notifications.
+const NOT_SUBSCRIBED: &str = "All included players are not subscribed";
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    app_id: &'a str,
+    include_player_ids: [&'a str; 1],
+    headings: Text<'a>,
+    contents: Text<'a>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    data: &'a BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Text<'a> {
+    en: &'a str,
+}
+
+pub(crate) struct OneSignal {
+    client: reqwest::Client,
+
+    /// REST API keys by app ID.
+    api_keys: HashMap<String, String>,
+}
+
+/// Returns the status for a OneSignal response body.
+///
+/// OneSignal reports invalid players with `200 OK`
+/// and a list of errors in the body.
+fn response_status(body: &Value) -> StatusCode {
+    let errors = &body["errors"];
+    let invalid_players = errors["invalid_player_ids"]
+        .as_array()
+        .is_some_and(|ids| !ids.is_empty());
+    let not_subscribed = errors
+        .as_array()
+        .is_some_and(|errors| errors.iter().any(|err| err == NOT_SUBSCRIBED));
+    if invalid_players || not_subscribed {
+        StatusCode::GONE
+    } else if body["id"].as_str().is_some_and(|id| !id.is_empty()) {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl OneSignal {
+    pub(crate) fn new(config: &Config, client: reqwest::Client) -> Self {
+        let api_keys = config
+            .onesignal_apps
+            .iter()
+            .map(|app| (app.app_id.clone(), app.api_key.clone()))
+            .collect();
+        Self { client, api_keys }
+    }
+
+    pub(crate) async fn notify(
+        &self,
+        app_id: &str,
+        player_id: &str,
+        template: &Template,
+        metrics: &Metrics,
+    ) -> Result<StatusCode> {
+        let fail = |reason: &str| {
+            metrics.count_failure(FailureLabels::new(
+                NotificationProvider::OneSignal,
+                reason,
+                "",
+            ));
+        };
+
+        let Some(api_key) = self.api_keys.get(app_id) else {
+            warn!("Cannot notify OneSignal because app {app_id} is not configured.");
+            fail("no_api_key");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        let notification = Notification {
+            app_id,
+            include_player_ids: [player_id],
+            headings: Text {
+                en: template.title.as_deref().unwrap_or_default(),
+            },
+            contents: Text {
+                en: template.body.as_deref().unwrap_or_default(),
+            },
+            data: &template.data,
+        };
+        let body = serde_json::to_string(&notification)?;
+        debug!("Sending OneSignal notification to {}.", redact(player_id));
+        let inflight = metrics.inflight_request(NotificationProvider::OneSignal);
+        let start = Instant::now();
+        let res = retry::send(
+            self.client
+                .post(URL)
+                .body(body)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Basic {api_key}")),
+            metrics,
+            NotificationProvider::OneSignal,
+        )
+        .await;
+        inflight.finish(outage::is_response_ok(&res));
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                warn!(
+                    provider = "onesignal";
+                    "Failed to send OneSignal notification to {}: {err}",
+                    redact(player_id)
+                );
+                fail("send");
+                return Err(err.into());
+            }
+        };
+        let status = res.status();
+        debug!(
+            "OneSignal responded with {status} in {:?}.",
+            start.elapsed()
+        );
+        let body: Value = serde_json::from_slice(&res.bytes().await?).unwrap_or_default();
+        let status_code = if status.is_success() {
+            response_status(&body)
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        if status_code.is_success() {
+            debug!(
+                "Delivered notification to OneSignal player {}.",
+                redact(player_id)
+            );
+            metrics.onesignal_notifications_total.inc();
+        } else {
+            // The body may list player IDs, so it is not logged.
+            warn!(
+                provider = "onesignal", status = status.as_u16();
+                "Failed to deliver OneSignal notification to {}: OneSignal responded with {status}, reporting {status_code}.",
+                redact(player_id)
+            );
+            metrics.count_failure(
+                FailureLabels::new(
+                    NotificationProvider::OneSignal,
+                    status_code.as_u16().to_string(),
+                    "",
+                )
+                .with_status(status.as_u16()),
+            );
+        }
+        Ok(status_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_response_status() {
+        assert_eq!(
+            response_status(
+                &json!({"id": "b98881cc-1e94-4366-bbd9-db8f3429292b", "recipients": 1})
+            ),
+            StatusCode::OK
+        );
+        assert_eq!(
+            response_status(
+                &json!({"id": "", "errors": {"invalid_player_ids": ["5fdc92b2-3b2a-11e5-ac13-8fdccfe4d986"]}})
+            ),
+            StatusCode::GONE
+        );
+        assert_eq!(
+            response_status(&json!({"id": "", "errors": [NOT_SUBSCRIBED]})),
+            StatusCode::GONE
+        );
+        assert_eq!(
+            response_status(&json!({"errors": ["Invalid app_id format"]})),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -1,47 +1,463 @@
 //! Token decryption using OpenPGP.
+//!
+//! Encrypted messages contain either a bare token
+//! or a JSON envelope with the token and the time of encryption:
+//! `{"token":"<token>","timestamp":<unix time>}`.
+//! Other fields of the envelope are ignored.
+//! Both may be followed by whitespace padding.
+//!
+//! The schedule stores decrypted tokens,
+//! so rotating the gateway key only requires keeping the retired key
+//! for decrypting tokens that relays still send encrypted to it.
+//! Tokens stored as sent by the relays can be re-encrypted
+//! with the new key using [`reencrypt_tokens`].
 
 use std::io::Cursor;
+use std::path::Path;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, ensure, Context as _, Result};
 use base64::Engine as _;
-use pgp::composed::{Deserializable as _, Message, SignedSecretKey};
+use log::*;
+use pgp::composed::{Deserializable as _, Message, SignedPublicKey, SignedSecretKey};
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::ser::Serialize as _;
+use pgp::types::PublicKeyTrait as _;
+use serde::Deserialize;
+
+use crate::schedule::Schedule;
+
+/// Tolerated difference between the clocks of the client and the gateway.
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Decrypted token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub token: String,
+
+    /// Unix timestamp of the encryption,
+    /// `None` if the message contains a bare token.
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EnvelopeJson {
+    token: String,
+    timestamp: u64,
+}
+
+impl Envelope {
+    fn parse(plaintext: &str) -> Result<Self> {
+        // Remove the padding that is added
+        // to avoid leaking token length.
+        let plaintext = plaintext.trim();
+        if !plaintext.starts_with('{') {
+            return Ok(Self {
+                token: plaintext.to_string(),
+                timestamp: None,
+            });
+        }
+        let envelope: EnvelopeJson =
+            serde_json::from_str(plaintext).context("Invalid token envelope")?;
+        Ok(Self {
+            token: envelope.token,
+            timestamp: Some(envelope.timestamp),
+        })
+    }
+
+    /// Returns true if the envelope was created at most `max_age` before `now`.
+    ///
+    /// Bare tokens are never fresh.
+    pub fn is_fresh(&self, now: u64, max_age: Duration) -> bool {
+        self.timestamp.is_some_and(|timestamp| {
+            timestamp <= now.saturating_add(CLOCK_SKEW.as_secs())
+                && now.saturating_sub(timestamp) <= max_age.as_secs()
+        })
+    }
+}
 
 /// OpenPGP message decryptor.
 pub struct PgpDecryptor {
-    /// Keyring of keys used for decryption.
+    /// Keyring of keys used for decryption,
+    /// tried in order.
     keyring: Vec<SignedSecretKey>,
+
+    /// Hex-encoded IDs of the primary keys in the keyring.
+    key_ids: Vec<String>,
+
+    /// ASCII-armored public keys of the current keys.
+    public_keys: Vec<String>,
+}
+
+/// Parses ASCII-armored secret keys.
+fn parse_secret_keys(keyring_armor: &str) -> Result<Vec<SignedSecretKey>> {
+    let cursor = Cursor::new(keyring_armor);
+    let (mut secret_keys_iter, _headers) = pgp::composed::signed_key::from_armor_many(cursor)?;
+    let mut secret_keys: Vec<SignedSecretKey> = Vec::new();
+    for key in (&mut *secret_keys_iter).flatten() {
+        if key.is_secret() {
+            secret_keys.push(key.into_secret());
+        }
+    }
+    Ok(secret_keys)
 }
 
 impl PgpDecryptor {
     /// Creates a new OpenPGP decryptor
     /// with the given secret keys.
     pub fn new(keyring_armor: &str) -> Result<Self> {
-        let cursor = Cursor::new(keyring_armor);
-        let (mut secret_keys_iter, _headers) = pgp::composed::signed_key::from_armor_many(cursor)?;
-        let mut secret_keys: Vec<SignedSecretKey> = Vec::new();
-        for key in (&mut *secret_keys_iter).flatten() {
-            if key.is_secret() {
-                secret_keys.push(key.into_secret());
-            }
+        let secret_keys = parse_secret_keys(keyring_armor)?;
+        let public_keys = secret_keys
+            .iter()
+            .map(|key| SignedPublicKey::from(key.clone()).to_armored_string(Default::default()))
+            .collect::<Result<_, _>>()?;
+        let mut decryptor = Self {
+            keyring: Vec::new(),
+            key_ids: Vec::new(),
+            public_keys,
+        };
+        decryptor.push_keys(secret_keys);
+        Ok(decryptor)
+    }
+
+    /// Creates a new OpenPGP decryptor from keyring files.
+    ///
+    /// Keys of the first file are current and their public keys are advertised,
+    /// keys of the other files are retired and only used for decryption,
+    /// so tokens encrypted to them keep working during a key rotation.
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self> {
+        let read = |path: &Path| {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read keyring {}", path.display()))
+        };
+        let (current, retired) = paths.split_first().context("No OpenPGP keyring")?;
+        let mut decryptor = Self::new(&read(current.as_ref())?)?;
+        for path in retired {
+            let secret_keys = parse_secret_keys(&read(path.as_ref())?)?;
+            ensure!(
+                !secret_keys.is_empty(),
+                "No secret keys in keyring {}",
+                path.as_ref().display()
+            );
+            decryptor.push_keys(secret_keys);
         }
-        Ok(Self {
-            keyring: secret_keys,
-        })
+        Ok(decryptor)
+    }
+
+    fn push_keys(&mut self, secret_keys: Vec<SignedSecretKey>) {
+        for key in secret_keys {
+            self.key_ids.push(format!("{:X}", key.primary_key.key_id()));
+            self.keyring.push(key);
+        }
+    }
+
+    /// Returns hex-encoded IDs of the keys in the order they are tried.
+    pub fn key_ids(&self) -> &[String] {
+        &self.key_ids
+    }
+
+    /// Returns ASCII-armored public keys
+    /// that can be used to encrypt tokens.
+    pub fn public_keys(&self) -> &[String] {
+        &self.public_keys
     }
 
     /// Decrypts incoming token from an base64-encoded OpenPGP message.
     pub fn decrypt(&self, message: &str) -> Result<String> {
+        Ok(self.decrypt_envelope(message)?.token)
+    }
+
+    /// Decrypts incoming token and its envelope
+    /// from an base64-encoded OpenPGP message.
+    pub fn decrypt_envelope(&self, message: &str) -> Result<Envelope> {
+        Ok(self.decrypt_envelope_with_key(message)?.0)
+    }
+
+    /// Decrypts incoming token and its envelope
+    /// from an base64-encoded OpenPGP message
+    /// and returns the ID of the key that decrypted it.
+    pub fn decrypt_envelope_with_key(&self, message: &str) -> Result<(Envelope, &str)> {
+        let (content, key_id) = self.decrypt_base64(message)?;
+        Ok((Envelope::parse(&String::from_utf8(content)?)?, key_id))
+    }
+
+    /// Decrypts a base64-encoded OpenPGP message
+    /// without parsing its content.
+    fn decrypt_base64(&self, message: &str) -> Result<(Vec<u8>, &str)> {
         let bytes = base64::engine::general_purpose::STANDARD.decode(message)?;
         let cursor = Cursor::new(bytes);
         let msg = Message::from_bytes(cursor)?;
-        let secret_key_refs: Vec<&SignedSecretKey> = self.keyring.iter().collect();
-        let (msg, _key_ids) = msg.decrypt(|| "".into(), &secret_key_refs)?;
-        let content = msg.get_content()?.unwrap_or_default();
-        let token = String::from_utf8(content)?;
+        self.decrypt_message(msg)
+    }
 
-        // Remove the padding that is added
-        // to avoid leaking token length.
-        let token = token.trim().to_string();
-        Ok(token)
+    /// Decrypts a binary or ASCII-armored OpenPGP message.
+    pub fn decrypt_bytes(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let (msg, _headers) = Message::from_reader_single(message)?;
+        Ok(self.decrypt_message(msg)?.0)
+    }
+
+    /// Decrypts the message with the first key it is encrypted to.
+    fn decrypt_message(&self, msg: Message) -> Result<(Vec<u8>, &str)> {
+        let mut last_err = None;
+        for (key, key_id) in self.keyring.iter().zip(&self.key_ids) {
+            match msg.decrypt(|| "".into(), &[key]) {
+                Ok((msg, _key_ids)) => {
+                    return Ok((msg.get_content()?.unwrap_or_default(), key_id));
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.map_or_else(|| anyhow!("No OpenPGP keys"), Into::into))
+    }
+}
+
+/// OpenPGP message encryptor.
+pub struct PgpEncryptor {
+    /// Public keys to encrypt to.
+    public_keys: Vec<SignedPublicKey>,
+}
+
+impl PgpEncryptor {
+    /// Creates a new OpenPGP encryptor with the given ASCII-armored keys.
+    ///
+    /// Secret keys are accepted as well, only their public part is used.
+    pub fn new(keys_armor: &str) -> Result<Self> {
+        let cursor = Cursor::new(keys_armor);
+        let (mut keys_iter, _headers) = pgp::composed::signed_key::from_armor_many(cursor)?;
+        let public_keys: Vec<SignedPublicKey> = (&mut *keys_iter)
+            .flatten()
+            .map(|key| {
+                if key.is_secret() {
+                    SignedPublicKey::from(key.into_secret())
+                } else {
+                    key.into_public()
+                }
+            })
+            .collect();
+        ensure!(
+            public_keys.iter().any(|key| key
+                .public_subkeys
+                .iter()
+                .any(|subkey| subkey.is_encryption_key())),
+            "No encryption subkey found"
+        );
+        Ok(Self { public_keys })
+    }
+
+    /// Encrypts the plaintext into a base64-encoded OpenPGP message,
+    /// the format of `openpgp:` tokens.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<String> {
+        let subkeys: Vec<_> = self
+            .public_keys
+            .iter()
+            .flat_map(|key| &key.public_subkeys)
+            .filter(|subkey| subkey.is_encryption_key())
+            .collect();
+        let msg = Message::new_literal_bytes("", plaintext).encrypt_to_keys_seipdv1(
+            rand::thread_rng(),
+            SymmetricKeyAlgorithm::AES128,
+            &subkeys,
+        )?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(msg.to_bytes()?))
+    }
+}
+
+/// Result of [`reencrypt_tokens`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReencryptionReport {
+    /// Number of re-encrypted tokens.
+    pub reencrypted: usize,
+
+    /// Number of tokens that could not be decrypted and were left as is.
+    pub failed: usize,
+}
+
+/// Re-encrypts `openpgp:` tokens stored in the schedule
+/// as sent by the relays with the public key at `public_key_path`.
+///
+/// Tokens are decrypted with the keyrings at `keyring_paths`.
+pub fn reencrypt_tokens(
+    schedule: &Schedule,
+    keyring_paths: &[impl AsRef<Path>],
+    public_key_path: &Path,
+) -> Result<ReencryptionReport> {
+    let decryptor = PgpDecryptor::load(keyring_paths)?;
+    let public_key = std::fs::read_to_string(public_key_path)
+        .with_context(|| format!("Failed to read public key {}", public_key_path.display()))?;
+    let encryptor = PgpEncryptor::new(&public_key)?;
+    reencrypt(schedule, &decryptor, &encryptor)
+}
+
+/// Re-encrypts the stored tokens decryptable by `decryptor` with `encryptor`.
+///
+/// The decrypted content, including the envelope and padding,
+/// is kept as is.
+fn reencrypt(
+    schedule: &Schedule,
+    decryptor: &PgpDecryptor,
+    encryptor: &PgpEncryptor,
+) -> Result<ReencryptionReport> {
+    let mut report = ReencryptionReport::default();
+    schedule.rewrite_registered_tokens(|registered_token| {
+        let Some(message) = registered_token.strip_prefix("openpgp:") else {
+            return Ok(None);
+        };
+        let plaintext = match decryptor.decrypt_base64(message) {
+            Ok((plaintext, _key_id)) => plaintext,
+            Err(err) => {
+                warn!("Failed to decrypt stored token: {err:#}.");
+                report.failed += 1;
+                return Ok(None);
+            }
+        };
+        let message = encryptor.encrypt(&plaintext)?;
+        report.reencrypted += 1;
+        Ok(Some(format!("openpgp:{message}")))
+    })?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() -> Result<()> {
+        let bare = Envelope::parse("0123456789abcdef   ")?;
+        assert_eq!(bare.token, "0123456789abcdef");
+        assert_eq!(bare.timestamp, None);
+
+        // Other fields, e.g. a nonce, are ignored.
+        let envelope = Envelope::parse(
+            r#"{"token":"0123456789abcdef","timestamp":1700000000,"nonce":"a1b2"}    "#,
+        )?;
+        assert_eq!(envelope.token, "0123456789abcdef");
+        assert_eq!(envelope.timestamp, Some(1700000000));
+
+        assert!(Envelope::parse(r#"{"token":"0123456789abcdef"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let max_age = Duration::from_secs(3600);
+        let envelope = |timestamp| Envelope {
+            token: "token".to_string(),
+            timestamp,
+        };
+        let now = 1700000000;
+        assert!(envelope(Some(now)).is_fresh(now, max_age));
+        assert!(envelope(Some(now - 3600)).is_fresh(now, max_age));
+        assert!(!envelope(Some(now - 3601)).is_fresh(now, max_age));
+        assert!(envelope(Some(now + 60)).is_fresh(now, max_age));
+        assert!(!envelope(Some(now + 3600)).is_fresh(now, max_age));
+        assert!(!envelope(None).is_fresh(now, max_age));
+    }
+
+    fn generate_key() -> Result<SignedSecretKey> {
+        use pgp::composed::{KeyType, SecretKeyParamsBuilder, SubkeyParamsBuilder};
+
+        let mut rng = rand::thread_rng();
+        let params = SecretKeyParamsBuilder::default()
+            .key_type(KeyType::EdDSALegacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id("notifiers <notifiers@example.org>".into())
+            .subkeys(vec![SubkeyParamsBuilder::default()
+                .key_type(KeyType::ECDH(pgp::crypto::ecc_curve::ECCCurve::Curve25519))
+                .can_encrypt(true)
+                .build()?])
+            .build()?;
+        Ok(params.generate(&mut rng)?.sign(&mut rng, String::new)?)
+    }
+
+    #[test]
+    fn test_reencrypt_tokens() -> Result<()> {
+        let old_key = generate_key()?.to_armored_string(Default::default())?;
+        let new_key = generate_key()?.to_armored_string(Default::default())?;
+        let old_decryptor = PgpDecryptor::new(&old_key)?;
+        let new_decryptor = PgpDecryptor::new(&new_key)?;
+        let old_encryptor = PgpEncryptor::new(&old_decryptor.public_keys().join("\n"))?;
+        // Secret keys are accepted as well.
+        let new_encryptor = PgpEncryptor::new(&new_key)?;
+
+        let dir = tempfile::tempdir()?;
+        let schedule = Schedule::new(&dir.path().join("db.sled"))?;
+        let envelope = r#"{"token":"foo","timestamp":1700000000,"nonce":"a1b2"}  "#;
+        let encrypted = format!("openpgp:{}", old_encryptor.encrypt(envelope.as_bytes())?);
+        schedule.register_token("foo", Some(&encrypted), "relay", None, None)?;
+        schedule.register_token("bar", Some("openpgp:garbage"), "relay", None, None)?;
+        schedule.register_token("baz", None, "relay", None, None)?;
+
+        let report = reencrypt(&schedule, &old_decryptor, &new_encryptor)?;
+        assert_eq!(
+            report,
+            ReencryptionReport {
+                reencrypted: 1,
+                failed: 1
+            }
+        );
+
+        let registered_token = schedule
+            .provenance("foo")?
+            .and_then(|provenance| provenance.registered_token)
+            .unwrap();
+        assert_ne!(registered_token, encrypted);
+        let message = registered_token.strip_prefix("openpgp:").unwrap();
+        assert!(old_decryptor.decrypt(message).is_err());
+        let decrypted = new_decryptor.decrypt_envelope(message)?;
+        assert_eq!(decrypted.token, "foo");
+        assert_eq!(decrypted.timestamp, Some(1700000000));
+
+        assert_eq!(
+            schedule
+                .provenance("bar")?
+                .unwrap()
+                .registered_token
+                .as_deref(),
+            Some("openpgp:garbage")
+        );
+        assert_eq!(schedule.provenance("baz")?.unwrap().registered_token, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retired_keys() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let current_path = dir.path().join("current.privkey");
+        let retired_path = dir.path().join("retired.privkey");
+        std::fs::write(
+            &current_path,
+            generate_key()?.to_armored_string(Default::default())?,
+        )?;
+        std::fs::write(
+            &retired_path,
+            generate_key()?.to_armored_string(Default::default())?,
+        )?;
+
+        let current = PgpDecryptor::load(&[&current_path])?;
+        let retired = PgpDecryptor::load(&[&retired_path])?;
+        let decryptor = PgpDecryptor::load(&[&current_path, &retired_path])?;
+        assert_eq!(decryptor.public_keys(), current.public_keys());
+        assert_eq!(
+            decryptor.key_ids(),
+            [current.key_ids(), retired.key_ids()].concat()
+        );
+
+        for (keys, key_id) in [
+            (&current, &current.key_ids()[0]),
+            (&retired, &retired.key_ids()[0]),
+        ] {
+            let encryptor = PgpEncryptor::new(&keys.public_keys().join("\n"))?;
+            let message = encryptor.encrypt(b"foo")?;
+            let (envelope, decrypted_by) = decryptor.decrypt_envelope_with_key(&message)?;
+            assert_eq!(envelope.token, "foo");
+            assert_eq!(decrypted_by, key_id);
+        }
+
+        let message = PgpEncryptor::new(&retired.public_keys().join("\n"))?.encrypt(b"foo")?;
+        assert!(current.decrypt(&message).is_err());
+        assert!(PgpDecryptor::load(&[] as &[&Path]).is_err());
+        Ok(())
     }
 }
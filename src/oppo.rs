@@ -0,0 +1,265 @@
+//! # OPPO push provider.
+//!
+//! Devices of OPPO, OnePlus and realme phones running ColorOS,
+//! where FCM may be unavailable,
+//! register tokens of the form `oppo:<registration ID>`.
+//! Notifications are sent with the OPPO push server API
+//! using an auth token obtained with the app key and master secret.
+//! The auth token is valid for a day and is cached until it expires
+//! or the server rejects it.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Context as _, Result};
+use axum::http::StatusCode;
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::config::OppoConfig;
+use crate::logging::redact;
+use crate::metrics::{FailureLabels, Metrics, NotificationProvider};
+use crate::outage;
+use crate::retry;
+use crate::templates::Template;
+
+const AUTH_URL: &str = "https://api.push.oppomobile.com/server/v1/auth";
+const SEND_URL: &str = "https://api.push.oppomobile.com/server/v1/message/notification/unicast";
+
+/// Time after which the auth token is renewed.
+///
+/// Tokens are valid for 24 hours.
+const AUTH_TOKEN_LIFETIME: Duration = Duration::from_secs(23 * 60 * 60);
+
+/// Code of a successful request.
+const OK: i64 = 0;
+
+/// Code of a request with an invalid or expired auth token.
+const INVALID_AUTH_TOKEN: i64 = 11;
+
+/// Code for registration IDs that are invalid or no longer registered.
+const INVALID_REGISTRATION_ID: i64 = 10000;
+
+/// Target type of a message addressed by registration ID.
+const TARGET_REGISTRATION_ID: u8 = 2;
+
+#[derive(Debug, Serialize)]
+struct Message<'a> {
+    target_type: u8,
+    target_value: &'a str,
+    notification: Notification<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    title: &'a str,
+    content: &'a str,
+
+    /// Parameters passed to the app when the notification is tapped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action_parameters: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    code: i64,
+
+    #[serde(default)]
+    message: String,
+
+    #[serde(default)]
+    data: Option<AuthData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthData {
+    auth_token: String,
+}
+
+pub(crate) struct Oppo {
+    client: reqwest::Client,
+    config: Option<OppoConfig>,
+
+    /// Cached auth token and the time it was obtained.
+    auth_token: tokio::sync::Mutex<Option<(String, Instant)>>,
+}
+
+/// Signs the auth request.
+fn sign(config: &OppoConfig, timestamp: u128) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(config.app_key.as_bytes());
+    hasher.update(timestamp.to_string().as_bytes());
+    hasher.update(config.master_secret.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Returns the status for the code of a send request.
+fn code_status(code: i64) -> StatusCode {
+    match code {
+        OK => StatusCode::OK,
+        INVALID_REGISTRATION_ID => StatusCode::GONE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl Oppo {
+    pub(crate) fn new(config: Option<OppoConfig>, client: reqwest::Client) -> Self {
+        Self {
+            client,
+            config,
+            auth_token: Default::default(),
+        }
+    }
+
+    /// Returns a valid auth token, requesting a new one if needed.
+    async fn auth_token(&self, config: &OppoConfig, metrics: &Metrics) -> Result<String> {
+        let mut cached = self.auth_token.lock().await;
+        if let Some((token, obtained)) = &*cached {
+            if obtained.elapsed() < AUTH_TOKEN_LIFETIME {
+                return Ok(token.clone());
+            }
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        let form = [
+            ("app_key", config.app_key.clone()),
+            ("sign", sign(config, timestamp)),
+            ("timestamp", timestamp.to_string()),
+        ];
+        let res = retry::send(
+            self.client.post(AUTH_URL).form(&form),
+            metrics,
+            NotificationProvider::OPPO,
+        )
+        .await?
+        .error_for_status()?;
+        let res: Response = serde_json::from_slice(&res.bytes().await?)?;
+        if res.code != OK {
+            bail!("OPPO auth failed with {}: {}", res.code, res.message);
+        }
+        let token = res
+            .data
+            .context("OPPO auth response has no token")?
+            .auth_token;
+        *cached = Some((token.clone(), Instant::now()));
+        Ok(token)
+    }
+
+    pub(crate) async fn notify(
+        &self,
+        registration_id: &str,
+        template: &Template,
+        metrics: &Metrics,
+    ) -> Result<StatusCode> {
+        let fail = |reason: &str| {
+            metrics.count_failure(FailureLabels::new(NotificationProvider::OPPO, reason, ""));
+        };
+
+        let Some(config) = &self.config else {
+            warn!("Cannot notify OPPO device because OPPO credentials are not configured.");
+            fail("no_credentials");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        let auth_token = match self.auth_token(config, metrics).await {
+            Ok(auth_token) => auth_token,
+            Err(err) => {
+                warn!(provider = "oppo"; "Failed to get OPPO auth token: {err:#}");
+                fail("auth");
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let message = Message {
+            target_type: TARGET_REGISTRATION_ID,
+            target_value: registration_id,
+            notification: Notification {
+                title: template.title.as_deref().unwrap_or_default(),
+                content: template.body.as_deref().unwrap_or_default(),
+                action_parameters: (!template.data.is_empty())
+                    .then(|| serde_json::to_string(&template.data))
+                    .transpose()?,
+            },
+        };
+        let form = [
+            ("message", serde_json::to_string(&message)?),
+            ("auth_token", auth_token),
+        ];
+        debug!("Sending OPPO notification to {}.", redact(registration_id));
+        let inflight = metrics.inflight_request(NotificationProvider::OPPO);
+        let start = Instant::now();
+        let res = retry::send(
+            self.client.post(SEND_URL).form(&form),
+            metrics,
+            NotificationProvider::OPPO,
+        )
+        .await;
+        inflight.finish(outage::is_response_ok(&res));
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                warn!(provider = "oppo"; "Failed to send OPPO notification to {}: {err}", redact(registration_id));
+                fail("send");
+                return Err(err.into());
+            }
+        };
+        let status = res.status();
+        debug!("OPPO responded with {status} in {:?}.", start.elapsed());
+        let res: Option<Response> = serde_json::from_slice(&res.bytes().await?).ok();
+        let Some(res) = res.filter(|_| status.is_success()) else {
+            warn!(provider = "oppo", status = status.as_u16(); "Failed to deliver OPPO notification to {}", redact(registration_id));
+            metrics.count_failure(
+                FailureLabels::new(NotificationProvider::OPPO, status.as_u16().to_string(), "")
+                    .with_status(status.as_u16()),
+            );
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        if res.code == INVALID_AUTH_TOKEN {
+            // Request a new token for the next notification.
+            *self.auth_token.lock().await = None;
+        }
+        let status_code = code_status(res.code);
+        if status_code.is_success() {
+            debug!(
+                "Delivered notification to OPPO device {}.",
+                redact(registration_id)
+            );
+            metrics.oppo_notifications_total.inc();
+        } else {
+            warn!(provider = "oppo", code = res.code; "Failed to deliver OPPO notification to {}: {}", redact(registration_id), res.message);
+            metrics.count_failure(
+                FailureLabels::new(NotificationProvider::OPPO, res.code.to_string(), "")
+                    .with_status(status.as_u16()),
+            );
+        }
+        Ok(status_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        let config = OppoConfig {
+            app_key: "key".to_string(),
+            master_secret: "secret".to_string(),
+        };
+        // SHA-256 of "key1500000000000secret".
+        assert_eq!(
+            sign(&config, 1500000000000),
+            "674b66c9937a746fe82056a68af90467fe93391e91eeda2479cb9164993b098c"
+        );
+    }
+
+    #[test]
+    fn test_code_status() {
+        assert_eq!(code_status(OK), StatusCode::OK);
+        assert_eq!(code_status(INVALID_REGISTRATION_ID), StatusCode::GONE);
+        assert_eq!(
+            code_status(INVALID_AUTH_TOKEN),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
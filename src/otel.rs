@@ -0,0 +1,357 @@
+//! # OpenTelemetry tracing.
+//!
+//! With `--otlp-endpoint`, requests to `/register` and `/notify`,
+//! the provider calls they make and heartbeats are recorded as spans
+//! and exported to an OpenTelemetry collector
+//! using [OTLP/HTTP](https://opentelemetry.io/docs/specs/otlp/#otlphttp) with JSON encoding
+//! by the OpenTelemetry SDK.
+//!
+//! A relay passing a [W3C `traceparent`](https://www.w3.org/TR/trace-context/) header
+//! makes the spans of its request part of its own trace,
+//! so slow notifications can be followed from the relay to the provider.
+//! Spans are recorded if the relay marked its trace as sampled.
+//! Traces started by the gateway are sampled with `--otlp-sample-ratio`,
+//! heartbeats separately with `--otlp-heartbeat-sample-ratio`
+//! as there is one per registered token and heartbeat interval.
+//!
+//! The context of the current span is attached to the future it runs,
+//! so spans created deeper in the call stack, e.g. around provider calls,
+//! become its children without passing it around.
+//! Notifications delivered in the background start new traces.
+//!
+//! Spans are exported in batches.
+//! If the collector is slow or unreachable, spans are dropped
+//! instead of slowing down notifications.
+//! Failed spans carry an error kind such as `http_status`, never error messages,
+//! which may contain tokens.
+
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context as _, Result};
+use axum::extract::Request;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use log::*;
+use opentelemetry::propagation::{Extractor, TextMapPropagator as _};
+use opentelemetry::trace::{
+    Link, SamplingResult, Status, TraceContextExt as _, TraceId, Tracer as _, TracerProvider as _,
+};
+use opentelemetry::{Context, KeyValue, Value};
+use opentelemetry_otlp::{Protocol, WithExportConfig as _};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{
+    BatchConfigBuilder, BatchSpanProcessor, Sampler, SdkTracer, SdkTracerProvider, ShouldSample,
+};
+use opentelemetry_sdk::Resource;
+
+pub(crate) use opentelemetry::trace::SpanKind;
+
+/// Maximum number of spans waiting to be exported.
+const QUEUE_SIZE: usize = 8192;
+
+/// Timeout of export requests to the collector.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SERVICE_NAME: &str = "notifiers";
+
+/// Name of the root span of each heartbeat.
+pub(crate) const HEARTBEAT_SPAN: &str = "heartbeat";
+
+/// Reads the `traceparent` header.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Returns the trace context sent by the caller
+/// in a [W3C `traceparent`](https://www.w3.org/TR/trace-context/) header, if any.
+pub(crate) fn parent_from_headers(headers: &HeaderMap) -> Option<Context> {
+    let context = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    context.span().span_context().is_valid().then_some(context)
+}
+
+/// Runs `f` with `span` as the parent of spans created in it.
+pub(crate) async fn scope<F: Future>(span: &Span, f: F) -> F::Output {
+    match &span.context {
+        Some(context) => opentelemetry::context::FutureExt::with_context(f, context.clone()).await,
+        None => f.await,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<AttributeValue> for Value {
+    fn from(value: AttributeValue) -> Self {
+        match value {
+            AttributeValue::String(value) => value.into(),
+            AttributeValue::Int(value) => value.into(),
+        }
+    }
+}
+
+/// Span that is ended when dropped.
+///
+/// Spans that are not sampled or created while tracing is disabled
+/// record nothing.
+#[derive(Debug)]
+pub(crate) struct Span {
+    /// Context carrying the span, `None` while tracing is disabled.
+    context: Option<Context>,
+}
+
+impl Span {
+    /// Starts a span as child of `parent`
+    /// or as root of a new trace if there is no parent.
+    pub(crate) fn new(name: &str, kind: SpanKind, parent: Option<Context>) -> Self {
+        let Some(tracer) = TRACER.get() else {
+            return Self { context: None };
+        };
+        let parent = parent.unwrap_or_default();
+        let span = tracer
+            .span_builder(name.to_string())
+            .with_kind(kind)
+            .start_with_context(tracer, &parent);
+        Self {
+            context: Some(parent.with_span(span)),
+        }
+    }
+
+    /// Starts a span as child of the span the current task runs in.
+    pub(crate) fn child(name: &str, kind: SpanKind) -> Self {
+        Self::new(name, kind, Some(Context::current()))
+    }
+
+    pub(crate) fn set_attribute(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        if let Some(context) = &self.context {
+            context
+                .span()
+                .set_attribute(KeyValue::new(key, Value::from(value.into())));
+        }
+    }
+
+    /// Marks the span as failed with an error kind, e.g. `http_status`.
+    pub(crate) fn set_error(&mut self, kind: &'static str) {
+        if let Some(context) = &self.context {
+            let span = context.span();
+            span.set_attribute(KeyValue::new("error.type", kind));
+            span.set_status(Status::error(kind));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(context) = &self.context {
+            context.span().end();
+        }
+    }
+}
+
+/// Samples heartbeats and other traces started by the gateway with separate ratios
+/// and follows the decision of the parent for continued traces.
+#[derive(Debug, Clone)]
+struct GatewaySampler {
+    requests: Sampler,
+    heartbeats: Sampler,
+}
+
+impl ShouldSample for GatewaySampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let sampler = if name == HEARTBEAT_SPAN {
+            &self.heartbeats
+        } else {
+            &self.requests
+        };
+        sampler.should_sample(parent_context, trace_id, name, span_kind, attributes, links)
+    }
+}
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+static TRACER: OnceLock<SdkTracer> = OnceLock::new();
+
+/// Starts exporting spans to the OTLP/HTTP collector at `endpoint`,
+/// e.g. `http://localhost:4318`.
+///
+/// Traces started by the gateway are sampled with `sample_ratio`,
+/// heartbeats with `heartbeat_sample_ratio`.
+pub fn start(endpoint: &str, sample_ratio: f64, heartbeat_sample_ratio: f64) -> Result<()> {
+    ensure!(
+        (0.0..=1.0).contains(&sample_ratio) && (0.0..=1.0).contains(&heartbeat_sample_ratio),
+        "OTLP sample ratios must be between 0 and 1"
+    );
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    reqwest::Url::parse(&url).with_context(|| format!("Invalid OTLP endpoint {endpoint:?}"))?;
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpJson)
+        .with_endpoint(url)
+        .with_timeout(EXPORT_TIMEOUT)
+        .build()
+        .context("Failed to build OTLP exporter")?;
+    let processor = BatchSpanProcessor::builder(exporter)
+        .with_batch_config(
+            BatchConfigBuilder::default()
+                .with_max_queue_size(QUEUE_SIZE)
+                .build(),
+        )
+        .build();
+    let sampler = GatewaySampler {
+        requests: Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_ratio))),
+        heartbeats: Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            heartbeat_sample_ratio,
+        ))),
+    };
+    let resource = Resource::builder()
+        .with_service_name(SERVICE_NAME)
+        .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .build();
+    let provider = SdkTracerProvider::builder()
+        .with_span_processor(processor)
+        .with_sampler(sampler)
+        .with_resource(resource)
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    PROVIDER
+        .set(provider)
+        .map_err(|_| anyhow!("OTLP exporter is already started"))?;
+    TRACER.set(tracer).ok();
+    info!("Exporting traces to {endpoint}.");
+    Ok(())
+}
+
+/// Exports the remaining spans and stops the exporter.
+///
+/// Blocks until the export finished or timed out.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            warn!("Failed to export remaining spans: {err}.");
+        }
+    }
+}
+
+/// Records the request as a server span
+/// continuing the trace of the caller if it sent one.
+pub(crate) async fn trace_request(request: Request, next: Next) -> Response {
+    let parent = parent_from_headers(request.headers());
+    let name = format!("{} {}", request.method(), request.uri().path());
+    let mut span = Span::new(&name, SpanKind::Server, parent);
+    let response = scope(&span, next.run(request)).await;
+    let status = response.status();
+    span.set_attribute("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.set_error("http_status");
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(traceparent: &str) -> Option<Context> {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", traceparent.parse().unwrap());
+        parent_from_headers(&headers)
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        let context = parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        let span = context.span();
+        let span_context = span.span_context();
+        assert_eq!(
+            span_context.trace_id(),
+            TraceId::from(0x4bf92f3577b34da6a3ce929d0e0e4736)
+        );
+        assert_eq!(span_context.span_id(), 0x00f067aa0ba902b7u64.into());
+        assert!(span_context.is_sampled());
+        assert!(span_context.is_remote());
+
+        let context = parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap();
+        assert!(!context.span().span_context().is_sampled());
+
+        assert!(parent_from_headers(&HeaderMap::new()).is_none());
+        for invalid in [
+            "",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00",
+            "00-4bf92f3577b34da6a3ce929d0e0e473x-00f067aa0ba902b7-01",
+        ] {
+            assert!(parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_sampler() {
+        let sampler = GatewaySampler {
+            requests: Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(1.0))),
+            heartbeats: Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(0.0))),
+        };
+        let sample = |name: &str, parent: Option<&Context>| {
+            let trace_id = TraceId::from(0x4bf92f3577b34da6a3ce929d0e0e4736);
+            sampler
+                .should_sample(parent, trace_id, name, &SpanKind::Client, &[], &[])
+                .decision
+        };
+        let sampled = opentelemetry::trace::SamplingDecision::RecordAndSample;
+        assert_eq!(sample("POST /notify", None), sampled);
+        assert_ne!(sample(HEARTBEAT_SPAN, None), sampled);
+
+        // Traces continued from a relay follow its decision.
+        let parent = parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap();
+        assert_ne!(sample("provider.send", Some(&parent)), sampled);
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        // Without an exporter nothing is recorded or propagated.
+        let span = Span::new("test", SpanKind::Server, None);
+        assert!(span.context.is_none());
+        let child = scope(&span, async { Span::child("child", SpanKind::Client) }).await;
+        assert!(child.context.is_none());
+    }
+}
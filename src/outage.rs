@@ -0,0 +1,255 @@
+//! # Provider outage detection.
+//!
+//! Every request to a push provider is recorded as succeeded or failed.
+//! Connection errors and server errors count as failures,
+//! while rejections of individual tokens do not,
+//! because they say nothing about the provider.
+//!
+//! The health of each provider is derived from the error rate
+//! over the last [`WINDOW`], exported as the `provider_status` gauge
+//! and returned by `GET /status`,
+//! so relays and dashboards can tell gateway problems
+//! from Apple or Google outages.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use prometheus_client::encoding::EncodeLabelValue;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use serde::Serialize;
+
+use crate::metrics::{NotificationProvider, ProviderStatusLabels};
+
+/// Time over which the error rate is computed.
+const WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Duration of a single bucket of request counts.
+const BUCKET: Duration = Duration::from_secs(10);
+
+/// Minimum number of requests in the window to consider a provider unhealthy.
+const MIN_REQUESTS: u64 = 20;
+
+/// Error rate from which a provider is considered degraded.
+const DEGRADED_ERROR_RATE: f64 = 0.1;
+
+/// Error rate from which a provider is considered down.
+const DOWN_ERROR_RATE: f64 = 0.5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, EncodeLabelValue)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderStatus {
+    Healthy,
+    Degraded,
+    Down,
+}
+
+impl ProviderStatus {
+    const ALL: [Self; 3] = [Self::Healthy, Self::Degraded, Self::Down];
+}
+
+/// Request counts of a single bucket.
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    /// Start of the bucket, in buckets since the tracker was created.
+    index: u64,
+    succeeded: u64,
+    failed: u64,
+}
+
+/// Health of a provider returned by `GET /status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub provider: NotificationProvider,
+    pub status: ProviderStatus,
+
+    /// Number of requests in the window.
+    pub requests: u64,
+
+    /// Share of failed requests in the window.
+    pub error_rate: f64,
+}
+
+/// Tracks recent request outcomes of all providers.
+#[derive(Debug)]
+pub struct OutageDetector {
+    start: Instant,
+    buckets: Mutex<HashMap<NotificationProvider, VecDeque<Bucket>>>,
+    gauge: Family<ProviderStatusLabels, Gauge>,
+}
+
+impl OutageDetector {
+    pub fn new(gauge: Family<ProviderStatusLabels, Gauge>) -> Self {
+        Self {
+            start: Instant::now(),
+            buckets: Default::default(),
+            gauge,
+        }
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.start).as_secs()) / BUCKET.as_secs()
+    }
+
+    /// Records the outcome of a request to the provider.
+    pub fn record(&self, now: Instant, provider: NotificationProvider, succeeded: bool) {
+        let index = self.bucket_index(now);
+        let health = {
+            let mut buckets = self.buckets.lock();
+            let buckets = buckets.entry(provider).or_default();
+            if buckets.back().is_none_or(|bucket| bucket.index != index) {
+                buckets.push_back(Bucket {
+                    index,
+                    ..Default::default()
+                });
+            }
+            let bucket = buckets.back_mut().expect("bucket was just pushed");
+            if succeeded {
+                bucket.succeeded += 1;
+            } else {
+                bucket.failed += 1;
+            }
+            Self::prune(buckets, index);
+            health(provider, buckets)
+        };
+        self.export(&health);
+    }
+
+    /// Returns the health of all providers.
+    pub fn health(&self, now: Instant) -> Vec<ProviderHealth> {
+        let index = self.bucket_index(now);
+        let health: Vec<_> = {
+            let mut buckets = self.buckets.lock();
+            NotificationProvider::ALL
+                .iter()
+                .map(|provider| {
+                    let buckets = buckets.entry(*provider).or_default();
+                    Self::prune(buckets, index);
+                    health(*provider, buckets)
+                })
+                .collect()
+        };
+        for health in &health {
+            self.export(health);
+        }
+        health
+    }
+
+    /// Drops buckets that are out of the window.
+    fn prune(buckets: &mut VecDeque<Bucket>, index: u64) {
+        let window = WINDOW.as_secs() / BUCKET.as_secs();
+        while buckets
+            .front()
+            .is_some_and(|bucket| bucket.index + window <= index)
+        {
+            buckets.pop_front();
+        }
+    }
+
+    fn export(&self, health: &ProviderHealth) {
+        for status in ProviderStatus::ALL {
+            self.gauge
+                .get_or_create(&ProviderStatusLabels {
+                    provider: health.provider,
+                    status,
+                })
+                .set((status == health.status).into());
+        }
+    }
+}
+
+fn health(provider: NotificationProvider, buckets: &VecDeque<Bucket>) -> ProviderHealth {
+    let succeeded: u64 = buckets.iter().map(|bucket| bucket.succeeded).sum();
+    let failed: u64 = buckets.iter().map(|bucket| bucket.failed).sum();
+    let requests = succeeded + failed;
+    let error_rate = if requests == 0 {
+        0.0
+    } else {
+        failed as f64 / requests as f64
+    };
+    let status = if requests < MIN_REQUESTS || error_rate < DEGRADED_ERROR_RATE {
+        ProviderStatus::Healthy
+    } else if error_rate < DOWN_ERROR_RATE {
+        ProviderStatus::Degraded
+    } else {
+        ProviderStatus::Down
+    };
+    ProviderHealth {
+        provider,
+        status,
+        requests,
+        error_rate,
+    }
+}
+
+/// Returns true if the response shows that the provider works,
+/// even if it rejected the request.
+pub(crate) fn is_response_ok(res: &reqwest::Result<reqwest::Response>) -> bool {
+    res.as_ref()
+        .is_ok_and(|res| !res.status().is_server_error())
+}
+
+/// Returns true if the APNS response shows that APNS works,
+/// even if it rejected the notification.
+pub(crate) fn is_apns_response_ok(res: &Result<apns_h2::Response, apns_h2::Error>) -> bool {
+    match res {
+        Ok(_) => true,
+        Err(apns_h2::Error::ResponseError(res)) => res.code < 500,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(detector: &OutageDetector, now: Instant) -> ProviderStatus {
+        detector
+            .health(now)
+            .into_iter()
+            .find(|health| health.provider == NotificationProvider::APNS)
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn test_status() {
+        let detector = OutageDetector::new(Family::default());
+        let now = detector.start;
+        assert_eq!(status(&detector, now), ProviderStatus::Healthy);
+
+        // Few requests are not enough to declare an outage.
+        for _ in 0..10 {
+            detector.record(now, NotificationProvider::APNS, false);
+        }
+        assert_eq!(status(&detector, now), ProviderStatus::Healthy);
+
+        for _ in 0..40 {
+            detector.record(now, NotificationProvider::APNS, true);
+        }
+        assert_eq!(status(&detector, now), ProviderStatus::Degraded);
+
+        for _ in 0..40 {
+            detector.record(now, NotificationProvider::APNS, false);
+        }
+        assert_eq!(status(&detector, now), ProviderStatus::Down);
+
+        // Other providers are not affected.
+        let fcm = detector
+            .health(now)
+            .into_iter()
+            .find(|health| health.provider == NotificationProvider::FCM)
+            .unwrap();
+        assert_eq!(fcm.status, ProviderStatus::Healthy);
+        assert_eq!(fcm.requests, 0);
+
+        // Old requests leave the window.
+        assert_eq!(status(&detector, now + WINDOW), ProviderStatus::Healthy);
+        let labels = ProviderStatusLabels {
+            provider: NotificationProvider::APNS,
+            status: ProviderStatus::Healthy,
+        };
+        assert_eq!(detector.gauge.get_or_create(&labels).get(), 1);
+    }
+}
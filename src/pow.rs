@@ -0,0 +1,60 @@
+//! # Proof-of-work for registrations.
+//!
+//! Gateways exposed publicly can require clients to solve
+//! a small hashcash-style puzzle for each `/register` request.
+//!
+//! The client finds a `nonce` such that
+//! SHA-256 of `<token>:<nonce>` starts with at least `difficulty` zero bits,
+//! where `<token>` is the token exactly as sent in the request,
+//! and sends it as the `pow` field next to the `token`.
+
+use sha2::{Digest as _, Sha256};
+
+/// Returns the number of leading zero bits in the hash.
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut res = 0;
+    for byte in hash {
+        res += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    res
+}
+
+/// Returns true if `nonce` solves the puzzle for `token`.
+pub(crate) fn verify(token: &str, nonce: &str, difficulty: u8) -> bool {
+    let hash = Sha256::new()
+        .chain_update(token.as_bytes())
+        .chain_update(b":")
+        .chain_update(nonce.as_bytes())
+        .finalize();
+    leading_zero_bits(&hash) >= u32::from(difficulty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn test_verify() {
+        let token = "openpgp:abcdef";
+        let difficulty = 8;
+        let nonce = (0u64..)
+            .map(|n| n.to_string())
+            .find(|nonce| verify(token, nonce, difficulty))
+            .unwrap();
+        assert!(verify(token, &nonce, difficulty));
+        assert!(verify(token, &nonce, 0));
+
+        // Solution is bound to the token.
+        assert!(!(0..10).all(|i| verify(&format!("{token}{i}"), &nonce, difficulty)));
+    }
+}
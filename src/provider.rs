@@ -0,0 +1,1435 @@
+//! # Notification providers.
+//!
+//! Each push service is implemented as a [`Provider`]
+//! which sends direct notifications and heartbeats to its tokens
+//! and decides what a failed delivery means for the token.
+//! Providers are kept in a [`Providers`] registry on the state,
+//! looked up by the label of the token.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use anyhow::{bail, Context as _, Result};
+use apns_h2::request::payload::{Payload, PayloadLike as _};
+use apns_h2::{
+    CollapseId, DefaultNotificationBuilder, Error::ResponseError, ErrorReason, NotificationBuilder,
+    NotificationOptions, Priority, PushType, WebNotificationBuilder, WebPushAlert,
+};
+use axum::async_trait;
+use axum::http::StatusCode;
+use base64::Engine as _;
+use chrono::{Local, TimeDelta};
+use log::*;
+use uuid::Uuid;
+use web_push_native::jwt_simple::prelude::ES256KeyPair;
+use web_push_native::{p256, Auth, WebPushBuilder};
+
+use crate::chaos::{ChaosConfig, ChaosProvider};
+use crate::dryrun::DryRunProvider;
+use crate::endpoints::Endpoints;
+use crate::flags::Flag;
+use crate::logging::{log_full_tokens, redact};
+use crate::metrics::{
+    self, FailureLabels, FcmProjectLabels, Metrics, NotificationKind, NotificationProvider,
+};
+use crate::outage;
+use crate::retry;
+use crate::server::{ApnsPushType, NotificationToken, NotifyOptions};
+use crate::state::{ApnsClient, State};
+use crate::templates::Template;
+use crate::unifiedpush;
+
+/// Maximum size of APNS payloads.
+///
+/// <https://developer.apple.com/documentation/usernotifications/generating-a-remote-notification>
+const MAX_APNS_PAYLOAD_SIZE: usize = 4 * 1024;
+
+/// Maximum size of APNS VoIP payloads.
+const MAX_APNS_VOIP_PAYLOAD_SIZE: usize = 5 * 1024;
+
+/// Result of a notification request to a provider.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Delivery {
+    /// Status returned to the relay.
+    pub(crate) status: StatusCode,
+
+    /// Error reason reported by the provider.
+    pub(crate) reason: Option<String>,
+
+    /// Failure to be counted by the caller
+    /// once it is known whether the token is removed.
+    pub(crate) failure: Option<FailureLabels>,
+}
+
+impl From<StatusCode> for Delivery {
+    fn from(status: StatusCode) -> Self {
+        Self {
+            status,
+            reason: None,
+            failure: None,
+        }
+    }
+}
+
+/// What a delivery means for the token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Disposition {
+    /// Notification was delivered.
+    Delivered,
+
+    /// Token is not valid anymore and should be removed.
+    Gone,
+
+    /// Delivery failed temporarily and may be retried later.
+    Retry,
+
+    /// Delivery failed for another reason.
+    Fatal,
+}
+
+#[async_trait]
+pub(crate) trait Provider: Send + Sync {
+    /// Label of the provider in metrics and the event log.
+    fn label(&self) -> NotificationProvider;
+
+    /// Returns true if the token can receive heartbeats.
+    fn supports_heartbeats(&self, _token: &NotificationToken) -> bool {
+        false
+    }
+
+    /// Sends a notification to the token.
+    ///
+    /// Failures that may lead the caller to remove the token
+    /// are returned in [`Delivery::failure`] for the caller to count,
+    /// other failures are counted by the provider.
+    /// Errors are returned if the request could not be sent.
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        options: &NotifyOptions,
+    ) -> Result<Delivery>;
+
+    /// Classifies the result of a delivery.
+    fn classify(&self, delivery: &Delivery) -> Disposition {
+        let status = delivery.status;
+        if status.is_success() {
+            Disposition::Delivered
+        } else if status == StatusCode::GONE {
+            Disposition::Gone
+        } else if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            Disposition::Retry
+        } else {
+            Disposition::Fatal
+        }
+    }
+}
+
+/// Registry of providers keyed by their label.
+pub(crate) struct Providers {
+    providers: HashMap<NotificationProvider, Box<dyn Provider>>,
+}
+
+impl Providers {
+    /// Creates the registry,
+    /// injecting faults into the providers if `chaos` is set
+    /// and only logging notifications if `dry_run` is set.
+    pub(crate) fn new(chaos: Option<&ChaosConfig>, dry_run: bool) -> Result<Self> {
+        let providers: Vec<Box<dyn Provider>> = vec![
+            Box::new(ApnsProvider),
+            Box::new(FcmProvider),
+            Box::new(UbportsProvider),
+            Box::new(WebPushProvider),
+            Box::new(OneSignalProvider),
+            Box::new(VivoProvider),
+            Box::new(OppoProvider),
+            Box::new(UnifiedPushProvider),
+            Box::new(WebhookProvider),
+            Box::new(WnsProvider),
+        ];
+        let providers = providers
+            .into_iter()
+            .map(|provider| {
+                // Dry runs never reach the provider or injected faults,
+                // so nothing they return can remove tokens.
+                let provider = ChaosProvider::wrap(provider, chaos)?;
+                Ok((provider.label(), DryRunProvider::wrap(provider, dry_run)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { providers })
+    }
+
+    /// Returns the provider with the given label.
+    pub(crate) fn get(&self, label: NotificationProvider) -> &dyn Provider {
+        // All providers are registered in `new`.
+        self.providers[&label].as_ref()
+    }
+
+    /// Returns the provider delivering notifications to the token.
+    pub(crate) fn for_token(&self, token: &NotificationToken) -> &dyn Provider {
+        self.get(token.provider())
+    }
+}
+
+struct ApnsProvider;
+
+#[async_trait]
+impl Provider for ApnsProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::APNS
+    }
+
+    fn supports_heartbeats(&self, token: &NotificationToken) -> bool {
+        // Safari cannot receive silent notifications.
+        matches!(
+            token,
+            NotificationToken::ApnsSandbox(_)
+                | NotificationToken::ApnsProduction(_)
+                | NotificationToken::ApnsApp { .. }
+        )
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        if kind == NotificationKind::Heartbeat {
+            return apns_heartbeat(state, id, token).await;
+        }
+        let status = match token {
+            NotificationToken::ApnsSandbox(token) => {
+                let client = state.sandbox_client();
+                notify_apns(
+                    state.clone(),
+                    client,
+                    None,
+                    state.topic(),
+                    token,
+                    id,
+                    options.push_type,
+                )
+                .await?
+            }
+            NotificationToken::ApnsProduction(token) => {
+                let client = state.production_client();
+                let fallback_client = if state.flags().is_enabled_for(Flag::SandboxFallback, &token)
+                {
+                    state.sandbox_client()
+                } else {
+                    None
+                };
+                notify_apns(
+                    state.clone(),
+                    client,
+                    fallback_client,
+                    state.topic(),
+                    token,
+                    id,
+                    options.push_type,
+                )
+                .await?
+            }
+            NotificationToken::ApnsApp {
+                topic,
+                sandbox,
+                token,
+            } => {
+                let client = state.apns_app_client(&topic, sandbox);
+                let fallback_client =
+                    if !sandbox && state.flags().is_enabled_for(Flag::SandboxFallback, &token) {
+                        state.apns_app_client(&topic, true)
+                    } else {
+                        None
+                    };
+                notify_apns(
+                    state.clone(),
+                    client,
+                    fallback_client,
+                    Some(&topic),
+                    token,
+                    id,
+                    options.push_type,
+                )
+                .await?
+            }
+            NotificationToken::Safari { sandbox, token } => {
+                // Website pushes need the website push certificate,
+                // configured as the APNS app of the website push ID.
+                let client = state
+                    .safari_topic()
+                    .and_then(|topic| state.apns_app_client(topic, sandbox));
+                notify_apns(
+                    state.clone(),
+                    client,
+                    None,
+                    None,
+                    token,
+                    id,
+                    ApnsPushType::Website,
+                )
+                .await?
+            }
+            _ => bail!("Not an APNS token"),
+        };
+        Ok(status.into())
+    }
+
+    /// Classifies APNS responses by their reason.
+    ///
+    /// Only reasons blaming the device token remove it.
+    /// Other client errors, e.g. expired provider tokens
+    /// or a topic the certificate is not valid for,
+    /// are problems of the gateway credentials and keep the token.
+    fn classify(&self, delivery: &Delivery) -> Disposition {
+        let status = delivery.status;
+        if status.is_success() {
+            return Disposition::Delivered;
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            return Disposition::Retry;
+        }
+        match delivery.reason.as_deref() {
+            Some("BadDeviceToken" | "DeviceTokenNotForTopic" | "Unregistered" | "ExpiredToken") => {
+                Disposition::Gone
+            }
+            // Direct notifications report removed tokens without a reason.
+            None if status == StatusCode::GONE => Disposition::Gone,
+            _ => Disposition::Fatal,
+        }
+    }
+}
+
+/// Builds the silent background notification sent as an APNS heartbeat.
+///
+/// According to <https://developer.apple.com/documentation/usernotifications/pushing-background-updates-to-your-app>
+/// a background notification sets `content-available` to 1,
+/// doesn't include `alert`, `badge` or `sound`,
+/// and is sent with the `background` push type and priority 5.
+/// Such notifications wake the app without showing anything to the user
+/// and don't count against the alert budget of the device.
+fn heartbeat_payload<'a>(device_token: &'a str, topic: Option<&'a str>) -> Payload<'a> {
+    DefaultNotificationBuilder::new().content_available().build(
+        device_token,
+        NotificationOptions {
+            // Normal priority (5) means
+            // "send the notification based on power considerations on the user’s device".
+            // Background notifications must not use high priority.
+            // <https://developer.apple.com/documentation/usernotifications/sending-notification-requests-to-apns>
+            apns_priority: Some(Priority::Normal),
+            apns_push_type: Some(PushType::Background),
+            apns_topic: topic,
+            ..Default::default()
+        },
+    )
+}
+
+/// Sends a silent heartbeat notification to an APNS token.
+async fn apns_heartbeat(state: &State, id: Uuid, token: NotificationToken) -> Result<Delivery> {
+    let metrics = state.metrics();
+    let (client, topic, device_token) = match token {
+        NotificationToken::ApnsSandbox(token) => (
+            state.sandbox_client(),
+            state.topic().map(str::to_string),
+            token,
+        ),
+        NotificationToken::ApnsProduction(token) => (
+            state.production_client(),
+            state.topic().map(str::to_string),
+            token,
+        ),
+        NotificationToken::ApnsApp {
+            topic,
+            sandbox,
+            token,
+        } => (state.apns_app_client(&topic, sandbox), Some(topic), token),
+        _ => bail!("Token cannot receive APNS heartbeats"),
+    };
+
+    let mut payload = heartbeat_payload(&device_token, topic.as_deref());
+
+    let template =
+        state
+            .templates()
+            .render(NotificationProvider::APNS, NotificationKind::Heartbeat, id);
+    for (key, value) in &template.data {
+        payload.add_custom_data(key.as_str(), value)?;
+    }
+
+    let Some(client) = client else {
+        bail!("APNS client is not configured");
+    };
+    debug!(
+        "Sending heartbeat to {}: priority={:?} push_type={:?} topic={:?}, payload size {} bytes.",
+        redact(&device_token),
+        payload.options.apns_priority,
+        payload.options.apns_push_type,
+        payload.options.apns_topic,
+        payload
+            .to_json_string()
+            .map(|s| s.len())
+            .unwrap_or_default()
+    );
+    let inflight = metrics.inflight_request(NotificationProvider::APNS);
+    let start = Instant::now();
+    let res = client.send(payload, metrics).await;
+    inflight.finish(outage::is_apns_response_ok(&res));
+    debug!("APNS heartbeat request took {:?}.", start.elapsed());
+    match res {
+        Ok(res) => match res.code {
+            200 => {
+                debug!("delivered notification for {}", redact(&device_token));
+                Ok(StatusCode::OK.into())
+            }
+            _ => {
+                bail!("unexpected status: {:?}", res);
+            }
+        },
+        Err(ResponseError(res)) => {
+            let failure = FailureLabels::new(
+                NotificationProvider::APNS,
+                res.code.to_string(),
+                res.error
+                    .as_ref()
+                    .map(|e| e.reason.to_string())
+                    .unwrap_or_default(),
+            )
+            .with_status(res.code);
+            // Reason name such as `BadDeviceToken`, used for classification.
+            let reason = res.error.as_ref().map(|e| format!("{:?}", e.reason));
+            info!(
+                provider = "apns", status = res.code;
+                "APNS rejected heartbeat to {}: {:?}.",
+                redact(&device_token),
+                res
+            );
+            Ok(Delivery {
+                status: StatusCode::from_u16(res.code)?,
+                reason,
+                failure: Some(failure),
+            })
+        }
+        Err(err) => {
+            metrics.count_failure(FailureLabels::new(NotificationProvider::APNS, "send", ""));
+            Err(err.into())
+        }
+    }
+}
+
+/// Sends a direct APNS notification,
+/// accounting the request in the in-flight gauge and the request duration histogram.
+async fn send_apns_request(
+    client: &ApnsClient,
+    payload: Payload<'_>,
+    metrics: &Metrics,
+) -> Result<apns_h2::Response, apns_h2::Error> {
+    let inflight = metrics.inflight_request(NotificationProvider::APNS);
+    let start = Instant::now();
+    let res = retry::send_apns(client, payload, metrics).await;
+    inflight.finish(outage::is_apns_response_ok(&res));
+    debug!(
+        "APNS {} request took {:?}.",
+        client.endpoint_label(),
+        start.elapsed()
+    );
+    res
+}
+
+/// Drops optional fields from the payload until it fits into `limit` bytes.
+///
+/// Returns the size of the trimmed payload,
+/// which is still over `limit` if the required fields do not fit.
+fn trim_apns_payload(payload: &mut Payload<'_>, limit: usize) -> Result<usize> {
+    let size = |payload: &Payload<'_>| -> Result<usize> { Ok(payload.to_json_string()?.len()) };
+    // Custom data such as the notification ID for delivery receipts goes first.
+    while size(payload)? > limit {
+        if payload.data.pop_last().is_none() {
+            break;
+        }
+    }
+    if size(payload)? > limit {
+        payload.aps.sound = None;
+    }
+    if size(payload)? > limit {
+        payload.aps.category = None;
+    }
+    if size(payload)? > limit {
+        payload.aps.thread_id = None;
+    }
+    size(payload)
+}
+
+/// Sends a direct notification to an APNS token.
+async fn notify_apns(
+    state: State,
+    client: Option<ApnsClient>,
+    fallback_client: Option<ApnsClient>,
+    app_topic: Option<&str>,
+    device_token: String,
+    id: Uuid,
+    push_type: ApnsPushType,
+) -> Result<StatusCode> {
+    let Some(client) = client else {
+        warn!(
+            "Cannot notify APNS because client is not configured (missing or invalid certificate)"
+        );
+        state.metrics().count_failure(FailureLabels::new(
+            NotificationProvider::APNS,
+            "no_certificate",
+            "",
+        ));
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let schedule = state.schedule();
+    let apns_id = id.hyphenated().to_string();
+    let template =
+        state
+            .templates()
+            .render(NotificationProvider::APNS, NotificationKind::Direct, id);
+    let topic;
+    let mut payload = match push_type {
+        ApnsPushType::Alert => {
+            let mut builder = DefaultNotificationBuilder::new();
+            if let Some(title) = &template.title {
+                builder = builder.title(title);
+            }
+            if let Some(title_loc_key) = &template.title_loc_key {
+                // Localization key for the title.
+                builder = builder.title_loc_key(title_loc_key);
+            }
+            if let Some(body) = &template.body {
+                builder = builder.body(body);
+            }
+            if let Some(loc_key) = &template.loc_key {
+                // Localization key for the body.
+                builder = builder.loc_key(loc_key);
+            }
+            builder.sound("default").mutable_content().build(
+                &device_token,
+                NotificationOptions {
+                    // Apple reports the same ID in its logs and responses.
+                    apns_id: Some(&apns_id),
+                    // High priority (10).
+                    // <https://developer.apple.com/documentation/usernotifications/sending-notification-requests-to-apns>
+                    apns_priority: Some(Priority::High),
+                    apns_topic: app_topic,
+                    apns_push_type: Some(PushType::Alert),
+                    apns_collapse_id: CollapseId::new("new_messages").ok(),
+                    ..Default::default()
+                },
+            )
+        }
+        ApnsPushType::Website => {
+            let Some(safari_topic) = state.safari_topic() else {
+                warn!("Cannot send Safari notification because Safari topic is not set.");
+                state.metrics().count_failure(FailureLabels::new(
+                    NotificationProvider::APNS,
+                    "no_topic",
+                    "",
+                ));
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+            };
+            // Website pushes use the legacy Safari payload format.
+            // <https://developer.apple.com/library/archive/documentation/NetworkingInternet/Conceptual/NotificationProgGuide/PushNotifications/PushNotifications.html>
+            let alert = WebPushAlert {
+                title: template.title.as_deref().unwrap_or_default(),
+                body: template.body.as_deref().unwrap_or_default(),
+                action: "View",
+            };
+            WebNotificationBuilder::new(alert, &[] as &[&str]).build(
+                &device_token,
+                NotificationOptions {
+                    apns_id: Some(&apns_id),
+                    apns_priority: Some(Priority::High),
+                    apns_topic: Some(safari_topic),
+                    apns_push_type: Some(PushType::Alert),
+                    ..Default::default()
+                },
+            )
+        }
+        ApnsPushType::Voip | ApnsPushType::PushToTalk => {
+            // VoIP and push-to-talk pushes go to the app topic with a suffix.
+            // <https://developer.apple.com/documentation/usernotifications/sending-notification-requests-to-apns>
+            let Some(app_topic) = app_topic else {
+                warn!("Cannot send {push_type:?} notification because APNS topic is not set.");
+                state.metrics().count_failure(FailureLabels::new(
+                    NotificationProvider::APNS,
+                    "no_topic",
+                    "",
+                ));
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+            };
+            let (suffix, apns_push_type) = match push_type {
+                ApnsPushType::Voip => ("voip", PushType::Voip),
+                _ => ("voip-ptt", PushType::PushToTalk),
+            };
+            topic = format!("{app_topic}.{suffix}");
+            DefaultNotificationBuilder::new().build(
+                &device_token,
+                NotificationOptions {
+                    apns_id: Some(&apns_id),
+                    apns_priority: Some(Priority::High),
+                    apns_topic: Some(&topic),
+                    apns_push_type: Some(apns_push_type),
+                    // Calls are only worth delivering immediately.
+                    apns_expiration: Some(0),
+                    ..Default::default()
+                },
+            )
+        }
+    };
+    for (key, value) in &template.data {
+        payload.add_custom_data(key.as_str(), value)?;
+    }
+    // Client app reports the ID back in a delivery receipt.
+    payload.add_custom_data("notification_id", &apns_id)?;
+    let limit = if push_type == ApnsPushType::Voip {
+        MAX_APNS_VOIP_PAYLOAD_SIZE
+    } else {
+        MAX_APNS_PAYLOAD_SIZE
+    };
+    let size = payload.to_json_string()?.len();
+    if size > limit {
+        warn!("APNS payload for notification {id} has {size} bytes, trimming it to {limit} bytes.");
+        state.metrics().apns_oversized_payloads_total.inc();
+        let size = trim_apns_payload(&mut payload, limit)?;
+        if size > limit {
+            warn!("Cannot send notification {id} because APNS payload has {size} bytes after trimming.");
+            state.metrics().count_failure(FailureLabels::new(
+                NotificationProvider::APNS,
+                "payload_too_large",
+                "",
+            ));
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    debug!(
+        "Sending APNS notification {id} to {}: priority={:?} push_type={:?} topic={:?} collapse_id={:?}, payload size {} bytes.",
+        redact(&device_token),
+        payload.options.apns_priority,
+        payload.options.apns_push_type,
+        payload.options.apns_topic,
+        payload.options.apns_collapse_id.as_ref().map(|id| id.value),
+        payload.to_json_string().map(|s| s.len()).unwrap_or_default()
+    );
+
+    let fallback = fallback_client.map(|client| (client, payload.clone()));
+    let res = send_apns_request(&client, payload, state.metrics()).await;
+    match res {
+        Ok(_) => {
+            debug!("delivered notification for {}", redact(&device_token));
+            state.metrics().direct_notifications_total.inc();
+            Ok(StatusCode::OK)
+        }
+        Err(ResponseError(res)) => {
+            // Counted once it is known whether the token is removed.
+            let mut failure = FailureLabels::new(
+                NotificationProvider::APNS,
+                res.code.to_string(),
+                res.error
+                    .as_ref()
+                    .map(|e| e.reason.to_string())
+                    .unwrap_or_default(),
+            )
+            .with_status(res.code);
+
+            let reason = res
+                .error
+                .as_ref()
+                .map_or_else(|| res.code.to_string(), |e| e.reason.to_string());
+            let bad_token = if let Some(err) = res.error {
+                err.reason == ErrorReason::BadDeviceToken
+            } else {
+                false
+            };
+
+            if let Some((fallback_client, payload)) = fallback.filter(|_| bad_token) {
+                // Token may have been registered without the `sandbox:` prefix.
+                let res = send_apns_request(&fallback_client, payload, state.metrics()).await;
+                if res.is_ok() {
+                    info!(
+                        "Delivered notification {id} to {} via APNS sandbox fallback.",
+                        redact(&device_token)
+                    );
+                    state.metrics().count_failure(failure);
+                    state.metrics().direct_notifications_total.inc();
+                    return Ok(StatusCode::OK);
+                }
+            }
+
+            if res.code == 410 || bad_token {
+                // 410 means that "The device token is no longer active for the topic."
+                // <https://developer.apple.com/documentation/usernotifications/handling-notification-responses-from-apns>
+                //
+                // Unsubscribe invalid token from heartbeat notification if it is subscribed.
+                match schedule.remove_stored_token(&device_token) {
+                    Err(err) => {
+                        state.metrics().count_failure(failure);
+                        error!("failed to remove {}: {:?}", redact(&device_token), err);
+                    }
+                    Ok((stored, provenance)) => {
+                        failure.token_removed = stored;
+                        state.metrics().count_failure(failure);
+                        info!(provider = "apns", status = res.code; "Removed token {} due to error {}.", redact(&device_token), reason);
+                        state.callbacks().token_removed(
+                            &device_token,
+                            provenance,
+                            &reason,
+                            state.metrics(),
+                        );
+                    }
+                }
+                // Return 410 Gone response so email server can remove the token.
+                Ok(StatusCode::GONE)
+            } else {
+                warn!(provider = "apns", status = res.code; "Failed to deliver notification {id} to {} with status {}: {}, keeping the token.", redact(&device_token), res.code, reason);
+                state.metrics().count_failure(failure);
+                Ok(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+        Err(err) => {
+            error!(provider = "apns"; "failed to send notification: {}, {:?}", redact(&device_token), err);
+            state.metrics().count_failure(FailureLabels::new(
+                NotificationProvider::APNS,
+                "send",
+                "",
+            ));
+            Ok(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+struct FcmProvider;
+
+#[async_trait]
+impl Provider for FcmProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::FCM
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::Fcm {
+            package_name,
+            token,
+        } = token
+        else {
+            bail!("Not an FCM token");
+        };
+        let metrics = state.metrics();
+        let project = state.fcm_projects().get(&package_name);
+        let Ok(fcm_token) = project.access_token().await else {
+            metrics.count_failure(FailureLabels::new(
+                NotificationProvider::FCM,
+                "api_key_fetch",
+                "",
+            ));
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into());
+        };
+        let status = notify_fcm(
+            state.fcm_endpoints(),
+            fcm_token.as_deref(),
+            project.project_id(),
+            &token,
+            id,
+            &state
+                .templates()
+                .render(NotificationProvider::FCM, kind, id),
+            metrics,
+        )
+        .await?;
+        Ok(status.into())
+    }
+}
+
+/// Notifies a single FCM token.
+///
+/// API documentation is available at
+/// <https://firebase.google.com/docs/cloud-messaging/send-message#rest>
+async fn notify_fcm(
+    endpoints: &Endpoints,
+    fcm_api_key: Option<&str>,
+    project_id: &str,
+    token: &str,
+    id: Uuid,
+    template: &Template,
+    metrics: &Metrics,
+) -> Result<StatusCode> {
+    let Some(fcm_api_key) = fcm_api_key else {
+        warn!("Cannot notify FCM because key for project {project_id} is not set");
+        metrics.count_failure(FailureLabels::new(
+            NotificationProvider::FCM,
+            "no_api_key",
+            "",
+        ));
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    if !token
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
+    {
+        return Ok(StatusCode::GONE);
+    }
+
+    let mut data = template.data.clone();
+    data.insert("notification_id".to_string(), id.to_string());
+    let mut message = serde_json::json!({
+        "token": token,
+        "data": data,
+        "android": {"priority": "high"},
+    });
+    if template.title.is_some() || template.body.is_some() {
+        message["notification"] = serde_json::json!({
+            "title": template.title,
+            "body": template.body,
+        });
+    }
+    let body = serde_json::json!({ "message": message }).to_string();
+    debug!(
+        "Sending FCM notification to {} with high priority, payload size {} bytes.",
+        redact(token),
+        body.len()
+    );
+    let mut candidates = endpoints.candidates(Instant::now()).into_iter().peekable();
+    let (endpoint, res, start) = loop {
+        let endpoint = candidates.next().context("No FCM endpoints")?;
+        let url = format!("{}/v1/projects/{project_id}/messages:send", endpoint.url());
+        let inflight = metrics.inflight_request(NotificationProvider::FCM);
+        let start = Instant::now();
+        let res = retry::send(
+            endpoint
+                .client()
+                .post(url)
+                .body(body.clone())
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {fcm_api_key}")),
+            metrics,
+            NotificationProvider::FCM,
+        )
+        .await;
+        inflight.finish(outage::is_response_ok(&res));
+        metrics.observe_request_duration(
+            NotificationProvider::FCM,
+            endpoint.region(),
+            metrics::http_outcome(&res),
+            start.elapsed(),
+        );
+        let failed = res
+            .as_ref()
+            .map_or(true, |res| res.status().is_server_error());
+        if failed {
+            endpoint.mark_failed(Instant::now());
+            if candidates.peek().is_some() {
+                warn!(provider = "fcm"; "FCM endpoint in region {} failed, trying the next one.", endpoint.region());
+                continue;
+            }
+        }
+        break (endpoint, res, start);
+    };
+    let res = res.map_err(|e| {
+        warn!(provider = "fcm"; "Failed to send FCM notification to {}: {e}", redact(token));
+        metrics.count_failure(FailureLabels::new(NotificationProvider::FCM, "send", ""));
+        e
+    })?;
+    let status = res.status();
+    debug!(
+        "FCM endpoint in region {} responded with {status} in {:?}.",
+        endpoint.region(),
+        start.elapsed()
+    );
+    if status.is_client_error() {
+        warn!(provider = "fcm", status = status.as_u16(); "Failed to deliver FCM notification to {}", redact(token));
+        if log_full_tokens() {
+            // Body contains the token.
+            warn!("BODY: {body:?}");
+        }
+        warn!("RES: {res:?}");
+        metrics.count_failure(
+            FailureLabels::new(NotificationProvider::FCM, status.as_u16().to_string(), "")
+                .with_status(status.as_u16()),
+        );
+        return Ok(StatusCode::GONE);
+    }
+    if status.is_server_error() {
+        warn!(provider = "fcm", status = status.as_u16(); "Internal server error while attempting to deliver FCM notification to {}", redact(token));
+        metrics.count_failure(
+            FailureLabels::new(NotificationProvider::FCM, status.as_u16().to_string(), "")
+                .with_status(status.as_u16()),
+        );
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    debug!("Delivered notification to FCM token {}", redact(token));
+    metrics.fcm_notifications_total.inc();
+    metrics
+        .fcm_project_notifications_total
+        .get_or_create(&FcmProjectLabels {
+            project: project_id.to_string(),
+        })
+        .inc();
+    Ok(StatusCode::OK)
+}
+
+struct UbportsProvider;
+
+#[async_trait]
+impl Provider for UbportsProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::UBports
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::UBports(token) = token else {
+            bail!("Not a UBports token");
+        };
+        let template = state
+            .templates()
+            .render(NotificationProvider::UBports, kind, id);
+        let status =
+            notify_ubports(state.http_client(), &token, &template, state.metrics()).await?;
+        Ok(status.into())
+    }
+}
+
+/// Notify the UBports push server
+///
+/// API documentation is available at
+/// <https://docs.ubports.com/en/latest/appdev/guides/pushnotifications.html>
+async fn notify_ubports(
+    client: &reqwest::Client,
+    token: &str,
+    template: &Template,
+    metrics: &Metrics,
+) -> Result<StatusCode> {
+    if !token
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
+    {
+        return Ok(StatusCode::GONE);
+    }
+
+    let url = "https://push.ubports.com/notify";
+    let expire_on = (Local::now() + TimeDelta::weeks(1)).to_rfc3339();
+    let mut data = serde_json::json!({
+        "notification": {
+            "tag": "sent_by_chatmail_server",
+            "card": {
+                "popup": true,
+                "persist": true,
+                "summary": template.title.as_deref().unwrap_or_default(),
+                "body": template.body.as_deref().unwrap_or_default(),
+            },
+            "sound": true,
+            "vibrate": {"pattern": [200], "duration": 200, "repeat": 1},
+        },
+        "sent-by": "Chatmail Server",
+    });
+    for (key, value) in &template.data {
+        data[key] = value.as_str().into();
+    }
+    let body = serde_json::json!({
+        "expire_on": expire_on,
+        "appid": "deltatouch.lotharketterer_deltatouch",
+        "token": token,
+        "data": data,
+    })
+    .to_string();
+    debug!(
+        "Sending UBports notification to {}, payload size {} bytes.",
+        redact(token),
+        body.len()
+    );
+    let inflight = metrics.inflight_request(NotificationProvider::UBports);
+    let start = Instant::now();
+    let res = retry::send(
+        client
+            .post(url)
+            .body(body.clone())
+            .header("Content-Type", "application/json"),
+        metrics,
+        NotificationProvider::UBports,
+    )
+    .await
+    .map_err(|e| {
+            warn!(provider = "ubports"; "Failed to send UBports notification to {}: {e}", redact(token));
+            metrics.count_failure(FailureLabels::new(NotificationProvider::UBports, "send", ""));
+            e
+        })?;
+    inflight.finish(!res.status().is_server_error());
+    let status = res.status();
+    debug!(
+        "UBports push server responded with {status} in {:?}.",
+        start.elapsed()
+    );
+    if status.is_client_error() {
+        warn!(provider = "ubports", status = status.as_u16(); "Failed to deliver UBports notification to {}", redact(token));
+        if log_full_tokens() {
+            // Body contains the token.
+            warn!("BODY: {body:?}");
+        }
+        warn!("RES: {res:?}");
+        metrics.count_failure(
+            FailureLabels::new(
+                NotificationProvider::UBports,
+                status.as_u16().to_string(),
+                "",
+            )
+            .with_status(status.as_u16()),
+        );
+        return Ok(StatusCode::GONE);
+    }
+    if status.is_server_error() {
+        warn!(
+            provider = "ubports", status = status.as_u16();
+            "Internal server error while attempting to deliver UBports notification to {}",
+            redact(token)
+        );
+        metrics.count_failure(
+            FailureLabels::new(
+                NotificationProvider::UBports,
+                status.as_u16().to_string(),
+                "",
+            )
+            .with_status(status.as_u16()),
+        );
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    debug!("Delivered notification to UBports token {}", redact(token));
+    metrics.ubports_notifications_total.inc();
+    Ok(StatusCode::OK)
+}
+
+struct WebPushProvider;
+
+#[async_trait]
+impl Provider for WebPushProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::WebPush
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        _id: Uuid,
+        token: NotificationToken,
+        _kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::WebPush {
+            endpoint,
+            ua_public_key,
+            ua_auth,
+        } = token
+        else {
+            bail!("Not a Web Push token");
+        };
+        let status = notify_webpush(
+            state.public_http_client(),
+            state.vapid_key(),
+            state.vapid_subject(),
+            &endpoint,
+            &ua_public_key,
+            &ua_auth,
+            state.metrics(),
+        )
+        .await?;
+        Ok(status.into())
+    }
+}
+
+/// Notify Web Push endpoint
+///
+/// Defined by 3 RFC:
+/// - Server to Server API in [RFC8030](https://www.rfc-editor.org/rfc/rfc8030)
+/// - Encryption in [RFC8291](https://www.rfc-editor.org/rfc/rfc8291)
+/// - Authorization in [RFC8292](https://www.rfc-editor.org/rfc/rfc8292) (VAPID)
+async fn notify_webpush(
+    client: &reqwest::Client,
+    vapid_key: &Option<ES256KeyPair>,
+    vapid_subject: &str,
+    endpoint: &str,
+    ua_public: &str,
+    ua_auth: &str,
+    metrics: &Metrics,
+) -> Result<StatusCode> {
+    let Some(vapid_key) = vapid_key else {
+        warn!("Cannot notify Web Push because VAPID key is not set");
+        metrics.count_failure(FailureLabels::new(
+            NotificationProvider::WebPush,
+            "no_vapid_key",
+            "",
+        ));
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let request = WebPushBuilder::new(
+        endpoint.parse()?,
+        p256::PublicKey::from_sec1_bytes(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(ua_public)?,
+        )?,
+        Auth::clone_from_slice(&base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(ua_auth)?),
+    )
+    .with_vapid(vapid_key, vapid_subject)
+    .build("ping")?;
+
+    debug!(
+        "Sending Web Push notification to {}, encrypted payload size {} bytes.",
+        redact(endpoint),
+        request.body().len()
+    );
+    let inflight = metrics.inflight_request(NotificationProvider::WebPush);
+    let start = Instant::now();
+    let res = retry::send(
+        client
+            .post(endpoint)
+            .headers(request.headers().clone())
+            .body(request.into_body()),
+        metrics,
+        NotificationProvider::WebPush,
+    )
+    .await
+    .map_err(|e| {
+            // Endpoint URL is a pushable identifier.
+            let e = e.without_url();
+            warn!(provider = "webpush"; "Failed to send web push notification to {}: {e}", redact(endpoint));
+            metrics.count_failure(FailureLabels::new(NotificationProvider::WebPush, "send", ""));
+            e
+        })?;
+    inflight.finish(!res.status().is_server_error());
+
+    let status = res.status();
+    debug!(
+        "Web Push endpoint responded with {status} in {:?}.",
+        start.elapsed()
+    );
+    // Map web push responses to chatmail/relay notifier values
+    match status.as_u16() {
+        201 => {
+            metrics.webpush_notifications_total.inc();
+            Ok(StatusCode::OK)
+        }
+        _ if status.is_client_error() => {
+            metrics.count_failure(
+                FailureLabels::new(
+                    NotificationProvider::WebPush,
+                    status.as_u16().to_string(),
+                    "",
+                )
+                .with_status(status.as_u16()),
+            );
+            Ok(StatusCode::GONE)
+        }
+        _ if status.is_server_error() => {
+            metrics.count_failure(
+                FailureLabels::new(
+                    NotificationProvider::WebPush,
+                    status.as_u16().to_string(),
+                    "",
+                )
+                .with_status(status.as_u16()),
+            );
+            Ok(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        _ => Ok(status),
+    }
+}
+
+struct OneSignalProvider;
+
+#[async_trait]
+impl Provider for OneSignalProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::OneSignal
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::OneSignal { app_id, player_id } = token else {
+            bail!("Not a OneSignal token");
+        };
+        let template = state
+            .templates()
+            .render(NotificationProvider::OneSignal, kind, id);
+        let status = state
+            .onesignal()
+            .notify(&app_id, &player_id, &template, state.metrics())
+            .await?;
+        Ok(status.into())
+    }
+}
+
+struct VivoProvider;
+
+#[async_trait]
+impl Provider for VivoProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::Vivo
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::Vivo(token) = token else {
+            bail!("Not a vivo token");
+        };
+        let template = state
+            .templates()
+            .render(NotificationProvider::Vivo, kind, id);
+        let status = state
+            .vivo()
+            .notify(&token, &template, state.metrics())
+            .await?;
+        Ok(status.into())
+    }
+}
+
+struct OppoProvider;
+
+#[async_trait]
+impl Provider for OppoProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::OPPO
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::Oppo(token) = token else {
+            bail!("Not an OPPO token");
+        };
+        let template = state
+            .templates()
+            .render(NotificationProvider::OPPO, kind, id);
+        let status = state
+            .oppo()
+            .notify(&token, &template, state.metrics())
+            .await?;
+        Ok(status.into())
+    }
+}
+
+struct UnifiedPushProvider;
+
+#[async_trait]
+impl Provider for UnifiedPushProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::UnifiedPush
+    }
+
+    fn supports_heartbeats(&self, _token: &NotificationToken) -> bool {
+        true
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        _id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::UnifiedPush(endpoint) = token else {
+            bail!("Not a UnifiedPush token");
+        };
+        unifiedpush::notify(state.public_http_client(), &endpoint, kind, state.metrics()).await
+    }
+}
+
+struct WebhookProvider;
+
+#[async_trait]
+impl Provider for WebhookProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::Webhook
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        id: Uuid,
+        token: NotificationToken,
+        _kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::Webhook(url) = token else {
+            bail!("Not a webhook token");
+        };
+        let status = state.webhooks().notify(&url, id, state.metrics()).await?;
+        Ok(status.into())
+    }
+}
+
+struct WnsProvider;
+
+#[async_trait]
+impl Provider for WnsProvider {
+    fn label(&self) -> NotificationProvider {
+        NotificationProvider::WNS
+    }
+
+    fn supports_heartbeats(&self, _token: &NotificationToken) -> bool {
+        true
+    }
+
+    async fn send(
+        &self,
+        state: &State,
+        _id: Uuid,
+        token: NotificationToken,
+        kind: NotificationKind,
+        _options: &NotifyOptions,
+    ) -> Result<Delivery> {
+        let NotificationToken::Wns(channel_uri) = token else {
+            bail!("Not a WNS token");
+        };
+        state
+            .wns()
+            .notify(&channel_uri, kind, state.metrics())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let providers = Providers::new(None, false).unwrap();
+        for label in NotificationProvider::ALL {
+            assert_eq!(providers.get(label).label(), label);
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let providers = Providers::new(None, false).unwrap();
+        let classify = |label, status: StatusCode| providers.get(label).classify(&status.into());
+
+        let up = NotificationProvider::UnifiedPush;
+        assert_eq!(classify(up, StatusCode::CREATED), Disposition::Delivered);
+        assert_eq!(classify(up, StatusCode::GONE), Disposition::Gone);
+        assert_eq!(
+            classify(up, StatusCode::TOO_MANY_REQUESTS),
+            Disposition::Retry
+        );
+        assert_eq!(classify(up, StatusCode::BAD_GATEWAY), Disposition::Retry);
+        assert_eq!(classify(up, StatusCode::FORBIDDEN), Disposition::Fatal);
+
+        let apns = NotificationProvider::APNS;
+        assert_eq!(classify(apns, StatusCode::OK), Disposition::Delivered);
+        assert_eq!(classify(apns, StatusCode::BAD_REQUEST), Disposition::Fatal);
+        assert_eq!(classify(apns, StatusCode::GONE), Disposition::Gone);
+        assert_eq!(
+            classify(apns, StatusCode::SERVICE_UNAVAILABLE),
+            Disposition::Retry
+        );
+    }
+
+    #[test]
+    fn test_classify_apns_heartbeat() {
+        let providers = Providers::new(None, false).unwrap();
+        let apns = providers.get(NotificationProvider::APNS);
+        let classify = |status: u16, reason: &str| {
+            apns.classify(&Delivery {
+                status: StatusCode::from_u16(status).unwrap(),
+                reason: Some(reason.to_string()),
+                failure: None,
+            })
+        };
+
+        // Rejected tokens are removed.
+        assert_eq!(classify(400, "BadDeviceToken"), Disposition::Gone);
+        assert_eq!(classify(400, "DeviceTokenNotForTopic"), Disposition::Gone);
+        assert_eq!(classify(410, "Unregistered"), Disposition::Gone);
+
+        // Credential problems of the gateway keep the token.
+        assert_eq!(classify(403, "ExpiredProviderToken"), Disposition::Fatal);
+        assert_eq!(classify(403, "InvalidProviderToken"), Disposition::Fatal);
+        assert_eq!(
+            classify(403, "BadCertificateEnvironment"),
+            Disposition::Fatal
+        );
+        assert_eq!(classify(400, "TopicDisallowed"), Disposition::Fatal);
+
+        // Throttled and failed heartbeats are rescheduled.
+        assert_eq!(classify(429, "TooManyRequests"), Disposition::Retry);
+        assert_eq!(classify(500, "InternalServerError"), Disposition::Retry);
+        assert_eq!(classify(503, "ServiceUnavailable"), Disposition::Retry);
+        assert_eq!(classify(500, "send"), Disposition::Retry);
+    }
+
+    #[test]
+    fn test_heartbeat_payload() {
+        let payload = heartbeat_payload("token", Some("chat.delta"));
+        assert!(matches!(
+            payload.options.apns_priority,
+            Some(Priority::Normal)
+        ));
+        assert_eq!(payload.options.apns_push_type, Some(PushType::Background));
+        assert_eq!(payload.options.apns_topic, Some("chat.delta"));
+        assert_eq!(payload.aps.content_available, Some(1));
+        assert!(payload.aps.alert.is_none());
+        assert!(payload.aps.badge.is_none());
+        assert!(payload.aps.sound.is_none());
+    }
+
+    #[test]
+    fn test_trim_apns_payload() -> Result<()> {
+        let mut payload = DefaultNotificationBuilder::new()
+            .body("You have new messages")
+            .sound("default")
+            .build("token", Default::default());
+        payload.add_custom_data("padding", &"x".repeat(MAX_APNS_PAYLOAD_SIZE))?;
+        let size = trim_apns_payload(&mut payload, MAX_APNS_PAYLOAD_SIZE)?;
+        assert!(size <= MAX_APNS_PAYLOAD_SIZE);
+        assert!(payload.data.is_empty());
+        assert!(payload.aps.sound.is_some());
+
+        // Required fields are not trimmed.
+        let long_body = "x".repeat(MAX_APNS_PAYLOAD_SIZE);
+        let mut payload = DefaultNotificationBuilder::new()
+            .body(&long_body)
+            .sound("default")
+            .build("token", Default::default());
+        let size = trim_apns_payload(&mut payload, MAX_APNS_PAYLOAD_SIZE)?;
+        assert!(size > MAX_APNS_PAYLOAD_SIZE);
+        assert!(payload.aps.sound.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! # Trusted reverse proxies.
+//!
+//! Behind a reverse proxy all requests come from the proxy address,
+//! so per-IP rate limits would apply to all clients together.
+//! For connections from a trusted proxy
+//! the client address is taken from the `X-Forwarded-For` header instead.
+//! The header is only honored for trusted proxies
+//! because clients can set it to anything.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::{bail, Context as _, Error, Result};
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+
+use crate::state::State;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// IP network written as an address with an optional prefix length,
+/// e.g. `10.0.0.0/8` or `::1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let prefix = usize::from(prefix);
+    let (bytes, bits) = (prefix / 8, prefix % 8);
+    if net[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Invalid IP address {addr:?}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().context("Invalid prefix length")?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            bail!("Prefix length {prefix} is too long for {addr}");
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Networks of reverse proxies allowed to set `X-Forwarded-For`.
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<Network>) -> Self {
+        Self { networks }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// Returns the address of the client that made the request.
+    ///
+    /// For connections from trusted proxies this is the rightmost address
+    /// in `X-Forwarded-For` not belonging to a trusted proxy.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        if !self.is_trusted(client) {
+            return client;
+        }
+        let hops: Vec<&str> = headers
+            .get_all(FORWARDED_FOR_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for hop in hops.into_iter().rev() {
+            let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+                // Addresses added before a malformed entry cannot be trusted.
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Extractor of the client address
+/// resolved with [`TrustedProxies::client_ip`].
+pub(crate) struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<State> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &State) -> Result<Self, StatusCode> {
+        let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        Ok(Self(
+            state.trusted_proxies().client_ip(peer.ip(), &parts.headers),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network() -> Result<()> {
+        let network: Network = "10.0.0.0/8".parse()?;
+        assert!(network.contains("10.1.2.3".parse()?));
+        assert!(network.contains("::ffff:10.1.2.3".parse()?));
+        assert!(!network.contains("11.0.0.1".parse()?));
+        assert!(!network.contains("::1".parse()?));
+
+        let network: Network = "172.16.0.0/12".parse()?;
+        assert!(network.contains("172.31.255.255".parse()?));
+        assert!(!network.contains("172.32.0.0".parse()?));
+
+        let network: Network = "::1".parse()?;
+        assert_eq!(network.to_string(), "::1/128");
+        assert!(network.contains("::1".parse()?));
+        assert!(!network.contains("::2".parse()?));
+
+        assert!("0.0.0.0/0".parse::<Network>()?.contains("1.2.3.4".parse()?));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("localhost".parse::<Network>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_ip() -> Result<()> {
+        let proxies = TrustedProxies::new(vec!["127.0.0.1".parse()?, "10.0.0.0/8".parse()?]);
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR_HEADER, "1.1.1.1, 2.2.2.2, 10.0.0.2".parse()?);
+
+        // Client is the rightmost untrusted hop.
+        assert_eq!(
+            proxies.client_ip("127.0.0.1".parse()?, &headers),
+            "2.2.2.2".parse::<IpAddr>()?
+        );
+
+        // Header is ignored for untrusted peers.
+        assert_eq!(
+            proxies.client_ip("3.3.3.3".parse()?, &headers),
+            "3.3.3.3".parse::<IpAddr>()?
+        );
+        assert_eq!(
+            TrustedProxies::default().client_ip("127.0.0.1".parse()?, &headers),
+            "127.0.0.1".parse::<IpAddr>()?
+        );
+
+        // Malformed entries end the search.
+        headers.insert(FORWARDED_FOR_HEADER, "1.1.1.1, garbage, 10.0.0.2".parse()?);
+        assert_eq!(
+            proxies.client_ip("127.0.0.1".parse()?, &headers),
+            "10.0.0.2".parse::<IpAddr>()?
+        );
+
+        // Without the header the proxy itself is the client.
+        assert_eq!(
+            proxies.client_ip("127.0.0.1".parse()?, &HeaderMap::new()),
+            "127.0.0.1".parse::<IpAddr>()?
+        );
+        Ok(())
+    }
+}
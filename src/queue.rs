@@ -0,0 +1,745 @@
+//! # Background delivery queue.
+//!
+//! Relays sending `Prefer: respond-async` with `/notify` requests
+//! get a `202 Accepted` response with a notification ID immediately,
+//! and the notification is delivered by a pool of background workers.
+//!
+//! Deliveries failing with a transient error are retried
+//! up to `--retry-attempts` times with exponential backoff and jitter
+//! before the notification is dead-lettered.
+//! With `--retry-transient-failures`, synchronous notifications
+//! failing with a transient error are handed to the queue for retries as well.
+//! The state of each notification can be polled
+//! with `GET /notifications/<id>` for [`STATUS_RETENTION`].
+//!
+//! At most one notification per token waits for delivery.
+//! A notification queued for a token that already has one waiting
+//! replaces it, so devices get a single alert
+//! instead of a stack of them after an outage.
+//!
+//! When the gateway is shutting down,
+//! workers deliver the notifications left in the queue and exit.
+//!
+//! Notifications are written to a journal in the database
+//! before they are acknowledged and removed from it once they are finished.
+//! Notifications still pending when the gateway stops,
+//! including those waiting for a retry,
+//! are queued again on the next start.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use axum::http::StatusCode;
+use log::*;
+use parking_lot::Mutex;
+use rand::Rng as _;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::metrics::ProviderLabels;
+use crate::provider::Disposition;
+use crate::schedule::storage::Tree;
+use crate::server::{self, NotificationToken, NotifyOptions};
+use crate::state::State;
+use crate::supervisor::{self, Heartbeat};
+
+/// Delay before the first retry, doubled for each following one.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Maximum number of doublings of [`RETRY_DELAY`].
+const MAX_BACKOFF_DOUBLINGS: u32 = 8;
+
+/// How failed deliveries are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Maximum number of delivery attempts of a notification.
+    pub max_attempts: u32,
+
+    /// Whether synchronous notifications failing with a transient error
+    /// are queued for retries.
+    pub retry_sync: bool,
+}
+
+/// Time for which the state of a finished notification is kept.
+const STATUS_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// Number of statuses after which finished notifications are pruned.
+const MAX_STATUSES: usize = 100_000;
+
+/// Notification accepted for background delivery.
+pub(crate) struct Job {
+    pub(crate) id: Uuid,
+
+    /// Value of the `relay` metrics label.
+    pub(crate) relay: String,
+
+    pub(crate) token: NotificationToken,
+
+    pub(crate) options: NotifyOptions,
+
+    /// Number of failed delivery attempts so far.
+    pub(crate) attempts: u32,
+}
+
+/// Delivery state of a queued notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeliveryState {
+    /// Waiting for a worker.
+    Queued,
+
+    /// Previous attempt failed, waiting for the next one.
+    Retrying,
+
+    /// Accepted by the provider.
+    Delivered,
+
+    /// Rejected by the provider, e.g. because the token is invalid.
+    Failed,
+
+    /// All delivery attempts failed.
+    DeadLettered,
+
+    /// Replaced by a newer notification to the same token.
+    Coalesced,
+}
+
+/// Status returned by `GET /notifications/<id>`.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DeliveryStatus {
+    pub(crate) id: Uuid,
+
+    pub(crate) state: DeliveryState,
+
+    /// Number of finished delivery attempts.
+    pub(crate) attempts: u32,
+
+    /// HTTP status code of the last attempt.
+    pub(crate) status: Option<u16>,
+
+    /// Value of the `relay` metrics label of the caller.
+    #[serde(skip)]
+    pub(crate) relay: String,
+
+    #[serde(skip)]
+    updated: Option<Instant>,
+}
+
+impl DeliveryStatus {
+    fn is_finished(&self) -> bool {
+        !matches!(self.state, DeliveryState::Queued | DeliveryState::Retrying)
+    }
+
+    /// Returns true if the notification finished
+    /// longer than [`STATUS_RETENTION`] ago.
+    fn is_expired(&self, now: Instant) -> bool {
+        self.is_finished()
+            && self
+                .updated
+                .is_none_or(|updated| now.duration_since(updated) >= STATUS_RETENTION)
+    }
+}
+
+/// Journal entry of a pending notification.
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    relay: String,
+    token: String,
+    options: NotifyOptions,
+    attempts: u32,
+}
+
+/// Write-ahead journal of pending notifications,
+/// stored in a tree of the schedule database keyed by notification ID.
+pub(crate) struct Journal {
+    tree: Arc<dyn Tree>,
+}
+
+impl Journal {
+    pub(crate) fn new(tree: Arc<dyn Tree>) -> Self {
+        Self { tree }
+    }
+
+    /// Records the notification as pending.
+    pub(crate) fn record(&self, job: &Job) {
+        let entry = JournalEntry {
+            relay: job.relay.clone(),
+            token: job.token.to_string(),
+            options: job.options.clone(),
+            attempts: job.attempts,
+        };
+        let res = serde_json::to_vec(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|value| self.tree.insert(job.id.as_bytes(), &value));
+        if let Err(err) = res {
+            warn!("Failed to journal notification {}: {err:#}.", job.id);
+        }
+    }
+
+    /// Removes the finished notification.
+    pub(crate) fn remove(&self, id: Uuid) {
+        if let Err(err) = self.tree.remove(id.as_bytes()) {
+            warn!("Failed to remove notification {id} from journal: {err:#}.");
+        }
+    }
+
+    /// Writes the journal to disk.
+    pub(crate) async fn flush(&self) -> Result<()> {
+        self.tree.flush_async().await?;
+        Ok(())
+    }
+
+    /// Returns the pending notifications.
+    ///
+    /// Entries that cannot be read are removed.
+    fn load(&self) -> Result<Vec<Job>> {
+        let mut jobs = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            let job = Uuid::from_slice(&key)
+                .context("Invalid notification ID")
+                .and_then(|id| {
+                    let entry: JournalEntry = serde_json::from_slice(&value)?;
+                    Ok(Job {
+                        id,
+                        relay: entry.relay,
+                        token: entry.token.parse()?,
+                        options: entry.options,
+                        attempts: entry.attempts,
+                    })
+                });
+            match job {
+                Ok(job) => jobs.push(job),
+                Err(err) => {
+                    warn!("Dropping invalid journal entry: {err:#}.");
+                    self.tree.remove(&key)?;
+                }
+            }
+        }
+        Ok(jobs)
+    }
+}
+
+pub(crate) struct Queue {
+    sender: mpsc::Sender<Job>,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<Job>>,
+    capacity: usize,
+
+    /// Delivery status of recently accepted notifications.
+    statuses: Mutex<HashMap<Uuid, DeliveryStatus>>,
+
+    /// ID of the newest notification waiting for delivery by token.
+    pending: Mutex<HashMap<String, Uuid>>,
+
+    retry: RetryPolicy,
+
+    journal: Journal,
+}
+
+impl Queue {
+    pub(crate) fn new(capacity: usize, retry: RetryPolicy, journal: Journal) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        Self {
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver),
+            capacity,
+            statuses: Default::default(),
+            pending: Default::default(),
+            retry,
+            journal,
+        }
+    }
+
+    pub(crate) fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    /// Queues the notifications found in the journal.
+    pub(crate) fn replay(&self) -> Result<usize> {
+        let jobs = self.journal.load()?;
+        let mut replayed = 0;
+        for mut job in jobs {
+            // Relays do not know about retries of the previous run.
+            job.attempts = 0;
+            let id = job.id;
+            if self.push(job) {
+                replayed += 1;
+            } else {
+                warn!("Delivery queue is full, dropping pending notification {id}.");
+            }
+        }
+        Ok(replayed)
+    }
+
+    pub(crate) fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// Adds the job to the queue.
+    ///
+    /// Returns false if the queue is full.
+    pub(crate) fn push(&self, job: Job) -> bool {
+        let status = (job.attempts == 0).then(|| DeliveryStatus {
+            id: job.id,
+            state: DeliveryState::Queued,
+            attempts: 0,
+            status: None,
+            relay: job.relay.clone(),
+            updated: None,
+        });
+        let token = job.token.to_string();
+        let id = job.id;
+        self.journal.record(&job);
+        if self.sender.try_send(job).is_err() {
+            self.journal.remove(id);
+            return false;
+        }
+        if let Some(status) = status {
+            self.pending.lock().insert(token, id);
+            self.update_status(status);
+        }
+        true
+    }
+
+    /// Returns true if no newer notification to the same token was queued.
+    fn is_newest(&self, job: &Job) -> bool {
+        self.pending
+            .lock()
+            .get(&job.token.to_string())
+            .is_none_or(|id| *id == job.id)
+    }
+
+    /// Forgets the notification after it was finished.
+    fn finish(&self, id: Uuid, token: &NotificationToken) {
+        let mut pending = self.pending.lock();
+        let token = token.to_string();
+        if pending.get(&token) == Some(&id) {
+            pending.remove(&token);
+        }
+    }
+
+    /// Returns the number of notifications queued, being delivered
+    /// or waiting for a retry.
+    pub(crate) fn pending_len(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    /// Waits until no notifications are pending.
+    pub(crate) async fn drained(&self) {
+        while self.pending_len() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Returns the number of queued jobs.
+    pub(crate) fn len(&self) -> usize {
+        self.capacity - self.sender.capacity()
+    }
+
+    async fn pop(&self) -> Option<Job> {
+        self.receiver.lock().await.recv().await
+    }
+
+    /// Returns the next job if one is queued without waiting for it.
+    async fn try_pop(&self) -> Option<Job> {
+        self.receiver.lock().await.try_recv().ok()
+    }
+
+    /// Returns the status of a notification accepted from `relay`.
+    pub(crate) fn status(&self, id: Uuid, relay: &str) -> Option<DeliveryStatus> {
+        self.statuses
+            .lock()
+            .get(&id)
+            .filter(|status| status.relay == relay)
+            .cloned()
+    }
+
+    /// Drops expired statuses and releases unused memory.
+    ///
+    /// Statuses of recently finished notifications are kept
+    /// so they can still be queried.
+    pub(crate) fn shrink(&self, now: Instant) {
+        let mut statuses = self.statuses.lock();
+        statuses.retain(|_, status| !status.is_expired(now));
+        statuses.shrink_to_fit();
+        self.pending.lock().shrink_to_fit();
+    }
+
+    fn update_status(&self, mut status: DeliveryStatus) {
+        if status.is_finished() {
+            self.journal.remove(status.id);
+        }
+        let now = Instant::now();
+        status.updated = Some(now);
+        let mut statuses = self.statuses.lock();
+        if statuses.len() >= MAX_STATUSES {
+            statuses.retain(|_, status| !status.is_expired(now));
+        }
+        if statuses.len() >= MAX_STATUSES {
+            statuses.retain(|_, status| !status.is_finished());
+        }
+        statuses.insert(status.id, status);
+    }
+}
+
+/// Starts background delivery workers.
+///
+/// Returns handles completing once the workers have drained the queue
+/// after a shutdown.
+pub fn start(state: State, workers: usize, stall_timeout: Option<Duration>) -> Vec<JoinHandle<()>> {
+    (0..workers)
+        .map(|_| {
+            supervisor::spawn(
+                state.clone(),
+                "queue",
+                stall_timeout,
+                |state, heartbeat| async move {
+                    run_worker(state, heartbeat).await;
+                    Ok(())
+                },
+            )
+        })
+        .collect()
+}
+
+/// Waits for the next job.
+///
+/// Returns `None` once the gateway is shutting down and the queue is empty.
+async fn next_job(state: &State) -> Option<Job> {
+    tokio::select! {
+        job = state.queue().pop() => job,
+        _ = state.shutdown_requested() => state.queue().try_pop().await,
+    }
+}
+
+async fn run_worker(state: State, heartbeat: Heartbeat) {
+    while !heartbeat.is_cancelled() {
+        let Some(mut job) = next_job(&state).await else {
+            break;
+        };
+        state
+            .metrics()
+            .queued_notifications
+            .set(state.queue().len() as i64);
+        if !state.queue().is_newest(&job) {
+            debug!("Notification {} was replaced by a newer one.", job.id);
+            state.metrics().coalesced_notifications_total.inc();
+            state.queue().update_status(DeliveryStatus {
+                id: job.id,
+                state: DeliveryState::Coalesced,
+                attempts: job.attempts,
+                status: None,
+                relay: job.relay.clone(),
+                updated: None,
+            });
+            continue;
+        }
+        let busy = heartbeat.busy();
+        let res = tokio::select! {
+            res = server::deliver(&state, job.id, &job.relay, job.token.clone(), &job.options) => res,
+            _ = heartbeat.cancelled() => {
+                // Stalled, e.g. on a dead connection.
+                warn!("Requeueing notification {} of cancelled worker.", job.id);
+                retry_later(&state, job);
+                return;
+            }
+        };
+        drop(busy);
+        job.attempts += 1;
+        let status_code = match res {
+            Ok(status_code) => status_code,
+            Err(err) => {
+                warn!("Failed to deliver queued notification {}: {err:#}.", job.id);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        debug!(
+            "Attempt {} to deliver queued notification {} finished with {status_code}.",
+            job.attempts, job.id
+        );
+        let disposition = state
+            .providers()
+            .for_token(&job.token)
+            .classify(&status_code.into());
+        let delivery_state = match disposition {
+            Disposition::Delivered => DeliveryState::Delivered,
+            Disposition::Gone | Disposition::Fatal => DeliveryState::Failed,
+            Disposition::Retry if job.attempts < state.queue().retry.max_attempts => {
+                DeliveryState::Retrying
+            }
+            Disposition::Retry => {
+                warn!("Dead-lettering notification {}.", job.id);
+                count_dead_lettered(&state, &job.token);
+                DeliveryState::DeadLettered
+            }
+        };
+        state.queue().update_status(DeliveryStatus {
+            id: job.id,
+            state: delivery_state,
+            attempts: job.attempts,
+            status: Some(status_code.as_u16()),
+            relay: job.relay.clone(),
+            updated: None,
+        });
+        if delivery_state == DeliveryState::Retrying {
+            retry_later(&state, job);
+        } else {
+            state.queue().finish(job.id, &job.token);
+        }
+    }
+}
+
+/// Returns the delay before the next attempt
+/// after `attempts` failed ones.
+///
+/// The delay is doubled with each attempt
+/// and randomized by up to 50% in both directions,
+/// so notifications failing together are not retried together.
+fn backoff(attempts: u32) -> Duration {
+    let doublings = attempts.saturating_sub(1).min(MAX_BACKOFF_DOUBLINGS);
+    let delay = RETRY_DELAY * 2u32.pow(doublings);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+fn count_dead_lettered(state: &State, token: &NotificationToken) {
+    state
+        .metrics()
+        .dead_lettered_notifications_total
+        .get_or_create(&ProviderLabels {
+            provider: token.provider(),
+        })
+        .inc();
+}
+
+/// Queues the job again after the backoff delay.
+fn retry_later(state: &State, job: Job) {
+    state
+        .metrics()
+        .delivery_retries_total
+        .get_or_create(&ProviderLabels {
+            provider: job.token.provider(),
+        })
+        .inc();
+    let state = state.clone();
+    let delay = backoff(job.attempts);
+    debug!("Retrying notification {} in {delay:?}.", job.id);
+    tokio::task::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let id = job.id;
+        let relay = job.relay.clone();
+        let attempts = job.attempts;
+        let token = job.token.clone();
+        if !state.queue().push(job) {
+            warn!("Delivery queue is full, dead-lettering notification {id}.");
+            count_dead_lettered(&state, &token);
+            state.queue().finish(id, &token);
+            state.queue().update_status(DeliveryStatus {
+                id,
+                state: DeliveryState::DeadLettered,
+                attempts,
+                status: None,
+                relay,
+                updated: None,
+            });
+        }
+    });
+}
+
+/// Hands a synchronous notification whose first delivery attempt
+/// failed with `status` to the queue for retries.
+///
+/// Returns false if the notification is not retried,
+/// e.g. because another notification to the token is queued already.
+pub(crate) fn retry_failed(state: &State, mut job: Job, status: StatusCode) -> bool {
+    let queue = state.queue();
+    if queue.retry.max_attempts < 2 {
+        return false;
+    }
+    {
+        let mut pending = queue.pending.lock();
+        let token = job.token.to_string();
+        if pending.contains_key(&token) {
+            // The queued notification wakes up the device as well.
+            return false;
+        }
+        pending.insert(token, job.id);
+    }
+    job.attempts = 1;
+    queue.journal.record(&job);
+    queue.update_status(DeliveryStatus {
+        id: job.id,
+        state: DeliveryState::Retrying,
+        attempts: job.attempts,
+        status: Some(status.as_u16()),
+        relay: job.relay.clone(),
+        updated: None,
+    });
+    retry_later(state, job);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::storage::{MemoryStorage, Storage as _};
+
+    const RETRY_POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        retry_sync: false,
+    };
+
+    fn queue(capacity: usize) -> Queue {
+        let db = MemoryStorage::default();
+        let journal = Journal::new(db.open_tree("pending").unwrap());
+        Queue::new(capacity, RETRY_POLICY, journal)
+    }
+
+    fn job(relay: &str) -> Job {
+        Job {
+            id: Uuid::new_v4(),
+            relay: relay.to_string(),
+            token: NotificationToken::UBports("foo".to_string()),
+            options: NotifyOptions::default(),
+            attempts: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let queue = queue(2);
+        assert!(queue.push(job("anonymous")));
+        assert!(queue.push(job("anonymous")));
+        assert!(!queue.push(job("anonymous")));
+        assert_eq!(queue.len(), 2);
+
+        assert!(queue.pop().await.is_some());
+        assert_eq!(queue.len(), 1);
+
+        assert!(queue.try_pop().await.is_some());
+        assert!(queue.try_pop().await.is_none());
+    }
+
+    #[test]
+    fn test_status() {
+        let queue = queue(2);
+        let job = job("relay1");
+        let id = job.id;
+        assert!(queue.push(job));
+
+        let status = queue.status(id, "relay1").unwrap();
+        assert_eq!(status.state, DeliveryState::Queued);
+        assert_eq!(status.attempts, 0);
+
+        // Other relays cannot see the status.
+        assert!(queue.status(id, "relay2").is_none());
+        assert!(queue.status(Uuid::new_v4(), "relay1").is_none());
+    }
+
+    #[test]
+    fn test_shrink() {
+        let queue = queue(3);
+        let recent = job("relay1");
+        let recent_id = recent.id;
+        let old = job("relay1");
+        let old_id = old.id;
+        let pending = job("relay1");
+        let pending_id = pending.id;
+        assert!(queue.push(recent));
+        assert!(queue.push(old));
+        assert!(queue.push(pending));
+
+        let now = Instant::now();
+        let long_ago = now.checked_sub(2 * STATUS_RETENTION).unwrap();
+        {
+            let mut statuses = queue.statuses.lock();
+            let status = statuses.get_mut(&recent_id).unwrap();
+            status.state = DeliveryState::Delivered;
+            status.updated = Some(now);
+            let status = statuses.get_mut(&old_id).unwrap();
+            status.state = DeliveryState::Failed;
+            status.updated = Some(long_ago);
+            statuses.get_mut(&pending_id).unwrap().updated = Some(long_ago);
+        }
+
+        queue.shrink(now);
+        assert!(queue.status(recent_id, "relay1").is_some());
+        assert!(queue.status(old_id, "relay1").is_none());
+        assert!(queue.status(pending_id, "relay1").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_coalescing() {
+        let queue = queue(3);
+        let first = job("relay1");
+        let second = job("relay1");
+        let mut other = job("relay1");
+        other.token = NotificationToken::UBports("bar".to_string());
+        let (first_id, second_id) = (first.id, second.id);
+        assert!(queue.push(first));
+        assert!(queue.push(second));
+        assert!(queue.push(other));
+
+        // Only the newest notification to the token is delivered.
+        let job = queue.pop().await.unwrap();
+        assert_eq!(job.id, first_id);
+        assert!(!queue.is_newest(&job));
+        let job = queue.pop().await.unwrap();
+        assert_eq!(job.id, second_id);
+        assert!(queue.is_newest(&job));
+        let other = queue.pop().await.unwrap();
+        assert!(queue.is_newest(&other));
+
+        queue.finish(job.id, &job.token);
+        assert!(queue.pending.lock().get("ubports-foo").is_none());
+        assert!(queue.pending.lock().get("ubports-bar").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_replay() -> Result<()> {
+        let queue = queue(2);
+        let first = job("relay1");
+        let mut second = job("relay1");
+        second.token = NotificationToken::UBports("bar".to_string());
+        let first_id = first.id;
+        assert!(queue.push(first));
+        assert!(queue.push(second));
+        let job = queue.pop().await.unwrap();
+        queue.update_status(DeliveryStatus {
+            id: job.id,
+            state: DeliveryState::Delivered,
+            attempts: 1,
+            status: Some(200),
+            relay: job.relay.clone(),
+            updated: None,
+        });
+
+        // Only the unfinished notification is pending.
+        let jobs = queue.journal.load()?;
+        assert_eq!(jobs.len(), 1);
+        assert_ne!(jobs[0].id, first_id);
+        assert_eq!(jobs[0].token.to_string(), "ubports-bar");
+
+        queue.journal.tree.insert(b"garbage", b"garbage")?;
+        let replayed = Queue::new(2, RETRY_POLICY, Journal::new(queue.journal.tree.clone()));
+        assert_eq!(replayed.replay()?, 1);
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed.journal.tree.iter().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        for _ in 0..100 {
+            let first = backoff(1);
+            assert!(first >= RETRY_DELAY / 2 && first < RETRY_DELAY * 3 / 2);
+            let third = backoff(3);
+            assert!(third >= RETRY_DELAY * 2 && third < RETRY_DELAY * 6);
+        }
+        // Delay stops growing after a while.
+        assert!(backoff(u32::MAX) < RETRY_DELAY * 2u32.pow(MAX_BACKOFF_DOUBLINGS) * 3 / 2);
+    }
+}
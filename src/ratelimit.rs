@@ -0,0 +1,183 @@
+//! # Rate limiting.
+//!
+//! Token bucket rate limiter keyed by request source.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Error, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Maximum number of buckets kept before full buckets are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Rate limit configuration.
+///
+/// Parsed from strings like `10/1m`,
+/// meaning 10 requests in a burst, refilled over 1 minute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Maximum number of requests in a burst.
+    pub burst: u32,
+
+    /// Time to refill the whole bucket.
+    pub period: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (burst, period) = s
+            .split_once('/')
+            .context("Rate limit should have the form <requests>/<period>, e.g. 10/1m")?;
+        let burst: u32 = burst.trim().parse().context("Invalid number of requests")?;
+        let period = humantime::parse_duration(period.trim()).context("Invalid period")?;
+        anyhow::ensure!(burst > 0, "Number of requests should be positive");
+        anyhow::ensure!(!period.is_zero(), "Period should be positive");
+        Ok(Self { burst, period })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.burst,
+            humantime::format_duration(self.period)
+        )
+    }
+}
+
+impl Serialize for RateLimit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RateLimit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|err| serde::de::Error::custom(format!("{err:#}")))
+    }
+}
+
+struct Bucket {
+    /// Number of requests that can be made right now.
+    tokens: f64,
+
+    /// Time of the last refill.
+    updated: Instant,
+}
+
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`.
+    ///
+    /// Returns false if the request should be rejected.
+    pub(crate) fn check(&self, now: Instant, key: &str) -> bool {
+        self.check_n(now, key, 1)
+    }
+
+    /// Takes `n` tokens from the bucket of `key`,
+    /// e.g. one for each item of a batch request.
+    ///
+    /// Returns false and takes nothing if the bucket holds fewer tokens.
+    pub(crate) fn check_n(&self, now: Instant, key: &str, n: usize) -> bool {
+        let burst = f64::from(self.limit.burst);
+        let rate = burst / self.limit.period.as_secs_f64();
+
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // Buckets that are full again are equivalent to missing ones.
+            let period = self.limit.period;
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < period);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        let cost = n as f64;
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let limit: RateLimit = "10/1m".parse().unwrap();
+        assert_eq!(limit.burst, 10);
+        assert_eq!(limit.period, Duration::from_secs(60));
+
+        assert!("10".parse::<RateLimit>().is_err());
+        assert!("0/1m".parse::<RateLimit>().is_err());
+        assert!("10/0s".parse::<RateLimit>().is_err());
+
+        assert_eq!(limit.to_string().parse::<RateLimit>().unwrap(), limit);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut now = Instant::now();
+        let limiter = RateLimiter::new("2/10s".parse().unwrap());
+
+        assert!(limiter.check(now, "a"));
+        assert!(limiter.check(now, "a"));
+        assert!(!limiter.check(now, "a"));
+
+        // Other sources have their own buckets.
+        assert!(limiter.check(now, "b"));
+
+        // One request is refilled after 5 seconds.
+        now += Duration::from_secs(5);
+        assert!(limiter.check(now, "a"));
+        assert!(!limiter.check(now, "a"));
+
+        // Bucket never holds more than the burst.
+        now += Duration::from_secs(100);
+        assert!(limiter.check(now, "a"));
+        assert!(limiter.check(now, "a"));
+        assert!(!limiter.check(now, "a"));
+    }
+
+    #[test]
+    fn test_rate_limiter_batch() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new("10/10s".parse().unwrap());
+
+        assert!(limiter.check_n(now, "a", 8));
+        // Batches larger than the remaining tokens take nothing.
+        assert!(!limiter.check_n(now, "a", 3));
+        assert!(limiter.check_n(now, "a", 2));
+        assert!(!limiter.check(now, "a"));
+        assert!(!limiter.check_n(now, "b", 11));
+    }
+}
@@ -0,0 +1,145 @@
+//! # Client delivery receipts.
+//!
+//! APNS and FCM notifications carry their notification ID
+//! in the `notification_id` field of the payload.
+//! Client apps report displayed notifications
+//! with `POST /notifications/<id>/receipt`,
+//! so losses happening after the provider accepted the notification
+//! can be measured.
+//!
+//! Only receipts for notifications recently accepted by a provider are counted,
+//! and each notification is counted at most once.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use uuid::Uuid;
+
+use crate::metrics::NotificationProvider;
+
+/// Time during which a receipt for a delivered notification is accepted.
+const RECEIPT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum number of notifications awaiting a receipt.
+const MAX_PENDING: usize = 100_000;
+
+/// Notifications awaiting a receipt.
+#[derive(Default)]
+struct Pending {
+    by_id: HashMap<Uuid, (NotificationProvider, Instant)>,
+
+    /// Notification IDs ordered by the time of the delivery,
+    /// so the oldest ones are dropped without scanning all of them.
+    by_time: BTreeSet<(Instant, Uuid)>,
+}
+
+impl Pending {
+    fn remove(&mut self, id: Uuid) -> Option<(NotificationProvider, Instant)> {
+        let (provider, delivered) = self.by_id.remove(&id)?;
+        self.by_time.remove(&(delivered, id));
+        Some((provider, delivered))
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((_, id)) = self.by_time.pop_first() {
+            self.by_id.remove(&id);
+        }
+    }
+
+    /// Drops notifications delivered longer than [`RECEIPT_WINDOW`] ago.
+    fn expire(&mut self, now: Instant) {
+        while let Some((delivered, _)) = self.by_time.first() {
+            if now.saturating_duration_since(*delivered) < RECEIPT_WINDOW {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Receipts {
+    /// Notifications accepted by a provider and awaiting a receipt.
+    pending: Mutex<Pending>,
+}
+
+impl Receipts {
+    /// Records a notification accepted by the provider.
+    pub(crate) fn delivered(&self, now: Instant, id: Uuid, provider: NotificationProvider) {
+        let mut pending = self.pending.lock();
+        pending.remove(id);
+        pending.expire(now);
+        if pending.by_id.len() >= MAX_PENDING {
+            // Receipts are best-effort, drop the oldest notification.
+            pending.pop_oldest();
+        }
+        pending.by_id.insert(id, (provider, now));
+        pending.by_time.insert((now, id));
+    }
+
+    /// Drops expired notifications and releases unused memory.
+    pub(crate) fn shrink(&self, now: Instant) {
+        let mut pending = self.pending.lock();
+        pending.expire(now);
+        pending.by_id.shrink_to_fit();
+    }
+
+    /// Takes the receipt for the notification.
+    ///
+    /// Returns the provider of the notification
+    /// or `None` if the receipt is unknown, expired or duplicate.
+    pub(crate) fn receive(&self, now: Instant, id: Uuid) -> Option<NotificationProvider> {
+        let (provider, delivered) = self.pending.lock().remove(id)?;
+        (now.duration_since(delivered) < RECEIPT_WINDOW).then_some(provider)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipts() {
+        let receipts = Receipts::default();
+        let now = Instant::now();
+        let id = Uuid::new_v4();
+        receipts.delivered(now, id, NotificationProvider::APNS);
+
+        assert_eq!(receipts.receive(now, id), Some(NotificationProvider::APNS));
+
+        // Duplicate receipts are not counted.
+        assert_eq!(receipts.receive(now, id), None);
+        assert_eq!(receipts.receive(now, Uuid::new_v4()), None);
+
+        // Late receipts are not counted.
+        let id = Uuid::new_v4();
+        receipts.delivered(now, id, NotificationProvider::FCM);
+        assert_eq!(receipts.receive(now + RECEIPT_WINDOW, id), None);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let receipts = Receipts::default();
+        let now = Instant::now();
+        let ids: Vec<Uuid> = (0..=MAX_PENDING).map(|_| Uuid::new_v4()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            receipts.delivered(
+                now + Duration::from_millis(i as u64),
+                *id,
+                NotificationProvider::FCM,
+            );
+        }
+        assert_eq!(receipts.pending.lock().by_id.len(), MAX_PENDING);
+
+        let late = now + Duration::from_secs(60);
+        assert_eq!(receipts.receive(late, ids[0]), None);
+        assert_eq!(
+            receipts.receive(late, ids[1]),
+            Some(NotificationProvider::FCM)
+        );
+
+        receipts.shrink(now + RECEIPT_WINDOW + Duration::from_secs(200));
+        assert!(receipts.pending.lock().by_time.is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+//! # Retries after connection failures.
+//!
+//! Requests to providers are retried once after a short random delay
+//! if they never reached the provider:
+//! connecting failed, or the HTTP/2 stream was refused
+//! or not accepted before the connection was shut down.
+//!
+//! APNS requests are also retried once after a connection reset,
+//! even if APNS may have received them already.
+//! The retry carries the same `apns-id` and notification ID,
+//! so the notification stays idempotent.
+//! Other providers have no such identifier,
+//! so their requests are not retried after a reset
+//! to avoid pushing duplicates.
+
+use std::error::Error as _;
+use std::time::Duration;
+
+use log::*;
+use rand::Rng;
+
+use crate::metrics::{Metrics, NotificationProvider, ProviderLabels};
+use crate::state::ApnsClient;
+
+/// Waits for a random time before the retry.
+async fn jitter() {
+    let delay = rand::thread_rng().gen_range(50..250);
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+}
+
+fn count_retry(metrics: &Metrics, provider: NotificationProvider) {
+    debug!("Retrying {provider:?} request after connection failure.");
+    metrics
+        .retries_total
+        .get_or_create(&ProviderLabels { provider })
+        .inc();
+}
+
+/// Returns true if the error source chain shows
+/// that the HTTP/2 stream was not processed by the server:
+/// the request was canceled before it was written,
+/// the stream was refused
+/// or the connection was gracefully shut down before the stream was accepted.
+fn is_unprocessed_stream(mut err: Option<&(dyn std::error::Error + 'static)>) -> bool {
+    while let Some(source) = err {
+        if let Some(hyper_err) = source.downcast_ref::<hyper::Error>() {
+            if hyper_err.is_canceled() {
+                return true;
+            }
+        }
+        if let Some(h2_err) = source.downcast_ref::<h2::Error>() {
+            return h2_err.reason() == Some(h2::Reason::REFUSED_STREAM)
+                || (h2_err.is_go_away()
+                    && h2_err.is_remote()
+                    && h2_err.reason() == Some(h2::Reason::NO_ERROR));
+        }
+        err = source.source();
+    }
+    false
+}
+
+/// Returns true if the error source chain shows
+/// that the connection or the HTTP/2 stream was reset by the server
+/// or broke while the request was in flight.
+fn is_reset(mut err: Option<&(dyn std::error::Error + 'static)>) -> bool {
+    while let Some(source) = err {
+        if let Some(hyper_err) = source.downcast_ref::<hyper::Error>() {
+            if hyper_err.is_incomplete_message() {
+                return true;
+            }
+        }
+        if let Some(h2_err) = source.downcast_ref::<h2::Error>() {
+            if h2_err.is_remote() && (h2_err.is_reset() || h2_err.is_go_away()) {
+                return true;
+            }
+        }
+        if let Some(io_err) = source.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io_err.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+            );
+        }
+        err = source.source();
+    }
+    false
+}
+
+/// Returns true if the APNS request failed on the connection level:
+/// before APNS received it or because the connection was reset.
+///
+/// Timeouts and HTTP errors are not retried.
+fn is_apns_connection_error(err: &apns_h2::Error) -> bool {
+    match err {
+        apns_h2::Error::ClientError(err) => {
+            err.is_connect() || is_unprocessed_stream(err.source()) || is_reset(err.source())
+        }
+        _ => false,
+    }
+}
+
+/// Returns true if the request failed before the provider received it.
+///
+/// A connection broken while the request is in flight is not retried,
+/// as the provider may have received the request already.
+fn is_connection_error(err: &reqwest::Error) -> bool {
+    !err.is_timeout() && (err.is_connect() || is_unprocessed_stream(err.source()))
+}
+
+/// Sends an HTTP request, retrying once if it never reached the provider.
+///
+/// Connections reset after the request was written are not retried:
+/// the provider may have accepted the notification before the reset,
+/// and without an idempotency key sending it again
+/// would push a duplicate to the device.
+pub(crate) async fn send(
+    request: reqwest::RequestBuilder,
+    metrics: &Metrics,
+    provider: NotificationProvider,
+) -> reqwest::Result<reqwest::Response> {
+    let retry = request.try_clone();
+    match (request.send().await, retry) {
+        (Err(err), Some(retry)) if is_connection_error(&err) => {
+            count_retry(metrics, provider);
+            jitter().await;
+            retry.send().await
+        }
+        (res, _) => res,
+    }
+}
+
+/// Sends an APNS notification, retrying once after a connection failure.
+///
+/// Unlike [`send`], this also retries once after a connection reset.
+/// The payload is sent again unchanged,
+/// so the retry has the same `apns-id` and notification ID as the first attempt.
+pub(crate) async fn send_apns(
+    client: &ApnsClient,
+    payload: apns_h2::request::payload::Payload<'_>,
+    metrics: &Metrics,
+) -> Result<apns_h2::Response, apns_h2::Error> {
+    match client.send(payload.clone(), metrics).await {
+        Err(err) if is_apns_connection_error(&err) => {
+            count_retry(metrics, NotificationProvider::APNS);
+            jitter().await;
+            client.send(payload, metrics).await
+        }
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn test_is_unprocessed_stream() {
+        let refused = h2::Error::from(h2::Reason::REFUSED_STREAM);
+        assert!(is_unprocessed_stream(Some(&refused)));
+
+        let reset = h2::Error::from(h2::Reason::CANCEL);
+        assert!(!is_unprocessed_stream(Some(&reset)));
+
+        let io_err = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(!is_unprocessed_stream(Some(&io_err)));
+        assert!(!is_unprocessed_stream(None));
+
+        let timeout = apns_h2::Error::RequestTimeout(10);
+        assert!(!is_apns_connection_error(&timeout));
+    }
+
+    #[test]
+    fn test_is_reset() {
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(is_reset(Some(&reset)));
+        let broken = io::Error::from(io::ErrorKind::BrokenPipe);
+        assert!(is_reset(Some(&broken)));
+
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(!is_reset(Some(&refused)));
+        // Resets initiated by the client are not connection failures.
+        let canceled = h2::Error::from(h2::Reason::CANCEL);
+        assert!(!is_reset(Some(&canceled)));
+        assert!(!is_reset(None));
+    }
+
+    #[tokio::test]
+    async fn test_reset_not_retried() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut connections = 0;
+            while let Ok(Ok((mut stream, _))) =
+                tokio::time::timeout(Duration::from_secs(1), listener.accept()).await
+            {
+                connections += 1;
+                // Reset the connection after the request is received.
+                let mut buf = [0; 1024];
+                tokio::io::AsyncReadExt::read(&mut stream, &mut buf)
+                    .await
+                    .unwrap();
+                // Zero linger resets the connection on drop without blocking.
+                #[allow(deprecated)]
+                stream.set_linger(Some(Duration::ZERO)).unwrap();
+            }
+            connections
+        });
+
+        let metrics = Metrics::new();
+        let request = reqwest::Client::new().post(url).body("notification");
+        let res = send(request, &metrics, NotificationProvider::FCM).await;
+        assert!(res.is_err());
+        assert_eq!(server.await.unwrap(), 1);
+        let retries = metrics.retries_total.get_or_create(&ProviderLabels {
+            provider: NotificationProvider::FCM,
+        });
+        assert_eq!(retries.get(), 0);
+    }
+}
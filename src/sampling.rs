@@ -0,0 +1,137 @@
+//! # Heartbeat token sampling.
+//!
+//! Tokens of uninstalled apps only leave the schedule
+//! when APNS rejects a heartbeat sent to them.
+//! If heartbeats stall or are sent with the wrong credentials,
+//! dead tokens silently accumulate in the schedule.
+//!
+//! At a configured interval a random sample of heartbeat tokens
+//! gets a heartbeat ahead of schedule.
+//! Tokens rejected by APNS are removed as after a regular heartbeat,
+//! and the share of rejected tokens is exported
+//! as the `heartbeat_sample_divergence` gauge.
+//! Stale entries of removed and rescheduled tokens
+//! are dropped from the in-memory schedule at the same time,
+//! so the `heartbeat_tokens` gauge matches the database.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use log::*;
+
+use crate::exclusion;
+use crate::metrics::SampleLabels;
+use crate::notifier::{self, Outcome};
+use crate::state::State;
+
+/// Counts of sampled tokens by outcome.
+#[derive(Debug, Default)]
+struct Round {
+    delivered: usize,
+    removed: usize,
+    failed: usize,
+}
+
+impl Round {
+    fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Delivered => self.delivered += 1,
+            Outcome::Removed => self.removed += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    /// Returns the share of tokens rejected by APNS
+    /// among the tokens APNS responded for.
+    fn divergence(&self) -> f64 {
+        let responded = self.delivered + self.removed;
+        if responded == 0 {
+            0.0
+        } else {
+            self.removed as f64 / responded as f64
+        }
+    }
+}
+
+async fn sample(state: &State, size: usize) -> Result<Round> {
+    if exclusion::excluded_until(state.heartbeat_exclusions(), SystemTime::now()).is_some() {
+        debug!("Skipping heartbeat sampling in an exclusion window.");
+        return Ok(Round::default());
+    }
+    let schedule = state.schedule();
+    let stale = schedule.compact()?;
+    if stale > 0 {
+        debug!("Dropped {stale} stale schedule entries.");
+    }
+    state
+        .metrics()
+        .heartbeat_tokens
+        .set(schedule.token_count() as i64);
+
+    let mut round = Round::default();
+    for token in schedule.sample(size)? {
+        let outcome = match notifier::wakeup(state, token).await {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                warn!("Failed to notify sampled token: {err:#}.");
+                Outcome::Failed
+            }
+        };
+        let result = match outcome {
+            Outcome::Delivered => "delivered",
+            Outcome::Removed => "removed",
+            Outcome::Failed => "failed",
+        };
+        state
+            .metrics()
+            .heartbeat_samples_total
+            .get_or_create(&SampleLabels {
+                result: result.to_string(),
+            })
+            .inc();
+        round.record(outcome);
+    }
+    Ok(round)
+}
+
+/// Notifies a random sample of `size` heartbeat tokens every `interval`.
+pub async fn start(state: State, interval: Duration, size: usize) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately.
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        match sample(&state, size).await {
+            Ok(round) => {
+                let divergence = round.divergence();
+                info!(
+                    "Sampled heartbeat tokens: {} delivered, {} removed, {} failed.",
+                    round.delivered, round.removed, round.failed
+                );
+                state.metrics().heartbeat_sample_divergence.set(divergence);
+            }
+            Err(err) => error!("Failed to sample heartbeat tokens: {err:#}."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_divergence() {
+        let mut round = Round::default();
+        assert_eq!(round.divergence(), 0.0);
+
+        round.record(Outcome::Delivered);
+        round.record(Outcome::Delivered);
+        round.record(Outcome::Delivered);
+        round.record(Outcome::Removed);
+        assert_eq!(round.divergence(), 0.25);
+
+        // Failures without an APNS response are not counted.
+        round.record(Outcome::Failed);
+        assert_eq!(round.divergence(), 0.25);
+    }
+}
@@ -0,0 +1,248 @@
+//! # Token sanitation.
+//!
+//! Tokens are sent by relays and end up in logs, the schedule database
+//! and, for URL-shaped tokens such as web push endpoints,
+//! in outgoing HTTP requests.
+//! All tokens are checked here before they are parsed
+//! so that a token cannot smuggle control characters into logs
+//! or make the gateway send requests into its own private network.
+//!
+//! Checking the host of the URL is not enough,
+//! as public names may resolve to private addresses.
+//! Requests to URLs from tokens are therefore sent with a client
+//! resolving names with [`PublicResolver`] and not following redirects.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{bail, Context as _, Result};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+
+/// Maximum length of a token in bytes.
+///
+/// Web push tokens contain the endpoint URL and the keys
+/// and are the longest tokens by far.
+pub(crate) const MAX_TOKEN_LEN: usize = 4096;
+
+/// Checks that the token has an acceptable length,
+/// contains no control characters
+/// and has no embedded HTTP URLs pointing to private networks.
+pub(crate) fn check_token(token: &str) -> Result<()> {
+    if token.len() > MAX_TOKEN_LEN {
+        bail!("Token is longer than {MAX_TOKEN_LEN} bytes");
+    }
+    if token.chars().any(char::is_control) {
+        bail!("Token contains control characters");
+    }
+    if token.starts_with("webhook:") {
+        // Webhook URLs are only requested if the operator allowlisted the host,
+        // which may well be in a private network.
+        return Ok(());
+    }
+    for url in embedded_urls(token) {
+        if is_private_url(&url) {
+            bail!("Token contains a URL with a private host");
+        }
+    }
+    Ok(())
+}
+
+/// Parses an URL the gateway is going to send requests to.
+///
+/// Only HTTP(S) URLs with public hosts are accepted.
+pub(crate) fn check_url(s: &str) -> Result<Url> {
+    let url = parse_url(s)?;
+    if is_private_url(&url) {
+        bail!("URL host is in a private network");
+    }
+    Ok(url)
+}
+
+/// Parses an HTTP(S) URL with a host, which may be private.
+pub(crate) fn parse_url(s: &str) -> Result<Url> {
+    let url = Url::parse(s).context("Invalid URL")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("URL scheme {} is not supported", url.scheme());
+    }
+    if url.host_str().is_none() {
+        bail!("URL has no host");
+    }
+    Ok(url)
+}
+
+/// Returns HTTP(S) URLs found in the token.
+///
+/// URLs start at the scheme before `://`
+/// and end at a `|` separator or the end of the token.
+fn embedded_urls(token: &str) -> impl Iterator<Item = Url> + '_ {
+    token.match_indices("://").filter_map(move |(i, _)| {
+        let start = token[..i]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !(c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')))
+            .map_or(0, |(j, c)| j + c.len_utf8());
+        let end = token[i..].find('|').map_or(token.len(), |j| i + j);
+        Url::parse(&token[start..end])
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+    })
+}
+
+/// Returns true if the URL host is a loopback, private or otherwise
+/// non-public address or a name reserved for local use.
+fn is_private_url(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(&host)
+        .parse::<IpAddr>();
+    match ip {
+        Ok(ip) => is_private_ip(ip),
+        Err(_) => {
+            host == "localhost"
+                || host.ends_with(".localhost")
+                || host.ends_with(".local")
+                || host.ends_with(".internal")
+        }
+    }
+}
+
+/// Returns true if the address is loopback, private or otherwise non-public.
+fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => is_private_ipv6(ip),
+    }
+}
+
+/// DNS resolver dropping private addresses.
+///
+/// Resolving a name to private addresses only fails.
+pub(crate) struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| !is_private_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public addresses", name.as_str()).into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "This network", 0.0.0.0/8.
+        || a == 0
+        // Shared address space used for carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+}
+
+fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_private_ipv4(ipv4);
+    }
+    // NAT64 addresses embedding an IPv4 address, 64:ff9b::/96.
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return is_private_ipv4(Ipv4Addr::new(a, b, c, d));
+    }
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Deprecated IPv4-compatible addresses, ::/96.
+        || segments[..6] == [0; 6]
+        // Unique local addresses, fc00::/7.
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local and deprecated site-local addresses, fe80::/10 and fec0::/10.
+        || (segments[0] & 0xff80) == 0xfe80
+        // Documentation addresses, 2001:db8::/32.
+        || segments[..2] == [0x2001, 0xdb8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("https://push.example.org/send/abc").is_ok());
+        assert!(check_url("https://[2606:4700::1111]/").is_ok());
+
+        for url in [
+            "https://localhost/",
+            "https://LOCALHOST./",
+            "https://foo.localhost/",
+            "https://metadata.google.internal/",
+            "http://127.0.0.1:8080/",
+            "http://2130706433/",
+            "http://0x7f.1/",
+            "http://10.1.2.3/",
+            "http://172.16.0.1/",
+            "http://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[::]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[2001:db8::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::ffff:a9fe:a9fe]/",
+            "http://[64:ff9b::10.0.0.1]/",
+            "ftp://push.example.org/",
+            "file:///etc/passwd",
+            "not a url",
+        ] {
+            assert!(check_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_public_resolver() {
+        let resolve = |name: &str| PublicResolver.resolve(name.parse().unwrap());
+        assert!(resolve("localhost").await.is_err());
+        assert!(resolve("127.0.0.1").await.is_err());
+        assert!(resolve("10.0.0.1").await.is_err());
+        let addrs: Vec<_> = resolve("1.1.1.1").await.unwrap().collect();
+        assert_eq!(addrs, ["1.1.1.1:0".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_check_token() {
+        assert!(check_token("0123456789abcdef").is_ok());
+        assert!(check_token("webpush:https://push.example.org/abc|key|auth").is_ok());
+
+        assert!(check_token("abc\ndef").is_err());
+        assert!(check_token("abc\0").is_err());
+        assert!(check_token(&"a".repeat(MAX_TOKEN_LEN + 1)).is_err());
+        assert!(check_token("webpush:http://[::1]:8080/abc|key|auth").is_err());
+        assert!(check_token("ubports-x/http://192.168.0.1/").is_err());
+        // Webhook hosts are checked against the allowlist instead.
+        assert!(check_token("webhook:http://192.168.0.1/").is_ok());
+        assert!(check_token("webhook:http://192.168.0.1/\n").is_err());
+
+        // Non-HTTP URLs are never requested.
+        assert!(check_token("ubports-foo://localhost").is_ok());
+        assert!(check_token("ubports-é://localhost").is_ok());
+    }
+}
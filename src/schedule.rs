@@ -1,40 +1,600 @@
 use parking_lot::Mutex;
 use std::cmp::Reverse;
-use std::collections::BinaryHeap;
-use std::path::Path;
-use std::time::SystemTime;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use log::*;
+use prometheus_client::metrics::counter::Counter;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::logging::redact;
+use crate::server::NotificationToken;
+use storage::{Storage, StorageKind, Tree, TOKENS_TREE};
+
+pub mod storage;
+
+/// Record of who registered a heartbeat token and when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Identity of the relay that registered the token.
+    pub relay: String,
+
+    /// Unix timestamp of the registration by this relay.
+    ///
+    /// Zero for tokens registered before provenance was recorded.
+    pub registered_at: u64,
+
+    /// Token as sent by the relay if it differs from the stored token,
+    /// e.g. because it was encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registered_token: Option<String>,
+
+    /// Unix timestamp of the latest registration by any relay.
+    ///
+    /// Zero for tokens not registered again since this was recorded.
+    #[serde(default)]
+    pub last_seen: u64,
+
+    /// Heartbeat interval in seconds requested at registration,
+    /// if it differs from the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+}
+
+impl Provenance {
+    fn from_bytes(value: &[u8]) -> Self {
+        serde_json::from_slice(value).unwrap_or_else(|_| {
+            // Older databases store only the relay identity.
+            Self {
+                relay: String::from_utf8_lossy(value).into_owned(),
+                registered_at: 0,
+                registered_token: None,
+                last_seen: 0,
+                interval: None,
+            }
+        })
+    }
+}
+
+/// Line of the newline-delimited JSON written by `export-tokens`
+/// and read by `import-tokens`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedToken {
+    pub token: String,
+
+    /// Unix timestamp of the registration by the relay, zero if unknown.
+    #[serde(default)]
+    pub registered_at: u64,
+
+    /// Unix timestamp of the last heartbeat or registration.
+    ///
+    /// Zero schedules the next heartbeat right after the import.
+    #[serde(default)]
+    pub last_notified: u64,
+
+    /// Identity of the relay that registered the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+
+    /// Token as sent by the relay if it differs from the stored token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registered_token: Option<String>,
+
+    /// Unix timestamp of the latest registration by any relay.
+    #[serde(default)]
+    pub last_seen: u64,
+
+    /// Heartbeat interval in seconds requested at registration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+}
+
+/// Heartbeat intervals of the schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatIntervals {
+    /// Interval of tokens registered without requesting one.
+    pub default: Duration,
+
+    /// Shortest interval tokens can request.
+    pub min: Duration,
+
+    /// Longest interval tokens can request.
+    pub max: Duration,
+}
+
+impl HeartbeatIntervals {
+    /// Returns the requested interval in seconds within the bounds,
+    /// or `None` for the default interval.
+    fn clamp(&self, requested: Option<Duration>) -> Option<u64> {
+        let interval = requested?.clamp(self.min, self.max);
+        (interval != self.default).then_some(interval.as_secs())
+    }
+}
+
+/// Returns the time the token was last registered.
+fn seen_at(provenance: Option<&Provenance>) -> u64 {
+    provenance.map_or(0, |p| p.last_seen.max(p.registered_at))
+}
+
+/// Result of [`Schedule::register_token`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Registration {
+    /// Token was registered.
+    ///
+    /// Carries the token evicted to make room for it and its provenance.
+    Registered(Option<(String, Option<Provenance>)>),
+
+    /// Relay already registered its quota of tokens.
+    QuotaExceeded,
+
+    /// Schedule is full and no token can be evicted.
+    Full,
+}
+
+impl Registration {
+    pub fn is_registered(&self) -> bool {
+        matches!(self, Self::Registered(_))
+    }
+}
+
+/// Limit on the number of scheduled tokens.
+#[derive(Debug)]
+struct Capacity {
+    max_tokens: usize,
+
+    /// Time in seconds after which tokens not registered again may be evicted.
+    evict_unseen_after: Option<u64>,
+
+    /// Tokens ordered by the time they were last registered.
+    ///
+    /// Only kept if eviction is enabled.
+    seen: BTreeSet<(u64, String)>,
+}
+
+impl Capacity {
+    /// Takes the token registered least recently
+    /// if it may be evicted at `now`.
+    fn take_eviction_candidate(&mut self, now: u64) -> Option<String> {
+        let evict_unseen_after = self.evict_unseen_after?;
+        let (seen, _) = self.seen.first()?;
+        if seen.saturating_add(evict_unseen_after) > now {
+            return None;
+        }
+        self.seen.pop_first().map(|(_, token)| token)
+    }
+}
+
+/// Result of the integrity check done when the schedule is opened.
+#[derive(Debug, Default, Clone)]
+pub struct IntegrityReport {
+    /// Number of checked entries.
+    pub checked: usize,
+
+    /// Number of quarantined entries by reason.
+    pub quarantined: BTreeMap<&'static str, usize>,
+}
+
+/// Corrupt database entry moved out of the schedule.
+#[derive(Debug, Serialize)]
+struct QuarantinedEntry {
+    /// Name of the tree the entry was stored in.
+    tree: &'static str,
+
+    /// Hex-encoded key.
+    key: String,
+
+    /// Hex-encoded value.
+    value: String,
+
+    reason: &'static str,
+
+    /// Unix timestamp of the quarantine.
+    timestamp: u64,
+}
+
+/// Returns the path of the file corrupt entries are moved to.
+fn quarantine_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".quarantine.jsonl");
+    PathBuf::from(path)
+}
+
+/// Parses a stored heartbeat entry into the token and its timestamp.
+///
+/// Returns the quarantine reason if the entry is corrupt.
+fn check_entry(key: &[u8], value: &[u8]) -> Result<(String, u64), &'static str> {
+    let token = std::str::from_utf8(key).map_err(|_| "invalid_utf8")?;
+    if token.is_empty()
+        || token.chars().any(|c| c.is_whitespace() || c.is_control())
+        || token.parse::<NotificationToken>().is_err()
+    {
+        return Err("invalid_token");
+    }
+    let timestamp = value
+        .get(..8)
+        .and_then(|value| <[u8; 8]>::try_from(value).ok())
+        .ok_or("invalid_timestamp")?;
+    Ok((token.to_string(), u64::from_be_bytes(timestamp)))
+}
+
+/// Appends the entries to the quarantine file and removes them from the tree.
+fn quarantine(
+    path: &Path,
+    tree: &dyn Tree,
+    entries: &[QuarantinedEntry],
+    keys: &[Vec<u8>],
+) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open quarantine file {}", path.display()))?;
+    for entry in entries {
+        serde_json::to_writer(&mut file, entry)?;
+        file.write_all(b"\n")?;
+    }
+    file.sync_all()?;
+    for key in keys {
+        tree.remove(key)?;
+    }
+    Ok(())
+}
+
+/// Maximum number of tokens kept in the schedule cache.
+///
+/// The cache is cleared when it is full
+/// and refilled by subsequent lookups.
+const CACHE_CAPACITY: usize = 100_000;
+
+/// Schedule entry of a heartbeat token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenEntry {
+    /// Unix timestamp of the last heartbeat or registration.
+    pub last_notified: u64,
+
+    pub provenance: Option<Provenance>,
+}
+
+/// Read-through cache of schedule entries.
+///
+/// Tokens known not to be scheduled are cached as `None`,
+/// so removing unknown tokens does not hit the database either.
+///
+/// The cache is not locked during database I/O.
+/// Every change of the database bumps the generation,
+/// and entries read from the database are only cached
+/// if the generation did not change in the meantime.
+#[derive(Debug, Default)]
+struct ScheduleCache {
+    entries: HashMap<String, Option<TokenEntry>>,
+    generation: u64,
+    hits: Counter,
+    misses: Counter,
+}
+
+impl ScheduleCache {
+    fn insert(&mut self, token: &str, entry: Option<TokenEntry>) {
+        if self.entries.len() >= CACHE_CAPACITY && !self.entries.contains_key(token) {
+            self.entries.clear();
+        }
+        self.entries.insert(token.to_string(), entry);
+    }
+
+    /// Drops the entry of a token after it was changed in the database.
+    fn invalidate(&mut self, token: &str) {
+        self.entries.remove(token);
+        self.generation += 1;
+    }
+
+    /// Caches that a token is not scheduled after it was removed from the database.
+    ///
+    /// `generation` is the generation from before the removal.
+    fn removed(&mut self, token: &str, generation: u64) {
+        if self.generation == generation {
+            self.insert(token, None);
+            self.generation += 1;
+        } else {
+            self.invalidate(token);
+        }
+    }
+}
+
+/// Shifts the timestamp of the last heartbeat of a token with its own interval
+/// so that it becomes due after that interval
+/// when the schedule is processed with the default interval.
+fn shift(timestamp: u64, interval: Option<u64>, intervals: Option<&HeartbeatIntervals>) -> u64 {
+    match (interval, intervals) {
+        (Some(interval), Some(intervals)) => timestamp
+            .saturating_add(interval)
+            .saturating_sub(intervals.default.as_secs()),
+        _ => timestamp,
+    }
+}
+
+/// Number of consecutive failed heartbeats after which a token is quarantined.
+const QUARANTINE_AFTER: u32 = 5;
+
+/// Additional delay of the next heartbeat of a token when it is quarantined.
+const QUARANTINE_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Longest additional delay of heartbeats of quarantined tokens.
+const MAX_QUARANTINE_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns the additional delay of the next heartbeat
+/// after `failures` consecutive failed ones,
+/// or `None` if the token is not quarantined.
+///
+/// The delay doubles with every further failure.
+fn quarantine_delay(failures: u32) -> Option<Duration> {
+    let doublings = failures.checked_sub(QUARANTINE_AFTER)?;
+    let delay = QUARANTINE_DELAY
+        .checked_mul(2u32.saturating_pow(doublings))
+        .unwrap_or(MAX_QUARANTINE_DELAY);
+    Some(delay.min(MAX_QUARANTINE_DELAY))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
 #[derive(Debug)]
 pub struct Schedule {
-    /// Database to persist tokens and latest notification time.
-    db: sled::Db,
+    /// Database to persist tokens and auxiliary state.
+    storage: Arc<dyn Storage>,
+
+    /// Tree mapping tokens to their latest notification time.
+    db: Arc<dyn Tree>,
 
     /// Min-heap of tokens prioritized by the latest notification timestamp.
     heap: Mutex<BinaryHeap<(Reverse<u64>, String)>>,
+
+    /// Tree mapping tokens to their [`Provenance`].
+    owners: Arc<dyn Tree>,
+
+    /// Number of tokens registered by each relay.
+    owner_counts: Mutex<HashMap<String, usize>>,
+
+    /// Result of the integrity check done when the schedule was opened.
+    integrity: IntegrityReport,
+
+    /// Cache of entries of recently looked up tokens.
+    ///
+    /// Entries are invalidated after the database is modified.
+    cache: Mutex<ScheduleCache>,
+
+    /// Number of tokens stored in the database.
+    stored: AtomicUsize,
+
+    /// Limit on the number of tokens, if any.
+    ///
+    /// Locked after `owner_counts`.
+    capacity: Mutex<Option<Capacity>>,
+
+    /// Heartbeat intervals, if tokens can request their own.
+    intervals: Mutex<Option<HeartbeatIntervals>>,
+
+    /// Intervals in seconds of tokens that requested their own.
+    ///
+    /// Heap entries of these tokens are shifted by the difference
+    /// to the default interval,
+    /// so that they become due after their own interval.
+    /// Locked after `owner_counts` and `heap`.
+    token_intervals: Mutex<HashMap<String, u64>>,
+
+    /// Tree mapping tokens to their number of consecutive failed heartbeats.
+    failures: Arc<dyn Tree>,
+
+    /// Numbers of consecutive failed heartbeats of tokens
+    /// whose last heartbeat failed.
+    ///
+    /// Locked alone.
+    failure_counts: Mutex<HashMap<String, u32>>,
+
+    /// Number of quarantined tokens.
+    quarantined: AtomicUsize,
 }
 
 impl Schedule {
+    /// Opens the schedule in a sled database
+    /// and checks the integrity of its entries.
     pub fn new(db_path: &Path) -> Result<Self> {
-        let db = sled::open(db_path)?;
+        Self::open(StorageKind::Sled, db_path)
+    }
+
+    /// Opens the schedule and checks the integrity of its entries.
+    ///
+    /// Corrupt entries are moved to a `.quarantine.jsonl` file
+    /// next to the database.
+    pub fn open(kind: StorageKind, db_path: &Path) -> Result<Self> {
+        let storage = storage::open(kind, db_path)?;
+        let db = storage.open_tree(TOKENS_TREE)?;
+        let mut integrity = IntegrityReport::default();
+        let mut corrupt = Vec::new();
+        let mut corrupt_keys = Vec::new();
+        let mut tokens = HashSet::new();
         let mut heap = BinaryHeap::new();
         for entry in db.iter() {
             let (key, value) = entry?;
-            let token = String::from_utf8(key.to_vec()).unwrap();
+            integrity.checked += 1;
+            match check_entry(&key, &value) {
+                Ok((token, timestamp)) => {
+                    tokens.insert(token.clone());
+                    heap.push((Reverse(timestamp), token));
+                }
+                Err(reason) => {
+                    corrupt.push(QuarantinedEntry {
+                        tree: "schedule",
+                        key: hex::encode(&key),
+                        value: hex::encode(&value),
+                        reason,
+                        timestamp: unix_now(),
+                    });
+                    corrupt_keys.push(key);
+                }
+            }
+        }
+        let heap = Mutex::new(heap);
+        let stored = AtomicUsize::new(tokens.len());
 
-            let timestamp = if let Some(value) = value.get(..8) {
-                let mut buf: [u8; 8] = [0; 8];
-                buf.copy_from_slice(&value[..8]);
-                u64::from_be_bytes(buf)
-            } else {
-                0
+        let owners = storage.open_tree("owners")?;
+        let mut corrupt_owners = Vec::new();
+        let mut corrupt_owner_keys = Vec::new();
+        let mut owner_counts = HashMap::new();
+        let mut token_intervals = HashMap::new();
+        for entry in owners.iter() {
+            let (token, value) = entry?;
+            integrity.checked += 1;
+            if !std::str::from_utf8(&token).is_ok_and(|token| tokens.contains(token)) {
+                corrupt_owners.push(QuarantinedEntry {
+                    tree: "owners",
+                    key: hex::encode(&token),
+                    value: hex::encode(&value),
+                    reason: "orphaned_provenance",
+                    timestamp: unix_now(),
+                });
+                corrupt_owner_keys.push(token);
+                continue;
+            }
+            let provenance = Provenance::from_bytes(&value);
+            if let Some(interval) = provenance.interval {
+                token_intervals.insert(String::from_utf8_lossy(&token).into_owned(), interval);
+            }
+            *owner_counts.entry(provenance.relay).or_default() += 1;
+        }
+        let owner_counts = Mutex::new(owner_counts);
+
+        let failures = storage.open_tree("failures")?;
+        let mut failure_counts = HashMap::new();
+        for entry in failures.iter() {
+            let (token, value) = entry?;
+            let count = <[u8; 4]>::try_from(&*value).map(u32::from_be_bytes);
+            match (std::str::from_utf8(&token), count) {
+                (Ok(token), Ok(count)) if tokens.contains(token) => {
+                    failure_counts.insert(token.to_string(), count);
+                }
+                _ => {
+                    failures.remove(&token)?;
+                }
+            }
+        }
+
+        for entry in corrupt.iter().chain(&corrupt_owners) {
+            *integrity.quarantined.entry(entry.reason).or_default() += 1;
+        }
+        if !integrity.quarantined.is_empty() {
+            let path = quarantine_path(db_path);
+            quarantine(&path, &*db, &corrupt, &corrupt_keys)?;
+            quarantine(&path, &*owners, &corrupt_owners, &corrupt_owner_keys)?;
+            warn!(
+                "Moved corrupt schedule entries to {}: {:?}.",
+                path.display(),
+                integrity.quarantined
+            );
+        }
+        info!(
+            "Checked {} schedule entries, quarantined {}.",
+            integrity.checked,
+            corrupt.len() + corrupt_owners.len()
+        );
+
+        Ok(Self {
+            storage,
+            db,
+            heap,
+            owners,
+            owner_counts,
+            integrity,
+            cache: Default::default(),
+            stored,
+            capacity: Default::default(),
+            intervals: Default::default(),
+            token_intervals: Mutex::new(token_intervals),
+            failures,
+            quarantined: AtomicUsize::new(
+                failure_counts
+                    .values()
+                    .filter(|failures| quarantine_delay(**failures).is_some())
+                    .count(),
+            ),
+            failure_counts: Mutex::new(failure_counts),
+        })
+    }
+
+    /// Lets tokens request their own heartbeat interval
+    /// within the bounds at registration.
+    ///
+    /// Without this, requested intervals are ignored.
+    pub fn set_heartbeat_intervals(&self, intervals: HeartbeatIntervals) -> Result<()> {
+        *self.intervals.lock() = Some(intervals);
+        let token_intervals = self.token_intervals.lock().clone();
+        let mut entries = Vec::with_capacity(token_intervals.len());
+        for (token, interval) in token_intervals {
+            let Some(value) = self.db.get(token.as_bytes())? else {
+                continue;
             };
-            heap.push((Reverse(timestamp), token))
+            let Ok(value) = <[u8; 8]>::try_from(&*value) else {
+                continue;
+            };
+            let timestamp = u64::from_be_bytes(value);
+            let key = shift(timestamp, Some(interval), Some(&intervals));
+            entries.push((Reverse(key), token));
         }
-        let heap = Mutex::new(heap);
-        Ok(Self { db, heap })
+        // Entries pushed when the schedule was opened become stale.
+        self.heap.lock().extend(entries);
+        Ok(())
+    }
+
+    /// Returns the heap key of a token notified at `timestamp`.
+    fn heap_key(&self, token: &str, timestamp: u64) -> u64 {
+        let interval = self.token_intervals.lock().get(token).copied();
+        shift(timestamp, interval, self.intervals.lock().as_ref())
+    }
+
+    /// Limits the number of tokens to `max_tokens`.
+    ///
+    /// Once the limit is reached, new tokens are rejected
+    /// unless the token registered least recently
+    /// was last registered more than `evict_unseen_after` ago.
+    /// That token is then evicted to make room for the new one.
+    pub fn set_capacity(
+        &self,
+        max_tokens: usize,
+        evict_unseen_after: Option<Duration>,
+    ) -> Result<()> {
+        let mut seen = BTreeSet::new();
+        if evict_unseen_after.is_some() {
+            for entry in self.db.iter() {
+                let (key, _) = entry?;
+                let provenance = self
+                    .owners
+                    .get(&key)?
+                    .map(|value| Provenance::from_bytes(&value));
+                let token = String::from_utf8_lossy(&key).into_owned();
+                seen.insert((seen_at(provenance.as_ref()), token));
+            }
+        }
+        *self.capacity.lock() = Some(Capacity {
+            max_tokens,
+            evict_unseen_after: evict_unseen_after.map(|d| d.as_secs()),
+            seen,
+        });
+        Ok(())
+    }
+
+    /// Returns the result of the integrity check done when the schedule was opened.
+    pub fn integrity(&self) -> &IntegrityReport {
+        &self.integrity
     }
 
     /// Registers a new heartbeat notification token.
@@ -42,31 +602,453 @@ impl Schedule {
     /// This should also be called after successful notification
     /// to update latest notification time.
     pub fn insert_token(&self, token: &str, now: u64) -> Result<()> {
-        self.db.insert(token.as_bytes(), &u64::to_be_bytes(now))?;
+        if self
+            .db
+            .insert(token.as_bytes(), &u64::to_be_bytes(now))?
+            .is_none()
+        {
+            self.stored.fetch_add(1, Ordering::Relaxed);
+        }
+        self.cache.lock().invalidate(token);
+        let key = self.heap_key(token, now);
         let mut heap = self.heap.lock();
-        heap.push((Reverse(now), token.to_owned()));
+        heap.push((Reverse(key), token.to_owned()));
         Ok(())
     }
 
     pub fn insert_token_now(&self, token: &str) -> Result<()> {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
+        let now = unix_now();
         let mut rng = rand::thread_rng();
         let jitter = rng.gen_range(0..120);
         self.insert_token(token, now.saturating_sub(60).saturating_add(jitter))
     }
 
+    /// Reschedules a token after a failed heartbeat.
+    ///
+    /// After [`QUARANTINE_AFTER`] consecutive failures the token is quarantined:
+    /// its next heartbeat is delayed by [`QUARANTINE_DELAY`] in addition to the interval,
+    /// doubling with every further failure up to [`MAX_QUARANTINE_DELAY`].
+    /// Returns the additional delay if the token is quarantined.
+    pub fn reschedule_failed(&self, token: &str) -> Result<Option<Duration>> {
+        let failures = {
+            let mut failure_counts = self.failure_counts.lock();
+            let count = failure_counts.entry(token.to_string()).or_default();
+            *count = count.saturating_add(1);
+            self.failures
+                .insert(token.as_bytes(), &count.to_be_bytes())?;
+            if *count == QUARANTINE_AFTER {
+                self.quarantined.fetch_add(1, Ordering::Relaxed);
+            }
+            *count
+        };
+        match quarantine_delay(failures) {
+            Some(delay) => {
+                self.insert_token(token, unix_now().saturating_add(delay.as_secs()))?;
+                Ok(Some(delay))
+            }
+            None => {
+                self.insert_token_now(token)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Forgets the failed heartbeats of a token,
+    /// e.g. after a successful heartbeat or registration.
+    ///
+    /// Returns true if the token was quarantined.
+    pub fn reset_failures(&self, token: &str) -> Result<bool> {
+        let Some(failures) = self.failure_counts.lock().remove(token) else {
+            return Ok(false);
+        };
+        self.failures.remove(token.as_bytes())?;
+        let quarantined = quarantine_delay(failures).is_some();
+        if quarantined {
+            self.quarantined.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(quarantined)
+    }
+
+    /// Returns the number of quarantined tokens.
+    pub fn quarantined_count(&self) -> usize {
+        self.quarantined.load(Ordering::Relaxed)
+    }
+
+    /// Registers a token on behalf of a relay.
+    ///
+    /// `registered_token` is the token as sent by the relay
+    /// if it differs from `token`, e.g. because it was encrypted.
+    ///
+    /// Does not register the token
+    /// if the relay already registered `quota` other tokens
+    /// or the schedule is full.
+    ///
+    /// `interval` is the heartbeat interval requested for the token,
+    /// clamped to the bounds set with [`Schedule::set_heartbeat_intervals`].
+    pub fn register_token(
+        &self,
+        token: &str,
+        registered_token: Option<&str>,
+        owner: &str,
+        quota: Option<usize>,
+        interval: Option<Duration>,
+    ) -> Result<Registration> {
+        let now = unix_now();
+        let interval = self
+            .intervals
+            .lock()
+            .and_then(|intervals| intervals.clamp(interval));
+        self.store_token(token, Some(owner), quota, None, |previous| Provenance {
+            relay: owner.to_string(),
+            registered_at: previous
+                .filter(|p| p.relay == owner)
+                .map_or(now, |p| p.registered_at),
+            registered_token: registered_token.map(|t| t.to_string()),
+            last_seen: now,
+            interval,
+        })
+    }
+
+    /// Stores a token unless its relay registered `quota` other tokens
+    /// or the schedule is full.
+    ///
+    /// `provenance` builds the provenance of the token registered by `owner`
+    /// from the previous one.
+    /// Tokens without an owner keep their previous provenance.
+    ///
+    /// The next heartbeat is scheduled after `last_notified`
+    /// or soon if it is not known.
+    fn store_token(
+        &self,
+        token: &str,
+        owner: Option<&str>,
+        quota: Option<usize>,
+        last_notified: Option<u64>,
+        provenance: impl FnOnce(Option<&Provenance>) -> Provenance,
+    ) -> Result<Registration> {
+        let now = unix_now();
+        let mut owner_counts = self.owner_counts.lock();
+        let previous = self
+            .owners
+            .get(token.as_bytes())?
+            .map(|value| Provenance::from_bytes(&value));
+        let previous_owner = previous.as_ref().map(|p| p.relay.as_str());
+        if let (Some(owner), Some(quota)) = (owner, quota) {
+            if previous_owner != Some(owner)
+                && owner_counts.get(owner).copied().unwrap_or_default() >= quota
+            {
+                return Ok(Registration::QuotaExceeded);
+            }
+        }
+        let mut eviction_candidate = None;
+        if let Some(capacity) = &mut *self.capacity.lock() {
+            if self.stored.load(Ordering::Relaxed) >= capacity.max_tokens
+                && !self.db.contains_key(token.as_bytes())?
+            {
+                eviction_candidate = capacity.take_eviction_candidate(now);
+                if eviction_candidate.is_none() {
+                    return Ok(Registration::Full);
+                }
+            }
+        }
+        let provenance = match owner {
+            Some(owner) => {
+                if previous_owner != Some(owner) {
+                    *owner_counts.entry(owner.to_string()).or_default() += 1;
+                    if let Some(previous_owner) = previous_owner {
+                        decrement_count(&mut owner_counts, previous_owner);
+                    }
+                }
+                let provenance = provenance(previous.as_ref());
+                self.owners
+                    .insert(token.as_bytes(), &serde_json::to_vec(&provenance)?)?;
+                let mut token_intervals = self.token_intervals.lock();
+                match provenance.interval {
+                    Some(interval) => token_intervals.insert(token.to_string(), interval),
+                    None => token_intervals.remove(token),
+                };
+                Some(provenance)
+            }
+            None => previous.clone(),
+        };
+        drop(owner_counts);
+        self.cache.lock().invalidate(token);
+        if let Some(capacity) = &mut *self.capacity.lock() {
+            if capacity.evict_unseen_after.is_some() {
+                capacity
+                    .seen
+                    .remove(&(seen_at(previous.as_ref()), token.to_string()));
+                capacity
+                    .seen
+                    .insert((seen_at(provenance.as_ref()), token.to_string()));
+            }
+        }
+
+        if self.reset_failures(token)? {
+            info!(
+                "Released re-registered token {} from quarantine.",
+                redact(token)
+            );
+        }
+        match last_notified {
+            Some(last_notified) => self.insert_token(token, last_notified)?,
+            None => self.insert_token_now(token)?,
+        }
+        let evicted = match eviction_candidate {
+            Some(evicted) => {
+                let provenance = self.remove_token(&evicted)?;
+                Some((evicted, provenance))
+            }
+            None => None,
+        };
+        Ok(Registration::Registered(evicted))
+    }
+
+    /// Returns the number of tokens registered by the relay.
+    pub fn owner_token_count(&self, owner: &str) -> usize {
+        self.owner_counts
+            .lock()
+            .get(owner)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of registered tokens for each relay.
+    pub fn owners(&self) -> Vec<(String, usize)> {
+        let mut res: Vec<(String, usize)> = self
+            .owner_counts
+            .lock()
+            .iter()
+            .map(|(owner, count)| (owner.clone(), *count))
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// Returns the provenance of the token if it is known.
+    pub fn provenance(&self, token: &str) -> Result<Option<Provenance>> {
+        Ok(self
+            .owners
+            .get(token.as_bytes())?
+            .map(|value| Provenance::from_bytes(&value)))
+    }
+
+    /// Returns the schedule entry of the token if it is scheduled.
+    ///
+    /// Entries are served from the cache when possible.
+    pub fn lookup(&self, token: &str) -> Result<Option<TokenEntry>> {
+        let generation = {
+            let cache = self.cache.lock();
+            if let Some(entry) = cache.entries.get(token) {
+                cache.hits.inc();
+                return Ok(entry.clone());
+            }
+            cache.misses.inc();
+            cache.generation
+        };
+        let entry = match self.db.get(token.as_bytes())? {
+            Some(value) => Some(TokenEntry {
+                last_notified: <[u8; 8]>::try_from(&*value).map_or(0, u64::from_be_bytes),
+                provenance: self
+                    .owners
+                    .get(token.as_bytes())?
+                    .map(|value| Provenance::from_bytes(&value)),
+            }),
+            None => None,
+        };
+        let mut cache = self.cache.lock();
+        if cache.generation == generation {
+            cache.insert(token, entry.clone());
+        }
+        Ok(entry)
+    }
+
+    /// Uses the counters for cache hits and misses,
+    /// e.g. to export them as metrics.
+    pub fn set_cache_counters(&self, hits: Counter, misses: Counter) {
+        let mut cache = self.cache.lock();
+        cache.hits = hits;
+        cache.misses = misses;
+    }
+
+    /// Drops all cached entries.
+    pub fn shrink_cache(&self) {
+        let mut cache = self.cache.lock();
+        cache.entries = HashMap::new();
+    }
+
+    /// Returns all tokens registered by the relay.
+    pub fn owner_tokens(&self, owner: &str) -> Result<Vec<(String, Provenance)>> {
+        let mut res = Vec::new();
+        for entry in self.owners.iter() {
+            let (token, value) = entry?;
+            let provenance = Provenance::from_bytes(&value);
+            if provenance.relay == owner {
+                res.push((String::from_utf8_lossy(&token).into_owned(), provenance));
+            }
+        }
+        Ok(res)
+    }
+
+    /// Replaces the tokens as sent by the relays,
+    /// e.g. to re-encrypt them with a new key.
+    ///
+    /// `rewrite` returns the new token or `None` to keep the old one.
+    /// Returns the number of replaced tokens.
+    pub(crate) fn rewrite_registered_tokens(
+        &self,
+        mut rewrite: impl FnMut(&str) -> Result<Option<String>>,
+    ) -> Result<usize> {
+        let mut cache = self.cache.lock();
+        let mut rewritten = 0;
+        for entry in self.owners.iter() {
+            let (token, value) = entry?;
+            let mut provenance = Provenance::from_bytes(&value);
+            let Some(registered_token) = &provenance.registered_token else {
+                continue;
+            };
+            let Some(new_token) = rewrite(registered_token)? else {
+                continue;
+            };
+            provenance.registered_token = Some(new_token);
+            self.owners
+                .insert(&token, &serde_json::to_vec(&provenance)?)?;
+            cache.invalidate(&String::from_utf8_lossy(&token));
+            rewritten += 1;
+        }
+        Ok(rewritten)
+    }
+
+    /// Removes all tokens registered by the relay.
+    ///
+    /// Returns the number of removed tokens.
+    pub fn purge_owner(&self, owner: &str) -> Result<usize> {
+        let tokens = self.owner_tokens(owner)?;
+        for (token, _provenance) in &tokens {
+            self.remove_token(token)?;
+        }
+        Ok(tokens.len())
+    }
+
     pub async fn flush(&self) -> Result<()> {
-        self.db.flush_async().await?;
-        Ok(())
+        self.db.flush_async().await
+    }
+
+    /// Flushes the database without yielding to the runtime.
+    pub fn flush_blocking(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    /// Writes all stored tokens to `out` as newline-delimited JSON.
+    ///
+    /// Returns the number of exported tokens.
+    pub fn export_tokens(&self, out: &mut dyn std::io::Write) -> Result<usize> {
+        let mut exported = 0;
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            let Ok(token) = String::from_utf8(key) else {
+                warn!("Skipping token that is not valid UTF-8.");
+                continue;
+            };
+            let provenance = self
+                .owners
+                .get(token.as_bytes())?
+                .map(|value| Provenance::from_bytes(&value));
+            let line = ExportedToken {
+                registered_at: provenance.as_ref().map_or(0, |p| p.registered_at),
+                last_notified: <[u8; 8]>::try_from(&*value).map_or(0, u64::from_be_bytes),
+                last_seen: provenance.as_ref().map_or(0, |p| p.last_seen),
+                interval: provenance.as_ref().and_then(|p| p.interval),
+                registered_token: provenance.as_ref().and_then(|p| p.registered_token.clone()),
+                relay: provenance.map(|p| p.relay),
+                token,
+            };
+            serde_json::to_writer(&mut *out, &line)?;
+            out.write_all(b"\n")?;
+            exported += 1;
+        }
+        out.flush()?;
+        Ok(exported)
+    }
+
+    /// Stores a token exported by [`Schedule::export_tokens`]
+    /// like a registration, so the capacity of the schedule is enforced.
+    ///
+    /// Tokens that are already stored are kept as they are
+    /// and `None` is returned.
+    pub fn import_token(&self, exported: &ExportedToken) -> Result<Option<Registration>> {
+        let token = exported.token.as_str();
+        if self.db.contains_key(token.as_bytes())? {
+            return Ok(None);
+        }
+        let last_notified = Some(exported.last_notified).filter(|&t| t > 0);
+        let registration = self.store_token(
+            token,
+            exported.relay.as_deref(),
+            None,
+            last_notified,
+            |_| Provenance {
+                relay: exported.relay.clone().unwrap_or_default(),
+                registered_at: exported.registered_at,
+                registered_token: exported.registered_token.clone(),
+                last_seen: exported.last_seen,
+                interval: exported.interval,
+            },
+        )?;
+        Ok(Some(registration))
+    }
+
+    /// Writes a snapshot of the database to a new SQLite database at `path`.
+    ///
+    /// Returns the number of copied entries by tree.
+    pub fn backup(&self, path: &Path) -> Result<BTreeMap<String, usize>> {
+        self.db.flush()?;
+        storage::backup(&*self.storage, path)
     }
 
     /// Removes token from the schedule.
-    pub fn remove_token(&self, token: &str) -> Result<()> {
-        self.db.remove(token)?;
-        Ok(())
+    ///
+    /// Returns the provenance of the removed token if it is known.
+    pub fn remove_token(&self, token: &str) -> Result<Option<Provenance>> {
+        Ok(self.remove_stored_token(token)?.1)
+    }
+
+    /// Removes token from the schedule.
+    ///
+    /// Returns whether the token was stored
+    /// and the provenance of the removed token if it is known.
+    pub fn remove_stored_token(&self, token: &str) -> Result<(bool, Option<Provenance>)> {
+        let generation = {
+            let cache = self.cache.lock();
+            if let Some(None) = cache.entries.get(token) {
+                // Token is known not to be scheduled.
+                cache.hits.inc();
+                return Ok((false, None));
+            }
+            cache.generation
+        };
+        let stored = self.db.remove(token.as_bytes())?.is_some();
+        if stored {
+            self.stored.fetch_sub(1, Ordering::Relaxed);
+        }
+        let mut owner_counts = self.owner_counts.lock();
+        let provenance = self
+            .owners
+            .remove(token.as_bytes())?
+            .map(|value| Provenance::from_bytes(&value));
+        if let Some(provenance) = &provenance {
+            decrement_count(&mut owner_counts, &provenance.relay);
+        }
+        self.token_intervals.lock().remove(token);
+        if let Some(capacity) = &mut *self.capacity.lock() {
+            capacity
+                .seen
+                .remove(&(seen_at(provenance.as_ref()), token.to_string()));
+        }
+        drop(owner_counts);
+        self.cache.lock().removed(token, generation);
+        self.reset_failures(token)?;
+        Ok((stored, provenance))
     }
 
     pub fn pop(&self) -> Result<Option<(u64, String)>> {
@@ -79,7 +1061,7 @@ impl Schedule {
                 // Token was removed from the database already.
                 continue;
             };
-            if timestamp.0.to_be_bytes() != *value {
+            if !self.is_current(&token, timestamp.0, &value) {
                 // Token was reinserted with a different timestamp,
                 // e.g. by reregistration.
                 continue;
@@ -88,6 +1070,146 @@ impl Schedule {
         }
     }
 
+    /// Returns true if the heap key matches the timestamp stored in the database.
+    fn is_current(&self, token: &str, key: u64, value: &[u8]) -> bool {
+        <[u8; 8]>::try_from(value)
+            .is_ok_and(|value| self.heap_key(token, u64::from_be_bytes(value)) == key)
+    }
+
+    /// Checks that the database can be read.
+    pub fn check(&self) -> Result<()> {
+        self.db.contains_key(b"")?;
+        Ok(())
+    }
+
+    /// Pops up to `limit` tokens scheduled at or before `timestamp`.
+    pub fn pop_until(&self, timestamp: u64, limit: usize) -> Result<Vec<(u64, String)>> {
+        let mut heap = self.heap.lock();
+        let mut tokens = Vec::new();
+        while tokens.len() < limit {
+            let Some((Reverse(next), _)) = heap.peek() else {
+                break;
+            };
+            if *next > timestamp {
+                break;
+            }
+            let Some((Reverse(next), token)) = heap.pop() else {
+                break;
+            };
+            match self.db.get(token.as_bytes())? {
+                Some(value) if self.is_current(&token, next, &value) => tokens.push((next, token)),
+                // Token was removed or reinserted with a different timestamp.
+                _ => continue,
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Returns up to `n` tokens chosen uniformly at random.
+    pub fn sample(&self, n: usize) -> Result<Vec<String>> {
+        let mut rng = rand::thread_rng();
+        let mut sample = Vec::with_capacity(n);
+        for (i, entry) in self.db.iter().enumerate() {
+            let (key, _) = entry?;
+            let token = String::from_utf8_lossy(&key).into_owned();
+            if sample.len() < n {
+                sample.push(token);
+            } else {
+                // Reservoir sampling.
+                let j = rng.gen_range(0..=i);
+                if j < n {
+                    sample[j] = token;
+                }
+            }
+        }
+        Ok(sample)
+    }
+
+    /// Drops heap entries of removed and rescheduled tokens.
+    ///
+    /// Returns the number of dropped entries.
+    pub fn compact(&self) -> Result<usize> {
+        // The database is read without holding the heap lock,
+        // so heartbeats are not delayed by the compaction.
+        let entries: Vec<(Reverse<u64>, String)> = self.heap.lock().iter().cloned().collect();
+        let mut seen = HashSet::new();
+        let mut stale: HashMap<(Reverse<u64>, String), usize> = HashMap::new();
+        for entry in entries {
+            let current = match self.db.get(entry.1.as_bytes())? {
+                Some(value) => self.is_current(&entry.1, entry.0 .0, &value),
+                None => false,
+            };
+            if !current || !seen.insert(entry.1.clone()) {
+                *stale.entry(entry).or_default() += 1;
+            }
+        }
+
+        // Entries pushed in the meantime are kept.
+        let mut heap = self.heap.lock();
+        let before = heap.len();
+        heap.retain(|entry| match stale.get_mut(entry) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        });
+        Ok(before - heap.len())
+    }
+
+    /// Finds a scheduled token by the SHA-256 hash
+    /// of either the token or the token as sent by the relay.
+    ///
+    /// Returns the token and the timestamp of its last heartbeat.
+    /// Every token is hashed, so this is only meant for occasional lookups.
+    pub fn find_token(&self, hash: &[u8]) -> Result<Option<(String, u64)>> {
+        let mut found = None;
+        for entry in self.db.iter() {
+            let (key, _) = entry?;
+            if Sha256::digest(&key)[..] == *hash {
+                found = Some(key);
+                break;
+            }
+        }
+        if found.is_none() {
+            for entry in self.owners.iter() {
+                let (key, value) = entry?;
+                let provenance = Provenance::from_bytes(&value);
+                if provenance
+                    .registered_token
+                    .is_some_and(|token| Sha256::digest(token.as_bytes())[..] == *hash)
+                {
+                    found = Some(key);
+                    break;
+                }
+            }
+        }
+        let Some(key) = found else {
+            return Ok(None);
+        };
+        let Some(value) = self.db.get(&key)? else {
+            return Ok(None);
+        };
+        let timestamp = <[u8; 8]>::try_from(&*value).map_or(0, u64::from_be_bytes);
+        Ok(Some((
+            String::from_utf8_lossy(&key).into_owned(),
+            timestamp,
+        )))
+    }
+
+    /// Returns the number of tokens stored in the database.
+    ///
+    /// Unlike [`Schedule::token_count`], this does not count
+    /// outdated heap entries of rescheduled tokens.
+    pub fn stored_token_count(&self) -> usize {
+        self.stored.load(Ordering::Relaxed)
+    }
+
+    /// Opens an auxiliary tree in the schedule database.
+    pub(crate) fn open_tree(&self, name: &str) -> Result<Arc<dyn Tree>> {
+        self.storage.open_tree(name)
+    }
+
     /// Returns the number of tokens in the schedule.
     pub fn token_count(&self) -> usize {
         let heap = self.heap.lock();
@@ -95,6 +1217,15 @@ impl Schedule {
     }
 }
 
+fn decrement_count(owner_counts: &mut HashMap<String, usize>, owner: &str) {
+    if let Some(count) = owner_counts.get_mut(owner) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            owner_counts.remove(owner);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,4 +1302,426 @@ mod tests {
         assert_eq!(schedule.token_count(), 0);
         Ok(())
     }
+
+    #[test]
+    fn test_pop_until() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let schedule = Schedule::new(&db_path)?;
+
+        schedule.insert_token("foo", 10)?;
+        schedule.insert_token("bar", 20)?;
+        schedule.insert_token("baz", 30)?;
+        schedule.insert_token("qux", 40)?;
+        schedule.insert_token("foo", 50)?;
+
+        // Stale entries are skipped.
+        assert_eq!(
+            schedule.pop_until(30, 10)?,
+            [(20, "bar".to_string()), (30, "baz".to_string())]
+        );
+        assert_eq!(schedule.pop_until(100, 1)?, [(40, "qux".to_string())]);
+        assert_eq!(schedule.pop_until(45, 10)?, []);
+        assert_eq!(schedule.pop()?, Some((50, "foo".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_integrity_check() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        {
+            let schedule = Schedule::new(&db_path)?;
+            schedule.register_token("foo", None, "relay1", None, None)?;
+            schedule.insert_token("bar", 20)?;
+            schedule.db.insert(b"\xff\xfe", &u64::to_be_bytes(10))?;
+            schedule.db.insert(b"fcm-foo", &u64::to_be_bytes(10))?;
+            schedule.db.insert(b"baz", b"123")?;
+            schedule.owners.insert(b"gone", b"relay1")?;
+            schedule.db.flush()?;
+        }
+
+        let schedule = Schedule::new(&db_path)?;
+        let report = schedule.integrity();
+        assert_eq!(report.checked, 7);
+        assert_eq!(report.quarantined.get("invalid_utf8"), Some(&1));
+        assert_eq!(report.quarantined.get("invalid_token"), Some(&1));
+        assert_eq!(report.quarantined.get("invalid_timestamp"), Some(&1));
+        assert_eq!(report.quarantined.get("orphaned_provenance"), Some(&1));
+
+        // Valid entries are kept.
+        assert_eq!(schedule.token_count(), 2);
+        assert_eq!(schedule.owner_token_count("relay1"), 1);
+
+        let quarantined = std::fs::read_to_string(quarantine_path(&db_path))?;
+        assert_eq!(quarantined.lines().count(), 4);
+        assert!(quarantined.contains(&hex::encode("fcm-foo")));
+        drop(schedule);
+
+        // Corrupt entries are removed from the database.
+        let schedule = Schedule::new(&db_path)?;
+        assert_eq!(schedule.integrity().checked, 3);
+        assert!(schedule.integrity().quarantined.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_owner_quota() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let schedule = Schedule::new(&db_path)?;
+
+        assert!(schedule
+            .register_token("foo", None, "relay1", Some(2), None)?
+            .is_registered());
+        assert!(schedule
+            .register_token("bar", None, "relay1", Some(2), None)?
+            .is_registered());
+        assert!(!schedule
+            .register_token("baz", None, "relay1", Some(2), None)?
+            .is_registered());
+        assert_eq!(schedule.owner_token_count("relay1"), 2);
+
+        // Reregistration of own token does not count against the quota.
+        assert!(schedule
+            .register_token("foo", None, "relay1", Some(2), None)?
+            .is_registered());
+        assert_eq!(schedule.owner_token_count("relay1"), 2);
+
+        // Other relays have their own quota.
+        assert!(schedule
+            .register_token("baz", None, "relay2", Some(2), None)?
+            .is_registered());
+        assert_eq!(schedule.owner_token_count("relay2"), 1);
+
+        schedule.remove_token("foo")?;
+        assert_eq!(schedule.owner_token_count("relay1"), 1);
+        assert!(schedule
+            .register_token("qux", None, "relay1", Some(2), None)?
+            .is_registered());
+
+        // Counts are restored after restart.
+        drop(schedule);
+        let schedule = Schedule::new(&db_path)?;
+        assert_eq!(schedule.owner_token_count("relay1"), 2);
+        assert_eq!(schedule.owner_token_count("relay2"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_capacity() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let schedule = Schedule::new(&db_path)?;
+
+        // Token registered before registrations were recorded.
+        schedule.insert_token("old", 0)?;
+        schedule.set_capacity(2, Some(Duration::from_secs(24 * 60 * 60)))?;
+        assert!(schedule
+            .register_token("foo", None, "relay", None, None)?
+            .is_registered());
+        assert_eq!(
+            schedule.register_token("bar", None, "relay", None, None)?,
+            Registration::Registered(Some(("old".to_string(), None)))
+        );
+        assert_eq!(schedule.stored_token_count(), 2);
+
+        // Recently registered tokens are not evicted.
+        assert_eq!(
+            schedule.register_token("baz", None, "relay", None, None)?,
+            Registration::Full
+        );
+        assert_eq!(
+            schedule.register_token("foo", None, "relay", None, None)?,
+            Registration::Registered(None)
+        );
+
+        // Without eviction new tokens are rejected.
+        schedule.remove_token("foo")?;
+        schedule.set_capacity(1, None)?;
+        assert_eq!(
+            schedule.register_token("baz", None, "relay", None, None)?,
+            Registration::Full
+        );
+        assert!(schedule
+            .register_token("bar", None, "relay", None, None)?
+            .is_registered());
+        Ok(())
+    }
+
+    #[test]
+    fn test_provenance() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let schedule = Schedule::new(&db_path)?;
+
+        schedule.register_token("foo", None, "relay1", None, None)?;
+        schedule.register_token("bar", None, "relay1", None, None)?;
+        schedule.register_token("baz", None, "relay2", None, None)?;
+        let provenance = schedule.provenance("foo")?.unwrap();
+        assert_eq!(provenance.relay, "relay1");
+        assert!(provenance.registered_at > 0);
+        assert_eq!(schedule.provenance("unknown")?, None);
+        assert_eq!(
+            schedule.owners(),
+            [("relay1".to_string(), 2), ("relay2".to_string(), 1)]
+        );
+
+        // Plain relay identities written by older versions are still understood.
+        schedule.owners.insert(b"qux", b"relay2")?;
+        assert_eq!(
+            schedule.provenance("qux")?,
+            Some(Provenance {
+                relay: "relay2".to_string(),
+                registered_at: 0,
+                registered_token: None,
+                last_seen: 0,
+                interval: None,
+            })
+        );
+
+        // Encrypted form of the token is kept for the relay.
+        schedule.register_token("quux", Some("openpgp:quux"), "relay2", None, None)?;
+        let provenance = schedule.remove_token("quux")?.unwrap();
+        assert_eq!(provenance.registered_token.as_deref(), Some("openpgp:quux"));
+        assert_eq!(schedule.remove_token("quux")?, None);
+
+        // Tokens can be found by the hash of either form.
+        schedule.register_token("quux", Some("openpgp:quux"), "relay2", None, None)?;
+        let (token, _) = schedule.find_token(&Sha256::digest(b"quux"))?.unwrap();
+        assert_eq!(token, "quux");
+        let (token, _) = schedule
+            .find_token(&Sha256::digest(b"openpgp:quux"))?
+            .unwrap();
+        assert_eq!(token, "quux");
+        assert_eq!(schedule.find_token(&Sha256::digest(b"unknown"))?, None);
+        schedule.remove_token("quux")?;
+
+        assert_eq!(schedule.purge_owner("relay1")?, 2);
+        assert_eq!(schedule.owner_token_count("relay1"), 0);
+        assert_eq!(schedule.provenance("foo")?, None);
+        assert_eq!(schedule.owner_tokens("relay2")?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_heartbeat_intervals() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let intervals = HeartbeatIntervals {
+            default: Duration::from_secs(1200),
+            min: Duration::from_secs(600),
+            max: Duration::from_secs(3600),
+        };
+        {
+            let schedule = Schedule::new(&db_path)?;
+            schedule.set_heartbeat_intervals(intervals)?;
+            let hour = Some(Duration::from_secs(3600));
+            schedule.register_token("foo", None, "relay", None, hour)?;
+            schedule.register_token("bar", None, "relay", None, None)?;
+            // Requested intervals are clamped.
+            schedule.register_token("baz", None, "relay", None, Some(Duration::from_secs(1)))?;
+            assert_eq!(schedule.provenance("foo")?.unwrap().interval, Some(3600));
+            assert_eq!(schedule.provenance("bar")?.unwrap().interval, None);
+            assert_eq!(schedule.provenance("baz")?.unwrap().interval, Some(600));
+            schedule.remove_token("baz")?;
+
+            schedule.insert_token("foo", 1000)?;
+            schedule.insert_token("bar", 1000)?;
+            assert_eq!(schedule.pop_until(1000, 10)?, [(1000, "bar".to_string())]);
+            assert_eq!(schedule.pop_until(3399, 10)?, []);
+            assert_eq!(schedule.pop_until(3400, 10)?, [(3400, "foo".to_string())]);
+            schedule.insert_token("foo", 1000)?;
+            schedule.flush_blocking()?;
+        }
+
+        // Intervals are restored when the schedule is opened again.
+        let schedule = Schedule::new(&db_path)?;
+        schedule.set_heartbeat_intervals(intervals)?;
+        assert_eq!(schedule.pop_until(3399, 10)?, [(1000, "bar".to_string())]);
+        assert_eq!(schedule.pop()?, Some((3400, "foo".to_string())));
+
+        // Registering again without an interval restores the default.
+        schedule.register_token("foo", None, "relay", None, None)?;
+        assert_eq!(schedule.provenance("foo")?.unwrap().interval, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_failing_tokens() -> Result<()> {
+        assert_eq!(quarantine_delay(QUARANTINE_AFTER - 1), None);
+        assert_eq!(quarantine_delay(QUARANTINE_AFTER), Some(QUARANTINE_DELAY));
+        assert_eq!(
+            quarantine_delay(QUARANTINE_AFTER + 1),
+            Some(QUARANTINE_DELAY * 2)
+        );
+        assert_eq!(quarantine_delay(u32::MAX), Some(MAX_QUARANTINE_DELAY));
+
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        {
+            let schedule = Schedule::new(&db_path)?;
+            schedule.register_token("foo", None, "relay", None, None)?;
+            schedule.register_token("bar", None, "relay", None, None)?;
+            for _ in 1..QUARANTINE_AFTER {
+                assert_eq!(schedule.reschedule_failed("foo")?, None);
+                assert_eq!(schedule.reschedule_failed("bar")?, None);
+            }
+            assert_eq!(schedule.reschedule_failed("foo")?, Some(QUARANTINE_DELAY));
+            let last_notified = schedule.lookup("foo")?.unwrap().last_notified;
+            assert!(last_notified >= unix_now() + QUARANTINE_DELAY.as_secs() - 1);
+            assert_eq!(schedule.quarantined_count(), 1);
+
+            // Delivered heartbeats reset the failures.
+            assert!(!schedule.reset_failures("bar")?);
+            assert_eq!(schedule.reschedule_failed("bar")?, None);
+            schedule.flush_blocking()?;
+        }
+
+        // Failures are persisted.
+        let schedule = Schedule::new(&db_path)?;
+        assert_eq!(schedule.quarantined_count(), 1);
+        assert_eq!(
+            schedule.reschedule_failed("foo")?,
+            Some(QUARANTINE_DELAY * 2)
+        );
+
+        // Registering the token again releases it.
+        schedule.register_token("foo", None, "relay", None, None)?;
+        assert_eq!(schedule.quarantined_count(), 0);
+        assert_eq!(schedule.reschedule_failed("foo")?, None);
+
+        // Removed tokens forget their failures.
+        schedule.remove_token("bar")?;
+        assert!(schedule.failure_counts.lock().get("bar").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_import_tokens() -> Result<()> {
+        let dir = tempdir()?;
+        let schedule = Schedule::new(&dir.path().join("db.sled"))?;
+        schedule.register_token("foo", Some("openpgp:foo"), "relay", None, None)?;
+        schedule.insert_token("foo", 1000)?;
+        schedule.insert_token("bar", 2000)?;
+        let mut out = Vec::new();
+        assert_eq!(schedule.export_tokens(&mut out)?, 2);
+
+        let lines: Vec<ExportedToken> = out
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines[0].token, "bar");
+        assert_eq!(lines[0].relay, None);
+        assert_eq!(lines[1].token, "foo");
+        assert_eq!(lines[1].last_notified, 1000);
+        assert_eq!(lines[1].relay.as_deref(), Some("relay"));
+        assert_eq!(lines[1].registered_token.as_deref(), Some("openpgp:foo"));
+
+        let other = Schedule::open(StorageKind::Memory, &dir.path().join("other"))?;
+        other.insert_token("bar", 3000)?;
+        let imported: Vec<Option<Registration>> = lines
+            .iter()
+            .map(|line| other.import_token(line))
+            .collect::<Result<_>>()?;
+        assert_eq!(imported, [None, Some(Registration::Registered(None))]);
+        assert_eq!(other.lookup("bar")?.unwrap().last_notified, 3000);
+        assert_eq!(other.owner_token_count("relay"), 1);
+        assert_eq!(other.provenance("foo")?, schedule.provenance("foo")?);
+        assert_eq!(other.pop()?, Some((1000, "foo".to_string())));
+
+        // Imports respect the capacity of the schedule.
+        let full = Schedule::open(StorageKind::Memory, &dir.path().join("full"))?;
+        full.set_capacity(1, None)?;
+        full.insert_token("baz", 3000)?;
+        assert_eq!(full.import_token(&lines[1])?, Some(Registration::Full));
+        assert_eq!(full.lookup("foo")?, None);
+        assert_eq!(full.owner_token_count("relay"), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_storage() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let schedule = Schedule::open(StorageKind::Memory, &db_path)?;
+        schedule.register_token("foo", None, "relay", None, None)?;
+        schedule.insert_token("foo", 1000)?;
+        assert_eq!(schedule.pop()?, Some((1000, "foo".to_string())));
+        assert_eq!(schedule.owner_token_count("relay"), 1);
+        schedule.flush_blocking()?;
+        assert!(!db_path.exists());
+
+        // Nothing is persisted.
+        let schedule = Schedule::open(StorageKind::Memory, &db_path)?;
+        assert_eq!(schedule.pop()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("db.sled");
+        let schedule = Schedule::new(&db_path)?;
+        let (hits, misses) = (Counter::default(), Counter::default());
+        schedule.set_cache_counters(hits.clone(), misses.clone());
+
+        schedule.register_token("foo", None, "relay1", None, None)?;
+        let entry = schedule.lookup("foo")?.unwrap();
+        assert_eq!(entry.provenance.as_ref().unwrap().relay, "relay1");
+        assert_eq!(schedule.lookup("foo")?, Some(entry));
+        assert_eq!((hits.get(), misses.get()), (1, 1));
+
+        // Cached entries follow changes of the database.
+        schedule.insert_token("foo", 42)?;
+        assert_eq!(schedule.lookup("foo")?.unwrap().last_notified, 42);
+        schedule.register_token("foo", None, "relay2", None, None)?;
+        assert_eq!(schedule.provenance("foo")?.unwrap().relay, "relay2");
+        assert!(schedule.remove_token("foo")?.is_some());
+        assert_eq!(schedule.lookup("foo")?, None);
+
+        // Tokens without provenance are reported as removed.
+        schedule.insert_token("baz", 42)?;
+        assert_eq!(schedule.remove_stored_token("baz")?, (true, None));
+
+        // Unknown tokens are cached too.
+        let misses_before = misses.get();
+        assert_eq!(schedule.remove_token("bar")?, None);
+        assert_eq!(schedule.remove_stored_token("bar")?, (false, None));
+        assert_eq!(schedule.lookup("bar")?, None);
+        assert_eq!(misses.get(), misses_before);
+        schedule.insert_token("bar", 1)?;
+        assert_eq!(schedule.lookup("bar")?.unwrap().last_notified, 1);
+
+        schedule.shrink_cache();
+        assert_eq!(schedule.lookup("bar")?.unwrap().last_notified, 1);
+        assert_eq!(misses.get(), misses_before + 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_and_compact() -> Result<()> {
+        let dir = tempdir()?;
+        let schedule = Schedule::new(&dir.path().join("db.sled"))?;
+        assert!(schedule.sample(10)?.is_empty());
+
+        for token in ["foo", "bar", "baz"] {
+            schedule.insert_token(token, 10)?;
+        }
+        assert_eq!(schedule.sample(10)?.len(), 3);
+        let sample = schedule.sample(2)?;
+        assert_eq!(sample.len(), 2);
+        assert_ne!(sample[0], sample[1]);
+
+        // Rescheduled and removed tokens leave stale heap entries.
+        schedule.insert_token("foo", 20)?;
+        schedule.remove_token("bar")?;
+        assert_eq!(schedule.token_count(), 4);
+        assert_eq!(schedule.compact()?, 2);
+        assert_eq!(schedule.token_count(), 2);
+        assert_eq!(schedule.pop()?, Some((10, "baz".to_string())));
+        assert_eq!(schedule.pop()?, Some((20, "foo".to_string())));
+        Ok(())
+    }
 }
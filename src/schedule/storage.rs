@@ -0,0 +1,785 @@
+//! Storage backends of the schedule database.
+//!
+//! The schedule and auxiliary state such as feature flags and API keys
+//! are stored in named trees of binary keys and values ordered by key,
+//! following the data model of sled, the original backend.
+//! The SQLite backend stores all trees in one table of a single database file.
+//! The memory backend keeps trees only for the lifetime of the process,
+//! e.g. for tests and deployments not persisting heartbeats.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{bail, Context as _, Result};
+use axum::async_trait;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension as _};
+use serde::Deserialize;
+
+/// Name of the tree mapping scheduled tokens
+/// to the timestamp of their last heartbeat.
+///
+/// In sled databases this is the default tree.
+pub const TOKENS_TREE: &str = "tokens";
+
+/// Name of the default tree of sled databases.
+const SLED_DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// Number of entries read from SQLite or copied at once.
+const PAGE_SIZE: usize = 1000;
+
+/// Key and value of a tree entry.
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Named collection of binary keys and values ordered by key.
+#[async_trait]
+pub trait Tree: Send + Sync + fmt::Debug {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Inserts the value and returns the previous one.
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Removes the key and returns its value.
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Inserts many entries at once, e.g. when migrating.
+    fn insert_all(&self, entries: &[Entry]) -> Result<()> {
+        for (key, value) in entries {
+            self.insert(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Iterates over all entries in key order.
+    ///
+    /// The tree may be modified during the iteration.
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry>> + '_>;
+
+    /// Writes all changes to the database to disk.
+    fn flush(&self) -> Result<()>;
+
+    /// Writes all changes to the database to disk
+    /// without blocking the runtime.
+    async fn flush_async(&self) -> Result<()>;
+}
+
+/// Database of named trees.
+pub trait Storage: Send + Sync + fmt::Debug {
+    /// Opens the tree, creating it if it does not exist.
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn Tree>>;
+
+    /// Returns the names of the trees in the database.
+    fn tree_names(&self) -> Result<Vec<String>>;
+
+    /// Writes a consistent snapshot of all trees
+    /// to a new SQLite database at `path`.
+    fn write_snapshot(&self, path: &Path) -> Result<()>;
+}
+
+/// Storage backend selected with `--storage`
+/// or `storage` in the configuration file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    Sled,
+    Sqlite,
+
+    /// Nothing is persisted, the database path is ignored.
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sled" => Ok(Self::Sled),
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            _ => bail!("Unknown storage backend {s:?}, expected `sled`, `sqlite` or `memory`"),
+        }
+    }
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sled => f.write_str("sled"),
+            Self::Sqlite => f.write_str("sqlite"),
+            Self::Memory => f.write_str("memory"),
+        }
+    }
+}
+
+/// Opens the database at `path` with the backend.
+pub fn open(kind: StorageKind, path: &Path) -> Result<Arc<dyn Storage>> {
+    Ok(match kind {
+        StorageKind::Sled => Arc::new(SledStorage::open(path)?),
+        StorageKind::Sqlite => Arc::new(SqliteStorage::open(path)?),
+        StorageKind::Memory => Arc::new(MemoryStorage::default()),
+    })
+}
+
+/// Copies all trees from one database to another.
+///
+/// Returns the number of copied entries by tree.
+pub fn copy(from: &dyn Storage, to: &dyn Storage) -> Result<BTreeMap<String, usize>> {
+    let mut copied = BTreeMap::new();
+    for name in from.tree_names()? {
+        let source = from.open_tree(&name)?;
+        let target = to.open_tree(&name)?;
+        let mut count = 0;
+        let mut batch = Vec::with_capacity(PAGE_SIZE);
+        for entry in source.iter() {
+            batch.push(entry?);
+            if batch.len() == PAGE_SIZE {
+                target.insert_all(&batch)?;
+                count += batch.len();
+                batch.clear();
+            }
+        }
+        target.insert_all(&batch)?;
+        count += batch.len();
+        target.flush()?;
+        copied.insert(name, count);
+    }
+    Ok(copied)
+}
+
+/// Writes a snapshot of all trees to a new SQLite database at `path`.
+///
+/// The database may be in use, the snapshot is taken at a single point in time.
+/// Returns the number of copied entries by tree.
+pub fn backup(from: &dyn Storage, path: &Path) -> Result<BTreeMap<String, usize>> {
+    if path.exists() {
+        bail!(
+            "{} already exists, refusing to overwrite it",
+            path.display()
+        );
+    }
+    from.write_snapshot(path)?;
+    SqliteStorage::open(path)?.count_entries()
+}
+
+/// Copies all trees of the backup at `path` into an empty database.
+///
+/// Returns the number of restored entries by tree.
+pub fn restore(path: &Path, to: &dyn Storage) -> Result<BTreeMap<String, usize>> {
+    if !path.exists() {
+        bail!("Backup {} does not exist", path.display());
+    }
+    for name in to.tree_names()? {
+        if to.open_tree(&name)?.iter().next().is_some() {
+            bail!("Tree {name} is not empty, refusing to restore into it");
+        }
+    }
+    let from = SqliteStorage::open(path)?;
+    copy(&from, to)
+}
+
+/// Sled database.
+///
+/// Sled cannot read several trees at a single point in time,
+/// so writes wait while a snapshot is written.
+#[derive(Debug)]
+pub struct SledStorage {
+    db: sled::Db,
+
+    /// Held for reading by writes and for writing by snapshots.
+    writes: Arc<RwLock<()>>,
+}
+
+impl SledStorage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("Failed to open sled database {}", path.display()))?;
+        Ok(Self {
+            db,
+            writes: Default::default(),
+        })
+    }
+}
+
+impl Storage for SledStorage {
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn Tree>> {
+        let tree = if name == TOKENS_TREE {
+            (*self.db).clone()
+        } else {
+            self.db.open_tree(name)?
+        };
+        Ok(Arc::new(SledTree {
+            tree,
+            writes: self.writes.clone(),
+        }))
+    }
+
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let _writes = blocking(|| self.writes.write());
+        copy(self, &SqliteStorage::open(path)?)?;
+        Ok(())
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .db
+            .tree_names()
+            .iter()
+            .map(|name| match &**name {
+                SLED_DEFAULT_TREE => TOKENS_TREE.to_string(),
+                name => String::from_utf8_lossy(name).into_owned(),
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug)]
+struct SledTree {
+    tree: sled::Tree,
+    writes: Arc<RwLock<()>>,
+}
+
+impl SledTree {
+    /// Waits until no snapshot is being written.
+    fn lock_writes(&self) -> RwLockReadGuard<'_, ()> {
+        self.writes
+            .try_read()
+            .unwrap_or_else(|| blocking(|| self.writes.read()))
+    }
+}
+
+#[async_trait]
+impl Tree for SledTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _writes = self.lock_writes();
+        Ok(self.tree.insert(key, value)?.map(|value| value.to_vec()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _writes = self.lock_writes();
+        Ok(self.tree.remove(key)?.map(|value| value.to_vec()))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.tree.contains_key(key)?)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+        Box::new(self.tree.iter().map(|entry| {
+            let (key, value) = entry?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    async fn flush_async(&self) -> Result<()> {
+        self.tree.flush_async().await?;
+        Ok(())
+    }
+}
+
+/// Database kept in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    trees: Mutex<HashMap<String, Arc<MemoryTree>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn Tree>> {
+        let tree = self
+            .trees
+            .lock()
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        Ok(tree)
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.trees.lock().keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let snapshot = MemoryStorage::default();
+        {
+            let trees = self.trees.lock();
+            let locked: Vec<_> = trees
+                .iter()
+                .map(|(name, tree)| (name, tree.entries.read()))
+                .collect();
+            let mut snapshot_trees = snapshot.trees.lock();
+            for (name, entries) in locked {
+                let tree = MemoryTree {
+                    entries: RwLock::new(entries.clone()),
+                };
+                snapshot_trees.insert(name.clone(), Arc::new(tree));
+            }
+        }
+        copy(&snapshot, &SqliteStorage::open(path)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryTree {
+    entries: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+#[async_trait]
+impl Tree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.read().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.write().insert(key.to_vec(), value.to_vec()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.write().remove(key))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.entries.read().contains_key(key))
+    }
+
+    /// Iterates over a snapshot of the entries.
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+        let entries: Vec<Entry> = self
+            .entries
+            .read()
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Box::new(entries.into_iter().map(Ok))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn flush_async(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Maximum number of idle read connections kept open per SQLite database.
+const MAX_IDLE_READERS: usize = 4;
+
+/// Runs blocking database I/O, telling a multi-threaded runtime
+/// to move its other tasks off the current worker thread first.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// SQLite database in WAL mode.
+///
+/// Changes are written to the WAL file when they are made
+/// and to the database file on flush.
+/// Writes are serialized on one connection,
+/// reads use a pool of connections so they do not wait for writes.
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    conns: Arc<SqliteConnections>,
+}
+
+#[derive(Debug)]
+struct SqliteConnections {
+    path: PathBuf,
+
+    /// Dropped before the writer, so the writer closes last
+    /// and removes the WAL file.
+    readers: Mutex<Vec<Connection>>,
+
+    writer: Mutex<Connection>,
+}
+
+impl SqliteConnections {
+    /// Runs `f` on the write connection.
+    fn write<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        blocking(|| f(&mut self.writer.lock()))
+    }
+
+    /// Runs `f` on an idle read connection, opening one if there is none.
+    fn read<T>(&self, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        blocking(|| {
+            let conn = self.readers.lock().pop();
+            let conn = match conn {
+                Some(conn) => conn,
+                None => Connection::open_with_flags(
+                    &self.path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )
+                .with_context(|| {
+                    format!("Failed to open SQLite database {}", self.path.display())
+                })?,
+            };
+            let res = f(&conn);
+            let mut readers = self.readers.lock();
+            if readers.len() < MAX_IDLE_READERS {
+                readers.push(conn);
+            }
+            res
+        })
+    }
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open SQLite database {}", path.display()))?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (
+                tree TEXT NOT NULL,
+                key BLOB NOT NULL,
+                value BLOB NOT NULL,
+                PRIMARY KEY (tree, key)
+            ) WITHOUT ROWID;",
+        )?;
+        Ok(Self {
+            conns: Arc::new(SqliteConnections {
+                path: path.to_path_buf(),
+                writer: Mutex::new(conn),
+                readers: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// Returns the number of entries by tree.
+    fn count_entries(&self) -> Result<BTreeMap<String, usize>> {
+        self.conns.read(|conn| {
+            let mut stmt = conn.prepare("SELECT tree, COUNT(*) FROM entries GROUP BY tree")?;
+            let counts = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(counts)
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn Tree>> {
+        Ok(Arc::new(SqliteTree {
+            conns: self.conns.clone(),
+            name: name.to_string(),
+        }))
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        self.conns.read(|conn| {
+            let mut stmt = conn.prepare("SELECT DISTINCT tree FROM entries ORDER BY tree")?;
+            let names = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(names)
+        })
+    }
+
+    /// Writes the snapshot in a single read transaction,
+    /// so writes continue during the backup.
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let path = path
+            .to_str()
+            .with_context(|| format!("Backup path {} is not UTF-8", path.display()))?;
+        self.conns.read(|conn| {
+            conn.execute("VACUUM INTO ?1", [path])?;
+            Ok(())
+        })
+    }
+}
+
+/// Moves all changes from the WAL file to the database file.
+fn checkpoint(conn: &Connection) -> Result<()> {
+    conn.query_row("PRAGMA wal_checkpoint(FULL)", [], |_row| Ok(()))?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct SqliteTree {
+    conns: Arc<SqliteConnections>,
+    name: String,
+}
+
+impl SqliteTree {
+    fn get_with(&self, conn: &Connection, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(conn
+            .prepare_cached("SELECT value FROM entries WHERE tree = ?1 AND key = ?2")?
+            .query_row(params![self.name, key], |row| row.get(0))
+            .optional()?)
+    }
+
+    /// Returns up to [`PAGE_SIZE`] entries with keys after `after`.
+    fn page(&self, after: Option<&[u8]>) -> Result<Vec<Entry>> {
+        self.conns.read(|conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT key, value FROM entries
+                 WHERE tree = ?1 AND (?2 IS NULL OR key > ?2)
+                 ORDER BY key LIMIT ?3",
+            )?;
+            let entries = stmt
+                .query_map(params![self.name, after, PAGE_SIZE], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(entries)
+        })
+    }
+}
+
+#[async_trait]
+impl Tree for SqliteTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.conns.read(|conn| self.get_with(conn, key))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.conns.write(|conn| {
+            let previous = self.get_with(conn, key)?;
+            conn.prepare_cached(
+                "INSERT INTO entries (tree, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (tree, key) DO UPDATE SET value = excluded.value",
+            )?
+            .execute(params![self.name, key, value])?;
+            Ok(previous)
+        })
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.conns.write(|conn| {
+            Ok(conn
+                .query_row(
+                    "DELETE FROM entries WHERE tree = ?1 AND key = ?2 RETURNING value",
+                    params![self.name, key],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+    }
+
+    fn insert_all(&self, entries: &[Entry]) -> Result<()> {
+        self.conns.write(|conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "INSERT INTO entries (tree, key, value) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (tree, key) DO UPDATE SET value = excluded.value",
+                )?;
+                for (key, value) in entries {
+                    stmt.execute(params![self.name, key, value])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+        Box::new(SqliteIter {
+            tree: self,
+            last: None,
+            page: Vec::new().into_iter(),
+            done: false,
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.conns.write(|conn| checkpoint(conn))
+    }
+
+    async fn flush_async(&self) -> Result<()> {
+        let conns = self.conns.clone();
+        tokio::task::spawn_blocking(move || conns.write(|conn| checkpoint(conn))).await?
+    }
+}
+
+/// Iterator over a SQLite tree reading one page at a time,
+/// so the database is not locked during the iteration.
+struct SqliteIter<'a> {
+    tree: &'a SqliteTree,
+
+    /// Key of the last entry read.
+    last: Option<Vec<u8>>,
+
+    page: std::vec::IntoIter<Entry>,
+
+    /// Whether the last page was read.
+    done: bool,
+}
+
+impl Iterator for SqliteIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.page.next() {
+            return Some(Ok(entry));
+        }
+        if self.done {
+            return None;
+        }
+        let page = match self.tree.page(self.last.as_deref()) {
+            Ok(page) => page,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        };
+        self.done = page.len() < PAGE_SIZE;
+        self.last = page.last().map(|(key, _value)| key.clone());
+        self.page = page.into_iter();
+        self.page.next().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn check_tree(tree: &dyn Tree) -> Result<()> {
+        assert_eq!(tree.insert(b"b", b"1")?, None);
+        assert_eq!(tree.insert(b"b", b"2")?, Some(b"1".to_vec()));
+        assert_eq!(tree.insert(b"a", b"3")?, None);
+        assert_eq!(tree.get(b"b")?, Some(b"2".to_vec()));
+        assert!(tree.contains_key(b"a")?);
+        assert_eq!(tree.remove(b"a")?, Some(b"3".to_vec()));
+        assert_eq!(tree.remove(b"a")?, None);
+        assert!(!tree.contains_key(b"a")?);
+
+        let entries: Vec<Entry> = (0..PAGE_SIZE * 2 + 1)
+            .map(|i| (format!("k{i:05}").into_bytes(), vec![1]))
+            .collect();
+        tree.insert_all(&entries)?;
+        let keys: Vec<Vec<u8>> = tree
+            .iter()
+            .map(|entry| Ok(entry?.0))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys.len(), entries.len() + 1);
+        assert_eq!(keys[0], b"b");
+        assert!(keys[1..].windows(2).all(|pair| pair[0] < pair[1]));
+        tree.flush()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backends() -> Result<()> {
+        let dir = tempdir()?;
+        let sled = SledStorage::open(&dir.path().join("db.sled"))?;
+        check_tree(&*sled.open_tree(TOKENS_TREE)?)?;
+        let sqlite = SqliteStorage::open(&dir.path().join("db.sqlite"))?;
+        check_tree(&*sqlite.open_tree(TOKENS_TREE)?)?;
+        let memory = MemoryStorage::default();
+        check_tree(&*memory.open_tree(TOKENS_TREE)?)?;
+        // Trees are shared.
+        assert!(memory.open_tree(TOKENS_TREE)?.contains_key(b"b")?);
+        // Trees are separate.
+        assert_eq!(sqlite.open_tree("flags")?.get(b"b")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_reads_during_write() -> Result<()> {
+        let dir = tempdir()?;
+        let sqlite = SqliteStorage::open(&dir.path().join("db.sqlite"))?;
+        let tree = sqlite.open_tree(TOKENS_TREE)?;
+        tree.insert(b"a", b"1")?;
+        let writer = sqlite.conns.writer.lock();
+        // Reads use their own connections and see committed changes.
+        assert_eq!(tree.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(sqlite.tree_names()?, [TOKENS_TREE]);
+        drop(writer);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sqlite_in_runtime() -> Result<()> {
+        let dir = tempdir()?;
+        let sqlite = SqliteStorage::open(&dir.path().join("db.sqlite"))?;
+        check_tree(&*sqlite.open_tree(TOKENS_TREE)?)?;
+        sqlite.open_tree(TOKENS_TREE)?.flush_async().await
+    }
+
+    #[test]
+    fn test_copy() -> Result<()> {
+        let dir = tempdir()?;
+        let sled = SledStorage::open(&dir.path().join("db.sled"))?;
+        sled.open_tree(TOKENS_TREE)?
+            .insert(b"token", &42u64.to_be_bytes())?;
+        sled.open_tree("owners")?.insert(b"token", b"relay")?;
+
+        let path = dir.path().join("db.sqlite");
+        let sqlite = SqliteStorage::open(&path)?;
+        let copied = copy(&sled, &sqlite)?;
+        assert_eq!(copied.get(TOKENS_TREE), Some(&1));
+        assert_eq!(copied.get("owners"), Some(&1));
+        drop(sqlite);
+
+        let sqlite = SqliteStorage::open(&path)?;
+        assert_eq!(
+            sqlite.open_tree(TOKENS_TREE)?.get(b"token")?,
+            Some(42u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(sqlite.tree_names()?, ["owners", "tokens"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_restore() -> Result<()> {
+        let dir = tempdir()?;
+        let sled = SledStorage::open(&dir.path().join("db.sled"))?;
+        sled.open_tree(TOKENS_TREE)?
+            .insert(b"token", &42u64.to_be_bytes())?;
+        sled.open_tree("owners")?.insert(b"token", b"relay")?;
+
+        let path = dir.path().join("backup.sqlite");
+        let copied = backup(&sled, &path)?;
+        assert_eq!(copied.get("owners"), Some(&1));
+        assert!(!dir.path().join("backup.sqlite-wal").exists());
+        assert!(backup(&sled, &path).is_err());
+
+        // Restoring into a database with entries would merge them.
+        assert!(restore(&path, &sled).is_err());
+
+        let restored = MemoryStorage::default();
+        let copied = restore(&path, &restored)?;
+        assert_eq!(copied.get(TOKENS_TREE), Some(&1));
+        assert_eq!(
+            restored.open_tree("owners")?.get(b"token")?,
+            Some(b"relay".to_vec())
+        );
+        assert!(restore(&dir.path().join("missing.sqlite"), &restored).is_err());
+
+        // Memory and SQLite databases are backed up the same way.
+        let path = dir.path().join("memory-backup.sqlite");
+        assert_eq!(backup(&restored, &path)?.get("owners"), Some(&1));
+        let sqlite = SqliteStorage::open(&dir.path().join("db.sqlite"))?;
+        restore(&path, &sqlite)?;
+        let path = dir.path().join("sqlite-backup.sqlite");
+        let copied = backup(&sqlite, &path)?;
+        assert_eq!(copied.get(TOKENS_TREE), Some(&1));
+        assert_eq!(copied.get("owners"), Some(&1));
+        Ok(())
+    }
+}
@@ -1,40 +1,507 @@
-use anyhow::{bail, Error, Result};
-use apns_h2::{
-    CollapseId, DefaultNotificationBuilder, Error::ResponseError, ErrorReason, NotificationBuilder,
-    NotificationOptions, Priority, PushType,
-};
-use axum::http::StatusCode;
+use anyhow::{bail, Context as _, Error, Result};
+use axum::extract::{DefaultBodyLimit, Query, Request};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
-use base64::Engine as _;
-use chrono::{Local, TimeDelta};
+use axum::{Extension, Json};
 use log::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::time::Instant;
-use web_push_native::jwt_simple::prelude::ES256KeyPair;
-use web_push_native::{p256, Auth, WebPushBuilder};
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
 
-use crate::metrics::{FailureLabels, Metrics, NotificationProvider};
+use crate::admin;
+use crate::apikeys::{key_relay_identity, Scope};
+use crate::compression;
+use crate::eventlog::Event;
+use crate::fanout::{self, FanOutPolicy};
+use crate::http3;
+use crate::logging::redact;
+use crate::metrics::{
+    DebounceLabels, NotificationIdLabels, NotificationKind, NotificationProvider, ProviderLabels,
+    RateLimitLabels, RelayNotificationLabels, RelayRegistrationLabels,
+};
+use crate::otel;
+use crate::outage::ProviderHealth;
+use crate::pow;
+use crate::provider::Disposition;
+use crate::proxy::ClientIp;
+use crate::queue::{self, DeliveryStatus};
+use crate::ratelimit::RateLimiter;
+use crate::sanitize;
+use crate::schedule::Registration;
 use crate::state::State;
+use crate::stats::Stats;
+use crate::tls::Certificates;
+use crate::traces::{self, TraceEvent};
+use crate::wns;
+
+/// Supported API versions.
+const API_VERSIONS: &[u32] = &[1];
+
+/// Maximum size of request bodies.
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Maximum number of items in a batch request.
+const MAX_BATCH_LEN: usize = 1000;
+
+/// Prefixes of tokens accepted by `/register` and `/notify`.
+///
+/// Tokens without a known prefix are APNS production tokens.
+const TOKEN_PREFIXES: &[&str] = &[
+    "openpgp:",
+    "sandbox:",
+    "apns:",
+    "apns-sandbox:",
+    "safari:",
+    "safari-sandbox:",
+    "fcm-",
+    "ubports-",
+    "webpush:",
+    "up:",
+    "webhook:",
+    "fanout:",
+    "wns:",
+    "onesignal:",
+    "vivo:",
+    "oppo:",
+];
 
-pub async fn start(state: State, server: String, port: u16) -> Result<()> {
+/// Serves the public API, over HTTPS with `certificates`
+/// and additionally over HTTP/3 with `http3`.
+///
+/// Returns when the gateway is shutting down
+/// after the requests in progress are finished.
+pub async fn start(
+    state: State,
+    server: String,
+    port: u16,
+    certificates: Option<Certificates>,
+    http3: bool,
+) -> Result<()> {
+    let shutdown_state = state.clone();
     let app = axum::Router::new()
         .route("/", get(|| async { "Hello, world!" }))
-        .route("/register", post(register_device))
-        .route("/notify", post(notify_device))
-        .with_state(state);
+        .route("/config", get(capabilities))
+        .route("/health", get(|| async { "OK" }))
+        .route("/ready", get(readiness))
+        .route("/status", get(provider_status))
+        .route("/stats", get(stats))
+        .route(
+            "/register",
+            post(register_device)
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_register,
+                ))
+                .layer(middleware::from_fn(otel::trace_request)),
+        )
+        .route(
+            "/register/batch",
+            post(register_batch)
+                .layer(DefaultBodyLimit::max(compression::MAX_DECOMPRESSED_SIZE))
+                .layer(middleware::from_fn(compression::compress))
+                .layer(middleware::from_fn(compression::decompress))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_register,
+                ))
+                .layer(middleware::from_fn(otel::trace_request)),
+        )
+        .route(
+            "/notify",
+            post(notify_device)
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_notify,
+                ))
+                .layer(middleware::from_fn(otel::trace_request)),
+        )
+        .route(
+            "/notify/batch",
+            post(notify_batch)
+                .layer(DefaultBodyLimit::max(compression::MAX_DECOMPRESSED_SIZE))
+                .layer(middleware::from_fn(compression::compress))
+                .layer(middleware::from_fn(compression::decompress))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_notify,
+                ))
+                .layer(middleware::from_fn(otel::trace_request)),
+        )
+        .route(
+            "/notifications/:id",
+            get(notification_status).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_notify,
+            )),
+        )
+        .route("/notifications/:id/receipt", post(receive_receipt))
+        .route("/debug-codes", post(request_debug_code))
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(state.clone());
     let listener = tokio::net::TcpListener::bind((server, port)).await?;
+    let (app, endpoint) = match &certificates {
+        Some(certificates) if http3 => {
+            let address = listener.local_addr()?;
+            let endpoint = http3::bind(address, certificates)?;
+            let alt_svc = HeaderValue::from_str(&http3::alt_svc(address.port()))?;
+            let app = app.layer(middleware::map_response(move |mut response: Response| {
+                response
+                    .headers_mut()
+                    .insert(header::ALT_SVC, alt_svc.clone());
+                async move { response }
+            }));
+            (app, Some(endpoint))
+        }
+        _ => (app, None),
+    };
+    let http3_app = app.clone();
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let Some(certificates) = certificates else {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { shutdown_state.shutdown_requested().await })
+            .await?;
+        return Ok(());
+    };
+    let handle = axum_server::Handle::new();
+    {
+        let handle = handle.clone();
+        tokio::task::spawn(async move {
+            shutdown_state.shutdown_requested().await;
+            handle.graceful_shutdown(None);
+        });
+    }
+    let https = async {
+        axum_server::from_tcp(listener.into_std()?)?
+            .acceptor(certificates.acceptor())
+            .handle(handle)
+            .serve(app)
+            .await?;
+        Ok::<_, Error>(())
+    };
+    match endpoint {
+        Some(endpoint) => {
+            tokio::try_join!(https, http3::serve(state, http3_app, endpoint))?;
+        }
+        None => https.await?,
+    }
+    Ok(())
+}
+
+/// Returns the router for the `/admin/` endpoints.
+///
+/// Admin endpoints are not served on the public listener,
+/// but on the admin or metrics listener.
+pub(crate) fn admin_router(state: &State) -> axum::Router<State> {
+    axum::Router::new()
+        .nest(
+            "/admin",
+            admin::router().layer(middleware::from_fn_with_state(state.clone(), require_admin)),
+        )
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+}
+
+/// Starts a dedicated listener for the `/admin/` endpoints.
+pub async fn start_admin(state: State, address: String) -> Result<()> {
+    let app = admin_router(&state).with_state(state);
+    let listener = tokio::net::TcpListener::bind(address).await?;
     axum::serve(listener, app).await?;
     Ok(())
 }
 
+#[derive(Debug, Serialize)]
+struct Capabilities {
+    api_versions: &'static [u32],
+
+    token_prefixes: &'static [&'static str],
+
+    /// ASCII-armored OpenPGP keys for `openpgp:` tokens.
+    openpgp_public_keys: Vec<String>,
+
+    limits: Limits,
+}
+
+#[derive(Debug, Serialize)]
+struct Limits {
+    max_body_size: usize,
+
+    /// Maximum decompressed body size of batch requests.
+    max_batch_body_size: usize,
+
+    /// Maximum number of items in a batch request.
+    max_batch_len: usize,
+
+    /// Maximum number of heartbeat tokens per relay.
+    relay_token_quota: Option<usize>,
+
+    /// Proof-of-work difficulty required by `/register`.
+    register_pow_difficulty: Option<u8>,
+
+    /// Maximum age of encrypted token envelopes accepted by `/register` in seconds.
+    openpgp_max_token_age: Option<u64>,
+}
+
+/// Time after which obtaining an FCM access token counts as failed.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of the readiness check returned by `/ready`.
+#[derive(Debug, Serialize)]
+struct Readiness {
+    /// Schedule database can be read.
+    database: bool,
+
+    /// Clients of all configured APNS apps are constructed.
+    apns: bool,
+
+    /// Access tokens can be obtained for all FCM projects with a key.
+    fcm: bool,
+
+    /// Gateway stops taking new traffic before shutting down.
+    draining: bool,
+
+    shutting_down: bool,
+
+    /// Notifications queued, being delivered or waiting for a retry.
+    pending_notifications: usize,
+}
+
+impl Readiness {
+    fn is_ready(&self) -> bool {
+        self.database && self.apns && self.fcm && !self.draining && !self.shutting_down
+    }
+}
+
+/// Returns true if access tokens can be obtained for all FCM projects with a key.
+///
+/// Access tokens are cached, so only the first check
+/// and checks after token expiry contact Google.
+async fn fcm_ready(state: &State) -> bool {
+    for project in state.fcm_projects().projects() {
+        if !project.has_authenticator() {
+            continue;
+        }
+        match tokio::time::timeout(READINESS_TIMEOUT, project.access_token()).await {
+            Ok(Ok(Some(_))) => {}
+            Ok(Ok(None)) => return false,
+            Ok(Err(err)) => {
+                warn!(
+                    "Failed to get FCM access token for {}: {err:#}.",
+                    project.project_id()
+                );
+                return false;
+            }
+            Err(_) => {
+                warn!(
+                    "Timed out getting FCM access token for {}.",
+                    project.project_id()
+                );
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Reports whether the gateway can deliver notifications.
+///
+/// Responds with `503 Service Unavailable` if it cannot.
+async fn readiness(axum::extract::State(state): axum::extract::State<State>) -> Response {
+    let database = match state.schedule().check() {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("Schedule database is not readable: {err:#}.");
+            false
+        }
+    };
+    let readiness = Readiness {
+        database,
+        apns: state.apns_ready(),
+        fcm: fcm_ready(&state).await,
+        draining: state.is_draining(),
+        shutting_down: state.is_shutting_down(),
+        pending_notifications: state.queue().pending_len(),
+    };
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness)).into_response()
+}
+
+/// Returns the health of each push provider derived from recent error rates.
+async fn provider_status(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> Json<Vec<ProviderHealth>> {
+    let mut health = state.metrics().outage_detector.health(Instant::now());
+    if let Some(noise) = state.stats_noise() {
+        for health in &mut health {
+            let name = format!("{:?}_requests", health.provider);
+            health.requests = noise.count(&name, health.requests);
+        }
+    }
+    Json(health)
+}
+
+/// Returns aggregate counts with noise.
+///
+/// Exact counts are not published,
+/// so the route is not found unless noise is configured.
+async fn stats(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> Result<Json<Stats>, StatusCode> {
+    let noise = state.stats_noise().ok_or(StatusCode::NOT_FOUND)?;
+    let schedule = state.schedule();
+    let stats = Stats {
+        tokens: schedule.stored_token_count() as u64,
+        relays: schedule.owners().len() as u64,
+        heartbeats: state.metrics().heartbeat_notifications_total.get(),
+    };
+    Ok(Json(noise.stats(stats)))
+}
+
+/// Describes the gateway capabilities for feature negotiation.
+async fn capabilities(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> Json<Capabilities> {
+    Json(Capabilities {
+        api_versions: API_VERSIONS,
+        token_prefixes: TOKEN_PREFIXES,
+        openpgp_public_keys: state.openpgp_decryptor().public_keys().to_vec(),
+        limits: Limits {
+            max_body_size: MAX_BODY_SIZE,
+            max_batch_body_size: compression::MAX_DECOMPRESSED_SIZE,
+            max_batch_len: MAX_BATCH_LEN,
+            relay_token_quota: state.relay_token_quota(),
+            register_pow_difficulty: state.register_pow_difficulty(),
+            openpgp_max_token_age: state.openpgp_max_token_age().map(|age| age.as_secs()),
+        },
+    })
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct DeviceQuery {
     token: String,
+
+    /// Proof-of-work nonce, see [`crate::pow`].
+    #[serde(default)]
+    pow: Option<String>,
+
+    /// Requested heartbeat interval in seconds.
+    #[serde(default)]
+    interval: Option<u64>,
+}
+
+/// Identity of the caller established by API key authentication.
+#[derive(Debug, Clone)]
+pub(crate) struct Caller {
+    /// Name of the API key, `None` for anonymous callers.
+    pub(crate) key_name: Option<String>,
+}
+
+impl Caller {
+    /// Returns the value of the `relay` metrics label.
+    fn relay_label(&self) -> String {
+        self.key_name
+            .clone()
+            .unwrap_or_else(|| "anonymous".to_string())
+    }
+}
+
+/// Checks that the request carries an API key with the required scope.
+///
+/// Anonymous requests are only allowed to public endpoints
+/// while no API keys are configured.
+async fn authorize(state: &State, scope: Scope, mut request: Request, next: Next) -> Response {
+    let api_keys = state.api_keys();
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let caller = match bearer {
+        Some(secret) => {
+            let Some(key) = api_keys.authenticate(secret.trim()) else {
+                debug!("Rejecting request with unknown API key.");
+                return StatusCode::UNAUTHORIZED.into_response();
+            };
+            if !key.has_scope(scope) {
+                debug!("API key {:?} lacks scope {scope:?}.", key.name());
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            if !key.check_rate_limit(Instant::now()) {
+                debug!("API key {:?} exceeded its rate limit.", key.name());
+                state
+                    .metrics()
+                    .rate_limited_requests_total
+                    .get_or_create(&RateLimitLabels {
+                        route: scope.as_str().to_string(),
+                    })
+                    .inc();
+                return StatusCode::TOO_MANY_REQUESTS.into_response();
+            }
+            Caller {
+                key_name: Some(key.name().to_string()),
+            }
+        }
+        None if api_keys.allows_anonymous(scope) => Caller { key_name: None },
+        None => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+    request.extensions_mut().insert(caller);
+    next.run(request).await
 }
 
-struct AppError(anyhow::Error);
+async fn require_register(
+    axum::extract::State(state): axum::extract::State<State>,
+    request: Request,
+    next: Next,
+) -> Response {
+    authorize(&state, Scope::Register, request, next).await
+}
+
+async fn require_notify(
+    axum::extract::State(state): axum::extract::State<State>,
+    request: Request,
+    next: Next,
+) -> Response {
+    authorize(&state, Scope::Notify, request, next).await
+}
+
+async fn require_admin(
+    axum::extract::State(state): axum::extract::State<State>,
+    request: Request,
+    next: Next,
+) -> Response {
+    authorize(&state, Scope::Admin, request, next).await
+}
+
+pub(crate) struct AppError(anyhow::Error);
+
+/// Error of a request rejected with `400 Bad Request` and the reason.
+#[derive(Debug)]
+struct BadRequest(String);
+
+impl fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
+/// Returns the response status of a failed request.
+fn error_status(err: &Error) -> StatusCode {
+    if err.downcast_ref::<BadRequest>().is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
 
 impl<E> From<E> for AppError
 where
@@ -47,6 +514,9 @@ where
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let Some(BadRequest(reason)) = self.0.downcast_ref() {
+            return (StatusCode::BAD_REQUEST, reason.clone()).into_response();
+        }
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
@@ -55,32 +525,226 @@ impl IntoResponse for AppError {
     }
 }
 
+/// Returns false if the request source exceeded the rate limit of the route.
+///
+/// `cost` is the number of items in the request,
+/// so batch requests are charged like the same number of single requests.
+fn check_rate_limit(
+    state: &State,
+    limiter: Option<&RateLimiter>,
+    route: &str,
+    source: IpAddr,
+    cost: usize,
+) -> bool {
+    let Some(limiter) = limiter else {
+        return true;
+    };
+    if limiter.check_n(Instant::now(), &source.to_string(), cost) {
+        return true;
+    }
+    debug!("Rate limited {route} request.");
+    state
+        .metrics()
+        .rate_limited_requests_total
+        .get_or_create(&RateLimitLabels {
+            route: route.to_string(),
+        })
+        .inc();
+    false
+}
+
+/// Returns the identity of the relay making the request.
+///
+/// This is the API key name for authenticated callers
+/// and the source IP address otherwise.
+/// Registration quotas are accounted per relay identity.
+fn relay_identity(caller: &Caller, source: IpAddr) -> String {
+    if let Some(key_name) = &caller.key_name {
+        key_relay_identity(key_name)
+    } else {
+        source.to_string()
+    }
+}
+
+fn count_registration(state: &State, caller: &Caller, res: &Result<StatusCode>) {
+    let status = match res {
+        Ok(status) => *status,
+        Err(err) => error_status(err),
+    };
+    state
+        .metrics()
+        .relay_registrations_total
+        .get_or_create(&RelayRegistrationLabels {
+            relay: caller.relay_label(),
+            status: status.as_u16(),
+        })
+        .inc();
+}
+
 /// Registers a device for heartbeat notifications.
 async fn register_device(
     axum::extract::State(state): axum::extract::State<State>,
+    ClientIp(source): ClientIp,
+    Extension(caller): Extension<Caller>,
     body: String,
-) -> Result<(), AppError> {
+) -> Result<StatusCode, AppError> {
+    let res = register(&state, source, &caller, body).await;
+    count_registration(&state, &caller, &res);
+    Ok(res?)
+}
+
+async fn register(
+    state: &State,
+    source: IpAddr,
+    caller: &Caller,
+    body: String,
+) -> Result<StatusCode> {
+    if !check_rate_limit(state, state.register_rate_limiter(), "register", source, 1) {
+        return Ok(StatusCode::TOO_MANY_REQUESTS);
+    }
+
     let query: DeviceQuery = serde_json::from_str(&body)?;
+    let status = register_query(state, &relay_identity(caller, source), query)?;
+    if status.is_success() {
+        // Flush database to ensure we don't lose this token in case of restart.
+        state.schedule().flush().await?;
+    }
+    Ok(status)
+}
 
-    let mut device_token = query.token;
-    if let Some(openpgp_device_token) = device_token.strip_prefix("openpgp:") {
-        device_token = state.openpgp_decryptor().decrypt(openpgp_device_token)?;
+/// Registers many devices at once.
+///
+/// The body is a JSON array of registrations as accepted by `/register`.
+/// The response contains the status of each registration in the same order.
+async fn register_batch(
+    axum::extract::State(state): axum::extract::State<State>,
+    ClientIp(source): ClientIp,
+    Extension(caller): Extension<Caller>,
+    body: String,
+) -> Result<Response, AppError> {
+    let queries: Vec<DeviceQuery> = serde_json::from_str(&body)?;
+    if queries.len() > MAX_BATCH_LEN {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    let limiter = state.register_rate_limiter();
+    if !check_rate_limit(&state, limiter, "register", source, queries.len()) {
+        return Ok(StatusCode::TOO_MANY_REQUESTS.into_response());
     }
 
-    info!("Registering device {:?}.", device_token);
+    info!("Registering batch of {} devices.", queries.len());
+    let relay = relay_identity(&caller, source);
+    // Decryption and database writes block.
+    let results = tokio::task::spawn_blocking({
+        let state = state.clone();
+        move || {
+            let mut results = Vec::with_capacity(queries.len());
+            for query in queries {
+                let res = register_query(&state, &relay, query);
+                count_registration(&state, &caller, &res);
+                let status = res.unwrap_or_else(|err| {
+                    warn!("Failed to register device: {err:#}.");
+                    error_status(&err)
+                });
+                results.push(BatchResult { status, id: None });
+            }
+            results
+        }
+    })
+    .await?;
+    state.schedule().flush().await?;
+    Ok(Json(results).into_response())
+}
 
-    let schedule = state.schedule();
-    schedule.insert_token_now(&device_token)?;
+/// Registers a single device for `relay`
+/// without flushing the database.
+fn register_query(state: &State, relay: &str, query: DeviceQuery) -> Result<StatusCode> {
+    if let Some(difficulty) = state.register_pow_difficulty() {
+        let solved = query
+            .pow
+            .as_deref()
+            .is_some_and(|nonce| pow::verify(&query.token, nonce, difficulty));
+        if !solved {
+            debug!("Rejecting registration without valid proof-of-work.");
+            state.metrics().pow_rejections_total.inc();
+            return Ok(StatusCode::FORBIDDEN);
+        }
+    }
 
-    // Flush database to ensure we don't lose this token in case of restart.
-    schedule.flush().await?;
+    let registered_token = query.token;
+    let device_token = if let Some(openpgp_device_token) = registered_token.strip_prefix("openpgp:")
+    {
+        let (envelope, key_id) = state
+            .openpgp_decryptor()
+            .decrypt_envelope_with_key(openpgp_device_token)?;
+        state.metrics().count_openpgp_decryption(key_id);
+        if let Some(max_age) = state.openpgp_max_token_age() {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs();
+            if !envelope.is_fresh(now, max_age) {
+                // Captured ciphertexts must not re-subscribe removed devices.
+                debug!("Rejecting registration with stale token envelope.");
+                state.metrics().stale_envelope_rejections_total.inc();
+                return Ok(StatusCode::FORBIDDEN);
+            }
+        }
+        envelope.token
+    } else {
+        registered_token.clone()
+    };
+    check_registered_token(&device_token)?;
+
+    info!("Registering device {}.", redact(&device_token));
+
+    match state.schedule().register_token(
+        &device_token,
+        Some(registered_token.as_str()).filter(|t| *t != device_token),
+        relay,
+        state.relay_token_quota(),
+        query.interval.map(Duration::from_secs),
+    )? {
+        Registration::Registered(evicted) => {
+            if let Some((evicted, provenance)) = evicted {
+                info!("Evicted {} to make room for a new token.", redact(&evicted));
+                state.metrics().evicted_tokens_total.inc();
+                state
+                    .callbacks()
+                    .token_removed(&evicted, provenance, "evicted", state.metrics());
+            }
+        }
+        Registration::QuotaExceeded => {
+            warn!("Relay {relay} exceeded its registration quota.");
+            state.metrics().quota_rejections_total.inc();
+            return Ok(StatusCode::INSUFFICIENT_STORAGE);
+        }
+        Registration::Full => {
+            warn!("Rejecting registration because the schedule is full.");
+            state.metrics().schedule_full_rejections_total.inc();
+            return Ok(StatusCode::INSUFFICIENT_STORAGE);
+        }
+    }
+    debug!(
+        "Scheduled {} for heartbeat notifications.",
+        redact(&device_token)
+    );
 
     state.metrics().heartbeat_registrations_total.inc();
+    state.dead_tokens().remove(&device_token);
+    state
+        .traces()
+        .record(&device_token, || TraceEvent::new("registered"));
 
-    Ok(())
+    Ok(StatusCode::OK)
+}
+
+/// Checks a token to be registered,
+/// rejecting it as a bad request.
+fn check_registered_token(token: &str) -> Result<()> {
+    sanitize::check_token(token).map_err(|err| BadRequest(format!("{err:#}")).into())
 }
 
-pub(crate) enum NotificationToken {
+#[derive(Clone)]
+pub enum NotificationToken {
     /// Ubuntu touch app
     UBports(String),
 
@@ -108,14 +772,116 @@ pub(crate) enum NotificationToken {
 
     /// APNS production token.
     ApnsProduction(String),
+
+    /// APNS token of an app configured in `apns_apps`.
+    ApnsApp {
+        /// Bundle ID of the app selecting the credentials.
+        topic: String,
+
+        /// True if the token is for the sandbox server.
+        sandbox: bool,
+
+        token: String,
+    },
+
+    /// Safari website push token.
+    Safari {
+        /// True if the token is for the sandbox server.
+        sandbox: bool,
+
+        token: String,
+    },
+
+    /// OneSignal player of an app distributed with the OneSignal SDK.
+    OneSignal { app_id: String, player_id: String },
+
+    /// Vivo push registration ID.
+    Vivo(String),
+
+    /// OPPO push registration ID.
+    Oppo(String),
+
+    /// UnifiedPush endpoint URL.
+    UnifiedPush(String),
+
+    /// URL receiving JSON events.
+    Webhook(String),
+
+    /// WNS channel URI of a Windows app.
+    Wns(String),
+
+    /// Several tokens of the same device notified according to the policy.
+    FanOut {
+        policy: FanOutPolicy,
+        tokens: Vec<NotificationToken>,
+    },
+}
+
+impl NotificationToken {
+    /// Returns the provider responsible for delivering notifications to the token.
+    ///
+    /// Fan-out tokens report the provider of their first token.
+    pub(crate) fn provider(&self) -> NotificationProvider {
+        match self {
+            Self::FanOut { tokens, .. } => tokens
+                .first()
+                .map_or(NotificationProvider::APNS, |token| token.provider()),
+            Self::UBports(_) => NotificationProvider::UBports,
+            Self::WebPush { .. } => NotificationProvider::WebPush,
+            Self::Fcm { .. } => NotificationProvider::FCM,
+            Self::OneSignal { .. } => NotificationProvider::OneSignal,
+            Self::Vivo(_) => NotificationProvider::Vivo,
+            Self::Oppo(_) => NotificationProvider::OPPO,
+            Self::UnifiedPush(_) => NotificationProvider::UnifiedPush,
+            Self::Webhook(_) => NotificationProvider::Webhook,
+            Self::Wns(_) => NotificationProvider::WNS,
+            Self::ApnsSandbox(_)
+            | Self::ApnsProduction(_)
+            | Self::ApnsApp { .. }
+            | Self::Safari { .. } => NotificationProvider::APNS,
+        }
+    }
+}
+
+/// Returns true if `s` is a valid Android package name.
+fn is_valid_package_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_')
+}
+
+/// Returns true if `s` is a valid APNS topic, i.e. an app bundle ID.
+fn is_valid_topic(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+}
+
+/// Returns true if `s` is a valid Vivo or OPPO registration ID.
+fn is_valid_vendor_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Returns true if `s` only contains characters used in FCM registration tokens.
+fn is_valid_fcm_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':')
 }
 
 impl FromStr for NotificationToken {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
+        sanitize::check_token(s)?;
         if let Some(s) = s.strip_prefix("fcm-") {
-            if let Some((package_name, token)) = s.split_once(':') {
+            if let Some((package_name, token)) =
+                s.split_once(':').filter(|(package_name, token)| {
+                    is_valid_package_name(package_name) && is_valid_fcm_token(token)
+                })
+            {
                 Ok(Self::Fcm {
                     package_name: package_name.to_string(),
                     token: token.to_string(),
@@ -132,6 +898,7 @@ impl FromStr for NotificationToken {
                 iter.next().map(|x| x.to_string()),
                 iter.next().map(|x| x.to_string()),
             ) {
+                sanitize::check_url(&endpoint).context("Invalid web push endpoint")?;
                 Ok(Self::WebPush {
                     endpoint,
                     ua_public_key,
@@ -140,457 +907,862 @@ impl FromStr for NotificationToken {
             } else {
                 bail!("Invalid web push token");
             }
+        } else if let Some((sandbox, s)) = s
+            .strip_prefix("apns:")
+            .map(|s| (false, s))
+            .or_else(|| s.strip_prefix("apns-sandbox:").map(|s| (true, s)))
+        {
+            if let Some((topic, token)) = s
+                .split_once(':')
+                .filter(|(topic, token)| is_valid_topic(topic) && !token.is_empty())
+            {
+                Ok(Self::ApnsApp {
+                    topic: topic.to_string(),
+                    sandbox,
+                    token: token.to_string(),
+                })
+            } else {
+                bail!("Invalid APNS app token");
+            }
         } else if let Some(token) = s.strip_prefix("sandbox:") {
             Ok(Self::ApnsSandbox(token.to_string()))
+        } else if let Some(s) = s.strip_prefix("onesignal:") {
+            // App and player IDs are UUIDs.
+            if let Some((app_id, player_id)) = s.split_once(':').filter(|(app_id, player_id)| {
+                Uuid::parse_str(app_id).is_ok() && Uuid::parse_str(player_id).is_ok()
+            }) {
+                Ok(Self::OneSignal {
+                    app_id: app_id.to_string(),
+                    player_id: player_id.to_string(),
+                })
+            } else {
+                bail!("Invalid OneSignal token");
+            }
+        } else if let Some(token) = s.strip_prefix("vivo:") {
+            if !is_valid_vendor_token(token) {
+                bail!("Invalid Vivo token");
+            }
+            Ok(Self::Vivo(token.to_string()))
+        } else if let Some(token) = s.strip_prefix("oppo:") {
+            if !is_valid_vendor_token(token) {
+                bail!("Invalid OPPO token");
+            }
+            Ok(Self::Oppo(token.to_string()))
+        } else if let Some(endpoint) = s.strip_prefix("up:") {
+            sanitize::check_url(endpoint).context("Invalid UnifiedPush endpoint")?;
+            Ok(Self::UnifiedPush(endpoint.to_string()))
+        } else if let Some(url) = s.strip_prefix("webhook:") {
+            sanitize::parse_url(url).context("Invalid webhook URL")?;
+            Ok(Self::Webhook(url.to_string()))
+        } else if let Some(channel_uri) = s.strip_prefix("wns:") {
+            sanitize::check_url(channel_uri).context("Invalid WNS channel URI")?;
+            wns::check_channel_uri(channel_uri)?;
+            Ok(Self::Wns(channel_uri.to_string()))
+        } else if let Some(s) = s.strip_prefix("fanout:") {
+            let (policy, tokens) = fanout::parse(s)?;
+            Ok(Self::FanOut { policy, tokens })
+        } else if let Some(token) = s.strip_prefix("safari:") {
+            Ok(Self::Safari {
+                sandbox: false,
+                token: token.to_string(),
+            })
+        } else if let Some(token) = s.strip_prefix("safari-sandbox:") {
+            Ok(Self::Safari {
+                sandbox: true,
+                token: token.to_string(),
+            })
         } else {
             Ok(Self::ApnsProduction(s.to_string()))
         }
     }
 }
 
-/// Notify Web Push endpoint
-///
-/// Defined by 3 RFC:
-/// - Server to Server API in [RFC8030](https://www.rfc-editor.org/rfc/rfc8030)
-/// - Encryption in [RFC8291](https://www.rfc-editor.org/rfc/rfc8291)
-/// - Authorization in [RFC8292](https://www.rfc-editor.org/rfc/rfc8292) (VAPID)
-async fn notify_webpush(
-    client: &reqwest::Client,
-    vapid_key: &Option<ES256KeyPair>,
-    endpoint: &str,
-    ua_public: &str,
-    ua_auth: &str,
-    metrics: &Metrics,
-) -> Result<StatusCode> {
-    let Some(vapid_key) = vapid_key else {
-        warn!("Cannot notify Web Push because VAPID key is not set");
-        metrics
-            .failures_total
-            .get_or_create(&FailureLabels {
-                provider: NotificationProvider::WebPush,
-                reason: "no_vapid_key".to_string(),
-                details: String::new(),
-            })
-            .inc();
-        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
-    };
-
-    let request = WebPushBuilder::new(
-        endpoint.parse()?,
-        p256::PublicKey::from_sec1_bytes(
-            &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(ua_public)?,
-        )?,
-        Auth::clone_from_slice(&base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(ua_auth)?),
-    )
-    .with_vapid(vapid_key, "https://github.com/chatmail/notifiers/issues")
-    .build("ping")?;
-
-    let res = client
-        .post(endpoint)
-        .headers(request.headers().clone())
-        .body(request.into_body())
-        .send()
-        .await
-        .map_err(|e| {
-            warn!("Failed to send web push notification to {endpoint}: {e}");
-            metrics
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::WebPush,
-                    reason: "send".to_string(),
-                    details: String::new(),
-                })
-                .inc();
-            e
-        })?;
-
-    let status = res.status();
-    // Map web push responses to chatmail/relay notifier values
-    match status.as_u16() {
-        201 => {
-            metrics.webpush_notifications_total.inc();
-            Ok(StatusCode::OK)
-        }
-        _ if status.is_client_error() => {
-            metrics
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::WebPush,
-                    reason: status.as_u16().to_string(),
-                    details: String::new(),
-                })
-                .inc();
-            Ok(StatusCode::GONE)
-        }
-        _ if status.is_server_error() => {
-            metrics
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::WebPush,
-                    reason: status.as_u16().to_string(),
-                    details: String::new(),
-                })
-                .inc();
-            Ok(StatusCode::INTERNAL_SERVER_ERROR)
+/// Formats the token as accepted by [`FromStr`].
+impl fmt::Display for NotificationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UBports(token) => write!(f, "ubports-{token}"),
+            Self::WebPush {
+                endpoint,
+                ua_public_key,
+                ua_auth,
+            } => write!(f, "webpush:{endpoint}|{ua_public_key}|{ua_auth}"),
+            Self::Fcm {
+                package_name,
+                token,
+            } => write!(f, "fcm-{package_name}:{token}"),
+            Self::ApnsSandbox(token) => write!(f, "sandbox:{token}"),
+            Self::ApnsApp {
+                topic,
+                sandbox: false,
+                token,
+            } => write!(f, "apns:{topic}:{token}"),
+            Self::ApnsApp {
+                topic,
+                sandbox: true,
+                token,
+            } => write!(f, "apns-sandbox:{topic}:{token}"),
+            Self::Safari {
+                sandbox: false,
+                token,
+            } => write!(f, "safari:{token}"),
+            Self::Safari {
+                sandbox: true,
+                token,
+            } => write!(f, "safari-sandbox:{token}"),
+            Self::OneSignal { app_id, player_id } => write!(f, "onesignal:{app_id}:{player_id}"),
+            Self::Vivo(token) => write!(f, "vivo:{token}"),
+            Self::Oppo(token) => write!(f, "oppo:{token}"),
+            Self::UnifiedPush(endpoint) => write!(f, "up:{endpoint}"),
+            Self::Webhook(url) => write!(f, "webhook:{url}"),
+            Self::Wns(channel_uri) => write!(f, "wns:{channel_uri}"),
+            Self::FanOut { policy, tokens } => {
+                write!(f, "fanout:{}", fanout::format(*policy, tokens))
+            }
+            Self::ApnsProduction(token) => write!(f, "{token}"),
         }
-        _ => Ok(status),
     }
 }
 
-/// Notify the UBports push server
+/// Token of a direct notification ready for delivery.
+enum Prepared {
+    Token(NotificationToken),
+
+    /// Notification is handled without delivery, e.g. debounced.
+    Done(StatusCode),
+}
+
+/// Decrypts, debounces and parses the token of a direct notification.
+fn prepare_notification(state: &State, device_token: String) -> Result<Prepared> {
+    match decrypt_token(state, device_token) {
+        Ok(device_token) => prepare_decrypted_notification(state, device_token),
+        Err(status) => Ok(Prepared::Done(status)),
+    }
+}
+
+/// Decrypts the token if it is OpenPGP-encrypted.
 ///
-/// API documentation is available at
-/// <https://docs.ubports.com/en/latest/appdev/guides/pushnotifications.html>
-async fn notify_ubports(
-    client: &reqwest::Client,
-    token: &str,
-    metrics: &Metrics,
-) -> Result<StatusCode> {
-    if !token
-        .chars()
-        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
+/// Returns the status to respond with if decryption fails.
+fn decrypt_token(state: &State, device_token: String) -> Result<String, StatusCode> {
+    let Some(openpgp_device_token) = device_token.strip_prefix("openpgp:") else {
+        return Ok(device_token);
+    };
+    match state
+        .openpgp_decryptor()
+        .decrypt_envelope_with_key(openpgp_device_token)
     {
-        return Ok(StatusCode::GONE);
+        Ok((envelope, key_id)) => {
+            state.metrics().count_openpgp_decryption(key_id);
+            Ok(envelope.token)
+        }
+        Err(err) => {
+            error!("Failed to decrypt device token: {:#}.", err);
+
+            let metrics = state.metrics();
+            metrics.openpgp_decryption_failures_total.inc();
+
+            // Return 410 Gone response so email server can remove the token.
+            Err(StatusCode::GONE)
+        }
     }
+}
 
-    let url = "https://push.ubports.com/notify";
-    let expire_on = (Local::now() + TimeDelta::weeks(1)).to_rfc3339();
-    let body = format!(
-        r#"{{"expire_on":"{expire_on}","appid":"deltatouch.lotharketterer_deltatouch","token":"{token}","data":{{"notification":{{"tag":"sent_by_chatmail_server","card":{{"popup":true,"persist":true,"summary":"New message","body":"You have a new message"}},"sound":true,"vibrate":{{"pattern":[200],"duration":200,"repeat":1}} }},"sent-by":"Chatmail Server"}} }}"#
-    );
-    let res = client
-        .post(url)
-        .body(body.clone())
-        .header("Content-Type", "application/json")
-        .send()
-        .await
-        .map_err(|e| {
-            warn!("Failed to send UBports notification to {token}: {e}");
-            metrics
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::UBports,
-                    reason: "send".to_string(),
-                    details: String::new(),
-                })
-                .inc();
-            e
-        })?;
-    let status = res.status();
-    if status.is_client_error() {
-        warn!("Failed to deliver UBports notification to {token}");
-        warn!("BODY: {body:?}");
-        warn!("RES: {res:?}");
-        metrics
-            .failures_total
-            .get_or_create(&FailureLabels {
-                provider: NotificationProvider::UBports,
-                reason: status.as_u16().to_string(),
-                details: String::new(),
-            })
-            .inc();
-        return Ok(StatusCode::GONE);
+/// Debounces and parses the decrypted token of a direct notification.
+fn prepare_decrypted_notification(state: &State, device_token: String) -> Result<Prepared> {
+    debug!("Got direct notification for {}.", redact(&device_token));
+    let now = Instant::now();
+    if state.dead_tokens().contains(now, &device_token) {
+        debug!(
+            "Not notifying {} reported gone recently.",
+            redact(&device_token)
+        );
+        state.metrics().dead_token_hits_total.inc();
+        state.traces().record(&device_token, || {
+            TraceEvent::new("dead_token").with_status(StatusCode::GONE.as_u16())
+        });
+        return Ok(Prepared::Done(StatusCode::GONE));
     }
-    if status.is_server_error() {
-        warn!("Internal server error while attempting to deliver UBports notification to {token}");
+    if !state.debouncer().notify(now, device_token.clone()) {
+        // Token is debounced.
+        debug!("Debounced notification for {}.", redact(&device_token));
+        state
+            .traces()
+            .record(&device_token, || TraceEvent::new("debounced"));
+        let metrics = state.metrics();
         metrics
-            .failures_total
-            .get_or_create(&FailureLabels {
-                provider: NotificationProvider::UBports,
-                reason: status.as_u16().to_string(),
-                details: String::new(),
+            .debounced_notifications_total
+            .get_or_create(&DebounceLabels {
+                kind: NotificationKind::Direct,
             })
             .inc();
-        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        metrics
+            .debounced_set_size
+            .set(state.debouncer().count() as i64);
+        return Ok(Prepared::Done(StatusCode::OK));
     }
-    debug!("Delivered notification to UBports token {token}");
-    metrics.ubports_notifications_total.inc();
-    Ok(StatusCode::OK)
+    state
+        .metrics()
+        .debounced_set_size
+        .set(state.debouncer().count() as i64);
+
+    Ok(Prepared::Token(device_token.as_str().parse()?))
 }
 
-/// Notifies a single FCM token.
+/// Queues a notification for background delivery.
 ///
-/// API documentation is available at
-/// <https://firebase.google.com/docs/cloud-messaging/send-message#rest>
-async fn notify_fcm(
-    client: &reqwest::Client,
-    fcm_api_key: Option<&str>,
-    _package_name: &str,
-    token: &str,
-    metrics: &Metrics,
-) -> Result<StatusCode> {
-    let Some(fcm_api_key) = fcm_api_key else {
-        warn!("Cannot notify FCM because key is not set");
-        metrics
-            .failures_total
-            .get_or_create(&FailureLabels {
-                provider: NotificationProvider::FCM,
-                reason: "no_api_key".to_string(),
-                details: String::new(),
-            })
-            .inc();
-        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+/// Returns false if the queue is full.
+fn enqueue(
+    state: &State,
+    id: Uuid,
+    relay: String,
+    token: NotificationToken,
+    options: NotifyOptions,
+) -> bool {
+    let job = queue::Job {
+        id,
+        relay,
+        token,
+        options,
+        attempts: 0,
     };
-
-    if !token
-        .chars()
-        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
-    {
-        return Ok(StatusCode::GONE);
-    }
-
-    let url = "https://fcm.googleapis.com/v1/projects/delta-chat-fcm/messages:send";
-    let body =
-        format!("{{\"message\":{{\"token\":\"{token}\",\"data\":{{\"level\":\"awesome\"}},\"android\":{{\"priority\":\"high\"}} }} }}");
-    let res = client
-        .post(url)
-        .body(body.clone())
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {fcm_api_key}"))
-        .send()
-        .await
-        .map_err(|e| {
-            warn!("Failed to send FCM notification to {token}: {e}");
-            metrics
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::FCM,
-                    reason: "send".to_string(),
-                    details: String::new(),
-                })
-                .inc();
-            e
-        })?;
-    let status = res.status();
-    if status.is_client_error() {
-        warn!("Failed to deliver FCM notification to {token}");
-        warn!("BODY: {body:?}");
-        warn!("RES: {res:?}");
-        metrics
-            .failures_total
-            .get_or_create(&FailureLabels {
-                provider: NotificationProvider::FCM,
-                reason: status.as_u16().to_string(),
-                details: String::new(),
-            })
-            .inc();
-        return Ok(StatusCode::GONE);
-    }
-    if status.is_server_error() {
-        warn!("Internal server error while attempting to deliver FCM notification to {token}");
-        metrics
-            .failures_total
-            .get_or_create(&FailureLabels {
-                provider: NotificationProvider::FCM,
-                reason: status.as_u16().to_string(),
-                details: String::new(),
-            })
-            .inc();
-        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+    if !state.queue().push(job) {
+        warn!("Delivery queue is full.");
+        return false;
     }
-    debug!("Delivered notification to FCM token {token}");
-    metrics.fcm_notifications_total.inc();
-    Ok(StatusCode::OK)
+    state
+        .metrics()
+        .queued_notifications
+        .set(state.queue().len() as i64);
+    debug!("Queued notification {id}.");
+    true
 }
 
-async fn notify_apns(
-    state: State,
-    client: Option<apns_h2::Client>,
+/// APNS push type of a direct notification.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ApnsPushType {
+    /// Visible notification about new messages.
+    #[default]
+    Alert,
+
+    /// PushKit notification about an incoming call.
+    Voip,
+
+    /// Notification for the push-to-talk framework.
+    PushToTalk,
+
+    /// Safari website notification, used for `safari:` and `safari-sandbox:` tokens.
+    #[serde(skip_deserializing)]
+    Website,
+}
+
+/// Query parameters of `/notify` requests.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NotifyOptions {
+    /// Push type used for APNS tokens, ignored for other providers.
+    #[serde(default)]
+    pub(crate) push_type: ApnsPushType,
+}
+
+/// Notifies a single device with a visible notification.
+async fn notify_device(
+    axum::extract::State(state): axum::extract::State<State>,
+    ClientIp(source): ClientIp,
+    Extension(caller): Extension<Caller>,
+    Query(options): Query<NotifyOptions>,
+    headers: HeaderMap,
     device_token: String,
-) -> Result<StatusCode> {
-    let Some(client) = client else {
-        warn!(
-            "Cannot notify APNS because client is not configured (missing or invalid certificate)"
-        );
-        state
-            .metrics()
-            .failures_total
-            .get_or_create(&FailureLabels {
-                provider: NotificationProvider::APNS,
-                reason: "no_certificate".to_string(),
-                details: String::new(),
-            })
-            .inc();
-        return Ok(StatusCode::INTERNAL_SERVER_ERROR);
-    };
+) -> Result<Response, AppError> {
+    if !check_rate_limit(&state, state.notify_rate_limiter(), "notify", source, 1) {
+        return Ok(StatusCode::TOO_MANY_REQUESTS.into_response());
+    }
 
-    let schedule = state.schedule();
-    let payload = DefaultNotificationBuilder::new()
-        .title("New messages")
-        .title_loc_key("new_messages") // Localization key for the title.
-        .body("You have new messages")
-        .loc_key("new_messages_body") // Localization key for the body.
-        .sound("default")
-        .mutable_content()
-        .build(
-            &device_token,
-            NotificationOptions {
-                // High priority (10).
-                // <https://developer.apple.com/documentation/usernotifications/sending-notification-requests-to-apns>
-                apns_priority: Some(Priority::High),
-                apns_topic: state.topic(),
-                apns_push_type: Some(PushType::Alert),
-                apns_collapse_id: CollapseId::new("new_messages").ok(),
-                ..Default::default()
-            },
-        );
+    let device_token = match prepare_notification(&state, device_token)? {
+        Prepared::Token(device_token) => device_token,
+        Prepared::Done(status_code) => return Ok(status_code.into_response()),
+    };
 
-    match client.send(payload).await {
-        Ok(_) => {
-            debug!("delivered notification for {}", device_token);
-            state.metrics().direct_notifications_total.inc();
-            Ok(StatusCode::OK)
+    let id = Uuid::new_v4();
+    if prefers_async(&headers) {
+        if !enqueue(&state, id, caller.relay_label(), device_token, options) {
+            return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
         }
-        Err(ResponseError(res)) => {
-            info!("Removing token {} due to error {:?}.", &device_token, res);
-
-            state
-                .metrics()
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::APNS,
-                    reason: res.code.to_string(),
-                    details: res
-                        .error
-                        .as_ref()
-                        .map(|e| e.reason.to_string())
-                        .unwrap_or_default(),
-                })
-                .inc();
+        state.queue().journal().flush().await?;
+        return Ok(accepted(id));
+    }
 
-            let bad_token = if let Some(err) = res.error {
-                err.reason == ErrorReason::BadDeviceToken
-            } else {
-                false
+    let retry_token = state
+        .queue()
+        .retry_policy()
+        .retry_sync
+        .then(|| device_token.clone());
+    let res = deliver(&state, id, &caller.relay_label(), device_token, &options).await;
+    // Errors are permanent, e.g. invalid tokens, and are returned right away.
+    if let (Some(token), Ok(status_code)) = (retry_token, &res) {
+        let status_code = *status_code;
+        let disposition = state
+            .providers()
+            .for_token(&token)
+            .classify(&status_code.into());
+        if disposition == Disposition::Retry {
+            let job = queue::Job {
+                id,
+                relay: caller.relay_label(),
+                token,
+                options,
+                attempts: 0,
             };
-
-            if res.code == 410 || bad_token {
-                // 410 means that "The device token is no longer active for the topic."
-                // <https://developer.apple.com/documentation/usernotifications/handling-notification-responses-from-apns>
-                //
-                // Unsubscribe invalid token from heartbeat notification if it is subscribed.
-                if let Err(err) = schedule.remove_token(&device_token) {
-                    error!("failed to remove {}: {:?}", &device_token, err);
-                }
-                // Return 410 Gone response so email server can remove the token.
-                Ok(StatusCode::GONE)
-            } else {
-                Ok(StatusCode::INTERNAL_SERVER_ERROR)
+            if queue::retry_failed(&state, job, status_code) {
+                debug!("Queued notification {id} for retries.");
+                return Ok(accepted(id));
             }
         }
-        Err(err) => {
-            error!("failed to send notification: {}, {:?}", device_token, err);
-            state
-                .metrics()
-                .failures_total
-                .get_or_create(&FailureLabels {
-                    provider: NotificationProvider::APNS,
-                    reason: "send".to_string(),
-                    details: String::new(),
-                })
-                .inc();
-            Ok(StatusCode::INTERNAL_SERVER_ERROR)
-        }
     }
+    let response = match res {
+        Ok(status_code) => status_code.into_response(),
+        Err(err) => AppError(err).into_response(),
+    };
+    Ok(([(NOTIFICATION_ID_HEADER, id.to_string())], response).into_response())
 }
 
-/// Notifies a single device with a visible notification.
-async fn notify_device(
+/// Returns the response to a notification accepted for background delivery.
+fn accepted(id: Uuid) -> Response {
+    (
+        StatusCode::ACCEPTED,
+        [
+            (header::LOCATION, format!("/notifications/{id}")),
+            (NOTIFICATION_ID_HEADER, id.to_string()),
+        ],
+        Json(AcceptedNotification { id }),
+    )
+        .into_response()
+}
+
+/// Notifies many devices at once.
+///
+/// The body is a JSON array of tokens as accepted by `/notify`.
+/// Notifications are always delivered in the background
+/// as with `Prefer: respond-async`.
+/// The response contains the status and notification ID
+/// of each token in the same order.
+async fn notify_batch(
     axum::extract::State(state): axum::extract::State<State>,
-    mut device_token: String,
-) -> Result<StatusCode, AppError> {
-    // Decrypt the token if it is OpenPGP-encrypted.
-    if let Some(openpgp_device_token) = device_token.strip_prefix("openpgp:") {
-        match state.openpgp_decryptor().decrypt(openpgp_device_token) {
-            Ok(decrypted_device_token) => {
-                device_token = decrypted_device_token;
-            }
-            Err(err) => {
-                error!("Failed to decrypt device token: {:#}.", err);
+    ClientIp(source): ClientIp,
+    Extension(caller): Extension<Caller>,
+    Query(options): Query<NotifyOptions>,
+    body: String,
+) -> Result<Response, AppError> {
+    let device_tokens: Vec<String> = serde_json::from_str(&body)?;
+    if device_tokens.len() > MAX_BATCH_LEN {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+    let limiter = state.notify_rate_limiter();
+    if !check_rate_limit(&state, limiter, "notify", source, device_tokens.len()) {
+        return Ok(StatusCode::TOO_MANY_REQUESTS.into_response());
+    }
+    debug!("Got batch of {} direct notifications.", device_tokens.len());
+    // Decryption blocks.
+    let results = tokio::task::spawn_blocking({
+        let state = state.clone();
+        move || prepare_batch(&state, &caller, &options, device_tokens)
+    })
+    .await?;
+    state.queue().journal().flush().await?;
+    Ok(Json(results).into_response())
+}
 
-                let metrics = state.metrics();
-                metrics.openpgp_decryption_failures_total.inc();
+/// Decrypts, deduplicates and enqueues the tokens of a notification batch.
+fn prepare_batch(
+    state: &State,
+    caller: &Caller,
+    options: &NotifyOptions,
+    device_tokens: Vec<String>,
+) -> Vec<BatchResult> {
+    let mut results = Vec::with_capacity(device_tokens.len());
 
-                // Return 410 Gone response so email server can remove the token.
-                return Ok(StatusCode::GONE);
+    // Results by decrypted token.
+    //
+    // Relays may have registered the same token several times,
+    // encrypted differently each time.
+    // Duplicates get the result of the first notification
+    // instead of being sent to the provider again.
+    let mut seen: HashMap<String, BatchResult> = HashMap::new();
+    for device_token in device_tokens {
+        let device_token = match decrypt_token(state, device_token) {
+            Ok(device_token) => device_token,
+            Err(status) => {
+                results.push(BatchResult { status, id: None });
+                continue;
             }
+        };
+        if let Some(result) = seen.get(&device_token) {
+            debug!(
+                "Reusing result for duplicate token {} in batch.",
+                redact(&device_token)
+            );
+            results.push(result.clone());
+            continue;
         }
+        let result = match prepare_decrypted_notification(state, device_token.clone()) {
+            Ok(Prepared::Token(device_token)) => {
+                let id = Uuid::new_v4();
+                if enqueue(
+                    state,
+                    id,
+                    caller.relay_label(),
+                    device_token,
+                    options.clone(),
+                ) {
+                    BatchResult {
+                        status: StatusCode::ACCEPTED,
+                        id: Some(id),
+                    }
+                } else {
+                    BatchResult {
+                        status: StatusCode::SERVICE_UNAVAILABLE,
+                        id: None,
+                    }
+                }
+            }
+            Ok(Prepared::Done(status)) => BatchResult { status, id: None },
+            Err(err) => {
+                warn!("Failed to prepare notification: {err:#}.");
+                BatchResult {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    id: None,
+                }
+            }
+        };
+        seen.insert(device_token, result.clone());
+        results.push(result);
     }
+    results
+}
 
-    debug!("Got direct notification for {device_token}.");
-    let now = Instant::now();
-    if !state.debouncer().notify(now, device_token.clone()) {
-        // Token is debounced.
-        let metrics = state.metrics();
-        metrics.debounced_notifications_total.inc();
-        metrics
-            .debounced_set_size
-            .set(state.debouncer().count() as i64);
-        return Ok(StatusCode::OK);
-    }
+/// Result of a single item of a batch request.
+#[derive(Debug, Clone, Serialize)]
+struct BatchResult {
+    #[serde(serialize_with = "serialize_status")]
+    status: StatusCode,
+
+    /// Notification ID of a queued notification.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Uuid>,
+}
+
+fn serialize_status<S: serde::Serializer>(
+    status: &StatusCode,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u16(status.as_u16())
+}
+
+/// Response header carrying the ID of an accepted notification.
+///
+/// The same ID is used as `apns-id` and appears in logs,
+/// the event log and metric exemplars.
+const NOTIFICATION_ID_HEADER: HeaderName = HeaderName::from_static("x-notification-id");
+
+/// Returns true if the client asked for an asynchronous response
+/// with `Prefer: respond-async` as defined in RFC 7240.
+fn prefers_async(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case("respond-async"))
+}
+
+/// Returns the delivery status of a notification
+/// accepted with `Prefer: respond-async`.
+async fn notification_status(
+    axum::extract::State(state): axum::extract::State<State>,
+    Extension(caller): Extension<Caller>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<Json<DeliveryStatus>, StatusCode> {
+    state
+        .queue()
+        .status(id, &caller.relay_label())
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Records that the client app displayed the notification.
+async fn receive_receipt(
+    axum::extract::State(state): axum::extract::State<State>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> StatusCode {
+    let Some(provider) = state.receipts().receive(Instant::now(), id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    debug!("Received delivery receipt for notification {id}.");
     state
         .metrics()
-        .debounced_set_size
-        .set(state.debouncer().count() as i64);
-    let device_token: NotificationToken = device_token.as_str().parse()?;
-
-    let status_code = match device_token {
-        NotificationToken::WebPush {
-            endpoint,
-            ua_public_key,
-            ua_auth,
-        } => {
-            let client = state.http_client().clone();
-            let metrics = state.metrics();
-            notify_webpush(
-                &client,
-                state.vapid_key(),
-                &endpoint,
-                &ua_public_key,
-                &ua_auth,
-                metrics,
-            )
-            .await?
+        .notification_receipts_total
+        .get_or_create(&ProviderLabels { provider })
+        .inc();
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Debug, Serialize)]
+struct DebugCode {
+    /// Code to pass to the operator.
+    code: String,
+
+    /// Seconds during which the code can be redeemed.
+    expires_in: u64,
+}
+
+/// Returns a one-time code with which the operator can trace
+/// deliveries to the token in the body.
+async fn request_debug_code(
+    axum::extract::State(state): axum::extract::State<State>,
+    ClientIp(source): ClientIp,
+    device_token: String,
+) -> Response {
+    if !check_rate_limit(
+        &state,
+        state.register_rate_limiter(),
+        "debug_code",
+        source,
+        1,
+    ) || !check_rate_limit(
+        &state,
+        Some(state.debug_code_rate_limiter()),
+        "debug_code",
+        source,
+        1,
+    ) {
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+    let device_token = match decrypt_token(&state, device_token) {
+        Ok(device_token) => device_token,
+        Err(status) => return status.into_response(),
+    };
+    if device_token.parse::<NotificationToken>().is_err() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let Some(code) = state.traces().issue_code(Instant::now(), &device_token) else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    debug!("Issued debug code for {}.", redact(&device_token));
+    Json(DebugCode {
+        code,
+        expires_in: traces::CODE_TTL.as_secs(),
+    })
+    .into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct AcceptedNotification {
+    id: Uuid,
+}
+
+/// Delivers a direct notification,
+/// accounting it in metrics and the event log.
+pub(crate) async fn deliver(
+    state: &State,
+    id: Uuid,
+    relay: &str,
+    device_token: NotificationToken,
+    options: &NotifyOptions,
+) -> Result<StatusCode> {
+    if let NotificationToken::FanOut { policy, tokens } = device_token {
+        // Tokens are accounted separately.
+        let token = format!("fanout:{}", fanout::format(policy, &tokens));
+        let status_code = fanout::deliver(state, id, relay, policy, tokens, options).await?;
+        if status_code == StatusCode::GONE {
+            state.dead_tokens().insert(Instant::now(), token);
         }
-        NotificationToken::UBports(token) => {
-            let client = state.http_client().clone();
-            let metrics = state.metrics();
-            notify_ubports(&client, &token, metrics).await?
+        return Ok(status_code);
+    }
+    let provider = device_token.provider();
+    debug!("Delivering notification {id} via {provider:?}.");
+    let token = device_token.to_string();
+    let mut span = otel::Span::child("provider.send", otel::SpanKind::Client);
+    span.set_attribute("notification.id", id.to_string());
+    span.set_attribute("notification.provider", provider.as_str());
+    let started = Instant::now();
+    let res = dispatch(state, id, device_token, options).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let status_code = match &res {
+        Ok(status_code) => *status_code,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    span.set_attribute("http.response.status_code", status_code.as_u16());
+    match &res {
+        Ok(status_code) if !status_code.is_success() => span.set_error("http_status"),
+        Ok(_) => {}
+        Err(_) => span.set_error("send"),
+    }
+    drop(span);
+    state.traces().record(&token, || {
+        let event = TraceEvent::new("direct").with_status(status_code.as_u16());
+        match &res {
+            Ok(_) => event,
+            Err(err) => event.with_reason(format!("{err:#}")),
         }
-        NotificationToken::Fcm {
-            package_name,
-            token,
-        } => {
-            let client = state.http_client().clone();
-            let metrics = state.metrics();
-            let Ok(fcm_token) = state.fcm_token().await else {
-                metrics
-                    .failures_total
-                    .get_or_create(&FailureLabels {
-                        provider: NotificationProvider::FCM,
-                        reason: "api_key_fetch".to_string(),
-                        details: String::new(),
-                    })
-                    .inc();
-                return Ok(StatusCode::INTERNAL_SERVER_ERROR);
-            };
-            notify_fcm(
-                &client,
-                fcm_token.as_deref(),
-                &package_name,
-                &token,
-                metrics,
-            )
-            .await?
+    });
+    let disposition = state
+        .providers()
+        .get(provider)
+        .classify(&status_code.into());
+    if disposition == Disposition::Gone {
+        state.dead_tokens().insert(Instant::now(), token.clone());
+    }
+    if status_code.is_success() {
+        debug!(
+            notification_id = id.to_string(),
+            provider = provider.as_str(),
+            token:% = redact(&token),
+            status = status_code.as_u16(),
+            latency_ms;
+            "Delivered notification {id}."
+        );
+        if matches!(
+            provider,
+            NotificationProvider::APNS | NotificationProvider::FCM
+        ) {
+            state.receipts().delivered(Instant::now(), id, provider);
+        }
+    } else {
+        // Gone tokens are expected to be removed by the relay,
+        // other failures need attention.
+        let level = if disposition == Disposition::Gone {
+            Level::Info
+        } else {
+            Level::Warn
+        };
+        log!(
+            level,
+            notification_id = id.to_string(),
+            provider = provider.as_str(),
+            token:% = redact(&token),
+            status = status_code.as_u16(),
+            latency_ms;
+            "Failed to deliver notification {id}."
+        );
+    }
+    state
+        .metrics()
+        .relay_notifications_total
+        .get_or_create(&RelayNotificationLabels {
+            relay: relay.to_string(),
+            provider,
+            status: status_code.as_u16(),
+        })
+        .inc_by(
+            1,
+            Some(NotificationIdLabels {
+                notification_id: id.to_string(),
+            }),
+            None,
+        );
+    let status_code = res?;
+    if let Some(event_log) = state.event_log() {
+        event_log.record(
+            &Event::new(NotificationKind::Direct, provider, status_code.as_u16()).with_id(id),
+        );
+    }
+    Ok(status_code)
+}
+
+/// Sends a visible notification to the provider of the token.
+async fn dispatch(
+    state: &State,
+    id: Uuid,
+    device_token: NotificationToken,
+    options: &NotifyOptions,
+) -> Result<StatusCode> {
+    if let NotificationToken::FanOut { .. } = device_token {
+        // Fan-out tokens are delivered by `deliver`.
+        bail!("Cannot dispatch fan-out token");
+    }
+    let provider = state.providers().for_token(&device_token);
+    let delivery = provider
+        .send(state, id, device_token, NotificationKind::Direct, options)
+        .await?;
+    if let Some(failure) = delivery.failure {
+        state.metrics().count_failure(failure);
+    }
+    Ok(delivery.status)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use proptest::sample::{select, Index};
+
+    use super::*;
+
+    /// Number of generated cases per property.
+    const CASES: u32 = 10_000;
+
+    /// Fragments arbitrary tokens are assembled from,
+    /// biased towards prefixes and separators.
+    const FRAGMENTS: &[&str] = &[
+        "fcm-",
+        "ubports-",
+        "webpush:",
+        "sandbox:",
+        "openpgp:",
+        "apns:",
+        "apns-sandbox:",
+        ":",
+        "|",
+        "-",
+        "_",
+        ".",
+        "/",
+        " ",
+        "\n",
+        "\0",
+        "a",
+        "Z",
+        "0",
+        "9",
+        "é",
+        "🔔",
+        "chat.delta",
+        "APA91b",
+    ];
+
+    /// Characters not allowed in FCM tokens.
+    const NON_FCM: &[&str] = &[" ", "/", "|", "\"", "\n", "é", "{"];
+
+    fn arbitrary_string() -> impl Strategy<Value = String> {
+        prop::collection::vec(select(FRAGMENTS), 0..12).prop_map(|fragments| fragments.concat())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(CASES))]
+
+        #[test]
+        fn test_parse_arbitrary(s in arbitrary_string()) {
+            // Parsing must not panic, and accepted tokens must round-trip.
+            if let Ok(token) = s.parse::<NotificationToken>() {
+                prop_assert_eq!(token.to_string(), s);
+            }
         }
-        NotificationToken::ApnsSandbox(token) => {
-            let client = state.sandbox_client().clone();
-            notify_apns(state, client, token).await?
+
+        #[test]
+        fn test_fcm_charset(
+            package_name in "[A-Za-z0-9._]{1,39}",
+            token in "[A-Za-z0-9_:-]{1,39}",
+            invalid in select(NON_FCM),
+            pos in any::<Index>(),
+        ) {
+            let s = format!("fcm-{package_name}:{token}");
+            prop_assert_eq!(s.parse::<NotificationToken>().unwrap().to_string(), s);
+
+            // Any character outside the charset is rejected.
+            let pos = pos.index(token.len() + 1);
+            let s = format!(
+                "fcm-{package_name}:{}{invalid}{}",
+                &token[..pos],
+                &token[pos..]
+            );
+            prop_assert!(s.parse::<NotificationToken>().is_err(), "{:?}", s);
         }
-        NotificationToken::ApnsProduction(token) => {
-            let client = state.production_client().clone();
-            notify_apns(state, client, token).await?
+
+        #[test]
+        fn test_roundtrip_prefixes(
+            a in "[A-Za-z0-9_.:/-]{0,39}",
+            b in "[A-Za-z0-9_.:/-]{0,39}",
+            c in "[A-Za-z0-9_.:/|-]{0,39}",
+            hex in "[0-9a-f]{1,39}",
+        ) {
+            for s in [
+                hex.clone(),
+                format!("sandbox:{a}"),
+                format!("safari:{a}"),
+                format!("safari-sandbox:{a}"),
+                format!("vivo:{}", hex.clone()),
+                format!("oppo:{}", hex.clone()),
+                format!("apns:chat.delta.testflight:{hex}"),
+                format!("apns-sandbox:chat.delta.testflight:{hex}"),
+                format!("ubports-{a}"),
+                format!("webpush:https://push.example.org/{a}|{b}|{c}"),
+                format!("up:https://ntfy.example.org/{a}"),
+                format!("webhook:https://hooks.example.org/{a}"),
+                format!("wns:https://db5p.notify.windows.com/?token={hex}"),
+                format!(
+                    r#"fanout:first:["fcm-chat.delta:{hex}","up:https://ntfy.example.org/{a}"]"#
+                ),
+            ] {
+                let token: NotificationToken = s.parse().unwrap();
+                prop_assert_eq!(token.to_string(), s);
+            }
         }
-    };
-    Ok(status_code)
+    }
+
+    #[test]
+    fn test_parse_fcm() {
+        assert!("fcm-".parse::<NotificationToken>().is_err());
+        assert!("fcm-chat.delta".parse::<NotificationToken>().is_err());
+        assert!("fcm-chat.delta:".parse::<NotificationToken>().is_err());
+        assert!("fcm-:token".parse::<NotificationToken>().is_err());
+        assert!("fcm-chat/delta:token".parse::<NotificationToken>().is_err());
+    }
+
+    #[test]
+    fn test_parse_prefixes() {
+        assert!(matches!(
+            "sandbox:abc".parse(),
+            Ok(NotificationToken::ApnsSandbox(token)) if token == "abc"
+        ));
+        assert!(matches!(
+            "abc".parse(),
+            Ok(NotificationToken::ApnsProduction(token)) if token == "abc"
+        ));
+        assert!("webpush:endpoint|key".parse::<NotificationToken>().is_err());
+        assert!("webpush:endpoint|key|auth"
+            .parse::<NotificationToken>()
+            .is_err());
+        assert!("webpush:https://10.0.0.1/|key|auth"
+            .parse::<NotificationToken>()
+            .is_err());
+        assert!("up:http://localhost:8080/up123"
+            .parse::<NotificationToken>()
+            .is_err());
+        assert!("up:not a url".parse::<NotificationToken>().is_err());
+        assert!(matches!(
+            "apns-sandbox:chat.delta.testflight:abc".parse(),
+            Ok(NotificationToken::ApnsApp { topic, sandbox: true, token })
+                if topic == "chat.delta.testflight" && token == "abc"
+        ));
+        assert!("apns:chat.delta".parse::<NotificationToken>().is_err());
+        assert!("apns::abc".parse::<NotificationToken>().is_err());
+
+        let onesignal =
+            "onesignal:b2f7f966-d8cc-11e4-bed1-df8f05be55ba:6392d91a-b206-4b7b-a620-cd68e32c3a76";
+        assert!(matches!(
+            onesignal.parse(),
+            Ok(NotificationToken::OneSignal { app_id, .. }) if app_id == "b2f7f966-d8cc-11e4-bed1-df8f05be55ba"
+        ));
+        assert_eq!(
+            onesignal.parse::<NotificationToken>().unwrap().to_string(),
+            onesignal
+        );
+        assert!("onesignal:app:player".parse::<NotificationToken>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_registered_token() {
+        assert!(check_registered_token("0123456789abcdef").is_ok());
+
+        let err = check_registered_token("abc\0").unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::BAD_REQUEST);
+        let response = AppError(err).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"Token contains control characters");
+
+        let err = anyhow::anyhow!("Database is closed");
+        assert_eq!(error_status(&err), StatusCode::INTERNAL_SERVER_ERROR);
+    }
 }
@@ -1,18 +1,312 @@
-use std::io::{Read, Seek};
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context as _, Result};
+use anyhow::{bail, Context as _, Result};
 use apns_h2::{Client, ClientConfig, Endpoint};
 use base64::Engine as _;
+use parking_lot::RwLock;
+use tokio::sync::watch;
 use web_push_native::jwt_simple::prelude::ECDSAP256PublicKeyLike as _;
 use web_push_native::p256::pkcs8::DecodePrivateKey as _;
 
+use crate::apikeys::ApiKeys;
+use crate::callbacks::Callbacks;
+use crate::config::{ApnsAppConfig, Config};
+use crate::deadtokens::DeadTokens;
 use crate::debouncer::Debouncer;
-use crate::metrics::Metrics;
+use crate::endpoints::Endpoints;
+use crate::eventlog::EventLog;
+use crate::exclusion::ExclusionWindow;
+use crate::expiry;
+use crate::fcm::{self, FcmProjectConfig, FcmProjects};
+use crate::flags::Flags;
+use crate::metrics::{self, Metrics, NotificationProvider, QuarantineLabels};
+use crate::notifier::Heartbeats;
+use crate::onesignal::OneSignal;
 use crate::openpgp::PgpDecryptor;
+use crate::oppo::Oppo;
+use crate::provider::Providers;
+use crate::proxy::TrustedProxies;
+use crate::queue::{Journal, Queue, RetryPolicy};
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::receipts::Receipts;
+use crate::sanitize::PublicResolver;
+use crate::schedule::storage::StorageKind;
 use crate::schedule::Schedule;
+use crate::stats::StatsNoise;
+use crate::templates::Templates;
+use crate::traces::{self, Traces};
+use crate::vivo::Vivo;
+use crate::webhook::Webhooks;
+use crate::wns::Wns;
+
+/// Settings for outbound connections to notification providers.
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    /// Interval of HTTP/2 PING frames sent on idle connections.
+    ///
+    /// `None` disables pings.
+    pub http2_keepalive_interval: Option<Duration>,
+
+    /// Time to wait for a PING acknowledgement
+    /// before closing an unresponsive connection.
+    ///
+    /// The APNS client always uses the library default of 20 seconds.
+    pub http2_keepalive_timeout: Duration,
+
+    /// Time after which idle APNS connections are closed.
+    ///
+    /// `None` keeps idle connections open.
+    pub apns_pool_idle_timeout: Option<Duration>,
+
+    /// Interval at which APNS clients are recreated,
+    /// forcing new connections.
+    ///
+    /// `None` reuses connections as long as they work.
+    pub apns_max_connection_age: Option<Duration>,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            http2_keepalive_interval: Some(Duration::from_secs(4 * 60)),
+            http2_keepalive_timeout: Duration::from_secs(20),
+            apns_pool_idle_timeout: None,
+            apns_max_connection_age: None,
+        }
+    }
+}
+
+impl ClientSettings {
+    fn http_client_builder(&self) -> reqwest::ClientBuilder {
+        // Redirects could lead requests to URLs from tokens into private networks.
+        let builder = reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(60))
+            .redirect(reqwest::redirect::Policy::none());
+        if let Some(interval) = self.http2_keepalive_interval {
+            builder
+                .http2_keep_alive_interval(interval)
+                .http2_keep_alive_timeout(self.http2_keepalive_timeout)
+                .http2_keep_alive_while_idle(true)
+        } else {
+            builder
+        }
+    }
+
+    fn apns_config(&self, endpoint: Endpoint) -> ClientConfig {
+        ClientConfig {
+            http2_keep_alive_interval: self.http2_keepalive_interval,
+            http2_keep_alive_while_idle: self.http2_keepalive_interval.is_some(),
+            pool_idle_timeout: self.apns_pool_idle_timeout,
+            ..ClientConfig::new(endpoint)
+        }
+    }
+
+    /// Creates APNS production and sandbox clients.
+    fn apns_clients(&self, credentials: &ApnsCredentials) -> ApnsClients {
+        let client = |endpoint: Endpoint| {
+            let config = self.apns_config(endpoint.clone());
+            let client = match credentials {
+                ApnsCredentials::Certificate {
+                    certificate,
+                    password,
+                } => Client::certificate(&mut &certificate[..], password, config),
+                ApnsCredentials::Token {
+                    key,
+                    key_id,
+                    team_id,
+                } => Client::token(&mut &key[..], key_id.as_str(), team_id.as_str(), config),
+            };
+            client.map(|client| ApnsClient { client, endpoint })
+        };
+        ApnsClients {
+            production: client(Endpoint::Production).ok(),
+            sandbox: client(Endpoint::Sandbox).ok(),
+        }
+    }
+}
+
+/// Settings of the gateway from the command line.
+#[derive(Debug)]
+pub struct Settings {
+    /// Path of the schedule database.
+    pub db: PathBuf,
+
+    /// Storage backend of the schedule.
+    pub storage: StorageKind,
+
+    /// APNS topic of the app.
+    pub topic: Option<String>,
+
+    /// Website push ID for Safari notifications.
+    pub safari_topic: Option<String>,
+
+    /// Default heartbeat interval.
+    pub interval: Duration,
+
+    /// Service account key of the default Firebase project.
+    pub fcm_key: Option<FcmKey>,
+
+    /// Path of the VAPID private key for WebPush.
+    pub vapid_key_path: Option<PathBuf>,
+
+    /// Contact URI of the operator sent to Web Push services with VAPID.
+    pub vapid_subject: String,
+
+    /// Paths of the OpenPGP keyrings for decrypting tokens.
+    pub openpgp_keyring_paths: Vec<PathBuf>,
+
+    /// Maximum age of encrypted token envelopes accepted by `/register`.
+    pub openpgp_max_token_age: Option<Duration>,
+
+    /// Maximum number of tokens kept by the debouncer.
+    pub debounce_max_entries: usize,
+
+    /// Time during which notifications to tokens reported gone
+    /// are answered without contacting the provider.
+    pub dead_token_ttl: Duration,
+
+    /// Proxies allowed to set the client address.
+    pub trusted_proxies: TrustedProxies,
+
+    /// Rate limit of registrations per client.
+    pub register_rate_limit: Option<RateLimit>,
+
+    /// Rate limit of notifications per client.
+    pub notify_rate_limit: Option<RateLimit>,
+
+    /// Proof-of-work difficulty required for registrations.
+    pub register_pow_difficulty: Option<u8>,
+
+    /// Maximum number of heartbeat tokens a single relay may register.
+    pub relay_token_quota: Option<usize>,
+
+    /// Settings for outbound connections.
+    pub client_settings: ClientSettings,
+
+    /// Maximum number of notifications waiting for background delivery.
+    pub queue_size: usize,
+
+    /// Retries of failed queued notifications.
+    pub retry_policy: RetryPolicy,
+
+    /// Noise added to published statistics.
+    pub stats_noise: Option<StatsNoise>,
+
+    /// Whether notifications are accounted without contacting providers.
+    pub dry_run: bool,
+}
+
+/// Credentials for authenticating to APNS.
+#[derive(Clone)]
+pub enum ApnsCredentials {
+    /// PKCS12 certificate and its password.
+    Certificate {
+        certificate: Vec<u8>,
+        password: String,
+    },
+
+    /// Provider token authentication with a `.p8` signing key.
+    ///
+    /// Unlike certificates, signing keys do not expire.
+    Token {
+        /// PKCS8 PEM-encoded private key.
+        key: Vec<u8>,
+        key_id: String,
+        team_id: String,
+    },
+}
+
+impl ApnsCredentials {
+    /// Reads the credentials of an additional APNS app.
+    fn load(config: &ApnsAppConfig) -> Result<Self> {
+        match (&config.certificate_path, &config.key_path) {
+            (Some(certificate_path), None) => Ok(Self::Certificate {
+                certificate: std::fs::read(certificate_path).with_context(|| {
+                    format!("Failed to read certificate {}", certificate_path.display())
+                })?,
+                password: config.password.clone(),
+            }),
+            (None, Some(key_path)) => Ok(Self::Token {
+                key: std::fs::read(key_path).with_context(|| {
+                    format!("Failed to read APNS signing key {}", key_path.display())
+                })?,
+                key_id: config.key_id.clone(),
+                team_id: config.team_id.clone(),
+            }),
+            _ => bail!(
+                "APNS app {} must have either certificate_path or key_path",
+                config.topic
+            ),
+        }
+    }
+}
+
+/// APNS app configured in addition to the default one.
+struct ApnsApp {
+    config: ApnsAppConfig,
+    credentials: RwLock<ApnsCredentials>,
+    clients: RwLock<ApnsClients>,
+}
+
+/// Service account key of the default Firebase project.
+#[derive(Debug, Clone)]
+pub enum FcmKey {
+    /// Path to the JSON key file.
+    Path(PathBuf),
+
+    /// Contents of the JSON key.
+    Json(String),
+}
+
+/// APNS client along with the endpoint it connects to.
+#[derive(Clone)]
+pub struct ApnsClient {
+    client: Client,
+    endpoint: Endpoint,
+}
+
+impl ApnsClient {
+    /// Returns the endpoint label of request metrics.
+    pub(crate) fn endpoint_label(&self) -> &'static str {
+        match self.endpoint {
+            Endpoint::Production => "production",
+            Endpoint::Sandbox => "sandbox",
+        }
+    }
+
+    /// Sends a single request and records its duration.
+    pub(crate) async fn send(
+        &self,
+        payload: apns_h2::request::payload::Payload<'_>,
+        metrics: &Metrics,
+    ) -> Result<apns_h2::Response, apns_h2::Error> {
+        let start = Instant::now();
+        let res = self.client.send(payload).await;
+        metrics.observe_request_duration(
+            NotificationProvider::APNS,
+            self.endpoint_label(),
+            metrics::apns_outcome(&res),
+            start.elapsed(),
+        );
+        res
+    }
+}
+
+#[derive(Default)]
+struct ApnsClients {
+    production: Option<ApnsClient>,
+    sandbox: Option<ApnsClient>,
+}
+
+impl ApnsClients {
+    fn is_complete(&self) -> bool {
+        self.production.is_some() && self.sandbox.is_some()
+    }
+}
 
 #[derive(Clone)]
 pub struct State {
@@ -24,83 +318,233 @@ pub struct InnerState {
 
     http_client: reqwest::Client,
 
-    apns_production_client: Option<Client>,
+    /// HTTP client for URLs from tokens,
+    /// only connecting to public addresses.
+    public_http_client: reqwest::Client,
+
+    /// Outbound FCM endpoints in the order of preference.
+    fcm_endpoints: Endpoints,
+
+    /// APNS credentials kept to recreate the clients.
+    apns_credentials: RwLock<Option<ApnsCredentials>>,
+
+    apns_clients: RwLock<ApnsClients>,
+
+    /// Additional APNS apps by topic.
+    apns_apps: HashMap<String, ApnsApp>,
 
-    apns_sandbox_client: Option<Client>,
+    client_settings: ClientSettings,
 
     topic: Option<String>,
 
+    /// Website push ID used as the topic of Safari notifications.
+    safari_topic: Option<String>,
+
     metrics: Metrics,
 
     /// Heartbeat notification interval.
     interval: Duration,
 
-    fcm_authenticator: Option<yup_oauth2::authenticator::DefaultAuthenticator>,
+    /// Firebase projects by package name.
+    fcm_projects: RwLock<Arc<FcmProjects>>,
+
+    /// Service account key of the default Firebase project.
+    fcm_key: Option<FcmKey>,
+
+    /// Firebase projects defined in the configuration file.
+    fcm_project_configs: Vec<FcmProjectConfig>,
 
     vapid_key: Option<web_push_native::jwt_simple::prelude::ES256KeyPair>,
 
+    /// Contact URI sent as the VAPID `sub` claim.
+    vapid_subject: String,
+
     /// Decryptor for incoming tokens
     /// storing the secret keyring inside.
     openpgp_decryptor: PgpDecryptor,
 
+    /// Debouncer for direct notifications.
     debouncer: Debouncer,
+
+    /// Pace and round of heartbeats shared by the notifier workers.
+    heartbeats: Heartbeats,
+
+    /// Tokens recently reported gone by the providers.
+    dead_tokens: DeadTokens,
+
+    /// Reverse proxies allowed to set `X-Forwarded-For`.
+    trusted_proxies: TrustedProxies,
+
+    /// Optional JSONL log of delivery events.
+    event_log: Option<EventLog>,
+
+    /// Per-source rate limiter for `/register`.
+    register_rate_limiter: Option<RateLimiter>,
+
+    /// Per-source rate limiter for `/notify`.
+    notify_rate_limiter: Option<RateLimiter>,
+
+    /// Per-source rate limiter for `/debug-codes`.
+    debug_code_rate_limiter: RateLimiter,
+
+    /// Number of leading zero bits required from registration proof-of-work.
+    register_pow_difficulty: Option<u8>,
+
+    /// Maximum number of heartbeat tokens registered per relay.
+    relay_token_quota: Option<usize>,
+
+    /// Maximum age of OpenPGP token envelopes accepted by `/register`.
+    openpgp_max_token_age: Option<Duration>,
+
+    /// API keys for authenticating callers.
+    api_keys: ApiKeys,
+
+    flags: Flags,
+
+    /// Queue of notifications accepted for background delivery.
+    queue: Queue,
+
+    /// Token invalidation callbacks to relays.
+    callbacks: Callbacks,
+
+    onesignal: OneSignal,
+
+    webhooks: Webhooks,
+
+    /// Registry of notification providers.
+    providers: Providers,
+
+    vivo: Vivo,
+
+    wns: Wns,
+
+    oppo: Oppo,
+
+    /// Notifications awaiting a delivery receipt.
+    receipts: Receipts,
+
+    /// Delivery traces of tokens with redeemed debug codes.
+    traces: Traces,
+
+    /// Notification payload templates.
+    templates: Templates,
+
+    /// Daily windows in which no heartbeats are sent.
+    heartbeat_exclusions: Vec<ExclusionWindow>,
+
+    /// Noise added to published statistics.
+    stats_noise: Option<StatsNoise>,
+
+    /// Set when the gateway stops taking new traffic before shutting down.
+    draining: AtomicBool,
+
+    /// Set to true when the gateway is shutting down.
+    shutdown: watch::Sender<bool>,
 }
 
 impl State {
-    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        db: &Path,
-        certificate: Option<std::fs::File>,
-        password: &str,
-        topic: Option<String>,
+        settings: Settings,
+        config: Config,
+        apns_credentials: Option<ApnsCredentials>,
         metrics: Metrics,
-        interval: Duration,
-        fcm_key_path: Option<PathBuf>,
-        vapid_key_path: Option<PathBuf>,
-        openpgp_keyring_path: String,
+        event_log: Option<EventLog>,
     ) -> Result<Self> {
-        let schedule = Schedule::new(db)?;
-        let http_client = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(60))
+        let Settings {
+            db,
+            storage,
+            topic,
+            safari_topic,
+            interval,
+            fcm_key,
+            vapid_key_path,
+            vapid_subject,
+            openpgp_keyring_paths,
+            openpgp_max_token_age,
+            debounce_max_entries,
+            dead_token_ttl,
+            trusted_proxies,
+            register_rate_limit,
+            notify_rate_limit,
+            register_pow_difficulty,
+            relay_token_quota,
+            client_settings,
+            queue_size,
+            retry_policy,
+            stats_noise,
+            dry_run,
+        } = settings;
+        let schedule = Schedule::open(storage, &db)?;
+        schedule.set_cache_counters(
+            metrics.schedule_cache_hits_total.clone(),
+            metrics.schedule_cache_misses_total.clone(),
+        );
+        for (reason, count) in &schedule.integrity().quarantined {
+            metrics
+                .schedule_quarantined_entries
+                .get_or_create(&QuarantineLabels {
+                    reason: reason.to_string(),
+                })
+                .inc_by(*count as u64);
+        }
+        let api_keys = ApiKeys::new(
+            schedule.open_tree("api_keys")?,
+            &config.api_keys,
+            config.anonymous_scopes.clone(),
+        )?;
+        let flags = Flags::new(schedule.open_tree("flags")?)?;
+        let journal = Journal::new(schedule.open_tree("pending")?);
+        let debouncer = Debouncer::default().with_max_entries(
+            debounce_max_entries,
+            metrics.debouncer_evictions_total.clone(),
+        );
+        let http_client = client_settings
+            .http_client_builder()
+            .build()
+            .context("Failed to build HTTP client (FCM/UBPorts)")?;
+        let public_http_client = client_settings
+            .http_client_builder()
+            .dns_resolver(Arc::new(PublicResolver))
             .build()
-            .context("Failed to build HTTP client (FCM/UBPorts/WebPush)")?;
-
-        let fcm_authenticator = if let Some(fcm_key_path) = fcm_key_path {
-            let key: yup_oauth2::ServiceAccountKey =
-                yup_oauth2::read_service_account_key(fcm_key_path)
-                    .await
-                    .context("Failed to read key")?;
-            let authenticator = yup_oauth2::ServiceAccountAuthenticator::builder(key)
-                .build()
-                .await
-                .context("Failed to create authenticator")?;
-            Some(authenticator)
+            .context("Failed to build HTTP client (WebPush/UnifiedPush/WNS)")?;
+        let callbacks = Callbacks::new(&config, http_client.clone());
+        let onesignal = OneSignal::new(&config, http_client.clone());
+        let webhooks = Webhooks::new(config.webhook.clone(), http_client.clone());
+        let vivo = Vivo::new(config.vivo.clone(), http_client.clone());
+        let wns = Wns::new(config.wns.clone(), public_http_client.clone());
+        let oppo = Oppo::new(config.oppo.clone(), http_client.clone());
+        let fcm_endpoints = Endpoints::new(
+            &config.fcm_endpoints,
+            config.fcm_url.as_deref().unwrap_or(fcm::DEFAULT_URL),
+            &http_client,
+            || client_settings.http_client_builder(),
+        )?;
+
+        let fcm_projects = FcmProjects::new(fcm_key.as_ref(), &config.fcm_projects).await?;
+
+        let apns_clients = if let Some(credentials) = &apns_credentials {
+            client_settings.apns_clients(credentials)
         } else {
-            None
+            ApnsClients::default()
         };
 
-        let (apns_production_client, apns_sandbox_client) = if let Some(mut cert_file) = certificate
-        {
-            let production_client = Client::certificate(
-                &mut cert_file,
-                password,
-                ClientConfig::new(Endpoint::Production),
-            )
-            .ok();
-
-            cert_file.rewind()?;
-
-            let sandbox_client = Client::certificate(
-                &mut cert_file,
-                password,
-                ClientConfig::new(Endpoint::Sandbox),
-            )
-            .ok();
-
-            (production_client, sandbox_client)
-        } else {
-            (None, None)
-        };
+        let mut apns_apps = HashMap::new();
+        for app in &config.apns_apps {
+            if apns_apps.contains_key(&app.topic) {
+                bail!("Duplicate APNS app {}", app.topic);
+            }
+            let credentials = ApnsCredentials::load(app)?;
+            let clients = client_settings.apns_clients(&credentials);
+            if clients.production.is_none() || clients.sandbox.is_none() {
+                log::warn!("Failed to create APNS clients for {}.", app.topic);
+            }
+            let app_state = ApnsApp {
+                config: app.clone(),
+                credentials: RwLock::new(credentials),
+                clients: RwLock::new(clients),
+            };
+            apns_apps.insert(app.topic.clone(), app_state);
+        }
 
         let vapid_key = if let Some(vapid_key_path) = vapid_key_path {
             let p256_sk =
@@ -116,37 +560,80 @@ impl State {
             None
         };
 
-        let mut keyring_file = std::fs::File::open(openpgp_keyring_path)?;
-        let mut keyring = String::new();
-        keyring_file.read_to_string(&mut keyring)?;
-        let openpgp_decryptor = PgpDecryptor::new(&keyring)?;
+        let openpgp_decryptor = PgpDecryptor::load(&openpgp_keyring_paths)?;
+        log::info!(
+            "Decrypting OpenPGP tokens with keys {}.",
+            openpgp_decryptor.key_ids().join(", ")
+        );
 
-        if apns_production_client.is_none() {
+        if apns_clients.production.is_none() {
             log::warn!("Starting without APNS production client!");
         }
-        if apns_sandbox_client.is_none() {
+        if apns_clients.sandbox.is_none() {
             log::warn!("Starting without APNS sandbox client!");
         }
-        if fcm_authenticator.is_none() {
+        if !fcm_projects.default_project().has_authenticator() {
             log::warn!("Starting without FCM authenticator!");
         }
         if vapid_key.is_none() {
             log::warn!("Starting without VAPID key!");
         }
+        if let Some(safari_topic) = &safari_topic {
+            if !apns_apps.contains_key(safari_topic) {
+                log::warn!(
+                    "Starting without Safari client, no APNS app configured for {safari_topic}!"
+                );
+            }
+        }
 
         Ok(State {
             inner: Arc::new(InnerState {
                 schedule,
                 http_client,
-                apns_production_client,
-                apns_sandbox_client,
+                public_http_client,
+                fcm_endpoints,
+                apns_credentials: RwLock::new(apns_credentials),
+                apns_clients: RwLock::new(apns_clients),
+                apns_apps,
+                client_settings,
                 topic,
+                safari_topic,
                 metrics,
                 interval,
-                fcm_authenticator,
+                fcm_projects: RwLock::new(Arc::new(fcm_projects)),
+                fcm_key,
+                fcm_project_configs: config.fcm_projects.clone(),
                 vapid_key,
+                vapid_subject,
                 openpgp_decryptor,
-                debouncer: Default::default(),
+                debouncer,
+                heartbeats: Heartbeats::new(interval),
+                dead_tokens: DeadTokens::new(dead_token_ttl),
+                trusted_proxies,
+                event_log,
+                register_rate_limiter: register_rate_limit.map(RateLimiter::new),
+                notify_rate_limiter: notify_rate_limit.map(RateLimiter::new),
+                debug_code_rate_limiter: RateLimiter::new(traces::CODE_RATE_LIMIT),
+                register_pow_difficulty: register_pow_difficulty.filter(|d| *d > 0),
+                relay_token_quota,
+                openpgp_max_token_age,
+                api_keys,
+                flags,
+                queue: Queue::new(queue_size, retry_policy, journal),
+                callbacks,
+                onesignal,
+                webhooks,
+                providers: Providers::new(config.chaos.as_ref(), dry_run)?,
+                vivo,
+                wns,
+                oppo,
+                receipts: Receipts::default(),
+                traces: Traces::default(),
+                templates: Templates::new(&config.templates),
+                heartbeat_exclusions: config.heartbeat_exclusions.clone(),
+                stats_noise,
+                draining: AtomicBool::new(false),
+                shutdown: watch::Sender::new(false),
             }),
         })
     }
@@ -159,35 +646,148 @@ impl State {
         &self.inner.http_client
     }
 
-    pub async fn fcm_token(&self) -> Result<Option<String>> {
-        let token = if let Some(authenticator) = &self.inner.fcm_authenticator {
-            authenticator
-                .token(&["https://www.googleapis.com/auth/firebase.messaging"])
-                .await?
-                .token()
-                .map(|s| s.to_string())
-        } else {
-            None
-        };
-        Ok(token)
+    /// Returns the HTTP client for URLs from tokens.
+    pub(crate) fn public_http_client(&self) -> &reqwest::Client {
+        &self.inner.public_http_client
+    }
+
+    pub(crate) fn fcm_endpoints(&self) -> &Endpoints {
+        &self.inner.fcm_endpoints
+    }
+
+    pub(crate) fn fcm_projects(&self) -> Arc<FcmProjects> {
+        self.inner.fcm_projects.read().clone()
     }
 
     pub fn vapid_key(&self) -> &Option<web_push_native::jwt_simple::prelude::ES256KeyPair> {
         &self.inner.vapid_key
     }
 
-    pub fn production_client(&self) -> &Option<Client> {
-        &self.inner.apns_production_client
+    pub(crate) fn vapid_subject(&self) -> &str {
+        &self.inner.vapid_subject
+    }
+
+    pub fn production_client(&self) -> Option<ApnsClient> {
+        self.inner.apns_clients.read().production.clone()
+    }
+
+    pub fn sandbox_client(&self) -> Option<ApnsClient> {
+        self.inner.apns_clients.read().sandbox.clone()
+    }
+
+    /// Returns the client of an additional APNS app.
+    ///
+    /// Returns `None` if the app is not configured
+    /// or its client could not be created.
+    pub(crate) fn apns_app_client(&self, topic: &str, sandbox: bool) -> Option<ApnsClient> {
+        let clients = self.inner.apns_apps.get(topic)?.clients.read();
+        if sandbox {
+            clients.sandbox.clone()
+        } else {
+            clients.production.clone()
+        }
+    }
+
+    /// Replaces APNS clients with new ones,
+    /// so the following requests use new connections.
+    ///
+    /// Requests in progress finish on the old connections.
+    pub fn refresh_apns_clients(&self) {
+        if let Some(credentials) = &*self.inner.apns_credentials.read() {
+            self.refresh_clients("APNS", credentials, &self.inner.apns_clients);
+        }
+        for (topic, app) in &self.inner.apns_apps {
+            self.refresh_clients(topic, &app.credentials.read(), &app.clients);
+        }
+    }
+
+    /// Replaces APNS and FCM credentials with ones read again from disk.
+    ///
+    /// `apns_credentials` are the credentials of the default APNS app.
+    /// Credentials are only replaced if all of them are valid,
+    /// otherwise the old ones are kept.
+    /// Requests in progress finish with the old credentials.
+    pub async fn reload_credentials(
+        &self,
+        apns_credentials: Option<ApnsCredentials>,
+    ) -> Result<()> {
+        let settings = &self.inner.client_settings;
+        let apns_clients = match &apns_credentials {
+            Some(credentials) => {
+                let clients = settings.apns_clients(credentials);
+                if clients.production.is_none() || clients.sandbox.is_none() {
+                    bail!("Failed to create APNS clients");
+                }
+                clients
+            }
+            None => ApnsClients::default(),
+        };
+        let mut apps = Vec::new();
+        for (topic, app) in &self.inner.apns_apps {
+            let credentials = ApnsCredentials::load(&app.config)?;
+            let clients = settings.apns_clients(&credentials);
+            if clients.production.is_none() || clients.sandbox.is_none() {
+                bail!("Failed to create APNS clients for {topic}");
+            }
+            apps.push((app, credentials, clients));
+        }
+        let fcm_projects =
+            FcmProjects::new(self.inner.fcm_key.as_ref(), &self.inner.fcm_project_configs).await?;
+
+        *self.inner.apns_credentials.write() = apns_credentials;
+        *self.inner.apns_clients.write() = apns_clients;
+        for (app, credentials, clients) in apps {
+            *app.credentials.write() = credentials;
+            *app.clients.write() = clients;
+        }
+        let old_fcm_projects = std::mem::replace(
+            &mut *self.inner.fcm_projects.write(),
+            Arc::new(fcm_projects),
+        );
+        old_fcm_projects.abort_refreshes();
+        expiry::update(self);
+        log::info!("Reloaded APNS and FCM credentials.");
+        Ok(())
     }
 
-    pub fn sandbox_client(&self) -> &Option<Client> {
-        &self.inner.apns_sandbox_client
+    fn refresh_clients(
+        &self,
+        name: &str,
+        credentials: &ApnsCredentials,
+        clients: &RwLock<ApnsClients>,
+    ) {
+        let apns_clients = self.inner.client_settings.apns_clients(credentials);
+        if apns_clients.production.is_none() || apns_clients.sandbox.is_none() {
+            log::warn!("Failed to recreate {name} clients, keeping the old ones.");
+            return;
+        }
+        *clients.write() = apns_clients;
+        log::debug!("Recreated {name} clients.");
+    }
+
+    /// Returns true if clients of all configured APNS apps are constructed.
+    pub(crate) fn apns_ready(&self) -> bool {
+        (self.inner.apns_credentials.read().is_none()
+            || self.inner.apns_clients.read().is_complete())
+            && self
+                .inner
+                .apns_apps
+                .values()
+                .all(|app| app.clients.read().is_complete())
+    }
+
+    pub(crate) fn apns_credentials(&self) -> Option<ApnsCredentials> {
+        self.inner.apns_credentials.read().clone()
     }
 
     pub fn topic(&self) -> Option<&str> {
         self.inner.topic.as_deref()
     }
 
+    pub fn safari_topic(&self) -> Option<&str> {
+        self.inner.safari_topic.as_deref()
+    }
+
     pub fn metrics(&self) -> &Metrics {
         &self.inner.metrics
     }
@@ -203,4 +803,150 @@ impl State {
     pub(crate) fn debouncer(&self) -> &Debouncer {
         &self.inner.debouncer
     }
+
+    pub(crate) fn heartbeats(&self) -> &Heartbeats {
+        &self.inner.heartbeats
+    }
+
+    pub(crate) fn dead_tokens(&self) -> &DeadTokens {
+        &self.inner.dead_tokens
+    }
+
+    pub(crate) fn trusted_proxies(&self) -> &TrustedProxies {
+        &self.inner.trusted_proxies
+    }
+
+    pub(crate) fn register_rate_limiter(&self) -> Option<&RateLimiter> {
+        self.inner.register_rate_limiter.as_ref()
+    }
+
+    pub(crate) fn notify_rate_limiter(&self) -> Option<&RateLimiter> {
+        self.inner.notify_rate_limiter.as_ref()
+    }
+
+    pub(crate) fn debug_code_rate_limiter(&self) -> &RateLimiter {
+        &self.inner.debug_code_rate_limiter
+    }
+
+    pub fn register_pow_difficulty(&self) -> Option<u8> {
+        self.inner.register_pow_difficulty
+    }
+
+    pub fn openpgp_max_token_age(&self) -> Option<Duration> {
+        self.inner.openpgp_max_token_age
+    }
+
+    pub fn relay_token_quota(&self) -> Option<usize> {
+        self.inner.relay_token_quota
+    }
+
+    pub(crate) fn api_keys(&self) -> &ApiKeys {
+        &self.inner.api_keys
+    }
+
+    pub(crate) fn flags(&self) -> &Flags {
+        &self.inner.flags
+    }
+
+    pub(crate) fn callbacks(&self) -> &Callbacks {
+        &self.inner.callbacks
+    }
+
+    pub(crate) fn onesignal(&self) -> &OneSignal {
+        &self.inner.onesignal
+    }
+
+    pub(crate) fn webhooks(&self) -> &Webhooks {
+        &self.inner.webhooks
+    }
+
+    pub(crate) fn providers(&self) -> &Providers {
+        &self.inner.providers
+    }
+
+    pub(crate) fn vivo(&self) -> &Vivo {
+        &self.inner.vivo
+    }
+
+    pub(crate) fn wns(&self) -> &Wns {
+        &self.inner.wns
+    }
+
+    pub(crate) fn oppo(&self) -> &Oppo {
+        &self.inner.oppo
+    }
+
+    pub(crate) fn heartbeat_exclusions(&self) -> &[ExclusionWindow] {
+        &self.inner.heartbeat_exclusions
+    }
+
+    pub(crate) fn templates(&self) -> &Templates {
+        &self.inner.templates
+    }
+
+    pub(crate) fn stats_noise(&self) -> Option<&StatsNoise> {
+        self.inner.stats_noise.as_ref()
+    }
+
+    pub(crate) fn receipts(&self) -> &Receipts {
+        &self.inner.receipts
+    }
+
+    pub(crate) fn traces(&self) -> &Traces {
+        &self.inner.traces
+    }
+
+    pub(crate) fn queue(&self) -> &Queue {
+        &self.inner.queue
+    }
+
+    /// Queues the notifications left pending by the previous run.
+    ///
+    /// Returns the number of queued notifications.
+    pub fn replay_pending(&self) -> Result<usize> {
+        self.inner.queue.replay()
+    }
+
+    /// Reports the gateway as not ready so load balancers stop sending traffic,
+    /// while requests are still served.
+    pub fn start_draining(&self) {
+        if !self.inner.draining.swap(true, Ordering::Relaxed) {
+            log::info!("Draining, reporting the gateway as not ready.");
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::Relaxed) || self.is_shutting_down()
+    }
+
+    /// Asks the HTTP server and the workers to stop.
+    pub fn shutdown(&self) {
+        self.inner.draining.store(true, Ordering::Relaxed);
+        self.inner.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.inner.shutdown.borrow()
+    }
+
+    /// Waits until [`State::shutdown`] is called.
+    pub async fn shutdown_requested(&self) {
+        let mut shutdown = self.inner.shutdown.subscribe();
+        // The sender is owned by the state and cannot be dropped while waiting.
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    }
+
+    /// Sleeps for `duration` unless the gateway is shutting down.
+    ///
+    /// Returns false if the sleep was cut short by a shutdown.
+    pub(crate) async fn sleep(&self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => true,
+            _ = self.shutdown_requested() => false,
+        }
+    }
+
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.inner.event_log.as_ref()
+    }
 }
@@ -0,0 +1,116 @@
+//! # Public statistics.
+//!
+//! `GET /stats` returns a few aggregate counts about the gateway.
+//! On small deployments these counts, like the request counts of `GET /status`,
+//! can reveal when individual users register or get notified.
+//! The counts are only published with `--stats-noise <epsilon>`,
+//! which adds Laplace noise
+//! calibrated for counts to which a single event contributes at most one,
+//! so that they are `epsilon`-differentially private.
+//! Smaller values of `epsilon` add more noise.
+//!
+//! The noise is derived from a secret salt and the true count,
+//! so repeated requests get the same noisy count
+//! and cannot be averaged to remove the noise.
+
+use anyhow::{ensure, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore as _, SeedableRng as _};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Counts returned by `GET /stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    /// Number of heartbeat tokens.
+    pub tokens: u64,
+
+    /// Number of relays with registered tokens.
+    pub relays: u64,
+
+    /// Number of heartbeats sent since the start.
+    pub heartbeats: u64,
+}
+
+/// Noise added to published counts.
+#[derive(Debug, Clone)]
+pub struct StatsNoise {
+    /// Privacy budget of a single published count.
+    epsilon: f64,
+
+    /// Secret from which the noise is derived.
+    salt: [u8; 32],
+}
+
+impl StatsNoise {
+    pub fn new(epsilon: f64) -> Result<Self> {
+        ensure!(
+            epsilon.is_finite() && epsilon > 0.0,
+            "Noise epsilon must be positive"
+        );
+        let mut salt = [0; 32];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Ok(Self { epsilon, salt })
+    }
+
+    /// Returns the count named `name` with noise added,
+    /// rounded and clamped to be non-negative.
+    pub fn count(&self, name: &str, count: u64) -> u64 {
+        let seed = Sha256::new()
+            .chain_update(self.salt)
+            .chain_update(name.as_bytes())
+            .chain_update(count.to_be_bytes())
+            .finalize();
+        self.count_with(count, &mut StdRng::from_seed(seed.into()))
+    }
+
+    fn count_with(&self, count: u64, rng: &mut impl Rng) -> u64 {
+        // Sensitivity of a count is 1.
+        let noisy = count as f64 + laplace(1.0 / self.epsilon, rng);
+        noisy.round().max(0.0) as u64
+    }
+
+    /// Adds noise to all counts.
+    pub fn stats(&self, stats: Stats) -> Stats {
+        Stats {
+            tokens: self.count("tokens", stats.tokens),
+            relays: self.count("relays", stats.relays),
+            heartbeats: self.count("heartbeats", stats.heartbeats),
+        }
+    }
+}
+
+/// Samples the Laplace distribution centered at zero.
+fn laplace(scale: f64, rng: &mut impl Rng) -> f64 {
+    // Inverse of the cumulative distribution function.
+    let u: f64 = rng.gen_range(-0.5..0.5);
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_noise() -> Result<()> {
+        assert!(StatsNoise::new(0.0).is_err());
+        assert!(StatsNoise::new(-1.0).is_err());
+        assert!(StatsNoise::new(f64::INFINITY).is_err());
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let noise = StatsNoise::new(0.5)?;
+        let samples: Vec<u64> = (0..10_000)
+            .map(|_| noise.count_with(1000, &mut rng))
+            .collect();
+        let mean = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+        assert!((mean - 1000.0).abs() < 0.5);
+        assert!(samples.iter().any(|count| *count != 1000));
+
+        // Counts never become negative.
+        assert!((0..1000).all(|_| noise.count_with(0, &mut rng) < 1000));
+
+        // Repeated requests get the same noise.
+        assert_eq!(noise.count("tokens", 10), noise.count("tokens", 10));
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+//! # Supervision of background workers.
+//!
+//! The panic hook logs and counts panics in workers and request handlers
+//! and wakes a task flushing the schedule database,
+//! so recently registered tokens are not lost if the process dies afterwards.
+//!
+//! Workers are spawned supervised and restarted after a panic or an error
+//! instead of silently dying and reducing throughput.
+//!
+//! Workers mark themselves busy while handling a notification.
+//! A watchdog cancels and restarts workers that stay busy
+//! longer than the stall timeout,
+//! e.g. because they are blocked on a dead connection.
+//! Cancelled workers stop waiting, reschedule the work they took
+//! and return, and are only aborted if they do not return
+//! within [`CANCEL_GRACE_PERIOD`].
+//!
+//! Workers return `Ok(())` when the gateway is shutting down,
+//! which ends their supervision.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::*;
+use parking_lot::Mutex;
+use prometheus_client::metrics::counter::Counter;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+use crate::metrics::WorkerLabels;
+use crate::state::State;
+
+/// Delay before restarting a failed worker.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Interval at which the watchdog checks workers.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time a cancelled worker has to return before it is aborted.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Progress marker shared by a worker and its watchdog.
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeat {
+    /// Time since which the worker is busy, `None` while idle.
+    busy_since: Arc<Mutex<Option<Instant>>>,
+
+    /// Set once the watchdog cancelled the worker.
+    cancelled: Arc<watch::Sender<bool>>,
+}
+
+/// Marks the worker idle when dropped.
+pub struct BusyGuard<'a>(&'a Heartbeat);
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.busy_since.lock() = None;
+    }
+}
+
+impl Heartbeat {
+    /// Marks the worker busy until the returned guard is dropped.
+    pub fn busy(&self) -> BusyGuard<'_> {
+        *self.busy_since.lock() = Some(Instant::now());
+        BusyGuard(self)
+    }
+
+    /// Returns true if the watchdog cancelled the worker.
+    ///
+    /// Workers check this between iterations.
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Completes once the watchdog cancelled the worker.
+    pub async fn cancelled(&self) {
+        let mut cancelled = self.cancelled.subscribe();
+        // The sender lives as long as `self`.
+        let _ = cancelled.wait_for(|cancelled| *cancelled).await;
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.cancelled.send_replace(true);
+    }
+
+    /// Returns true if the worker is busy for longer than `timeout`.
+    fn is_stalled(&self, now: Instant, timeout: Duration) -> bool {
+        self.busy_since
+            .lock()
+            .is_some_and(|busy_since| now.saturating_duration_since(busy_since) > timeout)
+    }
+}
+
+/// Supervision counters of a worker kind.
+struct Counters {
+    restarts: Counter,
+    stalls: Counter,
+}
+
+/// Installs a panic hook counting panics and scheduling a flush of the schedule.
+///
+/// The hook chains to the previously installed one.
+/// It does no storage I/O itself, which could deadlock
+/// if the panicking thread holds a database lock,
+/// and only wakes a task that flushes the schedule.
+pub fn install_panic_hook(state: State) {
+    let flush = Arc::new(Notify::new());
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new({
+        let state = state.clone();
+        let flush = flush.clone();
+        move |info| {
+            error!("{info}");
+            state.metrics().panics_total.inc();
+            flush.notify_one();
+            previous(info);
+        }
+    }));
+    tokio::task::spawn(async move {
+        loop {
+            flush.notified().await;
+            if let Err(err) = state.schedule().flush().await {
+                error!("Failed to flush schedule after panic: {err:#}.");
+            }
+        }
+    });
+}
+
+/// Spawns a worker that is restarted whenever it panics or fails.
+///
+/// If `stall_timeout` is set, the worker is also restarted
+/// when it stays busy for longer than the timeout.
+///
+/// The returned handle completes once the worker returns successfully.
+pub fn spawn<F, Fut>(
+    state: State,
+    worker: &'static str,
+    stall_timeout: Option<Duration>,
+    f: F,
+) -> JoinHandle<()>
+where
+    F: Fn(State, Heartbeat) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let labels = WorkerLabels {
+        worker: worker.to_string(),
+    };
+    let metrics = state.metrics();
+    let counters = Counters {
+        restarts: metrics.worker_restarts_total.get_or_create(&labels).clone(),
+        stalls: metrics.worker_stalls_total.get_or_create(&labels).clone(),
+    };
+    tokio::task::spawn(supervise(
+        worker,
+        move |heartbeat| f(state.clone(), heartbeat),
+        stall_timeout,
+        counters,
+    ))
+}
+
+async fn supervise<F, Fut>(worker: &str, f: F, stall_timeout: Option<Duration>, counters: Counters)
+where
+    F: Fn(Heartbeat) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        let heartbeat = Heartbeat::default();
+        let mut task = tokio::task::spawn(f(heartbeat.clone()));
+        let mut watchdog = tokio::time::interval(WATCHDOG_INTERVAL);
+        let res = loop {
+            tokio::select! {
+                res = &mut task => break Some(res),
+                _ = watchdog.tick() => {
+                    if stall_timeout.is_some_and(|timeout| heartbeat.is_stalled(Instant::now(), timeout)) {
+                        heartbeat.cancel();
+                        if tokio::time::timeout(CANCEL_GRACE_PERIOD, &mut task).await.is_err() {
+                            error!("Worker {worker} did not stop after cancellation, aborting it.");
+                            task.abort();
+                        }
+                        break None;
+                    }
+                }
+            }
+        };
+        match res {
+            Some(Ok(Ok(()))) => return,
+            Some(Ok(Err(err))) => error!("Worker {worker} failed: {err:#}."),
+            Some(Err(err)) if err.is_panic() => error!("Worker {worker} panicked."),
+            Some(Err(_)) => return,
+            None => {
+                warn!("Worker {worker} stalled, restarting it.");
+                counters.stalls.inc();
+                continue;
+            }
+        }
+        counters.restarts.inc();
+        tokio::time::sleep(RESTART_DELAY).await;
+        info!("Restarting worker {worker}.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use anyhow::bail;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_supervise() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counters = Counters {
+            restarts: Counter::default(),
+            stalls: Counter::default(),
+        };
+        let restarts = counters.restarts.clone();
+        let f = {
+            let runs = runs.clone();
+            move |_heartbeat| {
+                let runs = runs.clone();
+                async move {
+                    match runs.fetch_add(1, Ordering::SeqCst) {
+                        0 => panic!("worker panic"),
+                        1 => bail!("worker error"),
+                        _ => Ok(()),
+                    }
+                }
+            }
+        };
+        supervise("test", f, None, counters).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(restarts.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stalled() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let rescheduled = Arc::new(AtomicUsize::new(0));
+        let counters = Counters {
+            restarts: Counter::default(),
+            stalls: Counter::default(),
+        };
+        let stalls = counters.stalls.clone();
+        let f = {
+            let runs = runs.clone();
+            let rescheduled = rescheduled.clone();
+            move |heartbeat: Heartbeat| {
+                let runs = runs.clone();
+                let rescheduled = rescheduled.clone();
+                async move {
+                    if runs.fetch_add(1, Ordering::SeqCst) > 0 {
+                        return Ok(());
+                    }
+                    let _busy = heartbeat.busy();
+                    // Stalled since long before the first watchdog check.
+                    *heartbeat.busy_since.lock() = Some(Instant::now() - Duration::from_secs(60));
+                    tokio::select! {
+                        _ = std::future::pending::<()>() => {}
+                        _ = heartbeat.cancelled() => {
+                            rescheduled.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                    Ok(())
+                }
+            }
+        };
+        supervise("test", f, Some(Duration::from_secs(1)), counters).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(rescheduled.load(Ordering::SeqCst), 1);
+        assert_eq!(stalls.get(), 1);
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let heartbeat = Heartbeat::default();
+        let timeout = Duration::from_secs(60);
+        let now = Instant::now();
+        assert!(!heartbeat.is_stalled(now + timeout * 2, timeout));
+
+        let busy = heartbeat.busy();
+        assert!(!heartbeat.is_stalled(Instant::now(), timeout));
+        assert!(heartbeat.is_stalled(Instant::now() + timeout * 2, timeout));
+
+        // Idle workers are never stalled.
+        drop(busy);
+        assert!(!heartbeat.is_stalled(Instant::now() + timeout * 2, timeout));
+    }
+}
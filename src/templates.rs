@@ -0,0 +1,193 @@
+//! # Notification payload templates.
+//!
+//! Titles, bodies, localization keys and data fields of notifications
+//! are taken from templates selected by provider and notification kind.
+//! Deployments can replace the built-in templates in the configuration file.
+//!
+//! Template strings may contain the variables `{notification_id}` and `{provider}`.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::metrics::{NotificationKind, NotificationProvider};
+
+/// Template defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateConfig {
+    pub provider: NotificationProvider,
+
+    #[serde(default = "default_kind")]
+    pub kind: NotificationKind,
+
+    #[serde(flatten)]
+    pub template: Template,
+}
+
+fn default_kind() -> NotificationKind {
+    NotificationKind::Direct
+}
+
+/// Content of a notification.
+///
+/// Providers ignore the fields they do not support,
+/// e.g. only APNS uses localization keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Template {
+    #[serde(default)]
+    pub title: Option<String>,
+
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// APNS localization key of the title.
+    #[serde(default)]
+    pub title_loc_key: Option<String>,
+
+    /// APNS localization key of the body.
+    #[serde(default)]
+    pub loc_key: Option<String>,
+
+    /// Custom data fields.
+    #[serde(default)]
+    pub data: BTreeMap<String, String>,
+}
+
+/// Substitutes template variables in `s`.
+fn substitute(s: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter().fold(s.to_string(), |s, (name, value)| {
+        s.replace(&format!("{{{name}}}"), value)
+    })
+}
+
+impl Template {
+    /// Returns the built-in template.
+    fn builtin(provider: NotificationProvider, kind: NotificationKind) -> Self {
+        let text = |title: &str, body: &str| Self {
+            title: Some(title.to_string()),
+            body: Some(body.to_string()),
+            ..Default::default()
+        };
+        match (provider, kind) {
+            (_, NotificationKind::Heartbeat) => Self::default(),
+            (NotificationProvider::APNS, _) => Self {
+                title_loc_key: Some("new_messages".to_string()),
+                loc_key: Some("new_messages_body".to_string()),
+                ..text("New messages", "You have new messages")
+            },
+            (NotificationProvider::FCM, _) => Self {
+                data: [("level".to_string(), "awesome".to_string())].into(),
+                ..Default::default()
+            },
+            (NotificationProvider::UBports, _) => text("New message", "You have a new message"),
+            (
+                NotificationProvider::WebPush
+                | NotificationProvider::UnifiedPush
+                | NotificationProvider::Webhook
+                | NotificationProvider::WNS,
+                _,
+            ) => Self::default(),
+            (
+                NotificationProvider::OneSignal
+                | NotificationProvider::Vivo
+                | NotificationProvider::OPPO,
+                _,
+            ) => text("New messages", "You have new messages"),
+        }
+    }
+
+    /// Returns the template with variables substituted.
+    pub(crate) fn render(&self, id: Uuid, provider: NotificationProvider) -> Self {
+        let id = id.hyphenated().to_string();
+        let provider = serde_json::to_value(provider)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let vars = [("notification_id", id.as_str()), ("provider", &provider)];
+        let render = |s: &Option<String>| s.as_deref().map(|s| substitute(s, &vars));
+        Self {
+            title: render(&self.title),
+            body: render(&self.body),
+            title_loc_key: render(&self.title_loc_key),
+            loc_key: render(&self.loc_key),
+            data: self
+                .data
+                .iter()
+                .map(|(key, value)| (key.clone(), substitute(value, &vars)))
+                .collect(),
+        }
+    }
+}
+
+pub(crate) struct Templates {
+    configured: HashMap<(NotificationProvider, NotificationKind), Template>,
+}
+
+impl Templates {
+    pub(crate) fn new(configured: &[TemplateConfig]) -> Self {
+        let configured = configured
+            .iter()
+            .map(|config| ((config.provider, config.kind), config.template.clone()))
+            .collect();
+        Self { configured }
+    }
+
+    /// Returns the rendered template for a notification.
+    ///
+    /// Configured templates replace the built-in ones.
+    pub(crate) fn render(
+        &self,
+        provider: NotificationProvider,
+        kind: NotificationKind,
+        id: Uuid,
+    ) -> Template {
+        match self.configured.get(&(provider, kind)) {
+            Some(template) => template.render(id, provider),
+            None => Template::builtin(provider, kind).render(id, provider),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_templates() -> Result<()> {
+        let config = Config::parse(
+            r#"
+[[templates]]
+provider = "fcm"
+title = "New messages"
+body = "Notification {notification_id} via {provider}"
+
+[templates.data]
+level = "awesome"
+id = "{notification_id}"
+"#,
+        )?;
+        let templates = Templates::new(&config.templates);
+        let id = Uuid::new_v4();
+
+        let fcm = templates.render(NotificationProvider::FCM, NotificationKind::Direct, id);
+        assert_eq!(
+            fcm.body.unwrap(),
+            format!("Notification {} via fcm", id.hyphenated())
+        );
+        assert_eq!(fcm.data["id"], id.hyphenated().to_string());
+        assert_eq!(fcm.data["level"], "awesome");
+
+        // Other providers and kinds use built-in templates.
+        let apns = templates.render(NotificationProvider::APNS, NotificationKind::Direct, id);
+        assert_eq!(apns.loc_key.as_deref(), Some("new_messages_body"));
+        let heartbeat =
+            templates.render(NotificationProvider::FCM, NotificationKind::Heartbeat, id);
+        assert_eq!(heartbeat, Template::default());
+        Ok(())
+    }
+}
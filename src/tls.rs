@@ -0,0 +1,126 @@
+//! # Automatic TLS certificates.
+//!
+//! Small deployments can serve the public API over HTTPS
+//! without a reverse proxy or certbot.
+//! With an `[acme]` section in the configuration file,
+//! the gateway obtains certificates for the configured domains
+//! from an ACME server such as Let's Encrypt
+//! and renews them before they expire.
+//!
+//! Domains are validated with the TLS-ALPN-01 challenge,
+//! which is answered on the public listener itself,
+//! so the listener must be reachable on port 443 of all domains.
+//! Certificates and the account key are cached in `cache_dir`
+//! to stay within the rate limits of the ACME server across restarts.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{ensure, Result};
+use log::*;
+use rustls::ServerConfig;
+use rustls_acme::acme::LETS_ENCRYPT_PRODUCTION_DIRECTORY;
+use rustls_acme::axum::AxumAcceptor;
+use rustls_acme::caches::DirCache;
+use rustls_acme::ResolvesServerCertAcme;
+use serde::Deserialize;
+use tokio_stream::StreamExt as _;
+
+/// ACME settings defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// Domains the certificate is issued for.
+    pub domains: Vec<String>,
+
+    /// Contact URIs of the ACME account,
+    /// e.g. `mailto:admin@example.org`.
+    #[serde(default)]
+    pub contact: Vec<String>,
+
+    /// Directory where certificates and the account key are stored.
+    pub cache_dir: PathBuf,
+
+    /// Directory URL of the ACME server, Let's Encrypt by default.
+    #[serde(default = "default_directory")]
+    pub directory: String,
+}
+
+fn default_directory() -> String {
+    LETS_ENCRYPT_PRODUCTION_DIRECTORY.to_string()
+}
+
+impl AcmeConfig {
+    pub(crate) fn check(&self) -> Result<()> {
+        ensure!(!self.domains.is_empty(), "At least one domain is required");
+        for contact in &self.contact {
+            ensure!(
+                contact.contains(':'),
+                "Contact {contact:?} must be a URI like mailto:admin@example.org"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Certificates obtained and renewed with ACME.
+#[derive(Clone)]
+pub struct Certificates {
+    resolver: Arc<ResolvesServerCertAcme>,
+    acceptor: AxumAcceptor,
+}
+
+impl Certificates {
+    /// Starts obtaining and renewing certificates in the background.
+    ///
+    /// Until the first certificate is deployed,
+    /// TLS handshakes other than ACME challenges fail.
+    pub fn start(config: &AcmeConfig) -> Self {
+        let mut acme = rustls_acme::AcmeConfig::new(&config.domains)
+            .contact(&config.contact)
+            .cache(DirCache::new(config.cache_dir.clone()))
+            .directory(&config.directory)
+            .state();
+        let resolver = acme.resolver();
+        let acceptor = acme.axum_acceptor(server_config(
+            resolver.clone(),
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        ));
+        tokio::task::spawn(async move {
+            while let Some(event) = acme.next().await {
+                match event {
+                    Ok(event) => info!("ACME: {event:?}."),
+                    Err(err) => error!("ACME: {err}."),
+                }
+            }
+        });
+        Self { resolver, acceptor }
+    }
+
+    /// Returns the acceptor terminating TLS on the public listener.
+    pub(crate) fn acceptor(&self) -> AxumAcceptor {
+        self.acceptor.clone()
+    }
+
+    /// Returns a TLS configuration with the certificates
+    /// for the HTTP/3 listener.
+    pub(crate) fn http3_config(&self) -> Arc<ServerConfig> {
+        server_config(self.resolver.clone(), vec![b"h3".to_vec()])
+    }
+}
+
+/// Returns a TLS configuration with the ACME certificates
+/// negotiating one of the `alpn_protocols`.
+fn server_config(
+    resolver: Arc<ResolvesServerCertAcme>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Arc<ServerConfig> {
+    let mut config =
+        ServerConfig::builder_with_provider(rustls::crypto::aws_lc_rs::default_provider().into())
+            .with_safe_default_protocol_versions()
+            .expect("default provider supports the default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+    config.alpn_protocols = alpn_protocols;
+    Arc::new(config)
+}
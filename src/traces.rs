@@ -0,0 +1,343 @@
+//! # Delivery traces.
+//!
+//! To debug reports of missing notifications,
+//! a client app requests a short one-time code for its token
+//! with `POST /debug-codes` and shows it to the user.
+//! The user passes the code to the operator,
+//! who redeems it with `POST /admin/debug-codes/<code>`.
+//! For a limited time, the gateway then records
+//! what happens to notifications for that token:
+//! registrations, debounced and dead token notifications,
+//! and the responses of the provider to direct and heartbeat notifications.
+//!
+//! Traces have a random ID and contain the token only redacted
+//! as it appears in the logs.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use rand::Rng as _;
+use serde::Serialize;
+
+use crate::logging::redact;
+use crate::ratelimit::RateLimit;
+use crate::server::NotificationToken;
+
+/// Time during which a code can be redeemed.
+pub(crate) const CODE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum number of codes waiting to be redeemed.
+const MAX_CODES: usize = 10_000;
+
+/// Codes a single source can request, independent of `--register-rate-limit`,
+/// so unauthenticated requests cannot fill the [`MAX_CODES`] slots.
+pub(crate) const CODE_RATE_LIMIT: RateLimit = RateLimit {
+    burst: 5,
+    period: Duration::from_secs(60 * 60),
+};
+
+/// Maximum number of events kept per trace.
+const MAX_EVENTS: usize = 200;
+
+/// Longest time a token can be traced.
+pub(crate) const MAX_TRACE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Characters of codes, without easily confused ones like `0` and `O`.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+/// Number of characters of a code.
+const CODE_LEN: usize = 8;
+
+/// Event recorded for a traced token.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TraceEvent {
+    /// Unix timestamp in milliseconds.
+    timestamp: u64,
+
+    /// What happened, e.g. `debounced` or `direct`.
+    event: &'static str,
+
+    /// Status of the provider response, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+
+    /// Reason reported by the provider or the gateway, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl TraceEvent {
+    pub(crate) fn new(event: &'static str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            timestamp,
+            event,
+            status: None,
+            reason: None,
+        }
+    }
+
+    pub(crate) fn with_status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub(crate) fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+}
+
+/// Trace returned by `GET /admin/traces/<id>`.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TraceInfo {
+    id: String,
+
+    /// Redacted token.
+    token: String,
+
+    /// Seconds until tracing stops.
+    expires_in: u64,
+
+    events: Vec<TraceEvent>,
+}
+
+struct Trace {
+    id: String,
+
+    /// Redacted token.
+    token: String,
+
+    until: Instant,
+    events: VecDeque<TraceEvent>,
+}
+
+#[derive(Default)]
+struct TracesState {
+    /// Tokens by code waiting to be redeemed.
+    codes: HashMap<String, (String, Instant)>,
+
+    /// Traces by token.
+    traces: HashMap<String, Trace>,
+}
+
+impl TracesState {
+    fn cleanup(&mut self, now: Instant) {
+        self.codes
+            .retain(|_, (_, issued)| now.duration_since(*issued) < CODE_TTL);
+        self.traces.retain(|_, trace| now < trace.until);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Traces {
+    state: Mutex<TracesState>,
+
+    /// Number of traces, so untraced notifications skip the lock.
+    active: AtomicUsize,
+}
+
+/// Returns the form of the token used as the key of traces.
+fn canonical(token: &str) -> String {
+    token
+        .parse::<NotificationToken>()
+        .map_or_else(|_| token.to_string(), |token| token.to_string())
+}
+
+/// Removes separators and lowercase letters users may add to codes.
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+impl Traces {
+    /// Returns a new code for the token
+    /// or `None` if too many codes wait to be redeemed.
+    pub(crate) fn issue_code(&self, now: Instant, token: &str) -> Option<String> {
+        let mut state = self.state.lock();
+        if state.codes.len() >= MAX_CODES {
+            state.cleanup(now);
+            if state.codes.len() >= MAX_CODES {
+                return None;
+            }
+        }
+        let mut rng = rand::thread_rng();
+        let code = loop {
+            let code: String = (0..CODE_LEN)
+                .map(|_| char::from(CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())]))
+                .collect();
+            if !state.codes.contains_key(&code) {
+                break code;
+            }
+        };
+        state.codes.insert(code.clone(), (canonical(token), now));
+        let (first, second) = code.split_at(CODE_LEN / 2);
+        Some(format!("{first}-{second}"))
+    }
+
+    /// Starts tracing the token of the code for `duration`.
+    ///
+    /// Each code can be redeemed once.
+    /// Returns the ID of the trace or `None` if the code is unknown or expired.
+    pub(crate) fn redeem(&self, now: Instant, code: &str, duration: Duration) -> Option<String> {
+        let mut state = self.state.lock();
+        state.cleanup(now);
+        let (token, _) = state.codes.remove(&normalize_code(code))?;
+        let until = now + duration.min(MAX_TRACE_DURATION);
+        let trace = state
+            .traces
+            .entry(token)
+            .and_modify(|trace| trace.until = trace.until.max(until));
+        let id = match trace {
+            Entry::Occupied(trace) => trace.get().id.clone(),
+            Entry::Vacant(trace) => {
+                let id = hex::encode(rand::random::<[u8; 8]>());
+                let token = redact(trace.key()).to_string();
+                trace.insert(Trace {
+                    id: id.clone(),
+                    token,
+                    until,
+                    events: VecDeque::new(),
+                });
+                id
+            }
+        };
+        self.active.store(state.traces.len(), Ordering::Relaxed);
+        Some(id)
+    }
+
+    /// Records an event if the token is traced.
+    ///
+    /// Expired traces are dropped by [`Traces::shrink`],
+    /// not here, so recording stays cheap.
+    pub(crate) fn record(&self, token: &str, event: impl FnOnce() -> TraceEvent) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let token = canonical(token);
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let Some(trace) = state.traces.get_mut(&token) else {
+            return;
+        };
+        if now >= trace.until {
+            return;
+        }
+        if trace.events.len() >= MAX_EVENTS {
+            trace.events.pop_front();
+        }
+        trace.events.push_back(event());
+    }
+
+    /// Returns the trace with the given ID.
+    pub(crate) fn get(&self, now: Instant, id: &str) -> Option<TraceInfo> {
+        let mut state = self.state.lock();
+        state.cleanup(now);
+        self.active.store(state.traces.len(), Ordering::Relaxed);
+        let trace = state.traces.values().find(|trace| trace.id == id)?;
+        Some(TraceInfo {
+            id: trace.id.clone(),
+            token: trace.token.clone(),
+            expires_in: trace.until.duration_since(now).as_secs(),
+            events: trace.events.iter().cloned().collect(),
+        })
+    }
+
+    /// Stops the trace with the given ID.
+    ///
+    /// Returns false if there is no such trace.
+    pub(crate) fn stop(&self, id: &str) -> bool {
+        let mut state = self.state.lock();
+        let before = state.traces.len();
+        state.traces.retain(|_, trace| trace.id != id);
+        self.active.store(state.traces.len(), Ordering::Relaxed);
+        state.traces.len() < before
+    }
+
+    /// Drops expired codes and traces and releases unused memory.
+    pub(crate) fn shrink(&self, now: Instant) {
+        let mut state = self.state.lock();
+        state.cleanup(now);
+        state.codes.shrink_to_fit();
+        state.traces.shrink_to_fit();
+        self.active.store(state.traces.len(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traces() {
+        let traces = Traces::default();
+        let now = Instant::now();
+        let token = "ubports-chat.delta_deltatouch_1.0.0";
+
+        // Untraced tokens are not recorded.
+        traces.record(token, || TraceEvent::new("direct"));
+        assert_eq!(traces.active.load(Ordering::Relaxed), 0);
+
+        let code = traces.issue_code(now, token).unwrap();
+        assert_eq!(code.len(), CODE_LEN + 1);
+        assert!(traces
+            .redeem(now, "AAAA-AAAA", Duration::from_secs(60))
+            .is_none());
+
+        let id = traces
+            .redeem(now, &code.to_lowercase(), Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(
+            traces.get(now, &id).unwrap().token,
+            redact(token).to_string()
+        );
+
+        // Codes are one-time.
+        assert!(traces.redeem(now, &code, Duration::from_secs(60)).is_none());
+
+        traces.record(token, || TraceEvent::new("direct").with_status(200));
+        traces.record("ubports-other", || TraceEvent::new("direct"));
+        let info = traces.get(now, &id).unwrap();
+        assert_eq!(info.events.len(), 1);
+        assert_eq!(info.events[0].status, Some(200));
+        assert_eq!(info.expires_in, 60);
+
+        assert!(traces.stop(&id));
+        assert!(traces.get(now, &id).is_none());
+
+        // Expired traces record nothing and are dropped when shrinking.
+        let code = traces.issue_code(now, token).unwrap();
+        let past = now - Duration::from_secs(120);
+        let id = traces.redeem(past, &code, Duration::from_secs(60)).unwrap();
+        traces.record(token, || TraceEvent::new("direct"));
+        assert_eq!(
+            traces
+                .state
+                .lock()
+                .traces
+                .values()
+                .next()
+                .unwrap()
+                .events
+                .len(),
+            0
+        );
+        traces.shrink(now);
+        assert_eq!(traces.active.load(Ordering::Relaxed), 0);
+        assert!(traces.get(now, &id).is_none());
+
+        // Expired codes cannot be redeemed.
+        let code = traces.issue_code(now, token).unwrap();
+        assert!(traces
+            .redeem(now + CODE_TTL, &code, Duration::from_secs(60))
+            .is_none());
+    }
+}
@@ -0,0 +1,147 @@
+//! # UnifiedPush provider.
+//!
+//! Devices using a UnifiedPush distributor such as ntfy or NextPush
+//! register tokens of the form `up:<endpoint-url>`.
+//! Notifications are delivered by POSTing a short message
+//! to the endpoint, as described in
+//! <https://unifiedpush.org/developers/spec/server/>.
+//!
+//! Unlike other providers except APNS,
+//! UnifiedPush tokens also receive heartbeat notifications.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use log::*;
+
+use crate::logging::redact;
+use crate::metrics::{FailureLabels, Metrics, NotificationKind, NotificationProvider};
+use crate::outage;
+use crate::provider::Delivery;
+use crate::retry;
+
+/// Body of UnifiedPush messages.
+///
+/// The app wakes up and fetches messages itself,
+/// so the message carries no content.
+const BODY: &str = "ping";
+
+/// Time for which the distributor keeps undelivered direct notifications.
+const DIRECT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Time for which the distributor keeps undelivered heartbeats.
+///
+/// A heartbeat delivered much later is of no use,
+/// as the next one is already due.
+const HEARTBEAT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Maps the status of the distributor response to the status returned to the relay.
+///
+/// Distributors respond with `404 Not Found` or `410 Gone`
+/// to messages for endpoints that were unregistered.
+fn response_status(status: StatusCode) -> StatusCode {
+    if status.is_success() {
+        StatusCode::OK
+    } else if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+        StatusCode::GONE
+    } else if status == StatusCode::TOO_MANY_REQUESTS {
+        StatusCode::TOO_MANY_REQUESTS
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Sends a message to the UnifiedPush endpoint.
+pub(crate) async fn notify(
+    client: &reqwest::Client,
+    endpoint: &str,
+    kind: NotificationKind,
+    metrics: &Metrics,
+) -> Result<Delivery> {
+    let (ttl, urgency) = match kind {
+        NotificationKind::Direct => (DIRECT_TTL, "high"),
+        NotificationKind::Heartbeat => (HEARTBEAT_TTL, "normal"),
+    };
+    debug!(
+        "Sending UnifiedPush {kind:?} notification to {}.",
+        redact(endpoint)
+    );
+    let inflight = metrics.inflight_request(NotificationProvider::UnifiedPush);
+    let start = Instant::now();
+    let res = retry::send(
+        client
+            .post(endpoint)
+            .body(BODY)
+            .header("Content-Type", "text/plain")
+            .header("TTL", ttl.as_secs().to_string())
+            .header("Urgency", urgency),
+        metrics,
+        NotificationProvider::UnifiedPush,
+    )
+    .await;
+    inflight.finish(outage::is_response_ok(&res));
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            // Endpoint URL is a pushable identifier.
+            let err = err.without_url();
+            warn!(provider = "unifiedpush"; "Failed to send UnifiedPush notification to {}: {err}", redact(endpoint));
+            metrics.count_failure(FailureLabels::new(
+                NotificationProvider::UnifiedPush,
+                "send",
+                "",
+            ));
+            return Err(err.into());
+        }
+    };
+    let status = res.status();
+    debug!(
+        "UnifiedPush distributor responded with {status} in {:?}.",
+        start.elapsed()
+    );
+    let mut delivery = Delivery::from(response_status(status));
+    if delivery.status.is_success() {
+        debug!(
+            "Delivered notification to UnifiedPush endpoint {}.",
+            redact(endpoint)
+        );
+        metrics.unifiedpush_notifications_total.inc();
+    } else {
+        warn!(provider = "unifiedpush", status = status.as_u16(); "Failed to deliver UnifiedPush notification to {}", redact(endpoint));
+        delivery.failure = Some(
+            FailureLabels::new(
+                NotificationProvider::UnifiedPush,
+                status.as_u16().to_string(),
+                "",
+            )
+            .with_status(status.as_u16()),
+        );
+    }
+    Ok(delivery)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_status() {
+        assert_eq!(response_status(StatusCode::CREATED), StatusCode::OK);
+        assert_eq!(response_status(StatusCode::OK), StatusCode::OK);
+        assert_eq!(response_status(StatusCode::NOT_FOUND), StatusCode::GONE);
+        assert_eq!(response_status(StatusCode::GONE), StatusCode::GONE);
+        assert_eq!(
+            response_status(StatusCode::TOO_MANY_REQUESTS),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            response_status(StatusCode::PAYLOAD_TOO_LARGE),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            response_status(StatusCode::BAD_GATEWAY),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! # Vivo push provider.
+//!
+//! Devices of Vivo phones, where FCM may be unavailable,
+//! register tokens of the form `vivo:<regId>`.
+//! Notifications are sent with the Vivo push server API
+//! using an auth token obtained with the app credentials.
+//! The auth token is valid for a day and is cached until it expires
+//! or the server rejects it.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Context as _, Result};
+use axum::http::StatusCode;
+use log::*;
+use md5::{Digest as _, Md5};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::VivoConfig;
+use crate::logging::redact;
+use crate::metrics::{FailureLabels, Metrics, NotificationProvider};
+use crate::outage;
+use crate::retry;
+use crate::templates::Template;
+
+const AUTH_URL: &str = "https://api-push.vivo.com.cn/message/auth";
+const SEND_URL: &str = "https://api-push.vivo.com.cn/message/send";
+
+/// Time after which the auth token is renewed.
+///
+/// Tokens are valid for 24 hours.
+const AUTH_TOKEN_LIFETIME: Duration = Duration::from_secs(23 * 60 * 60);
+
+/// Result code of a successful request.
+const OK: i64 = 0;
+
+/// Result code of a request with an invalid or expired auth token.
+const AUTH_FAILED: i64 = 10000;
+
+/// Result code for registration IDs that do not exist.
+const INVALID_REG_ID: i64 = 10302;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthRequest<'a> {
+    app_id: &'a str,
+    app_key: &'a str,
+    timestamp: u128,
+    sign: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Message<'a> {
+    reg_id: &'a str,
+
+    /// Ring and vibrate.
+    notify_type: u8,
+    title: &'a str,
+    content: &'a str,
+
+    /// Open the app when the notification is tapped.
+    skip_type: u8,
+
+    /// Unique ID of the request.
+    request_id: String,
+
+    /// System message, i.e. instant messaging rather than marketing.
+    classification: u8,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    client_custom_map: &'a BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    result: i64,
+
+    #[serde(default)]
+    desc: String,
+
+    #[serde(default)]
+    auth_token: Option<String>,
+}
+
+pub(crate) struct Vivo {
+    client: reqwest::Client,
+    config: Option<VivoConfig>,
+
+    /// Cached auth token and the time it was obtained.
+    auth_token: tokio::sync::Mutex<Option<(String, Instant)>>,
+}
+
+/// Signs the auth request.
+fn sign(config: &VivoConfig, timestamp: u128) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(config.app_id.as_bytes());
+    hasher.update(config.app_key.as_bytes());
+    hasher.update(timestamp.to_string().as_bytes());
+    hasher.update(config.app_secret.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Returns the status for the result code of a send request.
+fn result_status(result: i64) -> StatusCode {
+    match result {
+        OK => StatusCode::OK,
+        INVALID_REG_ID => StatusCode::GONE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl Vivo {
+    pub(crate) fn new(config: Option<VivoConfig>, client: reqwest::Client) -> Self {
+        Self {
+            client,
+            config,
+            auth_token: Default::default(),
+        }
+    }
+
+    /// Returns a valid auth token, requesting a new one if needed.
+    async fn auth_token(&self, config: &VivoConfig, metrics: &Metrics) -> Result<String> {
+        let mut cached = self.auth_token.lock().await;
+        if let Some((token, obtained)) = &*cached {
+            if obtained.elapsed() < AUTH_TOKEN_LIFETIME {
+                return Ok(token.clone());
+            }
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        let request = AuthRequest {
+            app_id: &config.app_id,
+            app_key: &config.app_key,
+            timestamp,
+            sign: sign(config, timestamp),
+        };
+        let res = retry::send(
+            self.client
+                .post(AUTH_URL)
+                .body(serde_json::to_string(&request)?)
+                .header("Content-Type", "application/json"),
+            metrics,
+            NotificationProvider::Vivo,
+        )
+        .await?
+        .error_for_status()?;
+        let res: Response = serde_json::from_slice(&res.bytes().await?)?;
+        if res.result != OK {
+            bail!("Vivo auth failed with {}: {}", res.result, res.desc);
+        }
+        let token = res.auth_token.context("Vivo auth response has no token")?;
+        *cached = Some((token.clone(), Instant::now()));
+        Ok(token)
+    }
+
+    pub(crate) async fn notify(
+        &self,
+        reg_id: &str,
+        template: &Template,
+        metrics: &Metrics,
+    ) -> Result<StatusCode> {
+        let fail = |reason: &str| {
+            metrics.count_failure(FailureLabels::new(NotificationProvider::Vivo, reason, ""));
+        };
+
+        let Some(config) = &self.config else {
+            warn!("Cannot notify Vivo device because Vivo credentials are not configured.");
+            fail("no_credentials");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        let auth_token = match self.auth_token(config, metrics).await {
+            Ok(auth_token) => auth_token,
+            Err(err) => {
+                warn!(provider = "vivo"; "Failed to get Vivo auth token: {err:#}");
+                fail("auth");
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let message = Message {
+            reg_id,
+            notify_type: 4,
+            title: template.title.as_deref().unwrap_or_default(),
+            content: template.body.as_deref().unwrap_or_default(),
+            skip_type: 1,
+            request_id: Uuid::new_v4().to_string(),
+            classification: 1,
+            client_custom_map: &template.data,
+        };
+        let body = serde_json::to_string(&message)?;
+        debug!("Sending Vivo notification to {}.", redact(reg_id));
+        let inflight = metrics.inflight_request(NotificationProvider::Vivo);
+        let start = Instant::now();
+        let res = retry::send(
+            self.client
+                .post(SEND_URL)
+                .body(body)
+                .header("Content-Type", "application/json")
+                .header("authToken", auth_token),
+            metrics,
+            NotificationProvider::Vivo,
+        )
+        .await;
+        inflight.finish(outage::is_response_ok(&res));
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                warn!(provider = "vivo"; "Failed to send Vivo notification to {}: {err}", redact(reg_id));
+                fail("send");
+                return Err(err.into());
+            }
+        };
+        let status = res.status();
+        debug!("Vivo responded with {status} in {:?}.", start.elapsed());
+        let res: Option<Response> = serde_json::from_slice(&res.bytes().await?).ok();
+        let Some(res) = res.filter(|_| status.is_success()) else {
+            warn!(provider = "vivo", status = status.as_u16(); "Failed to deliver Vivo notification to {}", redact(reg_id));
+            metrics.count_failure(
+                FailureLabels::new(NotificationProvider::Vivo, status.as_u16().to_string(), "")
+                    .with_status(status.as_u16()),
+            );
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        if res.result == AUTH_FAILED {
+            // Request a new token for the next notification.
+            *self.auth_token.lock().await = None;
+        }
+        let status_code = result_status(res.result);
+        if status_code.is_success() {
+            debug!("Delivered notification to Vivo device {}.", redact(reg_id));
+            metrics.vivo_notifications_total.inc();
+        } else {
+            warn!(provider = "vivo", result = res.result; "Failed to deliver Vivo notification to {}: {}", redact(reg_id), res.desc);
+            metrics.count_failure(
+                FailureLabels::new(NotificationProvider::Vivo, res.result.to_string(), "")
+                    .with_status(status.as_u16()),
+            );
+        }
+        Ok(status_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        let config = VivoConfig {
+            app_id: "1".to_string(),
+            app_key: "key".to_string(),
+            app_secret: "secret".to_string(),
+        };
+        // MD5 of "1key1500000000000secret".
+        assert_eq!(
+            sign(&config, 1500000000000),
+            "68c38fd5468bcd94edacdd09fbb8f3fd"
+        );
+    }
+
+    #[test]
+    fn test_result_status() {
+        assert_eq!(result_status(OK), StatusCode::OK);
+        assert_eq!(result_status(INVALID_REG_ID), StatusCode::GONE);
+        assert_eq!(
+            result_status(AUTH_FAILED),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! # Webhook provider.
+//!
+//! Self-hosted setups without a push service can register tokens
+//! of the form `webhook:<url>`.
+//! For each notification the gateway POSTs a small JSON event to the URL.
+//!
+//! Webhook URLs are chosen by whoever registers the token,
+//! so they are only requested if their scheme and host
+//! are allowed in the `[webhook]` section of the configuration file.
+//! Allowlisted hosts may be in private networks,
+//! and redirects are never followed to leave the allowlist.
+//! Without this section webhook tokens are rejected.
+//! If a `secret` is configured, the body is signed with HMAC-SHA256
+//! as for token invalidation callbacks.
+
+use std::time::{Instant, SystemTime};
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use log::*;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::callbacks::{sign, SIGNATURE_HEADER};
+use crate::logging::redact;
+use crate::metrics::{FailureLabels, Metrics, NotificationProvider};
+use crate::outage;
+use crate::retry;
+
+/// Webhook settings defined in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Allowed hosts, either exact names or wildcards like `*.example.org`.
+    pub hosts: Vec<String>,
+
+    /// Allowed URL schemes.
+    #[serde(default = "default_schemes")]
+    pub schemes: Vec<String>,
+
+    /// Secret for signing the events.
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+fn default_schemes() -> Vec<String> {
+    vec!["https".to_string()]
+}
+
+/// Body of the webhook request.
+#[derive(Debug, Serialize)]
+struct WebhookEvent {
+    event: &'static str,
+    notification_id: Uuid,
+
+    /// Unix timestamp of the notification.
+    timestamp: u64,
+}
+
+pub(crate) struct Webhooks {
+    client: reqwest::Client,
+    config: Option<WebhookConfig>,
+}
+
+/// Returns true if the host matches the allowlist entry.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// Maps the status of the webhook response to the status returned to the relay.
+///
+/// Only `410 Gone` removes the token.
+/// `404 Not Found` more likely means a misconfigured endpoint
+/// and is not retried.
+fn response_status(status: StatusCode) -> StatusCode {
+    if status.is_success() {
+        StatusCode::OK
+    } else if status == StatusCode::GONE || status == StatusCode::NOT_FOUND {
+        status
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Webhooks {
+    pub(crate) fn new(config: Option<WebhookConfig>, client: reqwest::Client) -> Self {
+        Self { client, config }
+    }
+
+    /// Returns true if requests to the URL are allowed.
+    fn is_allowed(&self, url: &Url) -> bool {
+        let Some(config) = &self.config else {
+            return false;
+        };
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        config.schemes.iter().any(|scheme| scheme == url.scheme())
+            && config
+                .hosts
+                .iter()
+                .any(|pattern| host_matches(&pattern.to_ascii_lowercase(), &host))
+    }
+
+    /// Sends a notification event to the webhook URL.
+    pub(crate) async fn notify(
+        &self,
+        url: &str,
+        id: Uuid,
+        metrics: &Metrics,
+    ) -> Result<StatusCode> {
+        let fail = |reason: &str| {
+            metrics.count_failure(FailureLabels::new(
+                NotificationProvider::Webhook,
+                reason,
+                "",
+            ));
+        };
+
+        let parsed_url: Url = url.parse()?;
+        if !self.is_allowed(&parsed_url) {
+            debug!("Webhook {} is not allowed.", redact(url));
+            fail("not_allowed");
+            return Ok(StatusCode::FORBIDDEN);
+        }
+        let event = WebhookEvent {
+            event: "notification",
+            notification_id: id,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+        };
+        let body = serde_json::to_vec(&event)?;
+        let mut request = self
+            .client
+            .post(parsed_url)
+            .header("Content-Type", "application/json");
+        if let Some(secret) = self.config.as_ref().and_then(|c| c.secret.as_deref()) {
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+        }
+        debug!("Sending webhook notification to {}.", redact(url));
+        let inflight = metrics.inflight_request(NotificationProvider::Webhook);
+        let start = Instant::now();
+        let res = retry::send(request.body(body), metrics, NotificationProvider::Webhook).await;
+        inflight.finish(outage::is_response_ok(&res));
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                // Webhook URL is a pushable identifier.
+                let err = err.without_url();
+                warn!(provider = "webhook"; "Failed to send webhook notification to {}: {err}", redact(url));
+                fail("send");
+                return Err(err.into());
+            }
+        };
+        let status = res.status();
+        debug!("Webhook responded with {status} in {:?}.", start.elapsed());
+        let status_code = response_status(status);
+        if status_code.is_success() {
+            metrics.webhook_notifications_total.inc();
+        } else {
+            warn!(provider = "webhook", status = status.as_u16(); "Failed to deliver webhook notification to {}", redact(url));
+            metrics.count_failure(
+                FailureLabels::new(
+                    NotificationProvider::Webhook,
+                    status.as_u16().to_string(),
+                    "",
+                )
+                .with_status(status.as_u16()),
+            );
+        }
+        Ok(status_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{Disposition, Providers};
+
+    #[test]
+    fn test_is_allowed() {
+        let webhooks = Webhooks::new(
+            Some(WebhookConfig {
+                hosts: vec!["hooks.example.org".to_string(), "*.example.net".to_string()],
+                schemes: default_schemes(),
+                secret: None,
+            }),
+            reqwest::Client::new(),
+        );
+        let allowed = |url: &str| webhooks.is_allowed(&url.parse().unwrap());
+        assert!(allowed("https://hooks.example.org/notify/abc"));
+        assert!(allowed("https://HOOKS.example.org./notify"));
+        assert!(allowed("https://a.example.net/"));
+        assert!(allowed("https://a.b.example.net/"));
+        assert!(!allowed("https://example.net/"));
+        assert!(!allowed("https://evilexample.net/"));
+        assert!(!allowed("http://hooks.example.org/"));
+        assert!(!allowed("https://other.example.org/"));
+
+        // Without configuration all webhooks are rejected.
+        let webhooks = Webhooks::new(None, reqwest::Client::new());
+        assert!(!webhooks.is_allowed(&"https://hooks.example.org/".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_response_status() {
+        assert_eq!(response_status(StatusCode::NO_CONTENT), StatusCode::OK);
+        assert_eq!(response_status(StatusCode::GONE), StatusCode::GONE);
+        assert_eq!(
+            response_status(StatusCode::NOT_FOUND),
+            StatusCode::NOT_FOUND
+        );
+        let providers = Providers::new(None, false).unwrap();
+        let webhook = providers.get(NotificationProvider::Webhook);
+        assert_eq!(
+            webhook.classify(&response_status(StatusCode::NOT_FOUND).into()),
+            Disposition::Fatal
+        );
+        assert_eq!(
+            webhook.classify(&response_status(StatusCode::GONE).into()),
+            Disposition::Gone
+        );
+        assert_eq!(
+            response_status(StatusCode::BAD_REQUEST),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
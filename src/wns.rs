@@ -0,0 +1,248 @@
+//! # Windows Push Notification Services provider.
+//!
+//! Windows devices register tokens of the form `wns:<channel URI>`.
+//! Notifications are delivered as raw notifications,
+//! which wake up the app without showing anything,
+//! by POSTing to the channel URI with an access token
+//! obtained with the OAuth client credentials flow.
+//! The access token is cached until it expires
+//! or WNS rejects it.
+//!
+//! Like UnifiedPush tokens, WNS tokens also receive heartbeat notifications.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context as _, Result};
+use axum::http::StatusCode;
+use log::*;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::config::WnsConfig;
+use crate::logging::redact;
+use crate::metrics::{FailureLabels, Metrics, NotificationKind, NotificationProvider};
+use crate::outage;
+use crate::provider::Delivery;
+use crate::retry;
+
+const AUTH_URL: &str = "https://login.live.com/accesstoken.srf";
+
+/// Scope of access tokens for sending notifications.
+const SCOPE: &str = "notify.windows.com";
+
+/// Suffix of channel URI hosts.
+///
+/// Access tokens are only sent to WNS servers.
+const CHANNEL_HOST_SUFFIX: &str = ".notify.windows.com";
+
+/// Body of raw notifications.
+///
+/// The app wakes up and fetches messages itself,
+/// so the notification carries no content.
+const BODY: &str = "ping";
+
+/// Time for which WNS keeps undelivered direct notifications.
+const DIRECT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Time for which WNS keeps undelivered heartbeats.
+const HEARTBEAT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Time before the expiration at which the access token is renewed.
+const AUTH_TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Deserialize)]
+struct AuthResponse {
+    access_token: String,
+
+    /// Lifetime of the token in seconds.
+    expires_in: u64,
+}
+
+/// Returns an error if the channel URI does not point to a WNS server.
+pub(crate) fn check_channel_uri(channel_uri: &str) -> Result<()> {
+    let url: Url = channel_uri.parse().context("Invalid channel URI")?;
+    ensure!(url.scheme() == "https", "Channel URI is not HTTPS");
+    let host = url.host_str().context("Channel URI has no host")?;
+    ensure!(
+        host.ends_with(CHANNEL_HOST_SUFFIX),
+        "Channel URI host {host} is not a WNS server"
+    );
+    Ok(())
+}
+
+/// Maps the status of the WNS response to the status returned to the relay.
+///
+/// WNS responds with `404 Not Found` to invalid channel URIs
+/// and with `410 Gone` to expired channels.
+fn response_status(status: StatusCode) -> StatusCode {
+    if status.is_success() {
+        StatusCode::OK
+    } else if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+        StatusCode::GONE
+    } else if status == StatusCode::NOT_ACCEPTABLE {
+        // The channel is throttled.
+        StatusCode::TOO_MANY_REQUESTS
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+pub(crate) struct Wns {
+    client: reqwest::Client,
+    config: Option<WnsConfig>,
+
+    /// Cached access token and the time it expires.
+    access_token: tokio::sync::Mutex<Option<(String, Instant)>>,
+}
+
+impl Wns {
+    pub(crate) fn new(config: Option<WnsConfig>, client: reqwest::Client) -> Self {
+        Self {
+            client,
+            config,
+            access_token: Default::default(),
+        }
+    }
+
+    /// Returns a valid access token, requesting a new one if needed.
+    async fn access_token(&self, config: &WnsConfig, metrics: &Metrics) -> Result<String> {
+        let mut cached = self.access_token.lock().await;
+        if let Some((token, expires)) = &*cached {
+            if Instant::now() < *expires {
+                return Ok(token.clone());
+            }
+        }
+
+        let res = retry::send(
+            self.client.post(AUTH_URL).form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", &config.client_id),
+                ("client_secret", &config.client_secret),
+                ("scope", SCOPE),
+            ]),
+            metrics,
+            NotificationProvider::WNS,
+        )
+        .await?
+        .error_for_status()?;
+        let res: AuthResponse = serde_json::from_slice(&res.bytes().await?)?;
+        let lifetime =
+            Duration::from_secs(res.expires_in).saturating_sub(AUTH_TOKEN_RENEWAL_MARGIN);
+        *cached = Some((res.access_token.clone(), Instant::now() + lifetime));
+        Ok(res.access_token)
+    }
+
+    /// Sends a raw notification to the channel.
+    pub(crate) async fn notify(
+        &self,
+        channel_uri: &str,
+        kind: NotificationKind,
+        metrics: &Metrics,
+    ) -> Result<Delivery> {
+        let fail = |reason: &str| Delivery {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            reason: None,
+            failure: Some(FailureLabels::new(NotificationProvider::WNS, reason, "")),
+        };
+
+        let Some(config) = &self.config else {
+            warn!("Cannot notify WNS channel because WNS credentials are not configured.");
+            return Ok(fail("no_credentials"));
+        };
+        let access_token = match self.access_token(config, metrics).await {
+            Ok(access_token) => access_token,
+            Err(err) => {
+                warn!(provider = "wns"; "Failed to get WNS access token: {err:#}");
+                return Ok(fail("auth"));
+            }
+        };
+        let ttl = match kind {
+            NotificationKind::Direct => DIRECT_TTL,
+            NotificationKind::Heartbeat => HEARTBEAT_TTL,
+        };
+        debug!(
+            "Sending WNS {kind:?} notification to {}.",
+            redact(channel_uri)
+        );
+        let inflight = metrics.inflight_request(NotificationProvider::WNS);
+        let start = Instant::now();
+        let res = retry::send(
+            self.client
+                .post(channel_uri)
+                .body(BODY)
+                .bearer_auth(access_token)
+                .header("Content-Type", "application/octet-stream")
+                .header("X-WNS-Type", "wns/raw")
+                .header("X-WNS-TTL", ttl.as_secs().to_string()),
+            metrics,
+            NotificationProvider::WNS,
+        )
+        .await;
+        inflight.finish(outage::is_response_ok(&res));
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                // Channel URI is a pushable identifier.
+                let err = err.without_url();
+                warn!(provider = "wns"; "Failed to send WNS notification to {}: {err}", redact(channel_uri));
+                metrics.count_failure(FailureLabels::new(NotificationProvider::WNS, "send", ""));
+                return Err(err.into());
+            }
+        };
+        let status = res.status();
+        debug!("WNS responded with {status} in {:?}.", start.elapsed());
+        if status == StatusCode::UNAUTHORIZED {
+            // Request a new token for the next notification.
+            *self.access_token.lock().await = None;
+        }
+        let mut delivery = Delivery::from(response_status(status));
+        if delivery.status.is_success() {
+            debug!(
+                "Delivered notification to WNS channel {}.",
+                redact(channel_uri)
+            );
+            metrics.wns_notifications_total.inc();
+        } else {
+            let description = res
+                .headers()
+                .get("X-WNS-Error-Description")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            warn!(provider = "wns", status = status.as_u16(); "Failed to deliver WNS notification to {}: {description}", redact(channel_uri));
+            delivery.failure = Some(
+                FailureLabels::new(NotificationProvider::WNS, status.as_u16().to_string(), "")
+                    .with_status(status.as_u16()),
+            );
+        }
+        Ok(delivery)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_channel_uri() {
+        assert!(check_channel_uri("https://db5p.notify.windows.com/?token=AwYAAAB").is_ok());
+        assert!(check_channel_uri("http://db5p.notify.windows.com/?token=AwYAAAB").is_err());
+        assert!(check_channel_uri("https://notify.windows.com.example.org/").is_err());
+        assert!(check_channel_uri("https://example.org/?notify.windows.com").is_err());
+        assert!(check_channel_uri("db5p.notify.windows.com").is_err());
+    }
+
+    #[test]
+    fn test_response_status() {
+        assert_eq!(response_status(StatusCode::OK), StatusCode::OK);
+        assert_eq!(response_status(StatusCode::NOT_FOUND), StatusCode::GONE);
+        assert_eq!(response_status(StatusCode::GONE), StatusCode::GONE);
+        assert_eq!(
+            response_status(StatusCode::NOT_ACCEPTABLE),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            response_status(StatusCode::UNAUTHORIZED),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}